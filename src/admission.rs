@@ -0,0 +1,56 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// 同時にEstablishedにできるpeerの数を制限する。全てのpeerで共有して用いる。
+#[derive(Debug, Default)]
+pub struct AdmissionControl {
+    /// Noneの場合は制限しない。
+    max_peers: Option<usize>,
+    established: AtomicUsize,
+}
+
+impl AdmissionControl {
+    pub fn new(max_peers: Option<usize>) -> Self {
+        Self {
+            max_peers,
+            established: AtomicUsize::new(0),
+        }
+    }
+
+    /// 現在Establishedのpeerの数。
+    pub fn established(&self) -> usize {
+        self.established.load(Ordering::SeqCst)
+    }
+
+    /// これ以上のpeerをEstablishedにできない場合はtrue。
+    pub fn is_full(&self) -> bool {
+        self.max_peers
+            .is_some_and(|max_peers| self.established() >= max_peers)
+    }
+
+    /// 上限に達していなければEstablishedのpeerとして数え、そのticketを返す。
+    /// ticketをdropすると数から除かれる。
+    pub fn try_admit(self: &Arc<Self>) -> Option<AdmissionTicket> {
+        self.established
+            .fetch_update(
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+                |established| match self.max_peers {
+                    Some(max_peers) if established >= max_peers => None,
+                    _ => Some(established + 1),
+                },
+            )
+            .ok()?;
+        Some(AdmissionTicket(Arc::clone(self)))
+    }
+}
+
+/// EstablishedのpeerがAdmissionControlに数えられている間、保持するticket。
+#[derive(Debug)]
+pub struct AdmissionTicket(Arc<AdmissionControl>);
+
+impl Drop for AdmissionTicket {
+    fn drop(&mut self) {
+        self.0.established.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -1,22 +1,41 @@
-use crate::error::ConvertBytesToBgpMessageError;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
 
-#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
-pub struct AutonomousSystemNumber(u16);
+use anyhow::Context;
+use bytes::{BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
 
+use crate::error::{ConfigParseError, ConvertBytesToBgpMessageError};
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct AutonomousSystemNumber(u32);
+
+impl AutonomousSystemNumber {
+    /// 4 octetのAS番号を2 octetで表現できないときに代わりに用いるAS番号(RFC 6793)。
+    pub const AS_TRANS: AutonomousSystemNumber = AutonomousSystemNumber(23456);
+}
+
+impl From<AutonomousSystemNumber> for u32 {
+    fn from(as_number: AutonomousSystemNumber) -> u32 {
+        as_number.0
+    }
+}
+
+/// 2 octetのAS番号への変換。2 octetで表現できない場合はAS_TRANSになる。
 impl From<AutonomousSystemNumber> for u16 {
     fn from(as_number: AutonomousSystemNumber) -> u16 {
-        as_number.0
+        u16::try_from(as_number.0).unwrap_or(AutonomousSystemNumber::AS_TRANS.0 as u16)
     }
 }
 
-impl From<u16> for AutonomousSystemNumber {
-    fn from(as_number: u16) -> Self {
+impl From<u32> for AutonomousSystemNumber {
+    fn from(as_number: u32) -> Self {
         Self(as_number)
     }
 }
 
-
-#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord, Default)]
 pub struct HoldTime(u16);
 
 impl From<HoldTime> for u16 {
@@ -31,12 +50,6 @@ impl From<u16> for HoldTime {
     }
 }
 
-impl Default for HoldTime {
-    fn default() -> Self {
-        HoldTime(0)
-    }
-}
-
 impl HoldTime {
     pub fn new() -> Self {
         Default::default()
@@ -78,3 +91,100 @@ impl Version {
         Default::default()
     }
 }
+
+/// VPNv4経路のRoute Distinguisher(RFC 4364 Section 4.2)。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum RouteDistinguisher {
+    Type0 { admin: u16, assigned: u32 },
+    Type1 { admin: Ipv4Addr, assigned: u16 },
+    Type2 { admin: u32, assigned: u16 },
+}
+
+impl FromStr for RouteDistinguisher {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (admin, assigned) = s
+            .split_once(':')
+            .context(format!("cannot parse {s} as route distinguisher"))?;
+        if let Ok(admin) = admin.parse::<Ipv4Addr>() {
+            let assigned = assigned
+                .parse()
+                .context(format!("cannot parse assigned number of {s}"))?;
+            return Ok(RouteDistinguisher::Type1 { admin, assigned });
+        }
+        let admin: u32 = admin
+            .parse()
+            .context(format!("cannot parse administrator of {s}"))?;
+        match u16::try_from(admin) {
+            Ok(admin) => Ok(RouteDistinguisher::Type0 {
+                admin,
+                assigned: assigned
+                    .parse()
+                    .context(format!("cannot parse assigned number of {s}"))?,
+            }),
+            Err(_) => Ok(RouteDistinguisher::Type2 {
+                admin,
+                assigned: assigned
+                    .parse()
+                    .context(format!("cannot parse assigned number of {s}"))?,
+            }),
+        }
+    }
+}
+
+impl From<&RouteDistinguisher> for BytesMut {
+    fn from(rd: &RouteDistinguisher) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        match rd {
+            RouteDistinguisher::Type0 { admin, assigned } => {
+                bytes.put_u16(0);
+                bytes.put_u16(*admin);
+                bytes.put_u32(*assigned);
+            }
+            RouteDistinguisher::Type1 { admin, assigned } => {
+                bytes.put_u16(1);
+                bytes.put(&admin.octets()[..]);
+                bytes.put_u16(*assigned);
+            }
+            RouteDistinguisher::Type2 { admin, assigned } => {
+                bytes.put_u16(2);
+                bytes.put_u32(*admin);
+                bytes.put_u16(*assigned);
+            }
+        }
+        bytes
+    }
+}
+
+impl TryFrom<&[u8]> for RouteDistinguisher {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() != 8 {
+            return Err(Self::Error::from(anyhow::anyhow!(
+                "Route Distinguisherは8 bytesが期待されていますが、{} bytesが渡されました。",
+                bytes.len()
+            )));
+        }
+        let type_ = u16::from_be_bytes([bytes[0], bytes[1]]);
+        match type_ {
+            0 => Ok(RouteDistinguisher::Type0 {
+                admin: u16::from_be_bytes([bytes[2], bytes[3]]),
+                assigned: u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            }),
+            1 => Ok(RouteDistinguisher::Type1 {
+                admin: Ipv4Addr::new(bytes[2], bytes[3], bytes[4], bytes[5]),
+                assigned: u16::from_be_bytes([bytes[6], bytes[7]]),
+            }),
+            2 => Ok(RouteDistinguisher::Type2 {
+                admin: u32::from_be_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]),
+                assigned: u16::from_be_bytes([bytes[6], bytes[7]]),
+            }),
+            _ => Err(Self::Error::from(anyhow::anyhow!(
+                "Route Distinguisherのtypeは0-2が期待されていますが、{} が渡されました。",
+                type_
+            ))),
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use bytes::{BufMut, BytesMut};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+use crate::bgp_type::AutonomousSystemNumber;
+use crate::event::Event;
+use crate::packets::notification::NotificationMessage;
+use crate::packets::open::OpenMessage;
+use crate::packets::update::UpdateMessage;
+
+/// BMPのversion(RFC 7854 Section 4.1)。
+const BMP_VERSION: u8 = 3;
+/// Common Headerの長さ。version, message length, message type。
+const COMMON_HEADER_LENGTH: usize = 6;
+/// Per-Peer Headerの長さ(RFC 7854 Section 4.2)。
+pub const PER_PEER_HEADER_LENGTH: usize = 42;
+/// Global Instance Peer。VRFなどを区別しない通常のpeer。
+const GLOBAL_INSTANCE_PEER: u8 = 0;
+/// Initiation MessageのsysName TLV(RFC 7854 Section 4.4)。
+const INFORMATION_TYPE_SYS_NAME: u16 = 2;
+
+/// BMP Messageの種類(RFC 7854 Section 4.1)。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum BmpMessageType {
+    RouteMonitoring = 0,
+    PeerDown = 2,
+    PeerUp = 3,
+    Initiation = 4,
+}
+
+/// BMP MessageのPer-Peer Headerで表すpeer。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub struct BmpPeer {
+    pub address: Ipv4Addr,
+    pub as_number: AutonomousSystemNumber,
+    /// 相手のBGP Identifier。OPENを受信する前は0.0.0.0になる。
+    pub bgp_identifier: Ipv4Addr,
+}
+
+/// Peer Down Notificationの理由(RFC 7854 Section 4.9)。
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum PeerDownReason {
+    /// 自身がセッションを閉じた。契機のFSM Event(RFC 4271 Section 8.1)を伝える。
+    LocalClosed(Event),
+    /// 相手からNOTIFICATIONを受信してセッションを閉じた。
+    RemoteNotification(NotificationMessage),
+    /// 相手がNOTIFICATIONを送らずに接続を閉じた。
+    RemoteClosed,
+}
+
+impl PeerDownReason {
+    /// eventを契機にセッションを閉じた場合の理由。
+    pub fn from_event(event: &Event) -> Self {
+        match event {
+            Event::NotifMsg(notification) => Self::RemoteNotification(notification.clone()),
+            Event::TcpConnectionFails => Self::RemoteClosed,
+            event => Self::LocalClosed(event.clone()),
+        }
+    }
+
+    fn code(&self) -> u8 {
+        match self {
+            Self::LocalClosed(_) => 2,
+            Self::RemoteNotification(_) => 3,
+            Self::RemoteClosed => 4,
+        }
+    }
+}
+
+/// RFC 4271 Section 8.1のEvent番号。自身の実装だけのeventは0にする。
+fn fsm_event_code(event: &Event) -> u16 {
+    match event {
+        Event::ManualStart => 1,
+        Event::ManualStop => 2,
+        Event::ConnectRetryTimerExpires => 9,
+        Event::HoldTimerExpires => 10,
+        Event::KeepaliveTimerExpires => 11,
+        Event::TcpConnectionConfirmed => 17,
+        Event::TcpConnectionFails => 18,
+        Event::BgpOpen(_) => 19,
+        Event::BgpHeaderErr => 21,
+        Event::BgpOpenMsgErr => 22,
+        Event::NotifMsg(_) => 25,
+        Event::KeepAliveMsg(_) => 26,
+        Event::UpdateMsg(_) => 27,
+        Event::UpdateMsgErr => 28,
+        _ => 0,
+    }
+}
+
+/// BMP collectorへセッションの状態と受信した経路を送る(RFC 7854)。
+/// 全てのpeerで共有し、送信は1つの接続に順番に書き込む。
+#[derive(Debug)]
+pub struct BmpClient {
+    stream: Mutex<TcpStream>,
+}
+
+impl BmpClient {
+    /// collectorに接続し、Initiation Messageを送る。
+    pub async fn connect(collector: SocketAddr) -> Result<Self> {
+        let stream = TcpStream::connect(collector)
+            .await
+            .context(format!("cannot connect to bmp collector {collector}"))?;
+        let client = Self {
+            stream: Mutex::new(stream),
+        };
+        let sys_name = env!("CARGO_PKG_NAME").as_bytes();
+        let mut body = BytesMut::new();
+        body.put_u16(INFORMATION_TYPE_SYS_NAME);
+        body.put_u16(sys_name.len() as u16);
+        body.put(sys_name);
+        client.send(BmpMessageType::Initiation, body).await?;
+        Ok(client)
+    }
+
+    /// セッションがEstablishedになったことを、交換したOPENとともに送る。
+    pub async fn peer_up(
+        &self,
+        peer: &BmpPeer,
+        local: SocketAddr,
+        remote: SocketAddr,
+        sent_open: &OpenMessage,
+        received_open: &OpenMessage,
+    ) -> Result<()> {
+        let mut body = per_peer_header(peer);
+        put_address(&mut body, local);
+        body.put_u16(local.port());
+        body.put_u16(remote.port());
+        body.put::<BytesMut>(sent_open.clone().into());
+        body.put::<BytesMut>(received_open.clone().into());
+        self.send(BmpMessageType::PeerUp, body).await
+    }
+
+    /// 受信したUPDATEをそのまま包んで送る。
+    pub async fn route_monitoring(&self, peer: &BmpPeer, update: &UpdateMessage) -> Result<()> {
+        let mut body = per_peer_header(peer);
+        body.put::<BytesMut>(update.clone().into());
+        self.send(BmpMessageType::RouteMonitoring, body).await
+    }
+
+    /// Establishedだったセッションが閉じたことを送る。
+    pub async fn peer_down(&self, peer: &BmpPeer, reason: &PeerDownReason) -> Result<()> {
+        let mut body = per_peer_header(peer);
+        body.put_u8(reason.code());
+        match reason {
+            PeerDownReason::LocalClosed(event) => body.put_u16(fsm_event_code(event)),
+            PeerDownReason::RemoteNotification(notification) => {
+                body.put::<BytesMut>(notification.clone().into())
+            }
+            PeerDownReason::RemoteClosed => {}
+        }
+        self.send(BmpMessageType::PeerDown, body).await
+    }
+
+    async fn send(&self, message_type: BmpMessageType, body: BytesMut) -> Result<()> {
+        let mut bytes = BytesMut::with_capacity(COMMON_HEADER_LENGTH + body.len());
+        bytes.put_u8(BMP_VERSION);
+        bytes.put_u32((COMMON_HEADER_LENGTH + body.len()) as u32);
+        bytes.put_u8(message_type as u8);
+        bytes.put(body);
+        self.stream
+            .lock()
+            .await
+            .write_all(&bytes)
+            .await
+            .context("cannot send bmp message")
+    }
+}
+
+/// Per-Peer Header。IPv4のaddressは16 octetの末尾4 octetに置く。
+fn per_peer_header(peer: &BmpPeer) -> BytesMut {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut bytes = BytesMut::with_capacity(PER_PEER_HEADER_LENGTH);
+    bytes.put_u8(GLOBAL_INSTANCE_PEER);
+    // Peer Flags。IPv4なのでV flagは立てない。
+    bytes.put_u8(0);
+    // Peer Distinguisher。Global Instance Peerでは0。
+    bytes.put_u64(0);
+    bytes.put_bytes(0, 12);
+    bytes.put_slice(&peer.address.octets());
+    bytes.put_u32(peer.as_number.into());
+    bytes.put_slice(&peer.bgp_identifier.octets());
+    bytes.put_u32(timestamp.as_secs() as u32);
+    bytes.put_u32(timestamp.subsec_micros());
+    bytes
+}
+
+/// 16 octetのaddress。IPv4の場合は末尾4 octetに置く。
+fn put_address(bytes: &mut BytesMut, address: SocketAddr) {
+    match address {
+        SocketAddr::V4(address) => {
+            bytes.put_bytes(0, 12);
+            bytes.put_slice(&address.ip().octets());
+        }
+        SocketAddr::V6(address) => bytes.put_slice(&address.ip().octets()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_peer_header_places_ipv4_address_at_end_of_16_octets() {
+        let peer = BmpPeer {
+            address: Ipv4Addr::new(10, 200, 100, 3),
+            as_number: 64513.into(),
+            bgp_identifier: Ipv4Addr::new(10, 200, 100, 3),
+        };
+        let header = per_peer_header(&peer);
+        assert_eq!(header.len(), PER_PEER_HEADER_LENGTH);
+        assert_eq!(&header[..2], &[GLOBAL_INSTANCE_PEER, 0]);
+        assert_eq!(&header[10..22], &[0; 12]);
+        assert_eq!(&header[22..26], &[10, 200, 100, 3]);
+        assert_eq!(&header[26..30], &64513u32.to_be_bytes());
+        assert_eq!(&header[30..34], &[10, 200, 100, 3]);
+    }
+}
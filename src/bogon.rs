@@ -0,0 +1,97 @@
+use std::str::FromStr;
+
+use anyhow::anyhow;
+
+use crate::error::ConfigParseError;
+use crate::routing::Ipv4Network;
+
+/// 経路を受け入れてはならない、特別な用途のprefix(RFC 6890)。これらに含まれるprefixは常に捨てる。
+const MARTIANS: [&str; 5] = [
+    "0.0.0.0/8",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+];
+
+/// RFC 1918のprivate address。`rfc1918`を指定した場合に捨てる。
+const PRIVATE_ADDRESSES: [&str; 3] = ["10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16"];
+
+/// 受信した経路のうち、bogon/martianのprefixを捨てる規則。
+///
+/// `bogon_filter=martians,rfc1918,default,le24`のように、カンマで区切って書く。
+/// martianのprefixは常に捨て、`rfc1918`ではprivate addressを、`default`ではdefault routeを、
+/// `le24`では24より長いprefixも捨てる。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord, Default)]
+pub struct BogonFilter {
+    private_addresses: bool,
+    default_route: bool,
+    max_prefix_length: Option<u8>,
+}
+
+impl BogonFilter {
+    /// networkがbogonであればtrueを返す。
+    pub fn is_bogon(&self, network: Ipv4Network) -> bool {
+        let is_within = |prefixes: &[&str]| {
+            prefixes.iter().any(|prefix| {
+                let prefix: Ipv4Network = prefix.parse().expect("prefixは正しいはずです。");
+                network.is_subnet_of(*prefix)
+            })
+        };
+        is_within(&MARTIANS)
+            || (self.private_addresses && is_within(&PRIVATE_ADDRESSES))
+            || (self.default_route && network.prefix() == 0)
+            || self
+                .max_prefix_length
+                .is_some_and(|max| network.prefix() > max)
+    }
+}
+
+impl FromStr for BogonFilter {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = Self::default();
+        for item in s.split(',') {
+            match item {
+                "martians" => {}
+                "rfc1918" => filter.private_addresses = true,
+                "default" => filter.default_route = true,
+                _ => match item.strip_prefix("le").map(str::parse) {
+                    Some(Ok(max)) if max <= 32 => filter.max_prefix_length = Some(max),
+                    _ => {
+                        return Err(anyhow!("unknown item `{item}` in bogon filter {s}").into());
+                    }
+                },
+            }
+        }
+        Ok(filter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bogon_filter_matches_configured_prefixes() {
+        let filter: BogonFilter = "martians,rfc1918,default,le24".parse().unwrap();
+        for bogon in [
+            "127.0.0.0/8",
+            "127.10.0.0/16",
+            "169.254.1.0/24",
+            "10.100.220.0/24",
+            "0.0.0.0/0",
+            "203.0.113.128/25",
+        ] {
+            assert!(filter.is_bogon(bogon.parse().unwrap()), "{bogon}");
+        }
+        assert!(!filter.is_bogon("203.0.113.0/24".parse().unwrap()));
+
+        let filter: BogonFilter = "martians".parse().unwrap();
+        assert!(!filter.is_bogon("10.100.220.0/24".parse().unwrap()));
+        assert!(!filter.is_bogon("0.0.0.0/0".parse().unwrap()));
+        assert!("martians,le33".parse::<BogonFilter>().is_err());
+        assert!("bogons".parse::<BogonFilter>().is_err());
+    }
+}
@@ -0,0 +1,90 @@
+use std::fmt::Write;
+
+use crate::packets::header::MessageType;
+use crate::packets::open::Capability;
+use crate::path_attribute::{MpReachNlri, PathAttribute};
+
+/// このbuildで有効になっているcargo feature。
+fn enabled_features() -> Vec<&'static str> {
+    let mut features = vec![];
+    if cfg!(feature = "testing") {
+        features.push("testing");
+    }
+    if cfg!(feature = "update-compression") {
+        features.push("update-compression");
+    }
+    features
+}
+
+/// このbuildが対応しているMessage Type, Capability, AFI/SAFI, Path Attributeの一覧。
+/// 実装しているenumのvariantから生成するので、実装と食い違わない。
+pub fn capability_report() -> String {
+    let mut report = String::new();
+    writeln!(
+        report,
+        "{} {}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    )
+    .unwrap();
+
+    writeln!(report, "message types:").unwrap();
+    for type_ in MessageType::supported() {
+        writeln!(report, "  {} ({})", type_.name(), u8::from(type_)).unwrap();
+    }
+
+    writeln!(report, "capabilities:").unwrap();
+    for capability in Capability::supported() {
+        writeln!(report, "  {} ({})", capability.name(), capability.code()).unwrap();
+    }
+
+    writeln!(report, "afi/safi:").unwrap();
+    for (afi, safi, name) in MpReachNlri::supported_afi_safis() {
+        writeln!(report, "  {name} ({afi}/{safi})").unwrap();
+    }
+
+    writeln!(report, "path attributes:").unwrap();
+    for attribute in PathAttribute::supported() {
+        let type_code = attribute
+            .type_code()
+            .expect("対応しているattributeはType Codeを持つはずです。");
+        writeln!(report, "  {} ({type_code})", attribute.name()).unwrap();
+    }
+
+    let features = enabled_features();
+    if features.is_empty() {
+        writeln!(report, "features: (none)").unwrap();
+    } else {
+        writeln!(report, "features: {}", features.join(", ")).unwrap();
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capability_report_lists_implemented_message_types() {
+        let report = capability_report();
+        for message_type in [
+            "OPEN (1)",
+            "UPDATE (2)",
+            "NOTIFICATION (3)",
+            "KEEPALIVE (4)",
+            "ROUTE-REFRESH (5)",
+        ] {
+            assert!(
+                report.contains(message_type),
+                "{message_type} is not in\n{report}"
+            );
+        }
+        assert!(report.contains("Enhanced Route Refresh (70)"));
+        assert!(report.contains("VPNv4 (1/128)"));
+        assert!(report.contains("AS4_PATH (17)"));
+        assert_eq!(
+            report.contains("update-compression"),
+            cfg!(feature = "update-compression")
+        );
+    }
+}
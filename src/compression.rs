@@ -0,0 +1,91 @@
+//! 双方がこのdaemonの場合に限り、UPDATEのbodyをzstdで圧縮して送受信する実験的な拡張。
+//!
+//! 双方がOPENでUpdate Compression Capabilityを広告した場合に、connectionが透過的に行う。
+//! 圧縮したbodyはzstdのframeのmagic numberから始まる。圧縮していないUPDATEのbodyは
+//! Withdrawn Routes Lengthから始まり、その値がmagic numberの先頭2 bytesになることは無いので、
+//! 圧縮しても小さくならないUPDATEは圧縮せずにそのまま送る。
+
+use anyhow::{Context, Result};
+use bytes::{BufMut, BytesMut};
+
+use crate::packets::header::MessageType;
+
+/// BGP Message Headerの長さ。
+const HEADER_LENGTH: usize = 19;
+/// zstdのframeの先頭4 bytes。
+const ZSTD_MAGIC_NUMBER: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const COMPRESSION_LEVEL: i32 = 3;
+
+fn is_update(bytes: &[u8]) -> bool {
+    bytes.len() > HEADER_LENGTH && bytes[18] == u8::from(MessageType::Update)
+}
+
+/// Messageのbyte列がUPDATEであれば、bodyを圧縮する。
+/// UPDATE以外のMessageと、圧縮しても小さくならないUPDATEはそのまま返す。
+pub fn compress_update(bytes: BytesMut) -> BytesMut {
+    if !is_update(&bytes) {
+        return bytes;
+    }
+    let body = match zstd::bulk::compress(&bytes[HEADER_LENGTH..], COMPRESSION_LEVEL) {
+        Ok(body) if body.len() < bytes.len() - HEADER_LENGTH => body,
+        _ => return bytes,
+    };
+    let mut compressed = BytesMut::with_capacity(HEADER_LENGTH + body.len());
+    compressed.put(&bytes[..16]);
+    compressed.put_u16((HEADER_LENGTH + body.len()) as u16);
+    compressed.put_u8(bytes[18]);
+    compressed.put(&body[..]);
+    compressed
+}
+
+/// 圧縮したUPDATEのbyte列であれば、bodyを展開する。それ以外はそのまま返す。
+pub fn decompress_update(bytes: BytesMut) -> Result<BytesMut> {
+    if !is_update(&bytes) || !bytes[HEADER_LENGTH..].starts_with(&ZSTD_MAGIC_NUMBER) {
+        return Ok(bytes);
+    }
+    let body = zstd::stream::decode_all(&bytes[HEADER_LENGTH..])
+        .context("cannot decompress update message")?;
+    let length = u16::try_from(HEADER_LENGTH + body.len())
+        .context("decompressed update message is too long")?;
+    let mut decompressed = BytesMut::with_capacity(HEADER_LENGTH + body.len());
+    decompressed.put(&bytes[..16]);
+    decompressed.put_u16(length);
+    decompressed.put_u8(bytes[18]);
+    decompressed.put(&body[..]);
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::packets::message::Message;
+    use crate::packets::update::UpdateMessage;
+    use crate::routing::Ipv4Network;
+
+    #[test]
+    fn compressed_update_is_smaller_and_decompresses_to_original() {
+        let networks: Vec<Ipv4Network> = (0..1000u32)
+            .map(|i| {
+                format!("10.{}.{}.0/24", 100 + i / 256, i % 256)
+                    .parse()
+                    .unwrap()
+            })
+            .collect();
+        let update = Message::Update(UpdateMessage::new(Arc::new(vec![]), networks, vec![]));
+        let bytes: BytesMut = update.into();
+
+        let compressed = compress_update(bytes.clone());
+        assert!(compressed.len() < bytes.len());
+        assert_eq!(
+            usize::from(u16::from_be_bytes([compressed[16], compressed[17]])),
+            compressed.len()
+        );
+        assert_eq!(decompress_update(compressed).unwrap(), bytes);
+
+        let keepalive: BytesMut = Message::new_keepalive().into();
+        assert_eq!(compress_update(keepalive.clone()), keepalive);
+        assert_eq!(decompress_update(keepalive.clone()).unwrap(), keepalive);
+    }
+}
@@ -1,9 +1,25 @@
 use crate::bgp_type::AutonomousSystemNumber;
+use crate::bogon::BogonFilter;
 use crate::error::ConfigParseError;
+use crate::path_attribute::{AsPath, ExtendedCommunity, Origin, PathAttribute};
+use crate::route_map::RouteMap;
 use crate::routing::Ipv4Network;
 use anyhow::{Context, Result};
-use std::net::Ipv4Addr;
+use rtnetlink::packet::constants::{RTPROT_BOOT, RTPROT_KERNEL, RTPROT_STATIC};
+use std::collections::HashMap;
+use std::env;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// ConnectRetryTimeの既定値(RFC 4271 Section 10)。
+const DEFAULT_CONNECT_RETRY_TIME: Duration = Duration::from_secs(120);
+const DEFAULT_RIB_STORE_STALE_TIME: Duration = Duration::from_secs(120);
+const DEFAULT_TIMER_JITTER: u8 = 10;
+/// neighborの設定の先頭に、この順に並べる値。peer groupではoptionとして書く。
+const POSITIONAL_FIELDS: [&str; 5] = ["local_as", "local_ip", "remote_as", "remote_ip", "mode"];
 
 #[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
 pub struct Config {
@@ -12,13 +28,166 @@ pub struct Config {
     pub remote_as: AutonomousSystemNumber,
     pub remote_ip: Ipv4Addr,
     pub mode: Mode,
-    pub networks: Vec<Ipv4Network>,
+    pub networks: Vec<NetworkStatement>,
+    pub port: u16,
+    pub max_rib_entries: Option<usize>,
+    pub rib_limit_policy: RibLimitPolicy,
+    /// 経路の広告を送る最小間隔(MinRouteAdvertisementIntervalTimer)。
+    /// 経路の取り消しはこの間隔に関わらずすぐに送る。
+    /// `mrai`または`advertisement_interval`で設定する。0の場合は広告もすぐに送る。
+    pub mrai: Duration,
+    /// 設定されている場合、セッションの確立後に最初に送る全ての経路を、この時間に分けて送る。
+    /// 大きな経路表で、送受信の負荷が一度に集中しないようにする。
+    /// `initial_advertisement_window=<秒>`で設定する。
+    pub initial_advertisement_window: Option<Duration>,
+    /// activeモードで接続するときの送信元ポート。
+    /// 設定した場合はSO_REUSEADDR/SO_REUSEPORTを有効にして、複数の接続で共有できるようにする。
+    pub source_port: Option<u16>,
+    /// 接続のSO_SNDBUF, SO_RCVBUF(bytes)。設定しない場合はOSの既定値を用いる。
+    pub send_buffer_size: Option<usize>,
+    pub receive_buffer_size: Option<usize>,
+    /// neighborの説明。ログとstatusに表示する。空白を含めることはできない。
+    pub description: Option<String>,
+    /// 隣接ASが異なる経路の間でもMEDを比較する。
+    pub always_compare_med: bool,
+    /// 隣接ASごとにbest pathを選んでから、それらを比較する。
+    /// 経路を比較する順番によってbest pathが変わらないようになる。
+    pub deterministic_med: bool,
+    /// FIBに書き込むECMPのnext hopの数の上限。1の場合はbest pathだけを書き込む。
+    pub maximum_paths: usize,
+    /// 設定されている場合、いずれかのRoute Targetを持つ経路のみを受け入れる。
+    pub import_route_targets: Vec<ExtendedCommunity>,
+    /// 受信した経路に適用するroute-map。`route_map_in=<file>`で、route-mapを書いたファイルを指定する。
+    pub route_map_in: Option<Arc<RouteMap>>,
+    /// 広告する経路に適用するroute-map。`route_map_out=<file>`で指定する。
+    pub route_map_out: Option<Arc<RouteMap>>,
+    /// 設定されている場合、bogon/martianのprefixへの受信した経路を捨てる。
+    pub bogon_filter: Option<BogonFilter>,
+    /// 設定されている場合、Graceful Restart Capabilityをこのrestart time(秒)で広告する。
+    pub graceful_restart_time: Option<u16>,
+    /// OPENで提案するHold Time(秒)。0の場合はHold Timer, Keepalive Timerを用いない。
+    pub hold_time: u16,
+    /// エラーでIdleに戻った後、再接続を試みるまでの時間(ConnectRetryTime)。
+    pub connect_retry_time: Duration,
+    /// 接続の確立にこの回数続けて失敗すると、NeighborUnreachableの警報を出す。再接続は続ける。
+    pub connect_failure_alarm: Option<u32>,
+    /// 設定されている場合、NOTIFICATIONによる切断の後にも再接続を試みる。再接続までの時間は
+    /// connect_retry_timeから切断が続くたびに倍にし、この時間を上限とする。
+    /// Establishedのままこの時間が経てば、connect_retry_timeに戻す。
+    /// 設定されていない場合、NOTIFICATIONを受信した後は再び`start`されるまでIdleに留まる。
+    pub notification_dampening: Option<Duration>,
+    /// 設定されている場合、エラーなどで自動的にIdleに戻った後、この時間(IdleHoldTime)が経つまでは
+    /// 再接続しない。`idle_hold_time=<秒>`で指定する。
+    pub idle_hold_time: Option<Duration>,
+    /// 設定されている場合、安定して動かないままIdleに戻るたびにIdleHoldTimeを倍にし、
+    /// この時間を上限とする(DampPeerOscillations)。Establishedのままこの時間が経てば数え直す。
+    pub damp_peer_oscillations: Option<Duration>,
+    /// 設定されている場合、LocRibに無くてもdefault route(0.0.0.0/0)を広告する。
+    pub default_originate: Option<DefaultOriginate>,
+    /// 広告する経路のAS_PATH中のremote_asをlocal_asに置き換える(as-override)。
+    pub as_override: bool,
+    /// IBGPのneighborに対して、best pathがIBGPで受信した経路の場合でも、
+    /// EBGPで受信した経路の中で最も優先される経路(best-external)を広告する。
+    pub best_external: bool,
+    /// 広告する全ての経路にGRACEFUL_SHUTDOWN community(RFC 8326)を付け、
+    /// 相手に他の経路へ切り替えてもらう。保守でセッションを止める前に用いる。
+    pub graceful_shutdown: bool,
+    /// 受信した経路のAS_PATHにlocal_asがこの回数まで含まれていても受け入れる(allowas-in)。
+    pub allowas_in: usize,
+    /// Route Refresh Capabilityを広告する。既定で広告し、`route_refresh=false`で止める。
+    pub route_refresh: bool,
+    /// Enhanced Route Refresh Capabilityを広告する。`route_refresh=false`でも、Route Refresh Capabilityを合わせて広告する。
+    pub enhanced_route_refresh: bool,
+    /// IPv4 UnicastとIPv6 UnicastのMultiprotocol Extensions Capabilityを広告し、
+    /// 双方が広告した場合はIPv6 Unicastの経路も送受信する。
+    pub ipv6_unicast: bool,
+    /// 実験的な機能。Update Compression Capabilityを広告し、双方が広告した場合は
+    /// UPDATEのbodyを圧縮して送受信する。`update-compression` featureが無効の場合は何もしない。
+    pub update_compression: bool,
+    /// 自身が広告元の経路を、ここで指定したneighborにだけ広告する。
+    /// 指定の無いnetworkは全てのneighborに広告する。
+    pub origination_scopes: Vec<OriginationScope>,
+    /// LocRibに監視するprefixがあるかどうかで、経路を広告するかどうかを切り替える。
+    pub conditional_advertisements: Vec<ConditionalAdvertisement>,
+    /// LocRibにあるより長いprefixの経路を集約して、networkへの経路を広告する。
+    pub aggregate_addresses: Vec<AggregateAddress>,
+    /// trueの場合、neighborを管理上停止し、セッションを確立しない。
+    pub admin_shutdown: bool,
+    /// 広告する経路のNEXT_HOPをlocal_ipに書き換えるかどうか。
+    /// 設定されていない場合、EBGPでは書き換え、IBGPでは受信したNEXT_HOPのまま広告する。
+    pub next_hop_self: Option<bool>,
+    /// 同時にEstablishedにできるpeerの数の上限。
+    /// 上限に達した後の接続はCease(Connection Rejected)で拒否する。
+    pub max_peers: Option<usize>,
+    /// このrangeの中のaddressからの接続を受け付け、このconfigを雛形とするneighborを動的に作る。
+    /// passiveでのみ用いることができ、remote_ipは用いない。
+    pub listen_range: Option<Ipv4Network>,
+    /// listen_rangeから同時に作るneighborの数の上限。上限に達した後の接続は閉じる。
+    pub listen_limit: Option<usize>,
+    /// 1回の`Peer::next`で受信経路を処理する数の上限。大量の経路を受信しても
+    /// KEEPALIVEの送信やtimerの処理が滞らないよう、残りは次の`next`に持ち越す。Noneの場合は上限なし。
+    pub max_routes_per_iteration: Option<usize>,
+    /// 受信した経路の取り消しでbest pathが無くなる場合は、LocRibとFIBへの反映を後回しにせず、
+    /// 同じ`Peer::next`で次善の経路へ切り替える。
+    pub fast_reroute: bool,
+    /// 同じconfederationに属する他のmember AS。
+    /// これらのneighborにはconfederationのsegmentを残したまま広告する。
+    pub confederation_peers: Vec<AutonomousSystemNumber>,
+    /// confederationの外から見えるAS番号(RFC 5065)。local_asはconfederation内のmember ASになる。
+    /// confederationの外のneighborとは、OPENやAS_PATHでlocal_asの代わりにこれを用いる。
+    pub confederation_id: Option<AutonomousSystemNumber>,
+    /// 受信した経路をpolicyの適用前のまま保持し、相手に再送を求めずに受信側のpolicyを適用し直せるようにする。
+    pub soft_reconfiguration_inbound: bool,
+    /// policyの適用前の経路を保持する数の上限。超えた場合はsoft reconfigurationをやめ、Route Refreshを用いる。
+    pub soft_reconfiguration_limit: Option<usize>,
+    /// Keepalive TimerとMRAIの間隔を、この割合(%)の範囲でランダムに増減させる。
+    /// 多くのpeerの送信が同じ時刻に揃わないようにする。
+    pub timer_jitter: u8,
+    /// neighborから受信する経路の数の上限。超えた場合はCease(Maximum Number of Prefixes Reached)で
+    /// セッションを閉じる。
+    pub max_prefix: Option<usize>,
+    /// `max_prefix`を超えてセッションを閉じた後、再接続を試みるまでIdleに留まる時間。
+    /// 設定されていない場合は、再び`start`されるまでIdleに留まる。
+    pub max_prefix_restart: Option<Duration>,
+    /// trueの場合、`max_prefix`を超えても警告するだけでセッションを閉じない。
+    pub max_prefix_warning_only: bool,
+    /// カーネルの経路に関わらず広告する経路。AS_PATHなどのattributeを指定できる。
+    pub static_routes: Vec<StaticRoute>,
+    /// network statementに関わらず、このprotocolのカーネルの経路を全て広告する。
+    pub redistribute: Vec<RedistributeProtocol>,
+    /// 自身が広告元の経路のORIGIN。network statementやstatic routeで指定しなかった場合に用いる。
+    pub default_origin: Origin,
+    /// origin validationに用いるROAのJSONファイル。設定されていない場合は検証しない。
+    pub roa_file: Option<PathBuf>,
+    /// origin validationでInvalidになった経路の扱い。
+    pub invalid_route_policy: InvalidRoutePolicy,
+    /// 設定されている場合、自身が広告元の経路と同じprefixの経路を受信したときの扱い。
+    /// AS_PATHによるloopの検出とは別に、経路の折り返しや設定の誤りを見つけるのに用いる。
+    pub own_prefix_reaction: Option<OwnPrefixReaction>,
+    /// 設定されている場合、このBMP collectorにセッションの状態と受信した経路を送る。
+    pub bmp_collector: Option<SocketAddr>,
+    /// 設定されている場合、起動してから全てのpeerが収束するか、この時間が経つまで
+    /// 転送テーブルに書き込まず、その後にまとめて書き込む。
+    pub fib_barrier_time: Option<Duration>,
+    /// 設定されている場合、転送テーブルの経路の数が前回の書き込みからこの割合(%)を超えて
+    /// 減るような書き込みを拒否する。少数の経路しか受信できなかった場合などに、
+    /// 転送テーブルを不完全なものに置き換えないためのもの。設定しなければ確認しない。
+    pub fib_max_shrink_percent: Option<u8>,
+    /// 設定されている場合、終了時にLocRibと各neighborのAdj-RIB-Inをこのdirectoryに書き出し、
+    /// 次の起動時にstaleとして読み込む。
+    pub rib_store: Option<PathBuf>,
+    /// rib_storeから読み込んだ経路を、受信し直すまで保持する時間。
+    pub rib_store_stale_time: Duration,
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
 pub enum Mode {
     Passive,
     Active,
+    /// 相手からの接続をこの時間だけ待ち、届かなければ自分から接続する。
+    /// NATやfirewallで、一方向からしか接続できない場合に用いる。
+    /// `passive_then_active:30`のように、待つ時間を秒で書く。
+    PassiveThenActive(Duration),
 }
 
 impl FromStr for Mode {
@@ -27,17 +196,656 @@ impl FromStr for Mode {
         match s {
             "passive" | "Passive" => Ok(Mode::Passive),
             "active" | "Active" => Ok(Mode::Active),
+            _ => match s.split_once(':') {
+                Some(("passive_then_active" | "PassiveThenActive", timeout)) => {
+                    let timeout = timeout.parse().context(format!(
+                        "cannot parse passive_then_active timeout, `{timeout}`, as u64"
+                    ))?;
+                    Ok(Mode::PassiveThenActive(Duration::from_secs(timeout)))
+                }
+                _ => Err(ConfigParseError::from(anyhow::anyhow!("cannot parse {s}"))),
+            },
+        }
+    }
+}
+
+/// 広告するネットワーク。`10.100.0.0/16`、`10.100.0.0/16:cover`、
+/// `10.100.0.0/16:cover:incomplete`のように、照合方法とORIGINを続けて書ける。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct NetworkStatement {
+    pub network: Ipv4Network,
+    pub match_mode: NetworkMatch,
+    /// 広告する経路のORIGIN。指定しない場合は`default_origin`を用いる。
+    pub origin: Option<Origin>,
+}
+
+impl NetworkStatement {
+    /// kernelの経路`destination`をこのnetwork statementで広告するならtrueを返す。
+    pub fn matches(&self, destination: Ipv4Network) -> bool {
+        match self.match_mode {
+            NetworkMatch::Exact => destination == self.network,
+            NetworkMatch::Cover => destination.is_subnet_of(*self.network),
+        }
+    }
+}
+
+impl From<Ipv4Network> for NetworkStatement {
+    fn from(network: Ipv4Network) -> Self {
+        Self {
+            network,
+            match_mode: NetworkMatch::default(),
+            origin: None,
+        }
+    }
+}
+
+impl FromStr for NetworkStatement {
+    type Err = ConfigParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split(':');
+        let network: Ipv4Network = fields
+            .next()
+            .context(format!("cannot parse {s} as network statement"))?
+            .parse()?;
+        let mut statement = Self::from(network);
+        for field in fields {
+            match field {
+                "igp" | "egp" | "incomplete" => statement.origin = Some(field.parse()?),
+                _ => statement.match_mode = field.parse()?,
+            }
+        }
+        Ok(statement)
+    }
+}
+
+/// network statementとkernelの経路の照合方法。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord, Default)]
+pub enum NetworkMatch {
+    /// network statementと同じprefixの経路のみを広告する。
+    #[default]
+    Exact,
+    /// network statementに含まれる全ての経路を広告する。
+    Cover,
+}
+
+impl FromStr for NetworkMatch {
+    type Err = ConfigParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exact" => Ok(NetworkMatch::Exact),
+            "cover" => Ok(NetworkMatch::Cover),
+            _ => Err(ConfigParseError::from(anyhow::anyhow!("cannot parse {s}"))),
+        }
+    }
+}
+
+/// default routeを広告する条件。
+/// `default_originate=always`または`default_originate=10.100.0.0/16`のように書く。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum DefaultOriginate {
+    /// 常に広告する。
+    Always,
+    /// 指定したnetworkへの経路がLocRibにある場合のみ広告する。
+    IfPresent(Ipv4Network),
+}
+
+impl FromStr for DefaultOriginate {
+    type Err = ConfigParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(DefaultOriginate::Always),
+            _ => Ok(DefaultOriginate::IfPresent(s.parse()?)),
+        }
+    }
+}
+
+/// 自身が広告元の経路を広告するneighborの範囲。
+/// `originate_to=10.100.0.0/16@64514`または`originate_to=10.100.0.0/16@10.200.100.4`のように書く。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct OriginationScope {
+    pub network: Ipv4Network,
+    pub neighbor: Neighbor,
+}
+
+/// 指定したattributeで広告する経路。受信した経路を模擬するのに用いる。
+/// `static_route=10.100.240.0/24:as_path=65001,65002:origin=egp:med=10`のように書く。
+/// 指定しないattributeは、ORIGINは`default_origin`、AS_PATHは空、MEDは無しになる。
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+pub struct StaticRoute {
+    pub network: Ipv4Network,
+    pub as_path: Vec<AutonomousSystemNumber>,
+    pub origin: Option<Origin>,
+    pub med: Option<u32>,
+}
+
+impl StaticRoute {
+    /// next_hopを自身として、この経路のPath Attributeを作る。
+    /// ORIGINを指定していなければdefault_originを用いる。
+    pub fn path_attributes(
+        &self,
+        next_hop: Ipv4Addr,
+        default_origin: Origin,
+    ) -> Vec<PathAttribute> {
+        let mut path_attributes = vec![
+            PathAttribute::Origin(self.origin.unwrap_or(default_origin)),
+            PathAttribute::AsPath(AsPath::sequence(self.as_path.clone())),
+            PathAttribute::NextHop(next_hop),
+        ];
+        if let Some(med) = self.med {
+            path_attributes.push(PathAttribute::MultiExitDisc(med));
+        }
+        path_attributes
+    }
+}
+
+impl FromStr for StaticRoute {
+    type Err = ConfigParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split(':');
+        let network = fields
+            .next()
+            .context(format!("cannot parse {s} as static route"))?
+            .parse()?;
+        let mut route = Self {
+            network,
+            as_path: vec![],
+            origin: None,
+            med: None,
+        };
+        for field in fields {
+            match field.split_once('=') {
+                Some(("as_path", v)) => {
+                    route.as_path = v
+                        .split(',')
+                        .filter(|as_number| !as_number.is_empty())
+                        .map(|as_number| {
+                            as_number
+                                .parse::<u32>()
+                                .map(AutonomousSystemNumber::from)
+                                .context(format!("cannot parse {as_number} in as_path of {s}"))
+                        })
+                        .collect::<Result<_>>()?
+                }
+                Some(("origin", v)) => route.origin = Some(v.parse()?),
+                Some(("med", v)) => {
+                    route.med = Some(
+                        v.parse()
+                            .context(format!("cannot parse med, `{v}`, of {s}"))?,
+                    )
+                }
+                _ => {
+                    return Err(ConfigParseError::from(anyhow::anyhow!(
+                        "unknown field `{field}` in static route {s}"
+                    )))
+                }
+            }
+        }
+        Ok(route)
+    }
+}
+
+/// LocRibに`monitored`のbest pathがあるかどうかを条件として、`network`を広告する。
+/// 主経路が無くなったときだけ予備の経路を広告するのに用いる。
+/// `advertise_if=10.100.221.0/24:absent=10.100.220.0/24`のように書く。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct ConditionalAdvertisement {
+    pub network: Ipv4Network,
+    pub condition: AdvertiseCondition,
+    pub monitored: Ipv4Network,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum AdvertiseCondition {
+    Present,
+    Absent,
+}
+
+impl ConditionalAdvertisement {
+    /// monitoredのbest pathの有無で、networkを広告してよいか判断する。
+    pub fn is_satisfied(&self, monitored_exists: bool) -> bool {
+        match self.condition {
+            AdvertiseCondition::Present => monitored_exists,
+            AdvertiseCondition::Absent => !monitored_exists,
+        }
+    }
+}
+
+impl FromStr for ConditionalAdvertisement {
+    type Err = ConfigParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, condition) = s
+            .split_once(':')
+            .context(format!("cannot parse {s} as network:condition=network"))?;
+        let condition = match condition.split_once('=') {
+            Some(("present", monitored)) => (AdvertiseCondition::Present, monitored),
+            Some(("absent", monitored)) => (AdvertiseCondition::Absent, monitored),
+            _ => {
+                return Err(ConfigParseError::from(anyhow::anyhow!(
+                    "unknown condition `{condition}` in {s}, expected present=NETWORK or absent=NETWORK"
+                )))
+            }
+        };
+        Ok(Self {
+            network: network.parse()?,
+            condition: condition.0,
+            monitored: condition.1.parse()?,
+        })
+    }
+}
+
+/// より長いprefixの経路を集約した経路。集約した経路はFIBには書き込まない。
+/// `aggregate_address=10.100.0.0/16:as-set`のように書く。
+/// `as-set`を付けると、集約した経路のAS_PATHの全てのASをAS_SETとして持たせる。
+/// 付けない場合はAS_PATHを空にし、ATOMIC_AGGREGATEを付ける。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub struct AggregateAddress {
+    pub network: Ipv4Network,
+    pub as_set: bool,
+}
+
+impl FromStr for AggregateAddress {
+    type Err = ConfigParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, as_set) = match s.split_once(':') {
+            None => (s, false),
+            Some((network, "as-set")) => (network, true),
+            Some((_, option)) => {
+                return Err(ConfigParseError::from(anyhow::anyhow!(
+                    "unknown option `{option}` in aggregate address {s}"
+                )))
+            }
+        };
+        Ok(Self {
+            network: network.parse()?,
+            as_set,
+        })
+    }
+}
+
+/// AS番号またはアドレスで指定するneighbor。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum Neighbor {
+    As(AutonomousSystemNumber),
+    Address(Ipv4Addr),
+}
+
+impl Neighbor {
+    pub fn matches(&self, config: &Config) -> bool {
+        match self {
+            Neighbor::As(as_number) => *as_number == config.remote_as,
+            Neighbor::Address(address) => *address == config.remote_ip,
+        }
+    }
+}
+
+impl FromStr for OriginationScope {
+    type Err = ConfigParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, neighbor) = s
+            .split_once('@')
+            .context(format!("cannot parse {s} as network@neighbor"))?;
+        let neighbor = match neighbor.parse::<Ipv4Addr>() {
+            Ok(address) => Neighbor::Address(address),
+            Err(_) => Neighbor::As(AutonomousSystemNumber::from(
+                neighbor
+                    .parse::<u32>()
+                    .context(format!("cannot parse {neighbor} as as-number or address"))?,
+            )),
+        };
+        Ok(Self {
+            network: network.parse()?,
+            neighbor,
+        })
+    }
+}
+
+/// `fe80::1%eth0`のように、zone(interface)を付けて書くIPv6 link-local address。
+/// bind, connectに用いるscope idとしてinterface indexを合わせて持つ。
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+pub struct ScopedIpv6Addr {
+    pub address: Ipv6Addr,
+    pub interface: String,
+    pub scope_id: u32,
+}
+
+impl ScopedIpv6Addr {
+    pub fn socket_addr(&self, port: u16) -> SocketAddrV6 {
+        SocketAddrV6::new(self.address, port, 0, self.scope_id)
+    }
+
+    /// interface名をinterface indexに変換する。数字の場合はそのままindexとして扱う。
+    fn resolve_scope_id(interface: &str) -> Result<u32, ConfigParseError> {
+        if let Ok(index) = interface.parse() {
+            return Ok(index);
+        }
+        let path = format!("/sys/class/net/{interface}/ifindex");
+        let index =
+            std::fs::read_to_string(&path).context(format!("cannot find interface {interface}"))?;
+        Ok(index
+            .trim()
+            .parse()
+            .context(format!("cannot parse {path} as interface index"))?)
+    }
+}
+
+impl FromStr for ScopedIpv6Addr {
+    type Err = ConfigParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, interface) = s.split_once('%').context(format!(
+            "link-local address {s} must have a zone like %eth0"
+        ))?;
+        let address: Ipv6Addr = address
+            .parse()
+            .context(format!("cannot parse {address} as ipv6 address"))?;
+        if address.segments()[0] & 0xffc0 != 0xfe80 {
+            return Err(ConfigParseError::from(anyhow::anyhow!(
+                "{address} is not a link-local address"
+            )));
+        }
+        Ok(Self {
+            address,
+            interface: interface.to_owned(),
+            scope_id: Self::resolve_scope_id(interface)?,
+        })
+    }
+}
+
+/// RIBのエントリ数が`max_rib_entries`に達したときの振る舞い。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord, Default)]
+pub enum RibLimitPolicy {
+    /// 新しい経路を拒否する。
+    #[default]
+    RefuseNew,
+    /// 最も優先度の低い経路を追い出して新しい経路を受け入れる。
+    EvictLeastPreferred,
+}
+
+impl FromStr for RibLimitPolicy {
+    type Err = ConfigParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "refuse-new" => Ok(RibLimitPolicy::RefuseNew),
+            "evict-least-preferred" => Ok(RibLimitPolicy::EvictLeastPreferred),
             _ => Err(ConfigParseError::from(anyhow::anyhow!("cannot parse {s}"))),
         }
     }
 }
 
+/// origin validationでInvalidになった経路の扱い。`rpki_invalid=drop`のように書く。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord, Default)]
+pub enum InvalidRoutePolicy {
+    /// 他の経路と同じように扱う。
+    Accept,
+    /// Invalidでない経路があれば、そちらをbest pathに選ぶ。
+    #[default]
+    DePrefer,
+    /// LocRibに入れない。
+    Drop,
+}
+
+impl FromStr for InvalidRoutePolicy {
+    type Err = ConfigParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "accept" => Ok(InvalidRoutePolicy::Accept),
+            "de-prefer" => Ok(InvalidRoutePolicy::DePrefer),
+            "drop" => Ok(InvalidRoutePolicy::Drop),
+            _ => Err(ConfigParseError::from(anyhow::anyhow!("cannot parse {s}"))),
+        }
+    }
+}
+
+/// 自身が広告元のprefixへの経路を受信したときの扱い。`own_prefix_reaction=drop`のように書く。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum OwnPrefixReaction {
+    /// 警告を出して、経路はそのまま受け入れる。
+    Log,
+    /// 警告を出して、経路を捨てる。
+    Drop,
+}
+
+impl FromStr for OwnPrefixReaction {
+    type Err = ConfigParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "log" => Ok(OwnPrefixReaction::Log),
+            "drop" => Ok(OwnPrefixReaction::Drop),
+            _ => Err(ConfigParseError::from(anyhow::anyhow!("cannot parse {s}"))),
+        }
+    }
+}
+
+/// 再配布するカーネルの経路のprotocol(rtm_protocol)。
+/// `redistribute=static`または`redistribute=static,kernel`のように書く。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum RedistributeProtocol {
+    /// カーネルが自動で作った経路。接続しているnetworkへの経路など。
+    Kernel,
+    /// 起動時やprotocolを指定せずに`ip route add`で追加した経路。
+    Boot,
+    /// 管理者が追加したstatic route。
+    Static,
+}
+
+impl RedistributeProtocol {
+    pub fn rtm_protocol(&self) -> u8 {
+        match self {
+            RedistributeProtocol::Kernel => RTPROT_KERNEL,
+            RedistributeProtocol::Boot => RTPROT_BOOT,
+            RedistributeProtocol::Static => RTPROT_STATIC,
+        }
+    }
+}
+
+impl FromStr for RedistributeProtocol {
+    type Err = ConfigParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kernel" => Ok(RedistributeProtocol::Kernel),
+            "boot" => Ok(RedistributeProtocol::Boot),
+            "static" => Ok(RedistributeProtocol::Static),
+            _ => Err(ConfigParseError::from(anyhow::anyhow!("cannot parse {s}"))),
+        }
+    }
+}
+
+impl Config {
+    /// 環境変数から設定を読み込む。
+    /// `MRBGP_NETWORKS`はカンマまたは空白区切りで、設定されていなくても良い。
+    pub fn from_env() -> Result<Self, ConfigParseError> {
+        Self::from_env_with(|key| env::var(key).ok())
+    }
+
+    /// `from_env`と同じ設定を、環境変数の代わりにlookupで引いた値から読み込む。
+    pub fn from_env_with<F>(lookup: F) -> Result<Self, ConfigParseError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let var = |key: &str| {
+            lookup(key).with_context(|| format!("environment variable {key} is not set"))
+        };
+        let mut config = [
+            var("MRBGP_LOCAL_AS")?,
+            var("MRBGP_LOCAL_IP")?,
+            var("MRBGP_REMOTE_AS")?,
+            var("MRBGP_REMOTE_IP")?,
+            var("MRBGP_MODE")?,
+        ]
+        .join(" ");
+        if let Some(networks) = lookup("MRBGP_NETWORKS") {
+            networks
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|network| !network.is_empty())
+                .for_each(|network| {
+                    config += " ";
+                    config += network;
+                });
+        }
+        config.parse()
+    }
+
+    /// 1行に1つのneighborを書いた設定から、全てのneighborの設定を読み込む。
+    /// 空行と`#`で始まる行は無視する。
+    ///
+    /// `peer_group NAME option...`の行はpeer groupを定義する。`peer_group=NAME`を指定した
+    /// neighborは、peer groupのoptionを自身のoptionより前に書いたものとして解釈するので、
+    /// 同じoptionを指定すると上書きになり、network statementなどの繰り返せるものは追加になる。
+    /// neighborの先頭5つの値は`-`にすると、peer groupの`local_as=64512`などの値を用いる。
+    pub fn parse_all(s: &str) -> Result<Vec<Self>, ConfigParseError> {
+        let lines = s
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+        let mut peer_groups: HashMap<&str, Vec<&str>> = HashMap::new();
+        let mut neighbors = vec![];
+        for line in lines {
+            match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                ["peer_group", name, options @ ..] => {
+                    if peer_groups.insert(name, options.to_vec()).is_some() {
+                        return Err(ConfigParseError::from(anyhow::anyhow!(
+                            "peer group `{name}` is defined twice"
+                        )));
+                    }
+                }
+                tokens => neighbors.push(tokens.to_vec()),
+            }
+        }
+        let configs = neighbors
+            .into_iter()
+            .map(|tokens| {
+                Self::resolve_peer_group(tokens, &peer_groups)?
+                    .join(" ")
+                    .parse()
+            })
+            .collect::<Result<Vec<Self>, _>>()?;
+        Self::check_global_options(&configs)?;
+        Ok(configs)
+    }
+
+    /// LocRibやFIB、BMPなど、全てのneighborで1つだけ持つものの設定が、
+    /// neighborごとに異なっていればエラーにする。
+    /// どのneighborの値を用いるかが曖昧にならないよう、同じpeer_groupで指定するなどして揃える。
+    fn check_global_options(configs: &[Self]) -> Result<(), ConfigParseError> {
+        let Some((first, rest)) = configs.split_first() else {
+            return Ok(());
+        };
+        for config in rest {
+            let conflicting = [
+                ("maximum_paths", first.maximum_paths != config.maximum_paths),
+                (
+                    "aggregate_address",
+                    first.aggregate_addresses != config.aggregate_addresses,
+                ),
+                ("max_peers", first.max_peers != config.max_peers),
+                (
+                    "default_origin",
+                    first.default_origin != config.default_origin,
+                ),
+                ("bmp_collector", first.bmp_collector != config.bmp_collector),
+                (
+                    "fib_barrier_time",
+                    first.fib_barrier_time != config.fib_barrier_time,
+                ),
+                (
+                    "fib_max_shrink_percent",
+                    first.fib_max_shrink_percent != config.fib_max_shrink_percent,
+                ),
+                ("rib_store", first.rib_store != config.rib_store),
+                (
+                    "rib_store_stale_time",
+                    first.rib_store_stale_time != config.rib_store_stale_time,
+                ),
+            ];
+            if let Some((option, _)) = conflicting.iter().find(|(_, conflicts)| *conflicts) {
+                return Err(ConfigParseError::from(anyhow::anyhow!(
+                    "`{option}` is shared by all neighbors but differs between {} and {}",
+                    first.remote_ip,
+                    config.remote_ip
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// neighborのtokenに、指定されたpeer groupの値を補う。
+    fn resolve_peer_group<'a>(
+        mut tokens: Vec<&'a str>,
+        peer_groups: &HashMap<&str, Vec<&'a str>>,
+    ) -> Result<Vec<String>, ConfigParseError> {
+        let group_name = tokens
+            .iter()
+            .position(|token| token.starts_with("peer_group="))
+            .map(|i| &tokens.remove(i)["peer_group=".len()..]);
+        let group = match group_name {
+            Some(name) => peer_groups
+                .get(name)
+                .ok_or(anyhow::anyhow!("peer group `{name}` is not defined"))?,
+            None => return Ok(tokens.into_iter().map(str::to_owned).collect()),
+        };
+        let mut resolved = vec![];
+        for (i, field) in POSITIONAL_FIELDS.iter().enumerate() {
+            let value = match tokens.get(i) {
+                Some(&"-") | None => group
+                    .iter()
+                    .find_map(|option| option.strip_prefix(field)?.strip_prefix('='))
+                    .ok_or(anyhow::anyhow!(
+                        "{field} is neither set in the neighbor nor in peer group `{}`",
+                        group_name.unwrap_or_default()
+                    ))?,
+                Some(value) => value,
+            };
+            resolved.push(value.to_owned());
+        }
+        let is_positional = |option: &&str| {
+            POSITIONAL_FIELDS
+                .iter()
+                .any(|field| option.split_once('=').is_some_and(|(key, _)| key == *field))
+        };
+        resolved.extend(
+            group
+                .iter()
+                .filter(|option| !is_positional(option))
+                .map(|option| option.to_string()),
+        );
+        resolved.extend(
+            tokens
+                .iter()
+                .skip(POSITIONAL_FIELDS.len())
+                .map(|token| token.to_string()),
+        );
+        Ok(resolved)
+    }
+
+    /// local_asとremote_asが同じ、IBGPのneighborかどうか。
+    pub fn is_ibgp(&self) -> bool {
+        self.local_as == self.remote_as
+    }
+
+    /// IBGPでもconfederationのmember ASでもない、confederationの外のneighborかどうか。
+    pub fn is_outside_confederation(&self) -> bool {
+        !self.is_ibgp() && !self.confederation_peers.contains(&self.remote_as)
+    }
+
+    /// neighborに対して名乗るAS番号。confederationの外のneighborにはconfederation_idを名乗る。
+    pub fn advertised_local_as(&self) -> AutonomousSystemNumber {
+        match self.confederation_id {
+            Some(confederation_id) if self.is_outside_confederation() => confederation_id,
+            _ => self.local_as,
+        }
+    }
+
+    /// 広告する経路のNEXT_HOPをlocal_ipに書き換えるかどうか。
+    pub fn rewrites_next_hop(&self) -> bool {
+        self.next_hop_self.unwrap_or(!self.is_ibgp())
+    }
+}
+
 impl FromStr for Config {
     type Err = ConfigParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let config: Vec<&str> = s.split(" ").collect();
-        let local_as = AutonomousSystemNumber::from(config[0].parse::<u16>().context(format!(
+        let local_as = AutonomousSystemNumber::from(config[0].parse::<u32>().context(format!(
             "cannot parse 1st part of config, `{0}`,\
             as as-number and config is {1}
             ",
@@ -49,7 +857,7 @@ impl FromStr for Config {
           ",
             config[1], s
         ))?;
-        let remote_as = AutonomousSystemNumber::from(config[2].parse::<u16>().context(format!(
+        let remote_as = AutonomousSystemNumber::from(config[2].parse::<u32>().context(format!(
             "cannot parse 3rd part of config `{0}`,\
           as as-number and config is {1}
           ",
@@ -67,14 +875,473 @@ impl FromStr for Config {
           ",
             config[4], s
         ))?;
-        let mut networks: Vec<Ipv4Network> = vec![];
-        for network in &config[5..] {
-            networks.push(network.parse().context(format!(
-                "cannot parse config[5..], {0}\
-                as Ipv4Network and config is {1}
-                ",
-                network, s
-            ))?)
+        let mut networks: Vec<NetworkStatement> = vec![];
+        let mut port = 179;
+        let mut max_rib_entries = None;
+        let mut rib_limit_policy = RibLimitPolicy::default();
+        let mut mrai = Duration::ZERO;
+        let mut initial_advertisement_window = None;
+        let mut source_port = None;
+        let mut send_buffer_size = None;
+        let mut receive_buffer_size = None;
+        let mut description = None;
+        let mut always_compare_med = false;
+        let mut deterministic_med = false;
+        let mut maximum_paths = 1;
+        let mut import_route_targets = vec![];
+        let mut route_map_in = None;
+        let mut bogon_filter = None;
+        let mut route_map_out = None;
+        let mut graceful_restart_time = None;
+        let mut hold_time = 0;
+        let mut connect_retry_time = DEFAULT_CONNECT_RETRY_TIME;
+        let mut connect_failure_alarm = None;
+        let mut notification_dampening = None;
+        let mut idle_hold_time = None;
+        let mut damp_peer_oscillations = None;
+        let mut timer_jitter = DEFAULT_TIMER_JITTER;
+        let mut default_originate = None;
+        let mut as_override = false;
+        let mut best_external = false;
+        let mut graceful_shutdown = false;
+        let mut allowas_in = 0;
+        let mut route_refresh = true;
+        let mut enhanced_route_refresh = false;
+        let mut ipv6_unicast = false;
+        let mut update_compression = false;
+        let mut origination_scopes = vec![];
+        let mut conditional_advertisements = vec![];
+        let mut aggregate_addresses = vec![];
+        let mut static_routes = vec![];
+        let mut default_origin = Origin::Igp;
+        let mut redistribute = vec![];
+        let mut roa_file = None;
+        let mut invalid_route_policy = InvalidRoutePolicy::default();
+        let mut own_prefix_reaction = None;
+        let mut bmp_collector = None;
+        let mut admin_shutdown = false;
+        let mut next_hop_self = None;
+        let mut max_peers = None;
+        let mut listen_range = None;
+        let mut listen_limit = None;
+        let mut max_routes_per_iteration = None;
+        let mut fast_reroute = false;
+        let mut fib_barrier_time = None;
+        let mut fib_max_shrink_percent = None;
+        let mut rib_store = None;
+        let mut rib_store_stale_time = DEFAULT_RIB_STORE_STALE_TIME;
+        let mut confederation_peers = vec![];
+        let mut confederation_id = None;
+        let mut soft_reconfiguration_inbound = false;
+        let mut soft_reconfiguration_limit = None;
+        let mut max_prefix = None;
+        let mut max_prefix_restart = None;
+        let mut max_prefix_warning_only = false;
+        for token in &config[5..] {
+            match token.split_once('=') {
+                Some(("port", v)) => {
+                    port = v.parse().context(format!(
+                        "cannot parse port, `{0}`, \
+                        as u16 and config is {1}
+                        ",
+                        v, s
+                    ))?
+                }
+                Some(("max_rib_entries", v)) => {
+                    max_rib_entries = Some(v.parse().context(format!(
+                        "cannot parse max_rib_entries, `{0}`, \
+                        as usize and config is {1}
+                        ",
+                        v, s
+                    ))?)
+                }
+                Some(("max_peers", v)) => {
+                    max_peers = Some(v.parse().context(format!(
+                        "cannot parse max_peers, `{0}`, \
+                        as usize and config is {1}
+                        ",
+                        v, s
+                    ))?)
+                }
+                Some(("listen_range", v)) => {
+                    if mode != Mode::Passive {
+                        return Err(ConfigParseError::from(anyhow::anyhow!(
+                            "listen_range is available only in passive mode, config is {s}"
+                        )));
+                    }
+                    listen_range = Some(v.parse()?)
+                }
+                Some(("listen_limit", v)) => {
+                    listen_limit = Some(v.parse().context(format!(
+                        "cannot parse listen_limit, `{0}`, \
+                        as usize and config is {1}
+                        ",
+                        v, s
+                    ))?)
+                }
+                Some(("max_routes_per_iteration", v)) => {
+                    max_routes_per_iteration = Some(v.parse().context(format!(
+                        "cannot parse max_routes_per_iteration, `{0}`, \
+                        as usize and config is {1}
+                        ",
+                        v, s
+                    ))?)
+                }
+                Some(("fast_reroute", v)) => {
+                    fast_reroute = v.parse().context(format!(
+                        "cannot parse fast_reroute, `{0}`, \
+                        as bool and config is {1}
+                        ",
+                        v, s
+                    ))?
+                }
+                Some(("soft_reconfiguration_inbound", v)) => {
+                    soft_reconfiguration_inbound = v.parse().context(format!(
+                        "cannot parse soft_reconfiguration_inbound, `{0}`, \
+                        as bool and config is {1}
+                        ",
+                        v, s
+                    ))?
+                }
+                Some(("soft_reconfiguration_limit", v)) => {
+                    soft_reconfiguration_limit = Some(v.parse().context(format!(
+                        "cannot parse soft_reconfiguration_limit, `{0}`, \
+                        as usize and config is {1}
+                        ",
+                        v, s
+                    ))?)
+                }
+                Some(("max_prefix", v)) => {
+                    max_prefix = Some(v.parse().context(format!(
+                        "cannot parse max_prefix, `{0}`, \
+                        as usize and config is {1}
+                        ",
+                        v, s
+                    ))?)
+                }
+                Some(("max_prefix_restart", v)) => {
+                    max_prefix_restart = Some(Duration::from_secs(v.parse().context(format!(
+                        "cannot parse max_prefix_restart, `{0}`, \
+                        as seconds and config is {1}
+                        ",
+                        v, s
+                    ))?))
+                }
+                Some(("max_prefix_warning_only", v)) => {
+                    max_prefix_warning_only = v.parse().context(format!(
+                        "cannot parse max_prefix_warning_only, `{0}`, \
+                        as bool and config is {1}
+                        ",
+                        v, s
+                    ))?
+                }
+                Some(("rib_limit_policy", v)) => rib_limit_policy = v.parse()?,
+                Some(("description", v)) => description = Some(v.to_owned()),
+                Some(("import_route_target", v)) => import_route_targets.push(v.parse()?),
+                Some(("bogon_filter", v)) => bogon_filter = Some(v.parse()?),
+                Some(("route_map_in", v)) => route_map_in = Some(Arc::new(RouteMap::load(v)?)),
+                Some(("route_map_out", v)) => route_map_out = Some(Arc::new(RouteMap::load(v)?)),
+                Some(("default_originate", v)) => default_originate = Some(v.parse()?),
+                Some(("originate_to", v)) => origination_scopes.push(v.parse()?),
+                Some(("advertise_if", v)) => conditional_advertisements.push(v.parse()?),
+                Some(("aggregate_address", v)) => aggregate_addresses.push(v.parse()?),
+                Some(("static_route", v)) => static_routes.push(v.parse()?),
+                Some(("default_origin", v)) => default_origin = v.parse()?,
+                Some(("redistribute", v)) => {
+                    for protocol in v.split(',') {
+                        redistribute.push(protocol.parse()?);
+                    }
+                }
+                Some(("roa_file", v)) => roa_file = Some(PathBuf::from(v)),
+                Some(("rpki_invalid", v)) => invalid_route_policy = v.parse()?,
+                Some(("own_prefix_reaction", v)) => own_prefix_reaction = Some(v.parse()?),
+                Some(("bmp_collector", v)) => {
+                    bmp_collector = Some(v.parse().context(format!(
+                        "cannot parse bmp_collector, `{0}`, \
+                        as ip:port and config is {1}
+                        ",
+                        v, s
+                    ))?)
+                }
+                Some(("confederation_peer", v)) => confederation_peers.push(
+                    AutonomousSystemNumber::from(v.parse::<u32>().context(format!(
+                        "cannot parse confederation_peer, `{0}`, \
+                            as as-number and config is {1}
+                            ",
+                        v, s
+                    ))?),
+                ),
+                Some(("confederation_id", v)) => {
+                    confederation_id = Some(AutonomousSystemNumber::from(
+                        v.parse::<u32>().context(format!(
+                            "cannot parse confederation_id, `{0}`, \
+                            as as-number and config is {1}
+                            ",
+                            v, s
+                        ))?,
+                    ))
+                }
+                Some(("hold_time", v)) => {
+                    hold_time = v.parse().context(format!(
+                        "cannot parse hold_time, `{0}`, \
+                        as u16 and config is {1}
+                        ",
+                        v, s
+                    ))?
+                }
+                Some(("graceful_restart_time", v)) => {
+                    graceful_restart_time = Some(v.parse().context(format!(
+                        "cannot parse graceful_restart_time, `{0}`, \
+                        as u16 and config is {1}
+                        ",
+                        v, s
+                    ))?)
+                }
+                Some(("always_compare_med", v)) => {
+                    always_compare_med = v.parse().context(format!(
+                        "cannot parse always_compare_med, `{0}`, \
+                        as bool and config is {1}
+                        ",
+                        v, s
+                    ))?
+                }
+                Some(("deterministic_med", v)) => {
+                    deterministic_med = v.parse().context(format!(
+                        "cannot parse deterministic_med, `{0}`, \
+                        as bool and config is {1}
+                        ",
+                        v, s
+                    ))?
+                }
+                Some(("maximum_paths", v)) => {
+                    maximum_paths = v.parse().context(format!(
+                        "cannot parse maximum_paths, `{0}`, \
+                        as usize and config is {1}
+                        ",
+                        v, s
+                    ))?
+                }
+                Some(("source_port", v)) => {
+                    source_port = Some(v.parse().context(format!(
+                        "cannot parse source_port, `{0}`, \
+                        as u16 and config is {1}
+                        ",
+                        v, s
+                    ))?)
+                }
+                Some(("send_buffer_size", v)) => {
+                    send_buffer_size = Some(v.parse().context(format!(
+                        "cannot parse send_buffer_size, `{0}`, \
+                        as usize and config is {1}
+                        ",
+                        v, s
+                    ))?)
+                }
+                Some(("receive_buffer_size", v)) => {
+                    receive_buffer_size = Some(v.parse().context(format!(
+                        "cannot parse receive_buffer_size, `{0}`, \
+                        as usize and config is {1}
+                        ",
+                        v, s
+                    ))?)
+                }
+                Some(("fib_barrier_time", v)) => {
+                    fib_barrier_time = Some(Duration::from_secs(v.parse().context(format!(
+                        "cannot parse fib_barrier_time, `{0}`, \
+                        as seconds and config is {1}
+                        ",
+                        v, s
+                    ))?))
+                }
+                Some(("fib_max_shrink_percent", v)) => {
+                    let percent: u8 = v.parse().context(format!(
+                        "cannot parse fib_max_shrink_percent, `{0}`, \
+                        as u8 and config is {1}
+                        ",
+                        v, s
+                    ))?;
+                    if percent > 100 {
+                        return Err(ConfigParseError::from(anyhow::anyhow!(
+                            "fib_max_shrink_percentは100以下で指定してください。config is {s}"
+                        )));
+                    }
+                    fib_max_shrink_percent = Some(percent);
+                }
+                Some(("rib_store", v)) => rib_store = Some(PathBuf::from(v)),
+                Some(("rib_store_stale_time", v)) => {
+                    rib_store_stale_time = Duration::from_secs(v.parse().context(format!(
+                        "cannot parse rib_store_stale_time, `{0}`, \
+                        as seconds and config is {1}
+                        ",
+                        v, s
+                    ))?)
+                }
+                Some(("connect_retry_time", v)) => {
+                    connect_retry_time = Duration::from_secs(v.parse().context(format!(
+                        "cannot parse connect_retry_time, `{0}`, \
+                        as seconds and config is {1}
+                        ",
+                        v, s
+                    ))?)
+                }
+                Some(("connect_failure_alarm", v)) => {
+                    connect_failure_alarm = Some(v.parse().context(format!(
+                        "cannot parse connect_failure_alarm, `{0}`, \
+                        as u32 and config is {1}
+                        ",
+                        v, s
+                    ))?)
+                }
+                Some(("notification_dampening", v)) => {
+                    notification_dampening =
+                        Some(Duration::from_secs(v.parse().context(format!(
+                            "cannot parse notification_dampening, `{0}`, \
+                        as seconds and config is {1}
+                        ",
+                            v, s
+                        ))?))
+                }
+                Some(("idle_hold_time", v)) => {
+                    idle_hold_time = Some(Duration::from_secs(v.parse().context(format!(
+                        "cannot parse idle_hold_time, `{0}`, \
+                        as seconds and config is {1}
+                        ",
+                        v, s
+                    ))?))
+                }
+                Some(("damp_peer_oscillations", v)) => {
+                    damp_peer_oscillations =
+                        Some(Duration::from_secs(v.parse().context(format!(
+                            "cannot parse damp_peer_oscillations, `{0}`, \
+                        as seconds and config is {1}
+                        ",
+                            v, s
+                        ))?))
+                }
+                Some(("as_override", v)) => {
+                    as_override = v.parse().context(format!(
+                        "cannot parse as_override, `{0}`, \
+                        as bool and config is {1}
+                        ",
+                        v, s
+                    ))?
+                }
+                Some(("best_external", v)) => {
+                    best_external = v.parse().context(format!(
+                        "cannot parse best_external, `{0}`, \
+                        as bool and config is {1}
+                        ",
+                        v, s
+                    ))?
+                }
+                Some(("graceful_shutdown", v)) => {
+                    graceful_shutdown = v.parse().context(format!(
+                        "cannot parse graceful_shutdown, `{0}`, \
+                        as bool and config is {1}
+                        ",
+                        v, s
+                    ))?
+                }
+                Some(("allowas_in", v)) => {
+                    allowas_in = v.parse().context(format!(
+                        "cannot parse allowas_in, `{0}`, \
+                        as usize and config is {1}
+                        ",
+                        v, s
+                    ))?
+                }
+                Some(("route_refresh", v)) => {
+                    route_refresh = v.parse().context(format!(
+                        "cannot parse route_refresh, `{0}`, \
+                        as bool and config is {1}
+                        ",
+                        v, s
+                    ))?
+                }
+                Some(("enhanced_route_refresh", v)) => {
+                    enhanced_route_refresh = v.parse().context(format!(
+                        "cannot parse enhanced_route_refresh, `{0}`, \
+                        as bool and config is {1}
+                        ",
+                        v, s
+                    ))?
+                }
+                Some(("ipv6_unicast", v)) => {
+                    ipv6_unicast = v.parse().context(format!(
+                        "cannot parse ipv6_unicast, `{0}`, \
+                        as bool and config is {1}
+                        ",
+                        v, s
+                    ))?
+                }
+                Some(("update_compression", v)) => {
+                    update_compression = v.parse().context(format!(
+                        "cannot parse update_compression, `{0}`, \
+                        as bool and config is {1}
+                        ",
+                        v, s
+                    ))?
+                }
+                Some(("admin_shutdown", v)) => {
+                    admin_shutdown = v.parse().context(format!(
+                        "cannot parse admin_shutdown, `{0}`, \
+                        as bool and config is {1}
+                        ",
+                        v, s
+                    ))?
+                }
+                Some(("next_hop_self", v)) => {
+                    next_hop_self = Some(v.parse().context(format!(
+                        "cannot parse next_hop_self, `{0}`, \
+                        as bool and config is {1}
+                        ",
+                        v, s
+                    ))?)
+                }
+                Some(("timer_jitter", v)) => {
+                    timer_jitter = v.parse().context(format!(
+                        "cannot parse timer_jitter, `{0}`, \
+                        as percent and config is {1}
+                        ",
+                        v, s
+                    ))?;
+                    if timer_jitter > 100 {
+                        return Err(anyhow::anyhow!(
+                            "timer_jitter must be at most 100 percent and config is {}",
+                            s
+                        )
+                        .into());
+                    }
+                }
+                Some(("mrai" | "advertisement_interval", v)) => {
+                    mrai = Duration::from_secs(v.parse().context(format!(
+                        "cannot parse advertisement interval, `{0}`, \
+                        as seconds and config is {1}
+                        ",
+                        v, s
+                    ))?)
+                }
+                Some(("initial_advertisement_window", v)) => {
+                    initial_advertisement_window =
+                        Some(Duration::from_secs(v.parse().context(format!(
+                            "cannot parse initial_advertisement_window, `{0}`, \
+                        as seconds and config is {1}
+                        ",
+                            v, s
+                        ))?))
+                }
+                Some((key, _)) => {
+                    return Err(ConfigParseError::from(anyhow::anyhow!(
+                        "unknown option `{key}` in config {s}"
+                    )))
+                }
+                None => networks.push(token.parse().context(format!(
+                    "cannot parse config[5..], {0}\
+                    as network statement and config is {1}
+                    ",
+                    token, s
+                ))?),
+            }
         }
         Ok(Self {
             local_as,
@@ -83,6 +1350,256 @@ impl FromStr for Config {
             remote_ip,
             mode,
             networks,
+            port,
+            max_rib_entries,
+            rib_limit_policy,
+            mrai,
+            initial_advertisement_window,
+            source_port,
+            send_buffer_size,
+            receive_buffer_size,
+            description,
+            always_compare_med,
+            deterministic_med,
+            maximum_paths,
+            import_route_targets,
+            route_map_in,
+            bogon_filter,
+            route_map_out,
+            graceful_restart_time,
+            hold_time,
+            connect_retry_time,
+            connect_failure_alarm,
+            notification_dampening,
+            idle_hold_time,
+            damp_peer_oscillations,
+            default_originate,
+            as_override,
+            best_external,
+            graceful_shutdown,
+            allowas_in,
+            route_refresh,
+            enhanced_route_refresh,
+            ipv6_unicast,
+            update_compression,
+            origination_scopes,
+            conditional_advertisements,
+            aggregate_addresses,
+            admin_shutdown,
+            next_hop_self,
+            max_peers,
+            listen_range,
+            listen_limit,
+            max_routes_per_iteration,
+            fast_reroute,
+            confederation_peers,
+            confederation_id,
+            soft_reconfiguration_inbound,
+            soft_reconfiguration_limit,
+            timer_jitter,
+            max_prefix,
+            max_prefix_restart,
+            max_prefix_warning_only,
+            static_routes,
+            default_origin,
+            redistribute,
+            roa_file,
+            invalid_route_policy,
+            own_prefix_reaction,
+            bmp_collector,
+            fib_barrier_time,
+            fib_max_shrink_percent,
+            rib_store,
+            rib_store_stale_time,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_from_env_is_same_as_config_from_str() {
+        let env: HashMap<&str, &str> = HashMap::from([
+            ("MRBGP_LOCAL_AS", "64513"),
+            ("MRBGP_LOCAL_IP", "10.200.100.3"),
+            ("MRBGP_REMOTE_AS", "64512"),
+            ("MRBGP_REMOTE_IP", "10.200.100.2"),
+            ("MRBGP_MODE", "passive"),
+            ("MRBGP_NETWORKS", "10.100.220.0/24,10.100.221.0/24"),
+        ]);
+        let lookup = |key: &str| env.get(key).map(|value| value.to_string());
+
+        let expected: Config =
+            "64513 10.200.100.3 64512 10.200.100.2 passive 10.100.220.0/24 10.100.221.0/24"
+                .parse()
+                .unwrap();
+        assert_eq!(Config::from_env_with(lookup).unwrap(), expected);
+    }
+
+    #[test]
+    fn config_from_env_requires_all_but_networks() {
+        let env: HashMap<&str, &str> = HashMap::from([
+            ("MRBGP_LOCAL_AS", "64513"),
+            ("MRBGP_LOCAL_IP", "10.200.100.3"),
+            ("MRBGP_REMOTE_AS", "64512"),
+            ("MRBGP_REMOTE_IP", "10.200.100.2"),
+            ("MRBGP_MODE", "passive"),
+        ]);
+        let lookup = |key: &str| env.get(key).map(|value| value.to_string());
+        assert!(Config::from_env_with(lookup).unwrap().networks.is_empty());
+
+        let lookup = |key: &str| {
+            (key != "MRBGP_MODE")
+                .then(|| env.get(key).map(|value| value.to_string()))
+                .flatten()
+        };
+        assert!(Config::from_env_with(lookup).is_err());
+    }
+
+    #[test]
+    fn link_local_address_keeps_zone_and_scope() {
+        let address: ScopedIpv6Addr = "fe80::1%lo".parse().unwrap();
+        assert_eq!(address.address, "fe80::1".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(address.interface, "lo");
+        assert_eq!(address.scope_id, 1);
+        assert_eq!(address.socket_addr(179).scope_id(), 1);
+
+        let address: ScopedIpv6Addr = "fe80::1%4".parse().unwrap();
+        assert_eq!(address.scope_id, 4);
+        assert!("fe80::1".parse::<ScopedIpv6Addr>().is_err());
+        assert!("fd00::1%lo".parse::<ScopedIpv6Addr>().is_err());
+    }
+
+    #[test]
+    fn neighbors_inherit_peer_group_and_can_override_it() {
+        let configs = Config::parse_all(
+            "
+            # 外部のneighbor
+            peer_group EXTERNAL local_as=64512 remote_as=64513 mode=active hold_time=90 mrai=5
+            - 10.200.100.2 - 10.200.100.3 - peer_group=EXTERNAL
+            - 10.200.100.2 - 10.200.100.4 - peer_group=EXTERNAL hold_time=30
+            64512 10.200.100.2 64514 10.200.100.5 passive
+            ",
+        )
+        .unwrap();
+        assert_eq!(configs.len(), 3);
+        assert_eq!(configs[0].hold_time, 90);
+        assert_eq!(configs[0].remote_as, 64513.into());
+        assert_eq!(configs[0].mode, Mode::Active);
+        assert_eq!(configs[1].hold_time, 30);
+        assert_eq!(configs[1].remote_ip, Ipv4Addr::new(10, 200, 100, 4));
+        assert_eq!(configs[1].mrai, Duration::from_secs(5));
+        assert_eq!(configs[2].hold_time, 0);
+        assert_eq!(configs[2].mode, Mode::Passive);
+
+        assert!(Config::parse_all("- 10.200.100.2 - 10.200.100.3 - peer_group=UNKNOWN").is_err());
+        assert!(Config::parse_all(
+            "peer_group EXTERNAL hold_time=90
+            64512 10.200.100.2 - 10.200.100.3 active peer_group=EXTERNAL"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn neighbors_must_agree_on_options_shared_by_all_neighbors() {
+        let configs = Config::parse_all(
+            "
+            peer_group ALL max_peers=2 maximum_paths=4
+            64512 10.200.100.2 64513 10.200.100.3 active peer_group=ALL
+            64512 10.200.100.2 64514 10.200.100.4 active peer_group=ALL
+            ",
+        )
+        .unwrap();
+        assert!(configs.iter().all(|c| c.max_peers == Some(2)));
+
+        let error = Config::parse_all(
+            "
+            64512 10.200.100.2 64513 10.200.100.3 active max_peers=2
+            64512 10.200.100.2 64514 10.200.100.4 active max_peers=3
+            ",
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("max_peers"), "{error}");
+        assert!(Config::parse_all(
+            "
+            64512 10.200.100.2 64513 10.200.100.3 active maximum_paths=4
+            64512 10.200.100.2 64514 10.200.100.4 active
+            "
+        )
+        .is_err());
+    }
+
+    /// link-local addressを持つinterfaceがあれば、そのaddressとinterface名を返す。
+    fn find_link_local_address() -> Option<(Ipv6Addr, String)> {
+        let if_inet6 = std::fs::read_to_string("/proc/net/if_inet6").ok()?;
+        if_inet6.lines().find_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let address = u128::from_str_radix(fields.first()?, 16).ok()?;
+            let address = Ipv6Addr::from(address);
+            let interface = fields.last()?.to_string();
+            (address.segments()[0] == 0xfe80).then_some((address, interface))
+        })
+    }
+
+    /// link-local addressを持つinterfaceが必要なので、`cargo test -- --ignored`として実行する。
+    #[tokio::test]
+    #[ignore = "link-local addressを持つinterfaceが必要なため"]
+    async fn can_connect_over_link_local_address() {
+        let (address, interface) =
+            find_link_local_address().expect("link-local addressを持つinterfaceがありません。");
+        let address: ScopedIpv6Addr = format!("{address}%{interface}").parse().unwrap();
+        let listener = tokio::net::TcpListener::bind(address.socket_addr(0))
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let stream = tokio::net::TcpStream::connect(address.socket_addr(port))
+            .await
+            .unwrap();
+        listener.accept().await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap().port(), port);
+    }
+
+    #[test]
+    fn network_statement_and_static_route_can_specify_origin() {
+        let config: Config = "64513 10.200.100.3 64512 10.200.100.2 passive default_origin=egp \
+            10.100.220.0/24:incomplete 10.100.0.0/16:cover:igp 10.101.0.0/16 \
+            static_route=10.102.0.0/24:origin=incomplete static_route=10.103.0.0/24"
+            .parse()
+            .unwrap();
+        assert_eq!(config.default_origin, Origin::Egp);
+        let origins: Vec<Option<Origin>> = config.networks.iter().map(|n| n.origin).collect();
+        assert_eq!(
+            origins,
+            vec![Some(Origin::Incomplete), Some(Origin::Igp), None]
+        );
+        assert_eq!(config.networks[1].match_mode, NetworkMatch::Cover);
+        let origins: Vec<Option<Origin>> = config.static_routes.iter().map(|r| r.origin).collect();
+        assert_eq!(origins, vec![Some(Origin::Incomplete), None]);
+    }
+
+    #[test]
+    fn network_statement_can_specify_match_mode() {
+        let config: Config =
+            "64513 10.200.100.3 64512 10.200.100.2 passive 10.100.220.0/24 10.100.0.0/16:cover"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            config.networks,
+            vec![
+                NetworkStatement {
+                    network: "10.100.220.0/24".parse().unwrap(),
+                    match_mode: NetworkMatch::Exact,
+                    origin: None,
+                },
+                NetworkStatement {
+                    network: "10.100.0.0/16".parse().unwrap(),
+                    match_mode: NetworkMatch::Cover,
+                    origin: None,
+                },
+            ]
+        );
+    }
+}
@@ -1,18 +1,93 @@
-use std::io;
-
 use anyhow::{Context, Result};
-use bytes::{BufMut, BytesMut};
-use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpListener, TcpStream};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::AtomicBool;
+#[cfg(feature = "update-compression")]
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use futures::FutureExt;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::mpsc::{self, error::TryRecvError};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
 
+#[cfg(feature = "update-compression")]
+use crate::compression;
 use crate::config::{Config, Mode};
-use crate::error::CreateConnectionError;
+use crate::error::{
+    ConnectionNotSynchronizedError, CreateConnectionError, MessageError, MessageHeaderError,
+};
+#[cfg(test)]
+use crate::fault::{Direction, Fault, FaultInjector};
+use crate::packets::header::{MessageType, HEADER_LENGTH, MAX_MESSAGE_LENGTH};
 use crate::packets::message::Message;
+use crate::status::DeadLetterLog;
+
+/// 受信bufferの上限。BGP Messageの最大長4096 bytesより十分大きく、
+/// これを超えてもMessageの区切りが見つからない場合は同期が取れていないとみなす。
+const MAX_BUFFER_SIZE: usize = 64 * 1024;
+/// 受信taskからpeerへ渡す、まだ取り出されていないMessageの数の上限。
+/// 上限に達すると受信taskは読み込みを止めるので、Messageは捨てられない。
+const MESSAGE_CHANNEL_CAPACITY: usize = 64;
+const CEASE: u8 = 6;
+const CONNECTION_COLLISION_RESOLUTION: u8 = 7;
 
+/// 受信bufferからMessageを切り出す途中の状態。
+/// Headerから読み取ったMessageの長さを覚えておき、次の切り出しで読み直さない。
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum FramingState {
+    /// Headerが揃うのを待っている。
+    AwaitingHeader,
+    /// Headerを読み取り、lengthのMessage全体が揃うのを待っている。
+    AwaitingBody { length: usize },
+}
+
+/// 受信したbyte列をMessageの区切りで切り出す。
 #[derive(Debug)]
-pub struct Connection {
-    conn: TcpStream,
+struct MessageFramer {
     buffer: BytesMut,
+    framing_state: FramingState,
+}
+
+/// 受信taskがpeerへ渡すもの。
+#[derive(Debug)]
+enum Received {
+    Message(Message),
+    /// 区切りは分かるが解釈できなかったMessage。
+    Malformed {
+        bytes: BytesMut,
+        reason: String,
+    },
+    /// 解釈できず、NOTIFICATIONを送ってセッションを閉じるべきMessage。
+    /// 受信taskはこれを送って終了する。
+    Rejected {
+        bytes: BytesMut,
+        error: MessageError,
+    },
+    /// Messageの区切りが分からなくなった、またはMessageの長さが不正だった。
+    /// 受信taskはこれを送って終了する。
+    HeaderError(MessageHeaderError),
+}
+
+#[derive(Debug)]
+pub struct Connection {
+    writer: OwnedWriteHalf,
+    receiver: mpsc::Receiver<Received>,
+    /// `wait_readable`で受け取り、まだ`get_message`で取り出していないもの。
+    pending: Option<Received>,
+    reader: JoinHandle<()>,
+    /// 相手が接続を閉じたことを検知した場合はtrue。
+    closed: bool,
+    /// UPDATEのbodyを圧縮して送受信する場合はtrue。受信taskと共有する。
+    #[cfg_attr(not(feature = "update-compression"), allow(dead_code))]
+    update_compression: Arc<AtomicBool>,
+    #[cfg(test)]
+    faults: Option<Arc<FaultInjector>>,
 }
 
 impl Connection {
@@ -20,61 +95,297 @@ impl Connection {
         let conn = match config.mode {
             Mode::Active => Self::connect_to_remote_peer(config).await,
             Mode::Passive => Self::wait_connection_from_remote_peer(config).await,
+            Mode::PassiveThenActive(timeout) => {
+                Self::wait_then_connect_to_remote_peer(config, timeout).await
+            }
         }?;
-        let buffer = BytesMut::with_capacity(1500);
-        Ok(Self { conn, buffer })
+        Self::from_established(conn)
+    }
+
+    /// listen_rangeのlistenerなど、peerの外でacceptした接続を用いる。
+    pub fn from_accepted(stream: TcpStream) -> Result<Self, CreateConnectionError> {
+        Self::from_established(stream)
+    }
+
+    fn from_established(conn: TcpStream) -> Result<Self, CreateConnectionError> {
+        // KEEPALIVEなどの小さなMessageを遅らせないよう、Nagle algorithmを無効にする。
+        conn.set_nodelay(true).context("cannot set TCP_NODELAY")?;
+        let conn = Self::from_stream(conn);
+        info!(
+            "tcp connection is established, local={:?}, remote={:?}.",
+            conn.local_addr(),
+            conn.peer_addr()
+        );
+        Ok(conn)
+    }
+
+    /// 受信taskを起動する。受信taskはMessageを切り出して解釈し、channelでpeerへ渡す。
+    fn from_stream(conn: TcpStream) -> Self {
+        let (reader, writer) = conn.into_split();
+        let (sender, receiver) = mpsc::channel(MESSAGE_CHANNEL_CAPACITY);
+        let update_compression = Arc::new(AtomicBool::new(false));
+        let reader = tokio::spawn(Self::read_messages(
+            reader,
+            sender,
+            Arc::clone(&update_compression),
+        ));
+        Self {
+            writer,
+            receiver,
+            pending: None,
+            reader,
+            closed: false,
+            update_compression,
+            #[cfg(test)]
+            faults: None,
+        }
     }
 
     pub async fn send(&mut self, message: Message) {
-        let bytes: BytesMut = message.into();
-        self.conn.write_all(&bytes[..]).await;
+        #[cfg(test)]
+        let fault = self
+            .faults
+            .as_ref()
+            .and_then(|faults| faults.fault_for(Direction::Send, &message));
+        #[cfg_attr(not(feature = "update-compression"), allow(unused_mut))]
+        let mut bytes: BytesMut = message.into();
+        #[cfg(feature = "update-compression")]
+        if self.update_compression.load(Ordering::Acquire) {
+            bytes = compression::compress_update(bytes);
+        }
+        #[cfg(test)]
+        match (fault, &self.faults) {
+            (Some(Fault::Drop), _) => return,
+            (Some(Fault::Corrupt), Some(faults)) => faults.corrupt(&mut bytes),
+            (Some(Fault::Delay(delay)), _) => tokio::time::sleep(delay).await,
+            _ => {}
+        }
+        if let Err(e) = self.writer.write_all(&bytes[..]).await {
+            warn!("cannot send message, {:?}.", e);
+        }
     }
 
-    pub async fn get_message(&mut self) -> Option<Message> {
-        self.read_data_from_tcp_connection().await;
-        let buffer = self.split_buffer_at_message_separator()?;
-        Message::try_from(buffer).ok()
+    /// 以降に送受信するUPDATEのbodyを圧縮する。双方がUpdate Compression Capabilityを
+    /// 広告した場合に、相手がUPDATEを送り始める前に呼ぶ。
+    #[cfg(feature = "update-compression")]
+    pub fn enable_update_compression(&self) {
+        self.update_compression.store(true, Ordering::Release);
     }
 
-    async fn read_data_from_tcp_connection(&mut self) {
-        loop {
-            let mut buf: Vec<u8> = vec![];
-            // let result = self.conn.try_read_buf(&mut buf);
-            match self.conn.try_read_buf(&mut buf) {
-                Ok(0) => (),
-                Ok(n) => self.buffer.put(&buf[..]),
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
-                Err(e) => panic!(
-                    "read data from tcp connection でエラー{:?}が発生しました",
-                    e
-                ),
+    /// 以降に送受信するMessageに、faultsの障害を注入する。
+    #[cfg(test)]
+    pub fn set_fault_injector(&mut self, faults: Arc<FaultInjector>) {
+        self.faults = Some(faults);
+    }
+
+    /// 受信したmessageに障害を注入する。捨てた場合はNoneを返す。
+    /// 書き換えて解釈できなくなったMessageは、dead_lettersに記録して捨てる。
+    #[cfg(test)]
+    fn inject_received_fault(
+        &self,
+        message: Message,
+        dead_letters: &mut DeadLetterLog,
+    ) -> Option<Message> {
+        let Some(faults) = &self.faults else {
+            return Some(message);
+        };
+        match faults.fault_for(Direction::Receive, &message) {
+            Some(Fault::Drop) => None,
+            Some(Fault::Corrupt) => {
+                let mut bytes: BytesMut = message.into();
+                faults.corrupt(&mut bytes);
+                match Message::try_from(&bytes[..]) {
+                    Ok(message) => Some(message),
+                    Err(e) => {
+                        dead_letters.push(&bytes, e.to_string());
+                        None
+                    }
+                }
             }
+            _ => Some(message),
         }
     }
 
-    fn split_buffer_at_message_separator(&mut self) -> Option<BytesMut> {
-        let index = self.get_index_of_message_separator().ok()?;
-        if self.buffer.len() < index {
-            return None;
+    /// 受信したMessageを1つ取り出す。
+    /// 区切りは分かるが解釈できないMessageは、理由とともにdead_lettersに記録する。
+    /// そのうちNOTIFICATIONを送るべきもの(RFC 4271 Section 6)はエラーとして返す。
+    pub async fn get_message(
+        &mut self,
+        dead_letters: &mut DeadLetterLog,
+    ) -> Result<Option<Message>, MessageError> {
+        let received = match self.pending.take() {
+            Some(received) => received,
+            None => match self.receiver.try_recv() {
+                Ok(received) => received,
+                Err(TryRecvError::Empty) => return Ok(None),
+                Err(TryRecvError::Disconnected) => {
+                    self.closed = true;
+                    return Ok(None);
+                }
+            },
+        };
+        match received {
+            #[cfg(test)]
+            Received::Message(message) => Ok(self.inject_received_fault(message, dead_letters)),
+            #[cfg(not(test))]
+            Received::Message(message) => Ok(Some(message)),
+            Received::Malformed { bytes, reason } => {
+                dead_letters.push(&bytes, reason);
+                Ok(None)
+            }
+            Received::Rejected { bytes, error } => {
+                dead_letters.push(&bytes, error.to_string());
+                Err(error)
+            }
+            Received::HeaderError(e) => Err(e.into()),
         }
-        Some(self.buffer.split_to(index))
     }
 
-    fn get_index_of_message_separator(&self) -> Result<usize> {
-        let minimum_message_length = 19;
-        if self.buffer.len() < 19 {
-            return Err(anyhow::anyhow!(
-                "messageのseparatorを表すデータまでbufferに入っていません。\
-                データの受信が半端であることが想定されます。
-                "
-            ));
+    /// 接続の自身側のaddress。
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.writer.local_addr()
+    }
+
+    /// 接続の相手側のaddress。
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.writer.peer_addr()
+    }
+
+    /// 送信したMessageが相手に届くよう、FINを送ってから相手が接続を閉じるのを待って閉じる。
+    /// 未読のデータを残したまま閉じるとRSTになり、直前に送ったNOTIFICATIONが
+    /// 相手に捨てられることがあるので、待つ間に受信したものは読み捨てる。
+    /// 相手が閉じなくても、timeoutが経てば閉じる。
+    pub async fn close(mut self, timeout: Duration) {
+        let closed = tokio::time::timeout(timeout, async {
+            if let Err(e) = self.writer.flush().await {
+                warn!("cannot flush tcp connection, {:?}.", e);
+            }
+            if let Err(e) = self.writer.shutdown().await {
+                warn!("cannot shutdown tcp connection, {:?}.", e);
+                return;
+            }
+            while self.receiver.recv().await.is_some() {}
+        })
+        .await;
+        if closed.is_err() {
+            warn!(
+                "tcp connection is closed without waiting for remote after {:?}.",
+                timeout
+            );
         }
-        Ok(u16::from_be_bytes([self.buffer[16], self.buffer[17]]) as usize)
+    }
+
+    /// まだ取り出していないMessageを受け取っていればtrue。
+    pub fn has_buffered_message(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// 受信taskからMessageが届くまで待つ。
+    /// 相手が接続を閉じている場合は、Messageが届くことは無いのでずっと待つ。
+    pub async fn wait_readable(&mut self) {
+        if self.pending.is_some() {
+            return;
+        }
+        if !self.closed {
+            match self.receiver.recv().await {
+                Some(received) => {
+                    self.pending = Some(received);
+                    return;
+                }
+                None => self.closed = true,
+            }
+        }
+        std::future::pending::<()>().await;
+    }
+
+    /// 相手が接続を閉じるまで読み込み、切り出したMessageをsenderへ送る。
+    /// peerが取り出すのが遅れてchannelが埋まると、空くまで読み込みを止める。
+    async fn read_messages(
+        mut reader: OwnedReadHalf,
+        sender: mpsc::Sender<Received>,
+        update_compression: Arc<AtomicBool>,
+    ) {
+        let mut framer = MessageFramer::new();
+        loop {
+            let received = match framer.next_frame() {
+                Ok(Some(bytes)) => match Self::decompress_update(bytes, &update_compression) {
+                    Ok(bytes) => match Message::try_from(&bytes[..]) {
+                        Ok(message) => Received::Message(message),
+                        Err(e) => match e.message_error() {
+                            Some(error) => {
+                                let _ = sender.send(Received::Rejected { bytes, error }).await;
+                                return;
+                            }
+                            None => Received::Malformed {
+                                bytes,
+                                reason: e.to_string(),
+                            },
+                        },
+                    },
+                    Err(malformed) => malformed,
+                },
+                Ok(None) => {
+                    match reader.read_buf(&mut framer.buffer).await {
+                        Ok(0) => return,
+                        Ok(_) => {}
+                        Err(e) => {
+                            warn!(
+                                "failed to read data from tcp connection, remote={:?}, {:?}.",
+                                reader.peer_addr(),
+                                e
+                            );
+                            return;
+                        }
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    let _ = sender.send(Received::HeaderError(e)).await;
+                    return;
+                }
+            };
+            if sender.send(received).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// UPDATEのbodyを圧縮して送受信している場合は、受信したUPDATEを展開する。
+    /// 展開できなかった場合は、解釈できなかったMessageとして返す。
+    fn decompress_update(
+        bytes: BytesMut,
+        #[cfg_attr(not(feature = "update-compression"), allow(unused_variables))]
+        update_compression: &AtomicBool,
+    ) -> Result<BytesMut, Received> {
+        #[cfg(feature = "update-compression")]
+        if update_compression.load(Ordering::Acquire) {
+            return compression::decompress_update(bytes.clone()).map_err(|e| {
+                Received::Malformed {
+                    bytes,
+                    reason: format!("{e:#}"),
+                }
+            });
+        }
+        Ok(bytes)
     }
 
     async fn connect_to_remote_peer(config: &Config) -> Result<TcpStream> {
-        let bgp_port = 179;
-        TcpStream::connect((config.remote_ip, bgp_port))
+        let bgp_port = config.port;
+        // 相手がpeer_addrで接続元を確かめられるよう、local_ipから接続する。
+        let socket = match config.source_port {
+            Some(source_port) => Self::reusable_socket(config, source_port)?,
+            None => {
+                let socket = TcpSocket::new_v4()?;
+                socket.bind((config.local_ip, 0).into()).context(format!(
+                    "{0}にbindすることができませんでした。",
+                    config.local_ip
+                ))?;
+                socket
+            }
+        };
+        Self::set_buffer_sizes(SockRef::from(&socket), config)?;
+        socket
+            .connect((config.remote_ip, bgp_port).into())
             .await
             .context(format!(
                 "cannot connect to remote peer {0}:{1}",
@@ -82,23 +393,558 @@ impl Connection {
             ))
     }
 
-    async fn wait_connection_from_remote_peer(config: &Config) -> Result<TcpStream> {
-        let bgp_port = 179;
+    /// TCPのwindowの大きさに反映されるよう、接続を確立する前に設定する。
+    fn set_buffer_sizes(socket: SockRef, config: &Config) -> Result<()> {
+        if let Some(size) = config.send_buffer_size {
+            socket
+                .set_send_buffer_size(size)
+                .context(format!("cannot set SO_SNDBUF to {size}"))?;
+        }
+        if let Some(size) = config.receive_buffer_size {
+            socket
+                .set_recv_buffer_size(size)
+                .context(format!("cannot set SO_RCVBUF to {size}"))?;
+        }
+        Ok(())
+    }
+
+    /// SO_REUSEADDR/SO_REUSEPORTを有効にし、local_ip:source_portにbindしたsocketを作る。
+    fn reusable_socket(config: &Config, source_port: u16) -> Result<TcpSocket> {
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_reuse_port(true)?;
+        socket.set_nonblocking(true)?;
+        socket
+            .bind(&std::net::SocketAddr::from((config.local_ip, source_port)).into())
+            .context(format!(
+                "{0}:{1}にbindすることができませんでした。",
+                config.local_ip, source_port
+            ))?;
+        Ok(TcpSocket::from_std_stream(socket.into()))
+    }
+
+    /// local_ip:portで接続を待つlistenerを作る。
+    /// acceptした接続はlistenerのbufferの大きさを引き継ぐ。
+    pub async fn listen(config: &Config) -> Result<TcpListener> {
+        let bgp_port = config.port;
         let listener = TcpListener::bind((config.local_ip, bgp_port))
             .await
             .context(format!(
                 "{0}:{1}にbindすることができませんでした。",
                 config.local_ip, bgp_port
             ))?;
-        Ok(listener
-            .accept()
-            .await
-            .context(format!(
+        Self::set_buffer_sizes(SockRef::from(&listener), config)?;
+        Ok(listener)
+    }
+
+    async fn wait_connection_from_remote_peer(config: &Config) -> Result<TcpStream> {
+        let listener = Self::listen(config).await?;
+        Self::accept_from_remote_peer(&listener, config).await
+    }
+
+    /// timeoutまで相手からの接続を待ち、届かなければ自分から接続する。
+    /// 接続している間に相手からの接続も届いた場合は、RFC 4271 Section 6.8と同じく
+    /// BGP Identifierの大きい側が開始した接続を残す。BGP Identifierにはlocal_ipを用いている。
+    async fn wait_then_connect_to_remote_peer(
+        config: &Config,
+        timeout: Duration,
+    ) -> Result<TcpStream> {
+        let listener = Self::listen(config).await?;
+        if let Ok(inbound) =
+            tokio::time::timeout(timeout, Self::accept_from_remote_peer(&listener, config)).await
+        {
+            return inbound;
+        }
+        info!(
+            "no tcp connection from {} within {:?}, connecting actively.",
+            config.remote_ip, timeout
+        );
+        let outbound = tokio::select! {
+            inbound = Self::accept_from_remote_peer(&listener, config) => return inbound,
+            outbound = Self::connect_to_remote_peer(config) => outbound?,
+        };
+        match Self::accept_from_remote_peer(&listener, config).now_or_never() {
+            Some(Ok(inbound)) if config.local_ip < config.remote_ip => {
+                info!(
+                    "connection collision with {} is resolved, inbound connection is kept.",
+                    config.remote_ip
+                );
+                Self::dump_collided_connection(outbound).await;
+                Ok(inbound)
+            }
+            Some(Ok(inbound)) => {
+                info!(
+                    "connection collision with {} is resolved, outbound connection is kept.",
+                    config.remote_ip
+                );
+                Self::dump_collided_connection(inbound).await;
+                Ok(outbound)
+            }
+            _ => Ok(outbound),
+        }
+    }
+
+    /// 衝突の解決で残さない接続に、Cease(Connection Collision Resolution)を送って閉じる。
+    /// 相手はOpenCollisionDumpとしてこの接続をIdleに戻す。
+    async fn dump_collided_connection(mut stream: TcpStream) {
+        let cease: BytesMut =
+            Message::new_notification(CEASE, CONNECTION_COLLISION_RESOLUTION, BytesMut::new())
+                .into();
+        if let Err(e) = stream.write_all(&cease[..]).await {
+            warn!("cannot send cease to collided connection, {:?}.", e);
+        }
+    }
+
+    async fn accept_from_remote_peer(listener: &TcpListener, config: &Config) -> Result<TcpStream> {
+        let bgp_port = config.port;
+        // remote_ip以外からの接続は、別のneighborへのものなので閉じて待ち続ける。
+        loop {
+            let (stream, remote) = listener.accept().await.context(format!(
                 "{0}:{1}にてリモートからの TCP Connectionの要求を完遂することができませんでした。\
         リモートからTCP Connectionの要求が来ていない可能性が高いです。
         ",
                 config.local_ip, bgp_port
-            ))?
-            .0)
+            ))?;
+            if remote.ip() == IpAddr::V4(config.remote_ip) {
+                return Ok(stream);
+            }
+            warn!(
+                "tcp connection from unexpected remote {} is closed, expected {}.",
+                remote, config.remote_ip
+            );
+        }
+    }
+}
+
+/// `close`を経ずに捨てた場合(`Peer::stop`など)も、相手がすぐに切断を検知できるよう、
+/// 受信taskの終了を待たずにsocketを閉じ始める。
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let _ = SockRef::from(self.writer.as_ref()).shutdown(std::net::Shutdown::Both);
+        self.reader.abort();
+    }
+}
+
+impl MessageFramer {
+    fn new() -> Self {
+        Self {
+            buffer: BytesMut::with_capacity(1500),
+            framing_state: FramingState::AwaitingHeader,
+        }
+    }
+
+    /// 受信bufferからMessageを1つ切り出す。まだ揃っていなければNoneを返す。
+    /// Headerの長さがMessage Typeの最小の長さから4096 bytesの範囲に無ければエラーにする。
+    fn next_frame(&mut self) -> Result<Option<BytesMut>, MessageHeaderError> {
+        if self.buffer.len() > MAX_BUFFER_SIZE {
+            return Err(ConnectionNotSynchronizedError::from(anyhow::anyhow!(
+                "受信bufferが{}bytesを超えましたが、Messageの区切りが見つかりません。",
+                MAX_BUFFER_SIZE
+            ))
+            .into());
+        }
+        let length = match self.framing_state {
+            FramingState::AwaitingBody { length } => length,
+            FramingState::AwaitingHeader => {
+                let Ok(length) = self.get_index_of_message_separator() else {
+                    return Ok(None);
+                };
+                if length < HEADER_LENGTH {
+                    return Err(ConnectionNotSynchronizedError::from(anyhow::anyhow!(
+                        "Headerの長さ{}bytesより短い{}bytesのMessageを受信しました。",
+                        HEADER_LENGTH,
+                        length
+                    ))
+                    .into());
+                }
+                let type_ = self.buffer[18];
+                let Ok(message_type) = MessageType::try_from(type_) else {
+                    return Err(MessageHeaderError::BadMessageType(type_));
+                };
+                if length < usize::from(message_type.min_length())
+                    || length > usize::from(MAX_MESSAGE_LENGTH)
+                {
+                    return Err(MessageHeaderError::BadMessageLength(length as u16));
+                }
+                self.framing_state = FramingState::AwaitingBody { length };
+                length
+            }
+        };
+        if self.buffer.len() < length {
+            return Ok(None);
+        }
+        self.framing_state = FramingState::AwaitingHeader;
+        Ok(Some(self.buffer.split_to(length)))
+    }
+
+    /// 次のMessageを切り出すのに、あと何bytesの受信が必要か。
+    #[cfg(test)]
+    fn bytes_awaited(&self) -> usize {
+        let length = match self.framing_state {
+            FramingState::AwaitingHeader => HEADER_LENGTH,
+            FramingState::AwaitingBody { length } => length,
+        };
+        length.saturating_sub(self.buffer.len())
+    }
+
+    fn get_index_of_message_separator(&self) -> Result<usize> {
+        if self.buffer.len() < HEADER_LENGTH {
+            return Err(anyhow::anyhow!(
+                "messageのseparatorを表すデータまでbufferに入っていません。\
+                データの受信が半端であることが想定されます。
+                "
+            ));
+        }
+        Ok(u16::from_be_bytes([self.buffer[16], self.buffer[17]]) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::error::UpdateMessageError;
+    use crate::packets::open::OpenMessage;
+
+    #[tokio::test]
+    async fn active_connections_can_share_source_port_with_reuseport() {
+        let first_listener = TcpListener::bind(("127.0.0.3", 0)).await.unwrap();
+        let port = first_listener.local_addr().unwrap().port();
+        let second_listener = TcpListener::bind(("127.0.0.4", port)).await.unwrap();
+        let source_port = std::net::TcpListener::bind("127.0.0.2:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = |remote_ip: &str| -> Config {
+            format!(
+                "64512 127.0.0.2 64513 {remote_ip} active port={port} source_port={source_port}"
+            )
+            .parse()
+            .unwrap()
+        };
+
+        let first = Connection::connect(&config("127.0.0.3")).await.unwrap();
+        let second = Connection::connect(&config("127.0.0.4")).await.unwrap();
+        let (_, first_peer) = first_listener.accept().await.unwrap();
+        let (_, second_peer) = second_listener.accept().await.unwrap();
+
+        assert_eq!(first.writer.local_addr().unwrap().port(), source_port);
+        assert_eq!(second.writer.local_addr().unwrap().port(), source_port);
+        assert_eq!(first_peer.port(), source_port);
+        assert_eq!(second_peer.port(), source_port);
+    }
+
+    /// 127.0.0.2から127.0.0.3へ接続し、両端を返す。
+    async fn connected_pair() -> (Connection, TcpStream) {
+        let listener = TcpListener::bind(("127.0.0.3", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config: Config = format!("64512 127.0.0.2 64513 127.0.0.3 active port={port}")
+            .parse()
+            .unwrap();
+        let connection = Connection::connect(&config).await.unwrap();
+        let (remote, _) = listener.accept().await.unwrap();
+        (connection, remote)
+    }
+
+    #[tokio::test]
+    async fn peer_addr_and_local_addr_are_both_ends_of_connection() {
+        let (connection, remote) = connected_pair().await;
+        assert_eq!(
+            connection.peer_addr().unwrap(),
+            remote.local_addr().unwrap()
+        );
+        assert_eq!(
+            connection.local_addr().unwrap(),
+            remote.peer_addr().unwrap()
+        );
+        assert_eq!(
+            connection.local_addr().unwrap().ip(),
+            "127.0.0.2".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn remote_observes_eof_after_close_or_drop() {
+        let (mut connection, mut remote) = connected_pair().await;
+        connection.send(Message::new_keepalive()).await;
+        let close = tokio::spawn(connection.close(Duration::from_secs(1)));
+        let mut received = vec![];
+        let eof = tokio::time::timeout(
+            Duration::from_millis(500),
+            remote.read_to_end(&mut received),
+        )
+        .await
+        .unwrap();
+        assert_eq!(eof.unwrap(), 19);
+        drop(remote);
+        close.await.unwrap();
+
+        let (connection, mut remote) = connected_pair().await;
+        drop(connection);
+        let mut buf = [0u8; 19];
+        let eof = tokio::time::timeout(Duration::from_millis(500), remote.read(&mut buf))
+            .await
+            .unwrap();
+        assert_eq!(eof.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn connection_sets_nodelay_and_configured_buffer_sizes() {
+        let listener = TcpListener::bind(("127.0.0.3", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config: Config = format!(
+            "64512 127.0.0.2 64513 127.0.0.3 active port={port} \
+             send_buffer_size=65536 receive_buffer_size=131072"
+        )
+        .parse()
+        .unwrap();
+        let connection = Connection::connect(&config).await.unwrap();
+        let _remote = listener.accept().await.unwrap();
+
+        let socket = SockRef::from(connection.writer.as_ref());
+        assert!(socket.nodelay().unwrap());
+        // Linuxは管理領域の分として設定値の2倍を確保する。
+        assert!(socket.send_buffer_size().unwrap() >= 65536);
+        assert!(socket.recv_buffer_size().unwrap() >= 131072);
+    }
+
+    #[tokio::test]
+    async fn passive_connection_accepts_only_configured_remote() {
+        let port = TcpListener::bind(("127.0.0.3", 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config: Config = format!("64513 127.0.0.3 64512 127.0.0.2 passive port={port}")
+            .parse()
+            .unwrap();
+        let passive = tokio::spawn(async move { Connection::connect(&config).await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let connect_from = |local_ip: &'static str| async move {
+            let socket = TcpSocket::new_v4().unwrap();
+            socket
+                .bind((local_ip.parse::<IpAddr>().unwrap(), 0).into())
+                .unwrap();
+            socket
+                .connect(("127.0.0.3".parse::<IpAddr>().unwrap(), port).into())
+                .await
+                .unwrap()
+        };
+        let _unexpected = connect_from("127.0.0.4").await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!passive.is_finished());
+
+        let expected = connect_from("127.0.0.2").await;
+        let connection = passive.await.unwrap().unwrap();
+        assert_eq!(
+            connection.peer_addr().unwrap(),
+            expected.local_addr().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn message_delivered_one_byte_at_a_time_is_parsed_once() {
+        let open = Message::new_open(64513.into(), "127.0.0.3".parse().unwrap());
+        let bytes: BytesMut = open.clone().into();
+
+        let mut framer = MessageFramer::new();
+        for (i, byte) in bytes.iter().enumerate().take(bytes.len() - 1) {
+            framer.buffer.extend_from_slice(&[*byte]);
+            assert!(framer.next_frame().unwrap().is_none());
+            let expected_state = if i + 1 < HEADER_LENGTH {
+                FramingState::AwaitingHeader
+            } else {
+                FramingState::AwaitingBody {
+                    length: bytes.len(),
+                }
+            };
+            assert_eq!(framer.framing_state, expected_state);
+            assert_eq!(
+                framer.bytes_awaited(),
+                match expected_state {
+                    FramingState::AwaitingHeader => HEADER_LENGTH - (i + 1),
+                    FramingState::AwaitingBody { length } => length - (i + 1),
+                }
+            );
+        }
+        framer.buffer.extend_from_slice(&bytes[bytes.len() - 1..]);
+        assert_eq!(framer.next_frame().unwrap(), Some(bytes.clone()));
+        assert_eq!(framer.framing_state, FramingState::AwaitingHeader);
+        assert!(framer.buffer.is_empty());
+
+        let (mut connection, mut remote) = connected_pair().await;
+        let mut dead_letters = DeadLetterLog::new(1);
+        for byte in bytes.iter() {
+            remote.write_all(&[*byte]).await.unwrap();
+        }
+        tokio::time::timeout(Duration::from_secs(1), connection.wait_readable())
+            .await
+            .unwrap();
+        assert_eq!(
+            connection.get_message(&mut dead_letters).await.unwrap(),
+            Some(open)
+        );
+        assert!(connection
+            .get_message(&mut dead_letters)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn malformed_message_is_recorded_as_dead_letter() {
+        let (mut connection, mut remote) = connected_pair().await;
+        let mut dead_letters = DeadLetterLog::new(2);
+
+        // Message Subtype 9は存在しないROUTE-REFRESH。RFC 7313に従い無視する。
+        let mut malformed = vec![0xff; 16];
+        malformed.extend([0x00, 0x17, 0x05, 0x00, 0x01, 0x09, 0x01]);
+        remote.write_all(&malformed).await.unwrap();
+        let keepalive: BytesMut = Message::new_keepalive().into();
+        remote.write_all(&keepalive).await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(1), connection.wait_readable())
+            .await
+            .unwrap();
+        assert!(connection
+            .get_message(&mut dead_letters)
+            .await
+            .unwrap()
+            .is_none());
+        // 捨てたMessageの後に届いたMessageは、そのまま受信できる。
+        tokio::time::timeout(Duration::from_secs(1), connection.wait_readable())
+            .await
+            .unwrap();
+        assert!(matches!(
+            connection.get_message(&mut dead_letters).await.unwrap(),
+            Some(Message::Keepalive(_))
+        ));
+
+        let recorded = dead_letters.dead_letters();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(
+            recorded[0].bytes,
+            format!("{}00170500010901", "ff".repeat(16))
+        );
+        assert!(recorded[0].reason.contains("9"), "{}", recorded[0].reason);
+    }
+
+    #[test]
+    fn framer_rejects_message_length_out_of_range_for_its_type() {
+        let header_error = |length: u16, type_: u8| {
+            let mut framer = MessageFramer::new();
+            framer.buffer.extend_from_slice(&[0xff; 16]);
+            framer.buffer.extend_from_slice(&length.to_be_bytes());
+            framer.buffer.extend_from_slice(&[type_]);
+            framer.next_frame().unwrap_err()
+        };
+        // UPDATEはWithdrawn Routes LengthとTotal Path Attribute Lengthを含めて23 bytes以上。
+        assert!(matches!(
+            header_error(19, 2),
+            MessageHeaderError::BadMessageLength(19)
+        ));
+        assert!(matches!(
+            header_error(28, 1),
+            MessageHeaderError::BadMessageLength(28)
+        ));
+        assert!(matches!(
+            header_error(4097, 2),
+            MessageHeaderError::BadMessageLength(4097)
+        ));
+        let error = header_error(19, 9);
+        assert!(matches!(error, MessageHeaderError::BadMessageType(9)));
+        assert_eq!(error.subcode(), 3);
+        assert_eq!(&error.data()[..], &[9]);
+    }
+
+    #[tokio::test]
+    async fn malformed_update_is_recorded_and_returned_as_update_message_error() {
+        let (mut connection, mut remote) = connected_pair().await;
+        let mut dead_letters = DeadLetterLog::new(1);
+
+        // Withdrawn Routes LengthがMessageの長さを超えているUPDATE。
+        let mut malformed = vec![0xff; 16];
+        malformed.extend([0x00, 0x17, 0x02, 0x00, 0xff, 0x00, 0x00]);
+        remote.write_all(&malformed).await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(1), connection.wait_readable())
+            .await
+            .unwrap();
+        let error = connection.get_message(&mut dead_letters).await.unwrap_err();
+        assert!(matches!(
+            error,
+            MessageError::Update(UpdateMessageError::MalformedAttributeList(_))
+        ));
+        assert_eq!((error.code(), error.subcode()), (3, 1));
+        let recorded = dead_letters.dead_letters();
+        assert_eq!(recorded.len(), 1);
+        assert!(
+            recorded[0].reason.contains("Withdrawn Routes Length 255"),
+            "{}",
+            recorded[0].reason
+        );
+    }
+
+    #[tokio::test]
+    async fn padded_keepalive_is_bad_message_length() {
+        let (mut connection, mut remote) = connected_pair().await;
+        let mut dead_letters = DeadLetterLog::new(1);
+
+        let mut padded = vec![0xff; 16];
+        padded.extend([0x00, 23, 0x04, 0, 0, 0, 0]);
+        remote.write_all(&padded).await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(1), connection.wait_readable())
+            .await
+            .unwrap();
+        let error = connection.get_message(&mut dead_letters).await.unwrap_err();
+        assert!(matches!(
+            error,
+            MessageError::Header(MessageHeaderError::BadMessageLength(23))
+        ));
+        assert_eq!(error.subcode(), 2);
+        assert_eq!(&error.data()[..], &[0x00, 23]);
+    }
+
+    #[tokio::test]
+    async fn messages_flow_through_channel_in_order_without_drops_under_load() {
+        let (mut connection, mut remote) = connected_pair().await;
+        let mut dead_letters = DeadLetterLog::new(1);
+
+        // channelの容量より多く送り、受信taskが読み込みを待たされるようにする。
+        let count = MESSAGE_CHANNEL_CAPACITY as u16 * 8;
+        let writer = tokio::spawn(async move {
+            for hold_time in 0..count {
+                let open = OpenMessage::new(64513.into(), "127.0.0.3".parse().unwrap())
+                    .with_hold_time(hold_time.into());
+                let bytes: BytesMut = Message::Open(open).into();
+                remote.write_all(&bytes).await.unwrap();
+            }
+            remote
+        });
+
+        let mut received = vec![];
+        while received.len() < count as usize {
+            tokio::time::timeout(Duration::from_secs(1), connection.wait_readable())
+                .await
+                .unwrap();
+            match connection.get_message(&mut dead_letters).await.unwrap() {
+                Some(Message::Open(open)) => received.push(u16::from(open.hold_time())),
+                message => panic!("unexpected message {message:?}"),
+            }
+        }
+        writer.await.unwrap();
+
+        assert_eq!(received, (0..count).collect::<Vec<u16>>());
+        assert!(dead_letters.dead_letters().is_empty());
+        assert!(connection
+            .get_message(&mut dead_letters)
+            .await
+            .unwrap()
+            .is_none());
     }
 }
@@ -0,0 +1,248 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use futures::future::join_all;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::connection::Connection;
+use crate::peer::{self, Peer};
+use crate::routing::{Ipv4Network, LocRib};
+use crate::state::State;
+
+type PeerSetupCallback = dyn Fn(&mut Peer) + Send + Sync;
+
+struct PeerSetup(Box<PeerSetupCallback>);
+
+impl std::fmt::Debug for PeerSetup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PeerSetup")
+    }
+}
+
+/// listen_rangeの中のaddressからの接続を受け付け、雛形のconfigを引き継いだpeerを動的に作る。
+/// セッションが閉じてIdleに戻ったpeerは取り除き、同じaddressからの接続を再び受け付ける。
+#[derive(Debug)]
+pub struct DynamicNeighbors {
+    template: Config,
+    listen_range: Ipv4Network,
+    listener: TcpListener,
+    loc_rib: Arc<Mutex<LocRib>>,
+    peers: Vec<Peer>,
+    setup: Option<PeerSetup>,
+}
+
+impl DynamicNeighbors {
+    /// templateのlocal_ip:portで接続を待ち始める。templateにはlisten_rangeが必要。
+    pub async fn bind(template: Config, loc_rib: Arc<Mutex<LocRib>>) -> Result<Self> {
+        let listen_range = template
+            .listen_range
+            .context("dynamic neighborsにはlisten_rangeの設定が必要です。")?;
+        let listener = Connection::listen(&template).await?;
+        Ok(Self {
+            template,
+            listen_range,
+            listener,
+            loc_rib,
+            peers: vec![],
+            setup: None,
+        })
+    }
+
+    /// 動的に作ったpeerを開始する前に呼ばれるcallbackを登録する。
+    /// 静的なneighborと同じadmission controlやFIBを設定するのに用いる。
+    pub fn set_peer_setup(&mut self, setup: impl Fn(&mut Peer) + Send + Sync + 'static) {
+        self.setup = Some(PeerSetup(Box::new(setup)));
+    }
+
+    pub fn peers(&self) -> &[Peer] {
+        &self.peers
+    }
+
+    /// 終了時に、全てのpeerへ並行してCeaseを送って閉じる。
+    pub async fn notify_shutdown(&mut self) {
+        join_all(self.peers.iter_mut().map(|peer| peer.notify_shutdown())).await;
+        self.peers.clear();
+    }
+
+    /// 接続が届くか、いずれかのpeerに処理することができるまで待ち、全てのpeerの処理を1回ずつ進める。
+    pub async fn next(&mut self) {
+        self.wait_for_activity().await;
+        self.poll().await;
+    }
+
+    /// 接続が届くか、いずれかのpeerに処理することができるまで待つ。届いた接続はここで受け付ける。
+    pub async fn wait_for_activity(&mut self) {
+        // peerに処理することが残っていても、届いている接続は受け付ける。
+        let accepted = tokio::select! {
+            biased;
+            accepted = self.listener.accept() => accepted,
+            _ = peer::wait_for_any_activity(&mut self.peers) => return,
+        };
+        match accepted {
+            Ok((stream, remote)) => self.accept(stream, remote),
+            Err(e) => warn!("failed to accept tcp connection, {:?}.", e),
+        }
+    }
+
+    /// 待たずに全てのpeerの処理を1回ずつ進め、Idleに戻ったpeerを取り除く。
+    pub async fn poll(&mut self) {
+        for peer in &mut self.peers {
+            peer.poll().await;
+        }
+        self.peers.retain(|peer| {
+            let closed = peer.state() == State::Idle;
+            if closed {
+                info!("dynamic neighbor {} is removed.", peer.status().remote_ip);
+            }
+            !closed
+        });
+    }
+
+    fn accept(&mut self, stream: TcpStream, remote: SocketAddr) {
+        let IpAddr::V4(remote_ip) = remote.ip() else {
+            warn!(
+                "tcp connection from {} is closed, ipv6 is not supported.",
+                remote
+            );
+            return;
+        };
+        if !self.listen_range.contains(remote_ip) {
+            warn!(
+                "tcp connection from {} is closed, it is out of listen_range {}.",
+                remote, *self.listen_range
+            );
+            return;
+        }
+        if self
+            .peers
+            .iter()
+            .any(|peer| peer.status().remote_ip == remote_ip)
+        {
+            warn!(
+                "tcp connection from {} is closed, a session with it already exists.",
+                remote
+            );
+            return;
+        }
+        if self
+            .template
+            .listen_limit
+            .is_some_and(|limit| self.peers.len() >= limit)
+        {
+            warn!(
+                "tcp connection from {} is closed, {} dynamic neighbors already exist.",
+                remote,
+                self.peers.len()
+            );
+            return;
+        }
+        let connection = match Connection::from_accepted(stream) {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("failed to set up tcp connection from {}, {:?}.", remote, e);
+                return;
+            }
+        };
+
+        let mut config = self.template.clone();
+        config.remote_ip = remote_ip;
+        config.listen_range = None;
+        config.listen_limit = None;
+        let mut peer = Peer::new(config, Arc::clone(&self.loc_rib));
+        if let Some(setup) = &self.setup {
+            (setup.0)(&mut peer);
+        }
+        peer.set_accepted_connection(connection);
+        info!("dynamic neighbor {} is created.", remote_ip);
+        peer.start();
+        self.peers.push(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpSocket;
+
+    use super::*;
+
+    fn spawn_active_peer(local_ip: &str, port: u16) {
+        let config: Config = format!("64512 {local_ip} 64513 127.0.0.3 active port={port}")
+            .parse()
+            .unwrap();
+        tokio::spawn(async move {
+            let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+            let mut peer = Peer::new(config, loc_rib);
+            peer.start();
+            loop {
+                peer.next().await;
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn connections_within_listen_range_establish_dynamic_neighbors() {
+        let port = TcpListener::bind(("127.0.0.3", 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let template: Config = format!(
+            "64513 127.0.0.3 64512 0.0.0.0 passive port={port} \
+             listen_range=127.0.0.0/24 listen_limit=2"
+        )
+        .parse()
+        .unwrap();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&template).await.unwrap()));
+        let mut neighbors = DynamicNeighbors::bind(template, loc_rib).await.unwrap();
+
+        spawn_active_peer("127.0.0.2", port);
+        spawn_active_peer("127.0.0.4", port);
+        let established = tokio::time::timeout(Duration::from_secs(10), async {
+            while !(neighbors.peers().len() == 2
+                && neighbors
+                    .peers()
+                    .iter()
+                    .all(|peer| peer.state() == State::Established))
+            {
+                neighbors.next().await;
+            }
+        })
+        .await;
+        assert!(established.is_ok());
+        let mut remote_ips: Vec<_> = neighbors
+            .peers()
+            .iter()
+            .map(|peer| peer.status().remote_ip)
+            .collect();
+        remote_ips.sort();
+        assert_eq!(
+            remote_ips,
+            vec![
+                "127.0.0.2".parse::<std::net::Ipv4Addr>().unwrap(),
+                "127.0.0.4".parse().unwrap()
+            ]
+        );
+
+        // listen_limitに達しているので、3つ目の接続は閉じられる。
+        let socket = TcpSocket::new_v4().unwrap();
+        socket
+            .bind(("127.0.0.5".parse::<IpAddr>().unwrap(), 0).into())
+            .unwrap();
+        let mut third = socket
+            .connect(("127.0.0.3".parse::<IpAddr>().unwrap(), port).into())
+            .await
+            .unwrap();
+        neighbors.next().await;
+        assert_eq!(neighbors.peers().len(), 2);
+        let mut buf = [0; 1];
+        assert_eq!(third.read(&mut buf).await.unwrap(), 0);
+    }
+}
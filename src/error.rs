@@ -1,3 +1,4 @@
+use bytes::BytesMut;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -21,17 +22,202 @@ pub struct ConvertBytesToBgpMessageError {
     source: anyhow::Error,
 }
 
+impl ConvertBytesToBgpMessageError {
+    /// Message Typeに対してMessageの長さが不正な場合、その長さを返す。
+    pub fn bad_message_length(&self) -> Option<u16> {
+        self.source
+            .downcast_ref::<BadMessageLengthError>()
+            .map(|e| e.length)
+    }
+
+    /// path attributeの長さが不正な場合、そのエラーを返す。
+    pub fn attribute_length_error(&self) -> Option<&AttributeLengthError> {
+        self.source.downcast_ref::<AttributeLengthError>()
+    }
+
+    /// NOTIFICATIONを送ってセッションを閉じるべきエラーなら、その種類を返す。
+    /// Noneの場合は、Messageを捨てるだけにする。
+    pub fn message_error(&self) -> Option<MessageError> {
+        if let Some(length) = self.bad_message_length() {
+            return Some(MessageHeaderError::BadMessageLength(length).into());
+        }
+        if let Some(e) = self.attribute_length_error() {
+            return Some(UpdateMessageError::from(e.clone()).into());
+        }
+        if let Some(e) = self.source.downcast_ref::<UpdateMessageError>() {
+            return Some(e.clone().into());
+        }
+        self.source
+            .downcast_ref::<OpenMessageError>()
+            .map(|e| e.clone().into())
+    }
+}
+
+/// Message Typeに対して不正な長さのMessage(RFC 4271 Section 6.1)。
+#[derive(Error, Debug)]
+#[error("Message Typeが{type_name}のMessageの長さが不正です。length={length}")]
+pub struct BadMessageLengthError {
+    pub type_name: &'static str,
+    pub length: u16,
+}
+
+/// path attributeの長さが不正なUPDATE(RFC 4271 Section 6.3)。
+/// UPDATE Message Error / Attribute Length Errorに当たる。
+#[derive(Error, Debug, Clone)]
+#[error("Attribute Type Codeが{type_code}のpath attributeの長さが不正です。length={length}")]
+pub struct AttributeLengthError {
+    pub type_code: u8,
+    pub length: usize,
+}
+
 #[derive(Error, Debug)]
 #[error(transparent)]
-pub struct ConvertBgpMessageToBytesError {
+pub struct ConstructIpv4NetworkError {
     #[from]
     source: anyhow::Error,
 }
 
-
 #[derive(Error, Debug)]
 #[error(transparent)]
-pub struct ConstructIpv4NetworkError {
+pub struct ConnectionNotSynchronizedError {
     #[from]
     source: anyhow::Error,
 }
+
+/// 受信したMessageのHeaderのエラー(RFC 4271 Section 6.1)。
+/// Message Header ErrorのNOTIFICATIONを送り、セッションを閉じる。
+#[derive(Error, Debug)]
+pub enum MessageHeaderError {
+    #[error(transparent)]
+    ConnectionNotSynchronized(#[from] ConnectionNotSynchronizedError),
+    #[error("Message Lengthが不正です。length={0}")]
+    BadMessageLength(u16),
+    #[error("Message Typeが不正です。type={0}")]
+    BadMessageType(u8),
+}
+
+impl MessageHeaderError {
+    /// NOTIFICATIONのError Subcode。
+    pub fn subcode(&self) -> u8 {
+        match self {
+            MessageHeaderError::ConnectionNotSynchronized(_) => 1,
+            MessageHeaderError::BadMessageLength(_) => 2,
+            MessageHeaderError::BadMessageType(_) => 3,
+        }
+    }
+
+    /// NOTIFICATIONのData。Bad Message Length, Bad Message Typeでは不正だった値を入れる。
+    pub fn data(&self) -> BytesMut {
+        match self {
+            MessageHeaderError::ConnectionNotSynchronized(_) => BytesMut::new(),
+            MessageHeaderError::BadMessageLength(length) => {
+                BytesMut::from(&length.to_be_bytes()[..])
+            }
+            MessageHeaderError::BadMessageType(type_) => BytesMut::from(&[*type_][..]),
+        }
+    }
+}
+
+/// 受信したOPENのエラー(RFC 4271 Section 6.2)。
+/// OPEN Message ErrorのNOTIFICATIONを送り、セッションを閉じる。
+#[derive(Error, Debug, Clone)]
+pub enum OpenMessageError {
+    #[error("BGPのVersion {0}には対応していません。")]
+    UnsupportedVersionNumber(u8),
+}
+
+impl OpenMessageError {
+    /// 対応しているBGPのVersion。
+    const SUPPORTED_VERSION: u16 = 4;
+
+    pub fn subcode(&self) -> u8 {
+        match self {
+            OpenMessageError::UnsupportedVersionNumber(_) => 1,
+        }
+    }
+
+    /// NOTIFICATIONのData。Unsupported Version Numberでは対応しているVersionを入れる。
+    pub fn data(&self) -> BytesMut {
+        match self {
+            OpenMessageError::UnsupportedVersionNumber(_) => {
+                BytesMut::from(&Self::SUPPORTED_VERSION.to_be_bytes()[..])
+            }
+        }
+    }
+}
+
+/// 受信したUPDATEのエラー(RFC 4271 Section 6.3)。
+/// UPDATE Message ErrorのNOTIFICATIONを送り、セッションを閉じる。
+#[derive(Error, Debug, Clone)]
+pub enum UpdateMessageError {
+    #[error("UPDATEのpath attributeの並びが不正です。{0}")]
+    MalformedAttributeList(String),
+    #[error(transparent)]
+    AttributeLength(#[from] AttributeLengthError),
+    #[error("ORIGINが不正です。{0}")]
+    InvalidOrigin(String),
+    #[error("NLRIが不正です。{0}")]
+    InvalidNetworkField(String),
+    #[error("AS_PATHが不正です。{0}")]
+    MalformedAsPath(String),
+}
+
+impl UpdateMessageError {
+    pub fn subcode(&self) -> u8 {
+        match self {
+            UpdateMessageError::MalformedAttributeList(_) => 1,
+            UpdateMessageError::AttributeLength(_) => 5,
+            UpdateMessageError::InvalidOrigin(_) => 6,
+            UpdateMessageError::InvalidNetworkField(_) => 10,
+            UpdateMessageError::MalformedAsPath(_) => 11,
+        }
+    }
+}
+
+/// 受信したMessageのエラーのうち、NOTIFICATIONを送ってセッションを閉じるもの(RFC 4271 Section 6)。
+#[derive(Error, Debug)]
+pub enum MessageError {
+    #[error(transparent)]
+    Header(#[from] MessageHeaderError),
+    #[error(transparent)]
+    Open(#[from] OpenMessageError),
+    #[error(transparent)]
+    Update(#[from] UpdateMessageError),
+}
+
+impl MessageError {
+    /// NOTIFICATIONのError Code。
+    pub fn code(&self) -> u8 {
+        match self {
+            MessageError::Header(_) => 1,
+            MessageError::Open(_) => 2,
+            MessageError::Update(_) => 3,
+        }
+    }
+
+    pub fn subcode(&self) -> u8 {
+        match self {
+            MessageError::Header(e) => e.subcode(),
+            MessageError::Open(e) => e.subcode(),
+            MessageError::Update(e) => e.subcode(),
+        }
+    }
+
+    pub fn data(&self) -> BytesMut {
+        match self {
+            MessageError::Header(e) => e.data(),
+            MessageError::Open(e) => e.data(),
+            MessageError::Update(_) => BytesMut::new(),
+        }
+    }
+}
+
+/// peerの処理中に起きたエラー。
+/// エラーが起きたpeerはIdleに戻り、ConnectRetry Timerが満了した後に再接続を試みる。
+#[derive(Error, Debug)]
+pub enum PeerError {
+    #[error("TCP Connectionの確立ができませんでした。{0}")]
+    ConnectionFailed(#[from] CreateConnectionError),
+    #[error("TCP Connectionが確立できていません。")]
+    NotConnected,
+}
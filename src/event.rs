@@ -1,15 +1,28 @@
-use crate::packets::{keepalive::KeepaliveMessage, open::OpenMessage, update::UpdateMessage};
+use crate::packets::{
+    keepalive::KeepaliveMessage, notification::NotificationMessage, open::OpenMessage,
+    route_refresh::RouteRefreshMessage, update::UpdateMessage,
+};
 
 #[derive(PartialEq, Eq, Debug, Clone, Hash)]
 pub enum Event {
     ManualStart,
+    ManualStop,
     TcpConnectionConfirmed,
+    TcpConnectionFails,
+    ConnectRetryTimerExpires,
     BgpOpen(OpenMessage),
     KeepAliveMsg(KeepaliveMessage),
     UpdateMsg(UpdateMessage),
+    NotifMsg(NotificationMessage),
+    RouteRefreshMsg(RouteRefreshMessage),
+    BgpHeaderErr,
+    BgpOpenMsgErr,
+    UpdateMsgErr,
     Established,
     LocRib,
     LocRibChanged,
     AdjRibOutChanged,
     AdjRibInChanged,
+    HoldTimerExpires,
+    KeepaliveTimerExpires,
 }
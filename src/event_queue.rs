@@ -12,13 +12,26 @@ impl EventQueue {
         self.0.push_front(event)
     }
 
+    /// 既に積まれているeventより先に取り出されるよう積む。
+    pub fn enqueue_first(&mut self, event: Event) {
+        self.0.push_back(event)
+    }
+
     pub fn dequeue(&mut self) -> Option<Event> {
         self.0.pop_back()
     }
-}
 
-#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
-pub enum State {
-    Idle,
-    Connect,
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, event: &Event) -> bool {
+        self.0.contains(event)
+    }
+
+    /// 積まれているeventを、取り出される順に返す。
+    #[cfg(test)]
+    pub fn peek_all(&self) -> Vec<Event> {
+        self.0.iter().rev().cloned().collect()
+    }
 }
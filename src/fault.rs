@@ -0,0 +1,129 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use bytes::BytesMut;
+
+use crate::packets::header::MessageType;
+use crate::packets::message::Message;
+
+/// Messageに注入する障害。
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Fault {
+    /// Messageを捨てる。
+    Drop,
+    /// Messageのどこか1 byteを書き換える。
+    Corrupt,
+    /// 送信をこの時間だけ遅らせる。受信したMessageには効果が無い。
+    Delay(Duration),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Direction {
+    Send,
+    Receive,
+}
+
+/// 障害を注入するMessageの選び方。
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Trigger {
+    /// その方向でN番目(1から数える)のMessage。
+    Nth(usize),
+    /// その方向の、このMessage Typeの全てのMessage。
+    Type(MessageType),
+}
+
+#[derive(Debug)]
+struct Rule {
+    direction: Direction,
+    trigger: Trigger,
+    fault: Fault,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    sent: usize,
+    received: usize,
+    injected: usize,
+}
+
+/// テストで、Connectionが送受信するMessageに障害を注入する。
+/// `Peer::set_fault_injector`で設定すると、以降に確立する接続に適用される。
+#[derive(Debug)]
+pub struct FaultInjector {
+    rules: Vec<Rule>,
+    counters: Mutex<Counters>,
+    rng: Mutex<fastrand::Rng>,
+}
+
+impl FaultInjector {
+    pub fn new() -> Self {
+        Self {
+            rules: vec![],
+            counters: Mutex::new(Counters::default()),
+            rng: Mutex::new(fastrand::Rng::with_seed(0)),
+        }
+    }
+
+    pub fn with_fault(mut self, direction: Direction, trigger: Trigger, fault: Fault) -> Self {
+        self.rules.push(Rule {
+            direction,
+            trigger,
+            fault,
+        });
+        self
+    }
+
+    /// これまでに障害を注入したMessageの数。
+    pub fn injected(&self) -> usize {
+        self.counters.lock().unwrap().injected
+    }
+
+    /// messageを数え、注入すべき障害があれば返す。
+    pub(crate) fn fault_for(&self, direction: Direction, message: &Message) -> Option<Fault> {
+        let mut counters = self.counters.lock().unwrap();
+        let nth = match direction {
+            Direction::Send => {
+                counters.sent += 1;
+                counters.sent
+            }
+            Direction::Receive => {
+                counters.received += 1;
+                counters.received
+            }
+        };
+        let message_type = message_type(message);
+        let fault = self
+            .rules
+            .iter()
+            .filter(|rule| rule.direction == direction)
+            .find(|rule| match rule.trigger {
+                Trigger::Nth(n) => n == nth,
+                Trigger::Type(t) => t == message_type,
+            })
+            .map(|rule| rule.fault);
+        if fault.is_some() {
+            counters.injected += 1;
+        }
+        fault
+    }
+
+    /// bytesのどこか1 byteを、元と異なる値に書き換える。
+    pub(crate) fn corrupt(&self, bytes: &mut BytesMut) {
+        if bytes.is_empty() {
+            return;
+        }
+        let mut rng = self.rng.lock().unwrap();
+        let index = rng.usize(..bytes.len());
+        bytes[index] ^= rng.u8(1..);
+    }
+}
+
+fn message_type(message: &Message) -> MessageType {
+    match message {
+        Message::Open(_) => MessageType::Open,
+        Message::Keepalive(_) => MessageType::Keepalive,
+        Message::Update(_) => MessageType::Update,
+        Message::Notification(_) => MessageType::Notification,
+        Message::RouteRefresh(_) => MessageType::RouteRefresh,
+    }
+}
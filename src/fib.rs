@@ -0,0 +1,395 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use futures::TryStreamExt;
+use rtnetlink::new_connection;
+use rtnetlink::packet::route::Nla;
+
+use crate::routing::Ipv4Network;
+
+/// BGPで学習した経路のrtm_protocol。
+/// static routeと区別し、redistributeで自身が書き込んだ経路を広告し直さないようにする。
+pub const RTPROT_BGP: u8 = 186;
+/// RTA_MULTIPATHの中で、next hopのgatewayを表すattribute。
+const RTA_GATEWAY: u16 = 5;
+
+/// ECMPの1つのnext hop。trafficはweightに比例して振り分けられる。
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct WeightedNextHop {
+    pub gateway: Ipv4Addr,
+    /// 1から255。
+    pub weight: u8,
+}
+
+/// LocRibのbest pathを書き込む転送テーブル。
+/// テストでは`InMemoryFib`を用いて、カーネルのルーティングテーブルを変更せずに確認できる。
+pub trait Fib: Debug + Send + Sync {
+    fn install(
+        &self,
+        network: Ipv4Network,
+        gateway: Ipv4Addr,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
+
+    fn remove(&self, network: Ipv4Network)
+        -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
+
+    /// 複数のnext hopへ重みを付けて振り分ける経路を書き込む。
+    /// 対応していない転送テーブルでは、最初のnext hopだけを用いる。
+    fn install_multipath(
+        &self,
+        network: Ipv4Network,
+        next_hops: Vec<WeightedNextHop>,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        match next_hops.first() {
+            Some(next_hop) => self.install(network, next_hop.gateway),
+            None => self.remove(network),
+        }
+    }
+}
+
+/// rtnetlinkでカーネルのルーティングテーブルに書き込む。
+#[derive(Debug, Default)]
+pub struct KernelFib;
+
+impl Fib for KernelFib {
+    fn install(
+        &self,
+        network: Ipv4Network,
+        gateway: Ipv4Addr,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            let (connection, handle, _) = new_connection()?;
+            tokio::spawn(connection);
+            handle
+                .route()
+                .add()
+                .v4()
+                .destination_prefix(network.ip(), network.prefix())
+                .gateway(gateway)
+                .protocol(RTPROT_BGP)
+                .execute()
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn remove(
+        &self,
+        network: Ipv4Network,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            let (connection, handle, _) = new_connection()?;
+            tokio::spawn(connection);
+            let mut routes = handle.route().get(rtnetlink::IpVersion::V4).execute();
+            while let Some(route) = routes.try_next().await? {
+                if let Some((IpAddr::V4(addr), prefix)) = route.destination_prefix() {
+                    if Ipv4Network::new(addr, prefix)? == network {
+                        handle.route().del(route).execute().await?;
+                        break;
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn install_multipath(
+        &self,
+        network: Ipv4Network,
+        next_hops: Vec<WeightedNextHop>,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            let (connection, handle, _) = new_connection()?;
+            tokio::spawn(connection);
+            // 既に単一のnext hopの経路があれば置き換える。
+            let mut request = handle
+                .route()
+                .add()
+                .v4()
+                .destination_prefix(network.ip(), network.prefix())
+                .protocol(RTPROT_BGP)
+                .replace();
+            request
+                .message_mut()
+                .nlas
+                .push(Nla::MultiPath(multipath_bytes(&next_hops)));
+            request.execute().await?;
+            Ok(())
+        })
+    }
+}
+
+/// カーネルのルーティングテーブルにあるIPv4の経路。
+/// network statementやredistributeは、これを読んで自身が広告元の経路を作る。
+/// テストでは、カーネルを読まずに作ったものを渡せる。
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct KernelRoute {
+    pub destination: Ipv4Network,
+    pub table: u8,
+    pub protocol: u8,
+}
+
+impl KernelRoute {
+    /// rtnetlinkで、全てのtableのIPv4の経路を読む。宛先の無いdefault routeは含めない。
+    pub async fn lookup_all() -> Result<Vec<KernelRoute>> {
+        let (connection, handle, _) = new_connection()?;
+        tokio::spawn(connection);
+        let mut routes = handle.route().get(rtnetlink::IpVersion::V4).execute();
+        let mut results = vec![];
+        while let Some(route) = routes.try_next().await? {
+            if let Some((IpAddr::V4(addr), prefix)) = route.destination_prefix() {
+                results.push(KernelRoute {
+                    destination: Ipv4Network::new(addr, prefix)?,
+                    table: route.header.table,
+                    protocol: route.header.protocol,
+                });
+            }
+        }
+        Ok(results)
+    }
+}
+
+/// RTA_MULTIPATHの値。next hopごとにstruct rtnexthopとRTA_GATEWAYを並べる。
+/// rtnh_hopsは重みから1を引いた値で、netlinkなのでhost byte orderで書く。
+fn multipath_bytes(next_hops: &[WeightedNextHop]) -> Vec<u8> {
+    const RTNEXTHOP_LENGTH: u16 = 8;
+    const GATEWAY_ATTRIBUTE_LENGTH: u16 = 8;
+    let mut bytes = vec![];
+    for next_hop in next_hops {
+        bytes.extend_from_slice(&(RTNEXTHOP_LENGTH + GATEWAY_ATTRIBUTE_LENGTH).to_ne_bytes());
+        bytes.push(0);
+        bytes.push(next_hop.weight.saturating_sub(1));
+        bytes.extend_from_slice(&0i32.to_ne_bytes());
+        bytes.extend_from_slice(&GATEWAY_ATTRIBUTE_LENGTH.to_ne_bytes());
+        bytes.extend_from_slice(&RTA_GATEWAY.to_ne_bytes());
+        bytes.extend_from_slice(&next_hop.gateway.octets());
+    }
+    bytes
+}
+
+/// 書き込まれた経路をメモリ上に保持するだけの転送テーブル。
+#[derive(Debug, Default)]
+pub struct InMemoryFib {
+    routes: Mutex<BTreeMap<Ipv4Network, Ipv4Addr>>,
+    /// 複数のnext hopで書き込まれた経路。routesには最初のnext hopを書き込む。
+    multipaths: Mutex<BTreeMap<Ipv4Network, Vec<WeightedNextHop>>>,
+}
+
+impl InMemoryFib {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// networkへの経路のgateway。
+    pub fn gateway(&self, network: Ipv4Network) -> Option<Ipv4Addr> {
+        self.routes.lock().unwrap().get(&network).copied()
+    }
+
+    /// networkへの経路が複数のnext hopで書き込まれていれば、その全て。
+    pub fn multipath(&self, network: Ipv4Network) -> Option<Vec<WeightedNextHop>> {
+        self.multipaths.lock().unwrap().get(&network).cloned()
+    }
+
+    pub fn routes(&self) -> Vec<(Ipv4Network, Ipv4Addr)> {
+        self.routes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(network, gateway)| (*network, *gateway))
+            .collect()
+    }
+}
+
+impl Fib for InMemoryFib {
+    fn install(
+        &self,
+        network: Ipv4Network,
+        gateway: Ipv4Addr,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        self.routes.lock().unwrap().insert(network, gateway);
+        self.multipaths.lock().unwrap().remove(&network);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn remove(
+        &self,
+        network: Ipv4Network,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        self.routes.lock().unwrap().remove(&network);
+        self.multipaths.lock().unwrap().remove(&network);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn install_multipath(
+        &self,
+        network: Ipv4Network,
+        next_hops: Vec<WeightedNextHop>,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        let Some(first) = next_hops.first() else {
+            return self.remove(network);
+        };
+        self.routes.lock().unwrap().insert(network, first.gateway);
+        self.multipaths.lock().unwrap().insert(network, next_hops);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// 起動直後の収束するまでの間、転送テーブルへの書き込みを保留する。
+/// 保留中はprefixごとに最後の書き込みだけを覚えておき、`lift`でまとめて書き込む。
+/// 収束するまでの一時的な経路の追加と削除で、転送テーブルを揺らさないようにする。
+#[derive(Debug)]
+pub struct FibBarrier {
+    inner: Arc<dyn Fib>,
+    /// 保留している書き込み。next hopが空の場合は経路の削除。barrierを解除した後はNoneになる。
+    pending: Mutex<Option<BTreeMap<Ipv4Network, Vec<WeightedNextHop>>>>,
+}
+
+impl FibBarrier {
+    pub fn new(inner: Arc<dyn Fib>) -> Self {
+        Self {
+            inner,
+            pending: Mutex::new(Some(BTreeMap::new())),
+        }
+    }
+
+    pub fn is_lifted(&self) -> bool {
+        self.pending.lock().unwrap().is_none()
+    }
+
+    /// 保留していた書き込みをまとめて行い、以降は書き込みをそのまま転送テーブルに渡す。
+    pub async fn lift(&self) -> Result<()> {
+        let Some(pending) = self.pending.lock().unwrap().take() else {
+            return Ok(());
+        };
+        for (network, next_hops) in pending {
+            match next_hops.as_slice() {
+                [] => self.inner.remove(network).await?,
+                [next_hop] => self.inner.install(network, next_hop.gateway).await?,
+                _ => self.inner.install_multipath(network, next_hops).await?,
+            }
+        }
+        Ok(())
+    }
+
+    /// barrierを解除していなければ書き込みを保留してtrueを返す。
+    fn defer(&self, network: Ipv4Network, next_hops: Vec<WeightedNextHop>) -> bool {
+        match self.pending.lock().unwrap().as_mut() {
+            Some(pending) => {
+                pending.insert(network, next_hops);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Fib for FibBarrier {
+    fn install(
+        &self,
+        network: Ipv4Network,
+        gateway: Ipv4Addr,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        if self.defer(network, vec![WeightedNextHop { gateway, weight: 1 }]) {
+            return Box::pin(async { Ok(()) });
+        }
+        self.inner.install(network, gateway)
+    }
+
+    fn remove(
+        &self,
+        network: Ipv4Network,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        if self.defer(network, vec![]) {
+            return Box::pin(async { Ok(()) });
+        }
+        self.inner.remove(network)
+    }
+
+    fn install_multipath(
+        &self,
+        network: Ipv4Network,
+        next_hops: Vec<WeightedNextHop>,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        if self.defer(network, next_hops.clone()) {
+            return Box::pin(async { Ok(()) });
+        }
+        self.inner.install_multipath(network, next_hops)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RTA_MULTIPATHの値から、next hopごとのgatewayと重みを読む。
+    fn parse_multipath(bytes: &[u8]) -> Vec<WeightedNextHop> {
+        bytes
+            .chunks(16)
+            .map(|nh| WeightedNextHop {
+                gateway: Ipv4Addr::new(nh[12], nh[13], nh[14], nh[15]),
+                weight: nh[3] + 1,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn multipath_bytes_encode_gateway_and_weight_of_each_next_hop() {
+        let next_hops = vec![
+            WeightedNextHop {
+                gateway: "10.200.100.2".parse().unwrap(),
+                weight: 1,
+            },
+            WeightedNextHop {
+                gateway: "10.200.100.4".parse().unwrap(),
+                weight: 255,
+            },
+        ];
+        let bytes = multipath_bytes(&next_hops);
+        assert_eq!(bytes.len(), 32);
+        assert_eq!(u16::from_ne_bytes([bytes[0], bytes[1]]), 16);
+        assert_eq!(u16::from_ne_bytes([bytes[10], bytes[11]]), RTA_GATEWAY);
+        assert_eq!(parse_multipath(&bytes), next_hops);
+    }
+
+    /// カーネルに経路を書き込むので、テスト用のnetwork namespaceの中で
+    /// `cargo test -- --ignored`として実行する。
+    #[tokio::test]
+    #[ignore = "カーネルのルーティングテーブルを変更するため"]
+    async fn kernel_fib_installs_weighted_multipath_route() {
+        let network: Ipv4Network = "10.100.241.0/24".parse().unwrap();
+        let next_hops = vec![
+            WeightedNextHop {
+                gateway: "10.200.100.2".parse().unwrap(),
+                weight: 1,
+            },
+            WeightedNextHop {
+                gateway: "10.200.100.4".parse().unwrap(),
+                weight: 3,
+            },
+        ];
+        KernelFib
+            .install_multipath(network, next_hops.clone())
+            .await
+            .unwrap();
+
+        let (connection, handle, _) = new_connection().unwrap();
+        tokio::spawn(connection);
+        let mut routes = handle.route().get(rtnetlink::IpVersion::V4).execute();
+        let mut installed = None;
+        while let Some(route) = routes.try_next().await.unwrap() {
+            if route.destination_prefix() == Some((IpAddr::V4(network.ip()), network.prefix())) {
+                installed = route.nlas.iter().find_map(|nla| match nla {
+                    Nla::MultiPath(bytes) => Some(parse_multipath(bytes)),
+                    _ => None,
+                });
+            }
+        }
+        KernelFib.remove(network).await.unwrap();
+        assert_eq!(installed, Some(next_hops));
+    }
+}
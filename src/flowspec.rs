@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use bytes::{BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::packets::update::UpdateMessage;
+use crate::path_attribute::{ExtendedCommunity, MpReachNlri, MpUnreachNlri, PathAttribute};
+use crate::routing::Ipv4Network;
+
+const COMPONENT_TYPE_DESTINATION_PREFIX: u8 = 1;
+const COMPONENT_TYPE_SOURCE_PREFIX: u8 = 2;
+const COMPONENT_TYPE_IP_PROTOCOL: u8 = 3;
+/// Numeric Operator(RFC 8955 Section 4.2.1.1)。
+const OPERATOR_END_OF_LIST: u8 = 0b1000_0000;
+const OPERATOR_EQUAL: u8 = 0b0000_0001;
+/// NLRIの長さが240以上の場合は2 octetで表し、先頭4bitを立てる。
+const EXTENDED_LENGTH_THRESHOLD: usize = 240;
+
+/// FlowSpecのNLRIの1つの条件(RFC 8955 Section 4.2.2)。よく使われるものだけを扱う。
+#[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum FlowSpecComponent {
+    DestinationPrefix(Ipv4Network),
+    SourcePrefix(Ipv4Network),
+    /// いずれかのIP protocol番号に一致する。
+    IpProtocol(Vec<u8>),
+}
+
+impl FlowSpecComponent {
+    fn type_code(&self) -> u8 {
+        match self {
+            FlowSpecComponent::DestinationPrefix(_) => COMPONENT_TYPE_DESTINATION_PREFIX,
+            FlowSpecComponent::SourcePrefix(_) => COMPONENT_TYPE_SOURCE_PREFIX,
+            FlowSpecComponent::IpProtocol(_) => COMPONENT_TYPE_IP_PROTOCOL,
+        }
+    }
+}
+
+/// FlowSpec(AFI=1, SAFI=133)のNLRI。全てのcomponentに一致するtrafficを表す。
+/// componentはtype順に並べて保持する(RFC 8955 Section 4.2)。
+#[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct FlowSpecRule {
+    components: Vec<FlowSpecComponent>,
+}
+
+impl FlowSpecRule {
+    pub fn new(mut components: Vec<FlowSpecComponent>) -> Self {
+        components.sort_by_key(|c| c.type_code());
+        Self { components }
+    }
+
+    pub fn components(&self) -> &[FlowSpecComponent] {
+        &self.components
+    }
+
+    /// 連続して並んだNLRIを全て読む。
+    pub fn from_u8_slice(bytes: &[u8]) -> Result<Vec<Self>> {
+        let mut rules = vec![];
+        let mut i = 0;
+        while i < bytes.len() {
+            let (length, header_length) = if bytes[i] >= 0xf0 {
+                let length = u16::from_be_bytes([
+                    bytes[i] & 0x0f,
+                    *bytes
+                        .get(i + 1)
+                        .context("FlowSpec NLRIの長さが途中で終わっています。")?,
+                ]);
+                (length as usize, 2)
+            } else {
+                (bytes[i] as usize, 1)
+            };
+            let start = i + header_length;
+            let end = start + length;
+            let value = bytes
+                .get(start..end)
+                .context("FlowSpec NLRIのbytes列が途中で終わっています。")?;
+            rules.push(Self::from_components(value)?);
+            i = end;
+        }
+        Ok(rules)
+    }
+
+    fn from_components(mut value: &[u8]) -> Result<Self> {
+        let mut components = vec![];
+        while let Some((&type_code, rest)) = value.split_first() {
+            let (component, rest) = match type_code {
+                COMPONENT_TYPE_DESTINATION_PREFIX => {
+                    let (network, rest) = prefix_from_bytes(rest)?;
+                    (FlowSpecComponent::DestinationPrefix(network), rest)
+                }
+                COMPONENT_TYPE_SOURCE_PREFIX => {
+                    let (network, rest) = prefix_from_bytes(rest)?;
+                    (FlowSpecComponent::SourcePrefix(network), rest)
+                }
+                COMPONENT_TYPE_IP_PROTOCOL => {
+                    let (protocols, rest) = equal_values_from_bytes(rest)?;
+                    (FlowSpecComponent::IpProtocol(protocols), rest)
+                }
+                _ => {
+                    return Err(anyhow!(
+                        "type {} のFlowSpec componentには対応していません。",
+                        type_code
+                    ))
+                }
+            };
+            components.push(component);
+            value = rest;
+        }
+        Ok(Self::new(components))
+    }
+
+    pub fn bytes_len(&self) -> usize {
+        let length = self.components_bytes().len();
+        if length < EXTENDED_LENGTH_THRESHOLD {
+            1 + length
+        } else {
+            2 + length
+        }
+    }
+
+    fn components_bytes(&self) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        for component in &self.components {
+            bytes.put_u8(component.type_code());
+            match component {
+                FlowSpecComponent::DestinationPrefix(network)
+                | FlowSpecComponent::SourcePrefix(network) => {
+                    bytes.put::<BytesMut>(network.into());
+                }
+                FlowSpecComponent::IpProtocol(protocols) => {
+                    for (i, protocol) in protocols.iter().enumerate() {
+                        let mut operator = OPERATOR_EQUAL;
+                        if i == protocols.len() - 1 {
+                            operator |= OPERATOR_END_OF_LIST;
+                        }
+                        bytes.put_u8(operator);
+                        bytes.put_u8(*protocol);
+                    }
+                }
+            }
+        }
+        bytes
+    }
+}
+
+impl From<&FlowSpecRule> for BytesMut {
+    fn from(rule: &FlowSpecRule) -> BytesMut {
+        let components = rule.components_bytes();
+        let mut bytes = BytesMut::new();
+        if components.len() < EXTENDED_LENGTH_THRESHOLD {
+            bytes.put_u8(components.len() as u8);
+        } else {
+            bytes.put_u16(0xf000 | components.len() as u16);
+        }
+        bytes.put(components);
+        bytes
+    }
+}
+
+/// prefix長とprefixの、NLRIと同じ形式。
+fn prefix_from_bytes(bytes: &[u8]) -> Result<(Ipv4Network, &[u8])> {
+    let (&prefix, rest) = bytes
+        .split_first()
+        .context("FlowSpecのprefixが途中で終わっています。")?;
+    if prefix > 32 {
+        return Err(anyhow!("FlowSpecのprefix長{}が32を超えています。", prefix));
+    }
+    let prefix_bytes_len = (prefix as usize).div_ceil(8);
+    let prefix_bytes = rest
+        .get(..prefix_bytes_len)
+        .context("FlowSpecのprefixが途中で終わっています。")?;
+    let mut octets = [0u8; 4];
+    octets[..prefix_bytes_len].copy_from_slice(prefix_bytes);
+    let network = Ipv4Network::new(Ipv4Addr::from(octets), prefix)?;
+    Ok((network, &rest[prefix_bytes_len..]))
+}
+
+/// 1 octetの値との一致をORでつないだNumeric Operatorの列。それ以外の比較には対応していない。
+fn equal_values_from_bytes(mut bytes: &[u8]) -> Result<(Vec<u8>, &[u8])> {
+    let mut values = vec![];
+    loop {
+        let [operator, value, rest @ ..] = bytes else {
+            return Err(anyhow!(
+                "FlowSpecのNumeric Operatorが途中で終わっています。"
+            ));
+        };
+        if operator & !OPERATOR_END_OF_LIST != OPERATOR_EQUAL {
+            return Err(anyhow!(
+                "Numeric Operator {:#04x} には対応していません。1 octetの値との一致のみ扱えます。",
+                operator
+            ));
+        }
+        values.push(*value);
+        bytes = rest;
+        if operator & OPERATOR_END_OF_LIST != 0 {
+            return Ok((values, bytes));
+        }
+    }
+}
+
+/// 受信したFlowSpecの規則と、その動作を表すPath Attribute。
+/// 転送への反映には対応していないので、規則の追加と削除を記録するだけにする。
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct FlowSpecRib {
+    rules: HashMap<FlowSpecRule, Arc<Vec<PathAttribute>>>,
+}
+
+impl FlowSpecRib {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn install_from_update(&mut self, update: &UpdateMessage) {
+        for path_attribute in update.path_attributes.iter() {
+            match path_attribute {
+                PathAttribute::MpUnreachNlri(MpUnreachNlri::FlowSpec { withdrawn_rules }) => {
+                    for rule in withdrawn_rules {
+                        if self.rules.remove(rule).is_some() {
+                            info!("flowspec rule is removed, rule={:?}.", rule);
+                        }
+                    }
+                }
+                PathAttribute::MpReachNlri(MpReachNlri::FlowSpec { rules }) => {
+                    for rule in rules {
+                        info!(
+                            "flowspec rule is installed, rule={:?}, traffic_rate={:?}.",
+                            rule,
+                            traffic_rate(&update.path_attributes)
+                        );
+                        self.rules
+                            .insert(rule.clone(), Arc::clone(&update.path_attributes));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, rule: &FlowSpecRule) -> Option<&Arc<Vec<PathAttribute>>> {
+        self.rules.get(rule)
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.rules.clear();
+    }
+}
+
+/// traffic-rate(bytes/秒)の動作。0は破棄を表す。
+pub fn traffic_rate(path_attributes: &[PathAttribute]) -> Option<f32> {
+    path_attributes.iter().find_map(|p| match p {
+        PathAttribute::ExtendedCommunities(communities) => {
+            communities
+                .iter()
+                .find_map(|c| match ExtendedCommunity::from(*c) {
+                    ExtendedCommunity::TrafficRate { rate, .. } => Some(f32::from_bits(rate)),
+                    _ => None,
+                })
+        }
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn destination_prefix_rule_with_discard_action_round_trips() {
+        let rule = FlowSpecRule::new(vec![
+            FlowSpecComponent::IpProtocol(vec![6, 17]),
+            FlowSpecComponent::DestinationPrefix("10.100.220.0/24".parse().unwrap()),
+        ]);
+        let discard: [u8; 8] = ExtendedCommunity::traffic_rate(64512, 0.0).into();
+        let update = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::MpReachNlri(MpReachNlri::FlowSpec {
+                    rules: vec![rule.clone()],
+                }),
+                PathAttribute::ExtendedCommunities(vec![discard]),
+            ]),
+            vec![],
+            vec![],
+        );
+
+        let bytes: BytesMut = update.clone().into();
+        let decoded = UpdateMessage::try_from(bytes).unwrap();
+        assert_eq!(decoded, update);
+        let PathAttribute::MpReachNlri(MpReachNlri::FlowSpec { rules }) =
+            &decoded.path_attributes[0]
+        else {
+            panic!("unexpected path attribute {:?}", decoded.path_attributes[0]);
+        };
+        // componentはtype順に並び、protocolは最後の値にだけend-of-listが立つ。
+        assert_eq!(
+            BytesMut::from(&rules[0]).to_vec(),
+            vec![10, 1, 24, 10, 100, 220, 3, 0x01, 6, 0x81, 17]
+        );
+        assert_eq!(discard, [0x80, 0x06, 0xfc, 0x00, 0, 0, 0, 0]);
+
+        let mut rib = FlowSpecRib::new();
+        rib.install_from_update(&decoded);
+        assert_eq!(traffic_rate(rib.get(&rule).unwrap()), Some(0.0));
+
+        let withdrawal = UpdateMessage::new(
+            Arc::new(vec![PathAttribute::MpUnreachNlri(
+                MpUnreachNlri::FlowSpec {
+                    withdrawn_rules: vec![rule],
+                },
+            )]),
+            vec![],
+            vec![],
+        );
+        let bytes: BytesMut = withdrawal.into();
+        rib.install_from_update(&UpdateMessage::try_from(bytes).unwrap());
+        assert!(rib.is_empty());
+    }
+}
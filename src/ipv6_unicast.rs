@@ -0,0 +1,217 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::Ipv6Addr;
+use std::sync::Arc;
+
+use tracing::info;
+
+use crate::config::Config;
+use crate::packets::update::UpdateMessage;
+use crate::path_attribute::{MpReachNlri, MpUnreachNlri, PathAttribute};
+use crate::routing::Ipv6Network;
+
+/// 1つのUPDATEに含めるprefixの数の上限。
+/// IPv6のprefixは最大17 bytesなので、他のattributeを含めてもmessageの最大長に収まる。
+const MAX_ROUTES_PER_UPDATE: usize = 200;
+
+/// IPv6 Unicastの経路。MP_REACH_NLRI以外のPath Attributeと、Next Hopを持つ。
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub struct Ipv6Route {
+    pub next_hop: Ipv6Addr,
+    pub path_attributes: Arc<Vec<PathAttribute>>,
+}
+
+/// IPv6 Unicast(AFI=2, SAFI=1)の経路。
+/// best pathの選択と転送への反映には対応していないので、networkごとに1つの経路を記録し、
+/// IPv6 Unicastを合意したpeerとの間で送受信するだけにする。
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Ipv6UnicastRib {
+    routes: BTreeMap<Ipv6Network, Ipv6Route>,
+    /// Enhanced Route RefreshのBoRRを受信してから、再送されていない経路。
+    stale_routes: BTreeSet<Ipv6Network>,
+}
+
+impl Ipv6UnicastRib {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// 経路を追加する。同じnetworkへの経路は置き換え、変わった場合はtrueを返す。
+    pub fn insert(
+        &mut self,
+        network: Ipv6Network,
+        next_hop: Ipv6Addr,
+        path_attributes: Arc<Vec<PathAttribute>>,
+    ) -> bool {
+        self.stale_routes.remove(&network);
+        let route = Ipv6Route {
+            next_hop,
+            path_attributes,
+        };
+        self.routes.insert(network, route.clone()) != Some(route)
+    }
+
+    pub fn remove(&mut self, network: &Ipv6Network) -> bool {
+        self.stale_routes.remove(network);
+        self.routes.remove(network).is_some()
+    }
+
+    pub fn get(&self, network: &Ipv6Network) -> Option<&Ipv6Route> {
+        self.routes.get(network)
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.routes.clear();
+        self.stale_routes.clear();
+    }
+
+    /// 受信したUPDATEのMP_REACH_NLRI, MP_UNREACH_NLRIのうち、IPv6 Unicastのものを反映する。
+    pub fn install_from_update(&mut self, update: &UpdateMessage) {
+        let path_attributes: Arc<Vec<PathAttribute>> = Arc::new(
+            update
+                .path_attributes
+                .iter()
+                .filter(|p| {
+                    !matches!(
+                        p,
+                        PathAttribute::MpReachNlri(_) | PathAttribute::MpUnreachNlri(_)
+                    )
+                })
+                .cloned()
+                .collect(),
+        );
+        for path_attribute in update.path_attributes.iter() {
+            match path_attribute {
+                PathAttribute::MpUnreachNlri(MpUnreachNlri::Ipv6Unicast { withdrawn_routes }) => {
+                    withdrawn_routes.iter().for_each(|network| {
+                        self.remove(network);
+                    });
+                }
+                PathAttribute::MpReachNlri(MpReachNlri::Ipv6Unicast { next_hop, nlri }) => {
+                    for network in nlri {
+                        self.insert(*network, *next_hop, Arc::clone(&path_attributes));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub fn mark_all_stale(&mut self) {
+        self.stale_routes = self.routes.keys().copied().collect();
+    }
+
+    /// staleのまま残った経路を取り除き、その数を返す。
+    pub fn purge_stale_routes(&mut self) -> usize {
+        let stale_routes = std::mem::take(&mut self.stale_routes);
+        for network in &stale_routes {
+            self.routes.remove(network);
+        }
+        if !stale_routes.is_empty() {
+            info!(
+                "{} stale ipv6 unicast routes are purged.",
+                stale_routes.len()
+            );
+        }
+        stale_routes.len()
+    }
+
+    /// sourceと同じ経路を持つよう更新し、変わった経路を広告、取り消すUPDATEを返す。
+    pub fn update_from(&mut self, source: &Ipv6UnicastRib, config: &Config) -> Vec<UpdateMessage> {
+        let withdrawn_routes: Vec<Ipv6Network> = self
+            .routes
+            .keys()
+            .filter(|network| !source.routes.contains_key(network))
+            .copied()
+            .collect();
+        let changed = Self {
+            routes: source
+                .routes
+                .iter()
+                .filter(|(network, route)| self.routes.get(network) != Some(route))
+                .map(|(network, route)| (*network, route.clone()))
+                .collect(),
+            stale_routes: BTreeSet::new(),
+        };
+        self.routes = source.routes.clone();
+        let mut updates = Self::withdrawal_messages(withdrawn_routes);
+        updates.extend(changed.announcement_messages(config));
+        updates
+    }
+
+    fn withdrawal_messages(withdrawn_routes: Vec<Ipv6Network>) -> Vec<UpdateMessage> {
+        withdrawn_routes
+            .chunks(MAX_ROUTES_PER_UPDATE)
+            .map(|withdrawn_routes| {
+                UpdateMessage::new(
+                    Arc::new(vec![PathAttribute::MpUnreachNlri(
+                        MpUnreachNlri::Ipv6Unicast {
+                            withdrawn_routes: withdrawn_routes.to_vec(),
+                        },
+                    )]),
+                    vec![],
+                    vec![],
+                )
+            })
+            .collect()
+    }
+
+    /// 全ての経路を広告するUPDATEを作る。
+    /// EBGPではAS_PATHに自身のASを加え、LOCAL_PREFはAS(confederation)の外には伝えない。
+    pub fn announcement_messages(&self, config: &Config) -> Vec<UpdateMessage> {
+        let mut grouped: HashMap<&Ipv6Route, Vec<Ipv6Network>> = HashMap::new();
+        for (network, route) in &self.routes {
+            grouped.entry(route).or_default().push(*network);
+        }
+        let mut updates = vec![];
+        for (route, networks) in grouped {
+            let mut path_attributes = Vec::clone(&route.path_attributes);
+            if config.is_outside_confederation() {
+                path_attributes.retain(|p| !matches!(p, PathAttribute::LocalPref(_)));
+            }
+            for p in path_attributes.iter_mut() {
+                if let PathAttribute::AsPath(ases) = p {
+                    if config.is_outside_confederation() {
+                        *ases = ases.without_confederation_segments();
+                    }
+                    if !config.is_ibgp() {
+                        ases.push(config.advertised_local_as());
+                    }
+                }
+            }
+            for nlri in networks.chunks(MAX_ROUTES_PER_UPDATE) {
+                let mut path_attributes = path_attributes.clone();
+                path_attributes.push(PathAttribute::MpReachNlri(MpReachNlri::Ipv6Unicast {
+                    next_hop: route.next_hop,
+                    nlri: nlri.to_vec(),
+                }));
+                updates.push(UpdateMessage::new(
+                    Arc::new(path_attributes),
+                    vec![],
+                    vec![],
+                ));
+            }
+        }
+        updates
+    }
+
+    /// IPv6 UnicastのEnd-of-RIBマーカー(RFC 4724)。空のMP_UNREACH_NLRIだけを持つUPDATE。
+    pub fn end_of_rib() -> UpdateMessage {
+        UpdateMessage::new(
+            Arc::new(vec![PathAttribute::MpUnreachNlri(
+                MpUnreachNlri::Ipv6Unicast {
+                    withdrawn_routes: vec![],
+                },
+            )]),
+            vec![],
+            vec![],
+        )
+    }
+}
@@ -1,14 +1,31 @@
-#![feature(backtrace, exclusive_range_pattern, arc_unwrap_or_clone)]
-#![allow(dead_code, unused)]
-
-mod bgp_type;
+pub mod admission;
+pub mod bgp_type;
+pub mod bmp;
+mod bogon;
+pub mod build_info;
+pub mod clock;
+#[cfg(feature = "update-compression")]
+mod compression;
 pub mod config;
 mod connection;
-mod error;
-mod event;
+pub mod dynamic_neighbor;
+pub mod error;
+pub mod event;
 mod event_queue;
-mod packets;
-mod path_attribute;
+#[cfg(test)]
+mod fault;
+pub mod fib;
+pub mod flowspec;
+pub mod ipv6_unicast;
+pub mod mrt;
+pub mod next_hop;
+pub mod packets;
+pub mod path_attribute;
 pub mod peer;
+pub mod peer_manager;
+mod policy;
+mod route_map;
 pub mod routing;
-mod state;
+mod rpki;
+pub mod state;
+pub mod status;
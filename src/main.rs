@@ -1,37 +1,291 @@
 use std::env;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
+use anyhow::{Context, Result};
+use futures::future::select_all;
+use mrbgpdv2::admission::AdmissionControl;
+use mrbgpdv2::bmp::BmpClient;
+use mrbgpdv2::build_info;
 use mrbgpdv2::config::Config;
+use mrbgpdv2::dynamic_neighbor::DynamicNeighbors;
+use mrbgpdv2::fib::{Fib, FibBarrier, KernelFib};
+use mrbgpdv2::next_hop::NextHopTracker;
 use mrbgpdv2::peer::Peer;
+use mrbgpdv2::peer_manager::PeerManager;
 use mrbgpdv2::routing::LocRib;
+use mrbgpdv2::state::State;
 use tokio::sync::Mutex;
 
+/// `--once`指定時に、全てのpeerの収束を待つ最大時間。
+const ONCE_MODE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// `show neighbor`指定時に、全てのpeerがEstablishedになるのを待つ最大時間。
+const SHOW_NEIGHBOR_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// rib_storeの中で、LocRibを書き出すファイル。
+const LOC_RIB_FILE: &str = "loc_rib.json";
+
+/// rib_storeの中で、neighborごとのAdj-RIB-Inを書き出すファイル。
+fn adj_rib_in_file(rib_store: &Path, remote_ip: Ipv4Addr) -> PathBuf {
+    rib_store.join(format!("adj_rib_in_{remote_ip}.json"))
+}
+
+/// 終了時に、次の起動で読み込めるようLocRibと静的なneighborのAdj-RIB-Inを書き出す。
+async fn persist_ribs(rib_store: &Path, loc_rib: &Mutex<LocRib>, peers: &[Peer]) -> Result<()> {
+    std::fs::create_dir_all(rib_store)
+        .context(format!("cannot create rib store {}", rib_store.display()))?;
+    loc_rib.lock().await.persist(rib_store.join(LOC_RIB_FILE))?;
+    for peer in peers {
+        peer.persist_adj_rib_in(adj_rib_in_file(rib_store, peer.remote_ip()))?;
+    }
+    Ok(())
+}
+
+/// 相手が他の経路へ切り替えられるよう、全てのpeerへCeaseを送ってからkernelの経路を取り除く。
+async fn shutdown_peers(
+    peers: &mut PeerManager,
+    dynamic_neighbors: &mut [DynamicNeighbors],
+    loc_rib: &Mutex<LocRib>,
+    fib: &dyn Fib,
+) {
+    for neighbors in dynamic_neighbors {
+        neighbors.notify_shutdown().await;
+    }
+    if let Err(e) = peers.shutdown(loc_rib, fib).await {
+        tracing::warn!("failed to remove routes from fib, {:?}.", e);
+    }
+}
+
 #[tokio::main]
-async fn main() {
-    let config = env::args().skip(1).fold("".to_owned(), |mut acc, s| {
-        acc += &(s.to_owned() + " ");
-        acc
-    });
+async fn main() -> Result<()> {
+    // `show neighbor`などの出力と混ざらないよう、logはstderrに書く。
+    // RUST_LOGにはtrace, debug, info, warn, errorのいずれかを指定できる。
+    let level = env::var("RUST_LOG")
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(tracing::Level::INFO);
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr)
+        .init();
+    if env::args().nth(1).as_deref() == Some("capabilities") {
+        print!("{}", build_info::capability_report());
+        return Ok(());
+    }
+    let show_neighbor = env::args().skip(1).take(2).eq(["show", "neighbor"]);
+    let once = env::args().any(|arg| arg == "--once");
+    // `--config FILE`の場合は、peer groupを含む複数のneighborの設定をFILEから読み込む。
+    let config_file = env::args().skip_while(|arg| arg != "--config").nth(1);
+    let config = env::args()
+        .skip(if show_neighbor { 3 } else { 1 })
+        .filter(|arg| arg != "--once")
+        .fold("".to_owned(), |mut acc, s| {
+            acc += &(s.to_owned() + " ");
+            acc
+        });
     let config = config.trim_end();
-    let configs = vec![Config::from_str(&config).unwrap()];
+    let configs = if let Some(path) = config_file {
+        let file = std::fs::read_to_string(&path)
+            .with_context(|| format!("{path}を読み込めませんでした"))?;
+        Config::parse_all(&file)?
+    } else if config.is_empty() {
+        vec![Config::from_env()?]
+    } else {
+        vec![Config::from_str(config)?]
+    };
 
+    // LocRibやFIBなど全てのneighborで共有するものの設定は、`Config::parse_all`で
+    // neighbor間で揃っていることを確かめているので、最初のconfigから読む。
     let loc_rib = Arc::new(Mutex::new(
         LocRib::new(&configs[0])
             .await
-            .expect("LocRibの生成に失敗しました"),
+            .context("LocRibの生成に失敗しました")?,
     ));
-    let mut peers: Vec<Peer> = configs
-        .into_iter()
-        .map(|c| Peer::new(c, Arc::clone(&loc_rib)))
-        .collect();
-    for peer in &mut peers {
-        peer.start();
+    let admission = Arc::new(AdmissionControl::new(configs[0].max_peers));
+    let bmp = match configs[0].bmp_collector {
+        Some(collector) => match BmpClient::connect(collector).await {
+            Ok(client) => Some(Arc::new(client)),
+            Err(e) => {
+                tracing::warn!("bmp export is disabled, {:?}.", e);
+                None
+            }
+        },
+        None => None,
+    };
+    let fib_barrier_time = configs[0].fib_barrier_time;
+    let fib_barrier = fib_barrier_time.map(|_| Arc::new(FibBarrier::new(Arc::new(KernelFib))));
+    let fib: Arc<dyn Fib> = match &fib_barrier {
+        Some(barrier) => Arc::clone(barrier) as Arc<dyn Fib>,
+        None => Arc::new(KernelFib),
+    };
+    let rib_store = configs[0].rib_store.clone();
+    let rib_store_stale_time = configs[0].rib_store_stale_time;
+    if let Some(rib_store) = &rib_store {
+        let mut loc_rib = loc_rib.lock().await;
+        match loc_rib.load(rib_store.join(LOC_RIB_FILE)) {
+            Ok(loaded) => {
+                tracing::info!("{} routes are loaded from rib store as stale.", loaded);
+                if let Err(e) = loc_rib.write_to_fib(fib.as_ref()).await {
+                    tracing::warn!("failed to write routes to fib, {:?}.", e);
+                }
+                loc_rib.update_to_all_changed();
+            }
+            Err(e) => tracing::warn!("failed to load rib store, {:?}.", e),
+        }
+    }
+    let setup_peer = {
+        let fib = Arc::clone(&fib);
+        Arc::new(move |peer: &mut Peer| {
+            peer.set_admission_control(Arc::clone(&admission));
+            peer.set_fib(Arc::clone(&fib));
+            if let Some(bmp) = &bmp {
+                peer.set_bmp_client(Arc::clone(bmp));
+            }
+        })
+    };
+    // listen_rangeを持つconfigは、範囲内からの接続で作るneighborの雛形になる。
+    let (templates, configs): (Vec<Config>, Vec<Config>) =
+        configs.into_iter().partition(|c| c.listen_range.is_some());
+    let mut dynamic_neighbors = vec![];
+    for template in templates {
+        let mut neighbors = DynamicNeighbors::bind(template, Arc::clone(&loc_rib))
+            .await
+            .context("listen_rangeで接続を待ち始めることができませんでした")?;
+        let setup_peer = Arc::clone(&setup_peer);
+        neighbors.set_peer_setup(move |peer| setup_peer(peer));
+        dynamic_neighbors.push(neighbors);
     }
+    let mut peers = PeerManager::new(
+        configs
+            .into_iter()
+            .map(|c| {
+                let mut peer = Peer::new(c, Arc::clone(&loc_rib));
+                setup_peer(&mut peer);
+                peer
+            })
+            .collect(),
+    );
+    if let Some(rib_store) = &rib_store {
+        for peer in peers.peers_mut() {
+            let path = adj_rib_in_file(rib_store, peer.remote_ip());
+            if let Err(e) = peer.load_adj_rib_in(path, rib_store_stale_time).await {
+                tracing::warn!("failed to load adj-rib-in from rib store, {:?}.", e);
+            }
+        }
+    }
+    peers.start();
+
+    if show_neighbor {
+        let established = tokio::time::timeout(SHOW_NEIGHBOR_TIMEOUT, async {
+            while !peers
+                .states()
+                .iter()
+                .all(|(_, state)| *state == State::Established)
+            {
+                peers.next().await;
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .is_ok();
+        for peer in peers.peers() {
+            println!("{}", peer.status().to_json());
+        }
+        shutdown_peers(&mut peers, &mut dynamic_neighbors, &loc_rib, fib.as_ref()).await;
+        std::process::exit(if established { 0 } else { 1 });
+    }
+
+    if once {
+        let converged = peers.run_until_converged(ONCE_MODE_TIMEOUT).await;
+        shutdown_peers(&mut peers, &mut dynamic_neighbors, &loc_rib, fib.as_ref()).await;
+        std::process::exit(if converged { 0 } else { 1 });
+    }
+
+    let tracker = NextHopTracker::new(Arc::clone(&loc_rib), Arc::clone(&fib));
+    tokio::spawn(async move {
+        if let Err(e) = tracker.run().await {
+            tracing::warn!("next hop tracking is stopped, {:?}.", e);
+        }
+    });
 
+    let started = tokio::time::Instant::now();
+    let mut stale_routes_purged = rib_store.is_none();
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
     loop {
-        for peer in &mut peers {
-            peer.next().await;
+        // fib_barrier_timeやrib_store_stale_timeが過ぎたときにも、peerの処理を待たずに進める。
+        let deadline = [
+            fib_barrier
+                .as_ref()
+                .filter(|barrier| !barrier.is_lifted())
+                .and(fib_barrier_time),
+            (!stale_routes_purged).then_some(rib_store_stale_time),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        .map(|elapsed| started + elapsed);
+        let dynamic_neighbors_activity = async {
+            if dynamic_neighbors.is_empty() {
+                return std::future::pending().await;
+            }
+            select_all(
+                dynamic_neighbors
+                    .iter_mut()
+                    .map(|neighbors| Box::pin(neighbors.wait_for_activity())),
+            )
+            .await;
+        };
+        let timer = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = &mut shutdown => break,
+            _ = peers.wait_for_activity() => {}
+            _ = dynamic_neighbors_activity => {}
+            _ = timer => {}
+        }
+        peers.poll().await;
+        for neighbors in &mut dynamic_neighbors {
+            neighbors.poll().await;
+        }
+        if let (Some(barrier), Some(barrier_time)) = (&fib_barrier, fib_barrier_time) {
+            if !barrier.is_lifted() && (peers.all_converged() || started.elapsed() >= barrier_time)
+            {
+                if let Err(e) = barrier.lift().await {
+                    tracing::warn!("failed to write routes held by fib barrier, {:?}.", e);
+                }
+            }
+        }
+        // rib_storeから読み込み、受信し直さなかった経路を取り除く。
+        if !stale_routes_purged && started.elapsed() >= rib_store_stale_time {
+            stale_routes_purged = true;
+            let mut loc_rib = loc_rib.lock().await;
+            let purged = loc_rib.purge_stale_routes();
+            if purged > 0 {
+                tracing::info!("{} stale routes from rib store are purged.", purged);
+                if let Err(e) = loc_rib.write_to_fib(fib.as_ref()).await {
+                    tracing::warn!("failed to write routes to fib, {:?}.", e);
+                }
+                loc_rib.update_to_all_changed();
+            }
+        }
+    }
+
+    shutdown_peers(&mut peers, &mut dynamic_neighbors, &loc_rib, fib.as_ref()).await;
+
+    if let Some(rib_store) = &rib_store {
+        match persist_ribs(rib_store, &loc_rib, peers.peers()).await {
+            Ok(()) => tracing::info!("ribs are persisted to {}.", rib_store.display()),
+            Err(e) => tracing::warn!("failed to persist ribs, {:?}.", e),
         }
     }
+    Ok(())
 }
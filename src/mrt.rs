@@ -0,0 +1,157 @@
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use bytes::{BufMut, BytesMut};
+
+use crate::bgp_type::AutonomousSystemNumber;
+use crate::routing::RibEntry;
+
+/// MRTのtype(RFC 6396 Section 4)。
+const TABLE_DUMP_V2: u16 = 13;
+/// TABLE_DUMP_V2のsubtype(RFC 6396 Section 4.3)。
+const PEER_INDEX_TABLE: u16 = 1;
+const RIB_IPV4_UNICAST: u16 = 2;
+/// Peer Typeのflag。IPv4のaddressと4 octetのAS番号を用いることを表す。
+const PEER_TYPE_AS_NUMBER_IS_FOUR_OCTET: u8 = 0b0000_0010;
+
+/// MRTのPEER_INDEX_TABLEに書くpeer。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub struct MrtPeer {
+    pub address: Ipv4Addr,
+    pub as_number: AutonomousSystemNumber,
+    pub bgp_identifier: Ipv4Addr,
+}
+
+/// peerから受信した経路を、MRT TABLE_DUMP_V2(RFC 6396 Section 4.3)で書き込む。
+/// 最初にpeerだけを含むPEER_INDEX_TABLEを書き、続けてprefixごとにRIB_IPV4_UNICASTを書く。
+/// 経路を受信した時刻は保持していないので、Originated Timeには書き込んだ時刻を用いる。
+pub fn write_table_dump<'a>(
+    writer: &mut impl Write,
+    peer: &MrtPeer,
+    entries: impl Iterator<Item = &'a RibEntry>,
+) -> Result<()> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as u32;
+    write_record(writer, timestamp, PEER_INDEX_TABLE, peer_index_table(peer))?;
+
+    let mut entries: Vec<&RibEntry> = entries.collect();
+    entries.sort_by_key(|e| e.network_address);
+    for (sequence_number, entries) in entries
+        .chunk_by(|a, b| a.network_address == b.network_address)
+        .enumerate()
+    {
+        let body = rib_ipv4_unicast(sequence_number as u32, timestamp, entries);
+        write_record(writer, timestamp, RIB_IPV4_UNICAST, body)?;
+    }
+    Ok(())
+}
+
+/// Collector BGP IDとView Nameは持たないので、0.0.0.0と空にする。
+fn peer_index_table(peer: &MrtPeer) -> BytesMut {
+    let mut body = BytesMut::new();
+    body.put_slice(&Ipv4Addr::UNSPECIFIED.octets());
+    body.put_u16(0);
+    body.put_u16(1);
+    body.put_u8(PEER_TYPE_AS_NUMBER_IS_FOUR_OCTET);
+    body.put_slice(&peer.bgp_identifier.octets());
+    body.put_slice(&peer.address.octets());
+    body.put_u32(peer.as_number.into());
+    body
+}
+
+/// 同じprefixの経路をまとめたRIB Entry。peerは1つなので、Peer Indexは全て0になる。
+fn rib_ipv4_unicast(sequence_number: u32, timestamp: u32, entries: &[&RibEntry]) -> BytesMut {
+    let mut body = BytesMut::new();
+    body.put_u32(sequence_number);
+    body.put::<BytesMut>((&entries[0].network_address).into());
+    body.put_u16(entries.len() as u16);
+    for entry in entries {
+        let mut attributes = BytesMut::new();
+        for p in entry.path_attributes.iter() {
+            attributes.put(p.to_four_octet_bytes());
+        }
+        body.put_u16(0);
+        body.put_u32(timestamp);
+        body.put_u16(attributes.len() as u16);
+        body.put(attributes);
+    }
+    body
+}
+
+fn write_record(
+    writer: &mut impl Write,
+    timestamp: u32,
+    subtype: u16,
+    body: BytesMut,
+) -> Result<()> {
+    let mut bytes = BytesMut::with_capacity(12 + body.len());
+    bytes.put_u32(timestamp);
+    bytes.put_u16(TABLE_DUMP_V2);
+    bytes.put_u16(subtype);
+    bytes.put_u32(body.len() as u32);
+    bytes.put(body);
+    writer.write_all(&bytes).context("cannot write mrt record")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::path_attribute::{AsPath, Origin, PathAttribute};
+    use crate::routing::AdjRibIn;
+
+    #[test]
+    fn adj_rib_in_is_written_as_table_dump_v2() {
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.insert(Arc::new(RibEntry {
+            network_address: "10.100.220.0/24".parse().unwrap(),
+            path_attributes: Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::sequence(vec![65001.into(), 70000.into()])),
+                PathAttribute::NextHop("10.200.100.3".parse().unwrap()),
+            ]),
+            learned_from: None,
+        }));
+        let peer = MrtPeer {
+            address: "10.200.100.3".parse().unwrap(),
+            as_number: 65001.into(),
+            bgp_identifier: "10.200.100.3".parse().unwrap(),
+        };
+        let mut buffer = vec![];
+        adj_rib_in.write_mrt(&mut buffer, &peer).unwrap();
+
+        let (peer_index_table, rib) = buffer.split_at(12 + 21);
+        assert_eq!(&peer_index_table[4..12], &[0, 13, 0, 1, 0, 0, 0, 21]);
+        assert_eq!(
+            &peer_index_table[12..],
+            &[
+                0, 0, 0, 0, // Collector BGP ID
+                0, 0, // View Name Length
+                0, 1, // Peer Count
+                0b10, 10, 200, 100, 3, 10, 200, 100, 3, 0, 0, 0xfd, 0xe9,
+            ]
+        );
+
+        assert_eq!(&rib[4..12], &[0, 13, 0, 2, 0, 0, 0, 42]);
+        assert_eq!(rib.len(), 12 + 42);
+        let body = &rib[12..];
+        assert_eq!(&body[..10], &[0, 0, 0, 0, 24, 10, 100, 220, 0, 1]);
+        // Peer Index, Originated Time, Attribute Length
+        assert_eq!(&body[10..12], &[0, 0]);
+        assert_eq!(&body[12..16], &rib[..4]);
+        assert_eq!(&body[16..18], &[0, 24]);
+        assert_eq!(
+            &body[18..],
+            &[
+                0x40, 1, 1, 0, // ORIGIN
+                0x40, 2, 10, 2, 2, 0, 0, 0xfd, 0xe9, 0, 1, 0x11, 0x70, // AS_PATH
+                0x40, 3, 4, 10, 200, 100, 3, // NEXT_HOP
+            ]
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::StreamExt;
+use rtnetlink::constants::RTMGRP_IPV4_ROUTE;
+use rtnetlink::new_connection;
+use rtnetlink::sys::{AsyncSocket, SocketAddr};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::fib::{Fib, KernelRoute};
+use crate::routing::{Ipv4Network, LocRib};
+
+/// カーネルの経路の変更通知が無くても到達性を調べ直す間隔。
+/// 新しく受信した経路のnext hopも、この間隔で追跡の対象になる。
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// LocRibの経路のnext hopについて、カーネルのルーティングテーブルでの到達性を追跡する。
+/// 到達性が変わると、LocRibのbest pathを選び直してFIBに反映する。
+#[derive(Debug)]
+pub struct NextHopTracker {
+    loc_rib: Arc<Mutex<LocRib>>,
+    fib: Arc<dyn Fib>,
+}
+
+impl NextHopTracker {
+    pub fn new(loc_rib: Arc<Mutex<LocRib>>, fib: Arc<dyn Fib>) -> Self {
+        Self { loc_rib, fib }
+    }
+
+    /// カーネルのIPv4の経路の変更を購読し、変更があるたびに到達性を調べ直す。
+    pub async fn run(&self) -> Result<()> {
+        let (mut connection, _, mut messages) = new_connection()?;
+        connection
+            .socket_mut()
+            .socket_mut()
+            .bind(&SocketAddr::new(0, RTMGRP_IPV4_ROUTE))?;
+        tokio::spawn(connection);
+
+        let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+        loop {
+            tokio::select! {
+                message = messages.next() => {
+                    if message.is_none() {
+                        return Err(anyhow::anyhow!(
+                            "カーネルの経路の変更通知を受け取れなくなりました。"
+                        ));
+                    }
+                }
+                _ = interval.tick() => {}
+            }
+            if let Err(e) = self.refresh().await {
+                warn!("failed to refresh next hop reachability, {:?}.", e);
+            }
+        }
+    }
+
+    /// 追跡している全てのnext hopの到達性をカーネルの経路から調べ、変化した経路をFIBに反映する。
+    pub async fn refresh(&self) -> Result<()> {
+        let routes: Vec<Ipv4Network> = KernelRoute::lookup_all()
+            .await?
+            .into_iter()
+            .map(|route| route.destination)
+            .collect();
+        self.refresh_with(&routes).await
+    }
+
+    /// routesをカーネルの経路の宛先として、追跡している全てのnext hopの到達性を調べ、
+    /// 変化した経路をFIBに反映する。
+    pub async fn refresh_with(&self, routes: &[Ipv4Network]) -> Result<()> {
+        let mut loc_rib = self.loc_rib.lock().await;
+        for next_hop in loc_rib.tracked_next_hops() {
+            let reachable = is_reachable(routes, next_hop);
+            let affected = loc_rib.set_next_hop_reachability(next_hop, reachable);
+            for entry in affected {
+                if reachable {
+                    self.fib.install(entry.network_address, next_hop).await?;
+                } else {
+                    self.fib.remove(entry.network_address).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// default route以外にnext_hopを含む経路があれば到達できるとする。
+/// default routeで到達できることにすると、next hopが無くなっても経路を取り消せないため。
+fn is_reachable(routes: &[Ipv4Network], next_hop: Ipv4Addr) -> bool {
+    routes
+        .iter()
+        .any(|route| route.prefix() > 0 && route.contains(next_hop))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::fib::InMemoryFib;
+    use crate::path_attribute::{AsPath, Origin, PathAttribute};
+    use crate::routing::{AdjRibIn, RibEntry};
+
+    #[tokio::test]
+    async fn routes_are_withdrawn_and_restored_as_next_hop_reachability_changes() {
+        let connected: Ipv4Network = "10.220.0.0/24".parse().unwrap();
+        let default_route: Ipv4Network = "0.0.0.0/0".parse().unwrap();
+        let config: Config = "64512 10.200.100.2 64513 10.200.100.3 active"
+            .parse()
+            .unwrap();
+        let network: Ipv4Network = "10.230.0.0/24".parse().unwrap();
+        let next_hop: Ipv4Addr = "10.220.0.5".parse().unwrap();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let fib = Arc::new(InMemoryFib::new());
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.insert(Arc::new(RibEntry {
+            network_address: network,
+            path_attributes: Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::sequence(vec![64513.into()])),
+                PathAttribute::NextHop(next_hop),
+            ]),
+            learned_from: Some(config.remote_ip),
+        }));
+        {
+            let mut loc_rib = loc_rib.lock().await;
+            loc_rib.intsall_from_adj_rib_in(&adj_rib_in);
+            loc_rib.write_to_fib(fib.as_ref()).await.unwrap();
+            loc_rib.update_to_all_changed();
+        }
+        let tracker = NextHopTracker::new(Arc::clone(&loc_rib), Arc::clone(&fib) as Arc<dyn Fib>);
+        tracker.refresh_with(&[connected]).await.unwrap();
+        assert_eq!(fib.gateway(network), Some(next_hop));
+
+        // default routeだけでは到達できるとしない。
+        tracker.refresh_with(&[default_route]).await.unwrap();
+        assert!(loc_rib.lock().await.best_path(network).is_none());
+        assert_eq!(fib.gateway(network), None);
+
+        tracker
+            .refresh_with(&[default_route, connected])
+            .await
+            .unwrap();
+        assert!(loc_rib.lock().await.best_path(network).is_some());
+        assert_eq!(fib.gateway(network), Some(next_hop));
+    }
+}
@@ -1,5 +1,7 @@
-mod header;
+pub mod header;
 pub mod keepalive;
 pub mod message;
+pub mod notification;
 pub mod open;
+pub mod route_refresh;
 pub mod update;
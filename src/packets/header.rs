@@ -1,6 +1,11 @@
-use crate::error::{ConvertBgpMessageToBytesError, ConvertBytesToBgpMessageError};
+use crate::error::ConvertBytesToBgpMessageError;
 use bytes::{BufMut, BytesMut};
 
+/// Headerの長さ。
+pub const HEADER_LENGTH: usize = 19;
+/// Messageの最大の長さ(RFC 4271 Section 4.1)。
+pub const MAX_MESSAGE_LENGTH: u16 = 4096;
+
 #[derive(PartialEq, Eq, Debug, Clone, Hash)]
 pub struct Header {
     length: u16,
@@ -11,16 +16,35 @@ impl Header {
     pub fn new(length: u16, type_: MessageType) -> Self {
         Self { length, type_ }
     }
+
+    pub fn length(&self) -> u16 {
+        self.length
+    }
+}
+
+impl TryFrom<&[u8]> for Header {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let Some(&[length_high, length_low, type_]) = bytes.get(16..HEADER_LENGTH) else {
+            return Err(anyhow::anyhow!(
+                "Headerは{}bytesが期待されていますが、{}bytesしかありません。",
+                HEADER_LENGTH,
+                bytes.len()
+            )
+            .into());
+        };
+        let length = u16::from_be_bytes([length_high, length_low]);
+        let type_ = type_.try_into()?;
+        Ok(Header { length, type_ })
+    }
 }
 
 impl TryFrom<BytesMut> for Header {
     type Error = ConvertBytesToBgpMessageError;
 
     fn try_from(bytes: BytesMut) -> Result<Self, Self::Error> {
-        let marker = &bytes[0..16];
-        let length = u16::from_be_bytes([bytes[16], bytes[17]]);
-        let type_ = bytes[18].try_into()?;
-        Ok(Header { length, type_ })
+        Self::try_from(&bytes[..])
     }
 }
 
@@ -42,6 +66,38 @@ pub enum MessageType {
     Open,
     Keepalive,
     Update,
+    Notification,
+    RouteRefresh,
+}
+
+impl MessageType {
+    /// 受信時に解釈できる全てのMessage Type。
+    pub fn supported() -> Vec<MessageType> {
+        (u8::MIN..=u8::MAX)
+            .filter_map(|num| MessageType::try_from(num).ok())
+            .collect()
+    }
+
+    /// Message Typeごとの、Messageの最小の長さ(RFC 4271 Section 4, RFC 2918)。
+    pub fn min_length(&self) -> u16 {
+        match self {
+            MessageType::Open => 29,
+            MessageType::Update => 23,
+            MessageType::Notification => 21,
+            MessageType::Keepalive => 19,
+            MessageType::RouteRefresh => 23,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MessageType::Open => "OPEN",
+            MessageType::Keepalive => "KEEPALIVE",
+            MessageType::Update => "UPDATE",
+            MessageType::Notification => "NOTIFICATION",
+            MessageType::RouteRefresh => "ROUTE-REFRESH",
+        }
+    }
 }
 
 impl TryFrom<u8> for MessageType {
@@ -51,10 +107,12 @@ impl TryFrom<u8> for MessageType {
         match num {
             1 => Ok(MessageType::Open),
             2 => Ok(MessageType::Update),
+            3 => Ok(MessageType::Notification),
             4 => Ok(MessageType::Keepalive),
+            5 => Ok(MessageType::RouteRefresh),
             _ => Err(Self::Error::from(anyhow::anyhow!(
                 "Num {0}をBGP Message Typeに変換することができませんでした。\
-                num は 1-4が期待されています。
+                num は 1-5が期待されています。
                 ",
                 num
             ))),
@@ -67,7 +125,9 @@ impl From<MessageType> for u8 {
         match type_ {
             MessageType::Open => 1,
             MessageType::Update => 2,
+            MessageType::Notification => 3,
             MessageType::Keepalive => 4,
+            MessageType::RouteRefresh => 5,
         }
     }
 }
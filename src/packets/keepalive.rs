@@ -1,26 +1,45 @@
 use bytes::BytesMut;
 
-use crate::error::ConvertBytesToBgpMessageError;
+use crate::error::{BadMessageLengthError, ConvertBytesToBgpMessageError};
 
 use super::header::{Header, MessageType};
 
+/// KEEPALIVEはHeaderだけからなる。
+const KEEPALIVE_LENGTH: usize = 19;
+
 #[derive(PartialEq, Eq, Debug, Clone, Hash)]
 pub struct KeepaliveMessage {
     header: Header,
 }
 
-impl TryFrom<BytesMut> for KeepaliveMessage {
+impl TryFrom<&[u8]> for KeepaliveMessage {
     type Error = ConvertBytesToBgpMessageError;
 
-    fn try_from(bytes: BytesMut) -> Result<Self, Self::Error> {
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let length = bytes.len();
         let header = Header::try_from(bytes)?;
         if header.type_ != MessageType::Keepalive {
             return Err(anyhow::anyhow!("bytes列のtypeがkeepaliveではありません。").into());
         }
+        if length != KEEPALIVE_LENGTH || usize::from(header.length()) != KEEPALIVE_LENGTH {
+            return Err(anyhow::Error::from(BadMessageLengthError {
+                type_name: MessageType::Keepalive.name(),
+                length: u16::try_from(length).unwrap_or(u16::MAX),
+            })
+            .into());
+        }
         Ok(Self { header })
     }
 }
 
+impl TryFrom<BytesMut> for KeepaliveMessage {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(bytes: BytesMut) -> Result<Self, Self::Error> {
+        Self::try_from(&bytes[..])
+    }
+}
+
 impl From<KeepaliveMessage> for BytesMut {
     fn from(keepalive: KeepaliveMessage) -> Self {
         keepalive.header.into()
@@ -29,7 +48,7 @@ impl From<KeepaliveMessage> for BytesMut {
 
 impl KeepaliveMessage {
     pub fn new() -> Self {
-        let header = Header::new(19, MessageType::Keepalive);
+        let header = Header::new(KEEPALIVE_LENGTH as u16, MessageType::Keepalive);
         Self { header }
     }
 }
@@ -39,3 +58,19 @@ impl Default for KeepaliveMessage {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keepalive_longer_than_header_is_bad_message_length() {
+        let mut bytes = BytesMut::from(&[0xff; 16][..]);
+        bytes.extend_from_slice(&[0x00, 23, 4, 0, 0, 0, 0]);
+        let error = KeepaliveMessage::try_from(bytes).unwrap_err();
+        assert_eq!(error.bad_message_length(), Some(23));
+
+        let keepalive: BytesMut = KeepaliveMessage::new().into();
+        assert!(KeepaliveMessage::try_from(keepalive).is_ok());
+    }
+}
@@ -3,9 +3,11 @@ use crate::packets::header::{Header, MessageType};
 use bytes::BytesMut;
 use std::net::Ipv4Addr;
 
-use crate::error::{ConvertBgpMessageToBytesError, ConvertBytesToBgpMessageError};
+use crate::error::ConvertBytesToBgpMessageError;
 use crate::packets::keepalive::KeepaliveMessage;
+use crate::packets::notification::NotificationMessage;
 use crate::packets::open::OpenMessage;
+use crate::packets::route_refresh::{RouteRefreshMessage, RouteRefreshSubtype};
 
 use super::update::UpdateMessage;
 
@@ -14,12 +16,24 @@ pub enum Message {
     Open(OpenMessage),
     Keepalive(KeepaliveMessage),
     Update(UpdateMessage),
+    Notification(NotificationMessage),
+    RouteRefresh(RouteRefreshMessage),
 }
 
-impl TryFrom<BytesMut> for Message {
+/// capture fileなど`BytesMut`以外のbufferにあるbyte列から、copyせずに変換する。
+///
+/// ```
+/// use mrbgpdv2::packets::message::Message;
+///
+/// let mut keepalive = vec![0xff; 16];
+/// keepalive.extend_from_slice(&[0, 19, 4]);
+/// let message = Message::try_from(&keepalive[..]).unwrap();
+/// assert!(matches!(message, Message::Keepalive(_)));
+/// ```
+impl TryFrom<&[u8]> for Message {
     type Error = ConvertBytesToBgpMessageError;
 
-    fn try_from(bytes: BytesMut) -> Result<Self, Self::Error> {
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
         let header_bytes_length = 19;
 
         if bytes.len() < header_bytes_length {
@@ -30,21 +44,37 @@ impl TryFrom<BytesMut> for Message {
             )));
         }
 
-        let header = Header::try_from(BytesMut::from(&bytes[0..header_bytes_length]))?;
+        let header = Header::try_from(&bytes[0..header_bytes_length])?;
         match header.type_ {
             MessageType::Open => Ok(Message::Open(OpenMessage::try_from(bytes)?)),
             MessageType::Keepalive => Ok(Message::Keepalive(KeepaliveMessage::try_from(bytes)?)),
             MessageType::Update => Ok(Message::Update(UpdateMessage::try_from(bytes)?)),
+            MessageType::Notification => {
+                Ok(Message::Notification(NotificationMessage::try_from(bytes)?))
+            }
+            MessageType::RouteRefresh => {
+                Ok(Message::RouteRefresh(RouteRefreshMessage::try_from(bytes)?))
+            }
         }
     }
 }
 
+impl TryFrom<BytesMut> for Message {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(bytes: BytesMut) -> Result<Self, Self::Error> {
+        Self::try_from(&bytes[..])
+    }
+}
+
 impl From<Message> for BytesMut {
     fn from(message: Message) -> BytesMut {
         match message {
             Message::Open(open) => open.into(),
             Message::Keepalive(keepalive) => keepalive.into(),
             Message::Update(update) => update.into(),
+            Message::Notification(notification) => notification.into(),
+            Message::RouteRefresh(route_refresh) => route_refresh.into(),
         }
     }
 }
@@ -57,4 +87,106 @@ impl Message {
     pub fn new_keepalive() -> Self {
         Self::Keepalive(KeepaliveMessage::new())
     }
+
+    pub fn new_notification(error_code: u8, error_subcode: u8, data: BytesMut) -> Self {
+        Self::Notification(NotificationMessage::new(error_code, error_subcode, data))
+    }
+
+    pub fn new_route_refresh(subtype: RouteRefreshSubtype) -> Self {
+        Self::RouteRefresh(RouteRefreshMessage::new(subtype))
+    }
+
+    pub fn new_hard_reset(error_code: u8, error_subcode: u8, data: BytesMut) -> Self {
+        Self::Notification(NotificationMessage::new_hard_reset(
+            error_code,
+            error_subcode,
+            data,
+        ))
+    }
+}
+
+/// RFC 4271 Section 4に従って手で組み立てたbyte列と、serializer/parserの結果を比べる。
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::path_attribute::{AsPath, Origin, PathAttribute};
+
+    const MARKER: [u8; 16] = [0xff; 16];
+
+    fn golden(length: u16, type_: u8, body: &[u8]) -> BytesMut {
+        let mut bytes = BytesMut::from(&MARKER[..]);
+        bytes.extend_from_slice(&length.to_be_bytes());
+        bytes.extend_from_slice(&[type_]);
+        bytes.extend_from_slice(body);
+        assert_eq!(bytes.len(), length as usize);
+        bytes
+    }
+
+    fn assert_golden(message: Message, expected: BytesMut) {
+        let bytes: BytesMut = message.clone().into();
+        assert_eq!(bytes, expected);
+        assert_eq!(Message::try_from(expected).unwrap(), message);
+    }
+
+    #[test]
+    fn open_message_matches_golden_bytes() {
+        let open = OpenMessage::new(64512.into(), "10.200.100.2".parse().unwrap())
+            .with_hold_time(90.into());
+        #[rustfmt::skip]
+        let body = [
+            0x04,                   // version
+            0xfc, 0x00,             // my autonomous system: 64512
+            0x00, 0x5a,             // hold time: 90
+            0x0a, 0xc8, 0x64, 0x02, // bgp identifier: 10.200.100.2
+            0x00,                   // optional parameters length
+        ];
+        assert_golden(Message::Open(open), golden(29, 1, &body));
+    }
+
+    #[test]
+    fn message_is_parsed_from_byte_slice() {
+        let bytes: BytesMut = Message::new_notification(6, 2, BytesMut::new()).into();
+        let slice: &[u8] = &bytes;
+        assert_eq!(
+            Message::try_from(slice).unwrap(),
+            Message::new_notification(6, 2, BytesMut::new())
+        );
+        assert_eq!(
+            NotificationMessage::try_from(slice).unwrap().description(),
+            "Cease / Administrative Shutdown"
+        );
+        assert!(Message::try_from(&slice[..18]).is_err());
+    }
+
+    #[test]
+    fn keepalive_message_matches_golden_bytes() {
+        assert_golden(Message::new_keepalive(), golden(19, 4, &[]));
+    }
+
+    #[test]
+    fn update_message_matches_golden_bytes() {
+        let update = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::sequence(vec![64512.into()])),
+                PathAttribute::NextHop("10.200.100.2".parse().unwrap()),
+            ]),
+            vec!["10.100.220.0/24".parse().unwrap()],
+            vec![],
+        );
+        #[rustfmt::skip]
+        let body = [
+            0x00, 0x00,             // withdrawn routes length
+            0x00, 0x12,             // total path attribute length: 18
+            0x40, 0x01, 0x01, 0x00, // ORIGIN: IGP
+            0x40, 0x02, 0x04,       // AS_PATH
+            0x02, 0x01, 0xfc, 0x00, // AS_SEQUENCE: 64512
+            0x40, 0x03, 0x04,       // NEXT_HOP
+            0x0a, 0xc8, 0x64, 0x02, // 10.200.100.2
+            0x18, 0x0a, 0x64, 0xdc, // NLRI: 10.100.220.0/24
+        ];
+        assert_golden(Message::Update(update), golden(45, 2, &body));
+    }
 }
@@ -0,0 +1,174 @@
+use bytes::{BufMut, BytesMut};
+
+use crate::error::ConvertBytesToBgpMessageError;
+
+use super::header::{Header, MessageType};
+
+#[derive(PartialEq, Eq, Debug, Clone, Hash)]
+pub struct NotificationMessage {
+    header: Header,
+    pub error_code: u8,
+    pub error_subcode: u8,
+    pub data: BytesMut,
+}
+
+const CEASE: u8 = 6;
+const CONNECTION_COLLISION_RESOLUTION: u8 = 7;
+const HARD_RESET: u8 = 9;
+
+impl NotificationMessage {
+    pub fn new(error_code: u8, error_subcode: u8, data: BytesMut) -> Self {
+        let header = Header::new(21 + data.len() as u16, MessageType::Notification);
+        Self {
+            header,
+            error_code,
+            error_subcode,
+            data,
+        }
+    }
+
+    /// error code, error subcodeで表される理由を包んだHard Reset(RFC 8538)を作る。
+    pub fn new_hard_reset(error_code: u8, error_subcode: u8, data: BytesMut) -> Self {
+        let mut encapsulated = BytesMut::new();
+        encapsulated.put_u8(error_code);
+        encapsulated.put_u8(error_subcode);
+        encapsulated.put(data);
+        Self::new(CEASE, HARD_RESET, encapsulated)
+    }
+
+    pub fn is_hard_reset(&self) -> bool {
+        self.error_code == CEASE && self.error_subcode == HARD_RESET
+    }
+
+    /// 接続の衝突を解決するために閉じられたことを表すCeaseならtrue。
+    pub fn is_collision_resolution(&self) -> bool {
+        self.error_code == CEASE && self.error_subcode == CONNECTION_COLLISION_RESOLUTION
+    }
+
+    /// Hard Resetに包まれたNOTIFICATIONを返す。
+    pub fn encapsulated(&self) -> Option<NotificationMessage> {
+        match &self.data[..] {
+            [error_code, error_subcode, data @ ..] if self.is_hard_reset() => {
+                Some(Self::new(*error_code, *error_subcode, BytesMut::from(data)))
+            }
+            _ => None,
+        }
+    }
+
+    /// error code, error subcodeを人が読める文字列にする(RFC 4271 Section 4.5, RFC 4486)。
+    /// Hard Resetの場合は包まれた理由も含める。
+    pub fn description(&self) -> String {
+        if let Some(encapsulated) = self.encapsulated() {
+            return format!("Cease / Hard Reset ({})", encapsulated.description());
+        }
+        let error_code = match self.error_code {
+            1 => "Message Header Error",
+            2 => "OPEN Message Error",
+            3 => "UPDATE Message Error",
+            4 => "Hold Timer Expired",
+            5 => "Finite State Machine Error",
+            6 => "Cease",
+            7 => "ROUTE-REFRESH Message Error",
+            _ => return format!("Unknown Error (code {})", self.error_code),
+        };
+        let error_subcode = match (self.error_code, self.error_subcode) {
+            (_, 0) => return error_code.to_owned(),
+            (1, 1) => "Connection Not Synchronized",
+            (1, 2) => "Bad Message Length",
+            (1, 3) => "Bad Message Type",
+            (2, 1) => "Unsupported Version Number",
+            (2, 2) => "Bad Peer AS",
+            (2, 3) => "Bad BGP Identifier",
+            (2, 4) => "Unsupported Optional Parameter",
+            (2, 6) => "Unacceptable Hold Time",
+            (2, 7) => "Unsupported Capability",
+            (3, 1) => "Malformed Attribute List",
+            (3, 2) => "Unrecognized Well-known Attribute",
+            (3, 3) => "Missing Well-known Attribute",
+            (3, 4) => "Attribute Flags Error",
+            (3, 5) => "Attribute Length Error",
+            (3, 6) => "Invalid ORIGIN Attribute",
+            (3, 8) => "Invalid NEXT_HOP Attribute",
+            (3, 9) => "Optional Attribute Error",
+            (3, 10) => "Invalid Network Field",
+            (3, 11) => "Malformed AS_PATH",
+            (6, 1) => "Maximum Number of Prefixes Reached",
+            (6, 2) => "Administrative Shutdown",
+            (6, 3) => "Peer De-configured",
+            (6, 4) => "Administrative Reset",
+            (6, 5) => "Connection Rejected",
+            (6, 6) => "Other Configuration Change",
+            (6, 7) => "Connection Collision Resolution",
+            (6, 8) => "Out of Resources",
+            (6, 9) => "Hard Reset",
+            (7, 1) => "Invalid Message Length",
+            _ => return format!("{} / Unknown Subcode {}", error_code, self.error_subcode),
+        };
+        format!("{} / {}", error_code, error_subcode)
+    }
+
+    /// ログ出力用に、dataを16進数の文字列にする。
+    pub fn data_hex(&self) -> String {
+        self.data.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl TryFrom<&[u8]> for NotificationMessage {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() < 21 {
+            return Err(anyhow::anyhow!(
+                "NOTIFICATIONは21 bytes以上が期待されていますが、{} bytesでした。",
+                bytes.len()
+            )
+            .into());
+        }
+        let header = Header::try_from(&bytes[0..19])?;
+        if header.type_ != MessageType::Notification {
+            return Err(anyhow::anyhow!("bytes列のtypeがnotificationではありません。").into());
+        }
+        let error_code = bytes[19];
+        let error_subcode = bytes[20];
+        let data = BytesMut::from(&bytes[21..]);
+        Ok(Self {
+            header,
+            error_code,
+            error_subcode,
+            data,
+        })
+    }
+}
+
+impl TryFrom<BytesMut> for NotificationMessage {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(bytes: BytesMut) -> Result<Self, Self::Error> {
+        Self::try_from(&bytes[..])
+    }
+}
+
+impl From<NotificationMessage> for BytesMut {
+    fn from(message: NotificationMessage) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        bytes.put::<BytesMut>(message.header.into());
+        bytes.put_u8(message.error_code);
+        bytes.put_u8(message.error_subcode);
+        bytes.put(&message.data[..]);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_bytes_to_notification_message_and_notification_message_to_bytes() {
+        let notification = NotificationMessage::new(6, 2, BytesMut::from(&b"shutdown"[..]));
+        let notification_bytes: BytesMut = notification.clone().into();
+        let notification2: NotificationMessage = notification_bytes.try_into().unwrap();
+
+        assert_eq!(notification, notification2);
+    }
+}
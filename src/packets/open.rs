@@ -1,8 +1,9 @@
 use std::net::Ipv4Addr;
 
-use super::header::{self, Header, MessageType};
+use super::header::{Header, MessageType};
+use super::route_refresh::{AFI_IPV6, SAFI_UNICAST};
 use crate::bgp_type::{AutonomousSystemNumber, HoldTime, Version};
-use crate::error::ConvertBytesToBgpMessageError;
+use crate::error::{BadMessageLengthError, ConvertBytesToBgpMessageError, OpenMessageError};
 use anyhow::Context;
 use bytes::{BufMut, BytesMut};
 
@@ -11,7 +12,7 @@ pub struct OpenMessage {
     header: Header,
     version: Version,
     my_as_number: AutonomousSystemNumber,
-    hold_time: HoldTime, // 正常系のみ実装するので一旦実質的に使用しない。
+    hold_time: HoldTime,
     bgp_identifier: Ipv4Addr,
 
     // 使用しないが、相手から受信したときに一応保存しておくためにプロパティとして用意
@@ -19,6 +20,166 @@ pub struct OpenMessage {
     optional_parameters: BytesMut,
 }
 
+/// OPENのCapabilities Optional Parameter(RFC 5492)で広告するCapability。
+#[derive(PartialEq, Eq, Debug, Clone, Hash)]
+pub enum Capability {
+    /// Multiprotocol Extensions Capability(RFC 4760)。送受信するaddress familyごとに広告する。
+    Multiprotocol {
+        afi: u16,
+        safi: u8,
+    },
+    /// Graceful Restart Capability(RFC 4724)。restart_timeは秒。
+    /// notificationはNOTIFICATIONによる切断でもGraceful Restartを行うことを表すN bit(RFC 8538)。
+    GracefulRestart {
+        restart_time: u16,
+        notification: bool,
+    },
+    /// Route Refresh Capability(RFC 2918)。
+    RouteRefresh,
+    /// Enhanced Route Refresh Capability(RFC 7313)。
+    EnhancedRouteRefresh,
+    /// UPDATEのbodyをzstdで圧縮して送受信する、このdaemon独自のCapability。
+    /// 実験用(RFC 8810)のcodeを用いる。
+    UpdateCompression,
+    Unknown {
+        code: u8,
+        value: Vec<u8>,
+    },
+}
+
+impl Capability {
+    /// 実装しているCapabilityを1つずつ。値は代表値で、広告する値とは限らない。
+    pub fn supported() -> Vec<Capability> {
+        vec![
+            Capability::Multiprotocol {
+                afi: AFI_IPV6,
+                safi: SAFI_UNICAST,
+            },
+            Capability::RouteRefresh,
+            Capability::GracefulRestart {
+                restart_time: 0,
+                notification: false,
+            },
+            Capability::EnhancedRouteRefresh,
+            #[cfg(feature = "update-compression")]
+            Capability::UpdateCompression,
+        ]
+    }
+
+    pub fn code(&self) -> u8 {
+        match self {
+            Capability::Multiprotocol { .. } => CAPABILITY_CODE_MULTIPROTOCOL,
+            Capability::GracefulRestart { .. } => CAPABILITY_CODE_GRACEFUL_RESTART,
+            Capability::RouteRefresh => CAPABILITY_CODE_ROUTE_REFRESH,
+            Capability::EnhancedRouteRefresh => CAPABILITY_CODE_ENHANCED_ROUTE_REFRESH,
+            Capability::UpdateCompression => CAPABILITY_CODE_UPDATE_COMPRESSION,
+            Capability::Unknown { code, .. } => *code,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::Multiprotocol { .. } => "Multiprotocol Extensions",
+            Capability::GracefulRestart { .. } => "Graceful Restart",
+            Capability::RouteRefresh => "Route Refresh",
+            Capability::EnhancedRouteRefresh => "Enhanced Route Refresh",
+            Capability::UpdateCompression => "Update Compression",
+            Capability::Unknown { .. } => "Unknown",
+        }
+    }
+}
+
+const OPTIONAL_PARAMETER_TYPE_CAPABILITIES: u8 = 2;
+const CAPABILITY_CODE_MULTIPROTOCOL: u8 = 1;
+const CAPABILITY_CODE_ROUTE_REFRESH: u8 = 2;
+const CAPABILITY_CODE_GRACEFUL_RESTART: u8 = 64;
+const CAPABILITY_CODE_ENHANCED_ROUTE_REFRESH: u8 = 70;
+const CAPABILITY_CODE_UPDATE_COMPRESSION: u8 = 239;
+const GRACEFUL_RESTART_FLAG_NOTIFICATION: u16 = 0x4000;
+
+impl From<&Capability> for BytesMut {
+    fn from(capability: &Capability) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        match capability {
+            Capability::Multiprotocol { afi, safi } => {
+                bytes.put_u8(CAPABILITY_CODE_MULTIPROTOCOL);
+                bytes.put_u8(4);
+                bytes.put_u16(*afi);
+                bytes.put_u8(0);
+                bytes.put_u8(*safi);
+            }
+            Capability::GracefulRestart {
+                restart_time,
+                notification,
+            } => {
+                let mut flags_and_time = restart_time & 0x0fff;
+                if *notification {
+                    flags_and_time |= GRACEFUL_RESTART_FLAG_NOTIFICATION;
+                }
+                bytes.put_u8(CAPABILITY_CODE_GRACEFUL_RESTART);
+                bytes.put_u8(2);
+                bytes.put_u16(flags_and_time);
+            }
+            Capability::RouteRefresh => {
+                bytes.put_u8(CAPABILITY_CODE_ROUTE_REFRESH);
+                bytes.put_u8(0);
+            }
+            Capability::EnhancedRouteRefresh => {
+                bytes.put_u8(CAPABILITY_CODE_ENHANCED_ROUTE_REFRESH);
+                bytes.put_u8(0);
+            }
+            Capability::UpdateCompression => {
+                bytes.put_u8(CAPABILITY_CODE_UPDATE_COMPRESSION);
+                bytes.put_u8(0);
+            }
+            Capability::Unknown { code, value } => {
+                bytes.put_u8(*code);
+                bytes.put_u8(value.len() as u8);
+                bytes.put(&value[..]);
+            }
+        }
+        bytes
+    }
+}
+
+impl Capability {
+    fn from_u8_slice(bytes: &[u8]) -> Vec<Capability> {
+        let mut capabilities = vec![];
+        let mut i = 0;
+        while i + 2 <= bytes.len() {
+            let code = bytes[i];
+            let length = bytes[i + 1] as usize;
+            let Some(value) = bytes.get(i + 2..i + 2 + length) else {
+                break;
+            };
+            capabilities.push(match (code, value) {
+                (CAPABILITY_CODE_MULTIPROTOCOL, [afi_0, afi_1, _, safi]) => {
+                    Capability::Multiprotocol {
+                        afi: u16::from_be_bytes([*afi_0, *afi_1]),
+                        safi: *safi,
+                    }
+                }
+                (CAPABILITY_CODE_GRACEFUL_RESTART, [flags_and_time_0, flags_and_time_1, ..]) => {
+                    let flags_and_time = u16::from_be_bytes([*flags_and_time_0, *flags_and_time_1]);
+                    Capability::GracefulRestart {
+                        restart_time: flags_and_time & 0x0fff,
+                        notification: flags_and_time & GRACEFUL_RESTART_FLAG_NOTIFICATION != 0,
+                    }
+                }
+                (CAPABILITY_CODE_ROUTE_REFRESH, []) => Capability::RouteRefresh,
+                (CAPABILITY_CODE_ENHANCED_ROUTE_REFRESH, []) => Capability::EnhancedRouteRefresh,
+                (CAPABILITY_CODE_UPDATE_COMPRESSION, []) => Capability::UpdateCompression,
+                _ => Capability::Unknown {
+                    code,
+                    value: value.to_vec(),
+                },
+            });
+            i += 2 + length;
+        }
+        capabilities
+    }
+}
+
 impl OpenMessage {
     pub fn new(my_as_number: AutonomousSystemNumber, my_ip_addr: Ipv4Addr) -> Self {
         let header = Header::new(29, MessageType::Open);
@@ -32,20 +193,84 @@ impl OpenMessage {
             optional_parameters: BytesMut::new(),
         }
     }
+
+    pub fn with_hold_time(mut self, hold_time: HoldTime) -> Self {
+        self.hold_time = hold_time;
+        self
+    }
+
+    pub fn hold_time(&self) -> HoldTime {
+        self.hold_time
+    }
+
+    pub fn my_as_number(&self) -> AutonomousSystemNumber {
+        self.my_as_number
+    }
+
+    pub fn bgp_identifier(&self) -> Ipv4Addr {
+        self.bgp_identifier
+    }
+
+    /// capabilitiesを1つのCapabilities Optional Parameterとして設定する。
+    pub fn with_capabilities(mut self, capabilities: &[Capability]) -> Self {
+        let mut value = BytesMut::new();
+        capabilities
+            .iter()
+            .for_each(|c| value.put::<BytesMut>(c.into()));
+        let mut optional_parameters = BytesMut::new();
+        if !value.is_empty() {
+            optional_parameters.put_u8(OPTIONAL_PARAMETER_TYPE_CAPABILITIES);
+            optional_parameters.put_u8(value.len() as u8);
+            optional_parameters.put(value);
+        }
+        self.optional_parameter_length = optional_parameters.len() as u8;
+        self.header = Header::new(29 + optional_parameters.len() as u16, MessageType::Open);
+        self.optional_parameters = optional_parameters;
+        self
+    }
+
+    /// Capabilities Optional Parameterで広告されたCapabilityを返す。
+    pub fn capabilities(&self) -> Vec<Capability> {
+        let mut capabilities = vec![];
+        let mut i = 0;
+        while i + 2 <= self.optional_parameters.len() {
+            let parameter_type = self.optional_parameters[i];
+            let length = self.optional_parameters[i + 1] as usize;
+            let Some(value) = self.optional_parameters.get(i + 2..i + 2 + length) else {
+                break;
+            };
+            if parameter_type == OPTIONAL_PARAMETER_TYPE_CAPABILITIES {
+                capabilities.extend(Capability::from_u8_slice(value));
+            }
+            i += 2 + length;
+        }
+        capabilities
+    }
 }
 
-impl TryFrom<BytesMut> for OpenMessage {
+impl TryFrom<&[u8]> for OpenMessage {
     type Error = ConvertBytesToBgpMessageError;
 
-    fn try_from(bytes: BytesMut) -> Result<Self, Self::Error> {
-        let header = Header::try_from(BytesMut::from(&bytes[0..19]))?;
-        let version: Version = bytes[19].try_into()?;
-        let my_as_number = AutonomousSystemNumber::from(u16::from_be_bytes(
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let header = Header::try_from(bytes)?;
+        if bytes.len() < usize::from(MessageType::Open.min_length()) {
+            return Err(anyhow::Error::from(BadMessageLengthError {
+                type_name: MessageType::Open.name(),
+                length: u16::try_from(bytes.len()).unwrap_or(u16::MAX),
+            })
+            .into());
+        }
+        let version: Version = bytes[19].try_into().map_err(|_| {
+            ConvertBytesToBgpMessageError::from(anyhow::Error::from(
+                OpenMessageError::UnsupportedVersionNumber(bytes[19]),
+            ))
+        })?;
+        let my_as_number = AutonomousSystemNumber::from(u32::from(u16::from_be_bytes(
             bytes[20..22].try_into().context(format!(
                 "AS番号のbytes表現`{:?}`からAS番号に変換できませんでした",
                 &bytes[20..22]
             ))?,
-        ));
+        )));
         let hold_time = HoldTime::from(u16::from_be_bytes(bytes[22..24].try_into().context(
             format!(
                 "HoldTimeのbytes表現`{:?}`からHoldTimeに変換できませんでした。",
@@ -71,6 +296,14 @@ impl TryFrom<BytesMut> for OpenMessage {
     }
 }
 
+impl TryFrom<BytesMut> for OpenMessage {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(bytes: BytesMut) -> Result<Self, Self::Error> {
+        Self::try_from(&bytes[..])
+    }
+}
+
 impl From<OpenMessage> for BytesMut {
     fn from(message: OpenMessage) -> BytesMut {
         let mut bytes = BytesMut::new();
@@ -99,4 +332,55 @@ mod tests {
 
         assert_eq!(open_message, open_message2);
     }
+
+    #[test]
+    fn open_message_with_capabilities_round_trips() {
+        let capabilities = vec![
+            Capability::Multiprotocol {
+                afi: AFI_IPV6,
+                safi: SAFI_UNICAST,
+            },
+            Capability::GracefulRestart {
+                restart_time: 120,
+                notification: true,
+            },
+            Capability::EnhancedRouteRefresh,
+            Capability::Unknown {
+                code: 128,
+                value: vec![],
+            },
+        ];
+        let open_message = OpenMessage::new(64512.into(), "127.0.0.1".parse().unwrap())
+            .with_capabilities(&capabilities);
+        let open_message_bytes: BytesMut = open_message.clone().into();
+        assert_eq!(open_message_bytes.len(), 29 + 2 + 6 + 4 + 2 + 2);
+        let open_message2: OpenMessage = open_message_bytes.try_into().unwrap();
+
+        assert_eq!(open_message, open_message2);
+        assert_eq!(open_message2.capabilities(), capabilities);
+    }
+
+    #[test]
+    fn truncated_open_message_is_bad_message_length() {
+        let open_message = OpenMessage::new(64512.into(), "127.0.0.1".parse().unwrap());
+        let open_message_bytes: BytesMut = open_message.into();
+        for length in [19, 20, 28] {
+            let error = OpenMessage::try_from(&open_message_bytes[..length]).unwrap_err();
+            assert_eq!(error.bad_message_length(), Some(length as u16));
+        }
+        assert!(OpenMessage::try_from(&open_message_bytes[..18]).is_err());
+    }
+
+    #[test]
+    fn unsupported_version_is_open_message_error() {
+        let open_message = OpenMessage::new(64512.into(), "127.0.0.1".parse().unwrap());
+        let mut open_message_bytes: BytesMut = open_message.into();
+        open_message_bytes[19] = 5;
+        let error = OpenMessage::try_from(&open_message_bytes[..])
+            .unwrap_err()
+            .message_error()
+            .unwrap();
+        assert_eq!((error.code(), error.subcode()), (2, 1));
+        assert_eq!(&error.data()[..], &[0, 4]);
+    }
 }
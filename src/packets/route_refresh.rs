@@ -0,0 +1,143 @@
+use bytes::{BufMut, BytesMut};
+
+use crate::error::ConvertBytesToBgpMessageError;
+
+use super::header::{Header, MessageType};
+
+pub const AFI_IPV4: u16 = 1;
+pub const AFI_IPV6: u16 = 2;
+pub const SAFI_UNICAST: u8 = 1;
+
+/// ROUTE-REFRESH Message(RFC 2918)。subtypeはEnhanced Route Refresh(RFC 7313)で追加された。
+#[derive(PartialEq, Eq, Debug, Clone, Hash)]
+pub struct RouteRefreshMessage {
+    header: Header,
+    afi: u16,
+    pub subtype: RouteRefreshSubtype,
+    safi: u8,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum RouteRefreshSubtype {
+    /// 経路の再送を要求する。
+    Request,
+    /// 要求に応じた経路の再送の開始を表す(Begin-of-RIB Route Refresh)。
+    BeginOfRouteRefresh,
+    /// 要求に応じた経路の再送の終了を表す(End-of-RIB Route Refresh)。
+    EndOfRouteRefresh,
+}
+
+impl TryFrom<u8> for RouteRefreshSubtype {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(num: u8) -> Result<Self, Self::Error> {
+        match num {
+            0 => Ok(RouteRefreshSubtype::Request),
+            1 => Ok(RouteRefreshSubtype::BeginOfRouteRefresh),
+            2 => Ok(RouteRefreshSubtype::EndOfRouteRefresh),
+            _ => Err(Self::Error::from(anyhow::anyhow!(
+                "ROUTE-REFRESHのsubtypeは0-2が期待されていますが、{} が渡されました。",
+                num
+            ))),
+        }
+    }
+}
+
+impl From<RouteRefreshSubtype> for u8 {
+    fn from(subtype: RouteRefreshSubtype) -> u8 {
+        match subtype {
+            RouteRefreshSubtype::Request => 0,
+            RouteRefreshSubtype::BeginOfRouteRefresh => 1,
+            RouteRefreshSubtype::EndOfRouteRefresh => 2,
+        }
+    }
+}
+
+impl RouteRefreshMessage {
+    /// IPv4 unicastのROUTE-REFRESHを作る。
+    pub fn new(subtype: RouteRefreshSubtype) -> Self {
+        Self::for_afi_safi(AFI_IPV4, SAFI_UNICAST, subtype)
+    }
+
+    /// afi, safiで指定したaddress familyのROUTE-REFRESHを作る。
+    pub fn for_afi_safi(afi: u16, safi: u8, subtype: RouteRefreshSubtype) -> Self {
+        Self {
+            header: Header::new(23, MessageType::RouteRefresh),
+            afi,
+            subtype,
+            safi,
+        }
+    }
+
+    /// 対象のaddress familyのAFIとSAFI。
+    pub fn afi_safi(&self) -> (u16, u8) {
+        (self.afi, self.safi)
+    }
+
+    pub fn is_ipv4_unicast(&self) -> bool {
+        self.afi_safi() == (AFI_IPV4, SAFI_UNICAST)
+    }
+}
+
+impl TryFrom<&[u8]> for RouteRefreshMessage {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() != 23 {
+            return Err(anyhow::anyhow!(
+                "ROUTE-REFRESHは23 bytesが期待されていますが、{} bytesが渡されました。",
+                bytes.len()
+            )
+            .into());
+        }
+        let header = Header::try_from(&bytes[0..19])?;
+        if header.type_ != MessageType::RouteRefresh {
+            return Err(anyhow::anyhow!("bytes列のtypeがroute refreshではありません。").into());
+        }
+        Ok(Self {
+            header,
+            afi: u16::from_be_bytes([bytes[19], bytes[20]]),
+            subtype: bytes[21].try_into()?,
+            safi: bytes[22],
+        })
+    }
+}
+
+impl TryFrom<BytesMut> for RouteRefreshMessage {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(bytes: BytesMut) -> Result<Self, Self::Error> {
+        Self::try_from(&bytes[..])
+    }
+}
+
+impl From<RouteRefreshMessage> for BytesMut {
+    fn from(message: RouteRefreshMessage) -> BytesMut {
+        let mut bytes: BytesMut = message.header.into();
+        bytes.put_u16(message.afi);
+        bytes.put_u8(message.subtype.into());
+        bytes.put_u8(message.safi);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_bytes_to_route_refresh_message_and_route_refresh_message_to_bytes() {
+        for subtype in [
+            RouteRefreshSubtype::Request,
+            RouteRefreshSubtype::BeginOfRouteRefresh,
+            RouteRefreshSubtype::EndOfRouteRefresh,
+        ] {
+            let route_refresh = RouteRefreshMessage::new(subtype);
+            let bytes: BytesMut = route_refresh.clone().into();
+            assert_eq!(bytes.len(), 23);
+            assert_eq!(bytes[21], u8::from(subtype));
+            let route_refresh2: RouteRefreshMessage = bytes.try_into().unwrap();
+            assert_eq!(route_refresh, route_refresh2);
+        }
+    }
+}
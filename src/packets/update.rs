@@ -1,15 +1,15 @@
+use std::fmt;
 use std::sync::Arc;
 
-use anyhow::Context;
 use bytes::{BufMut, BytesMut};
 
 use crate::{
-    error::ConvertBytesToBgpMessageError,
-    path_attribute::{self, PathAttribute},
+    error::{BadMessageLengthError, ConvertBytesToBgpMessageError, UpdateMessageError},
+    path_attribute::PathAttribute,
     routing::Ipv4Network,
 };
 
-use super::header::{Header, MessageType};
+use super::header::{Header, MessageType, MAX_MESSAGE_LENGTH};
 
 #[derive(PartialEq, Eq, Debug, Clone, Hash)]
 pub struct UpdateMessage {
@@ -57,6 +57,120 @@ impl UpdateMessage {
     }
 }
 
+impl UpdateMessage {
+    /// IPv4 UnicastのEnd-of-RIBマーカー(RFC 4724)。
+    /// withdrawn routes, path attributes, NLRIがいずれも空のUPDATE。
+    pub fn new_end_of_rib() -> Self {
+        Self::new(Arc::new(vec![]), vec![], vec![])
+    }
+
+    pub fn is_end_of_rib(&self) -> bool {
+        self.withdrawn_routes.is_empty()
+            && self.path_attributes.is_empty()
+            && self.network_layer_reachability_information.is_empty()
+    }
+
+    /// 広告された経路のprefix。
+    pub fn nlri(&self) -> &[Ipv4Network] {
+        &self.network_layer_reachability_information
+    }
+
+    pub fn withdrawn_routes(&self) -> &[Ipv4Network] {
+        &self.withdrawn_routes
+    }
+
+    pub fn path_attributes(&self) -> &[PathAttribute] {
+        &self.path_attributes
+    }
+
+    /// 取り消しと広告を合わせた経路の数。
+    pub fn routes_len(&self) -> usize {
+        self.withdrawn_routes.len() + self.network_layer_reachability_information.len()
+    }
+
+    /// 取り消し、広告の順に数えて、先頭のn個の経路と残りの経路のUPDATEに分ける。
+    /// どちらもpath attributesは元のものを共有する。
+    pub fn split_at(mut self, n: usize) -> (Self, Self) {
+        let withdrawn = self.withdrawn_routes.len().min(n);
+        let rest_withdrawn_routes = self.withdrawn_routes.split_off(withdrawn);
+        let rest_nlri = self
+            .network_layer_reachability_information
+            .split_off((n - withdrawn).min(self.network_layer_reachability_information.len()));
+        let head = Self::new(
+            Arc::clone(&self.path_attributes),
+            self.network_layer_reachability_information,
+            self.withdrawn_routes,
+        );
+        let rest = Self::new(self.path_attributes, rest_nlri, rest_withdrawn_routes);
+        (head, rest)
+    }
+
+    /// Messageの長さがMAX_MESSAGE_LENGTHを超えないよう、経路を複数のUPDATEに分ける。
+    pub fn split_to_max_length(self) -> Vec<Self> {
+        let path_attributes_length: usize =
+            self.path_attributes.iter().map(|p| p.bytes_len()).sum();
+        let mut updates = vec![];
+        let mut rest = self;
+        loop {
+            let mut length = usize::from(MessageType::Update.min_length()) + path_attributes_length;
+            let fits = rest
+                .withdrawn_routes
+                .iter()
+                .chain(&rest.network_layer_reachability_information)
+                .take_while(|network| {
+                    length += network.bytes_len();
+                    length <= usize::from(MAX_MESSAGE_LENGTH)
+                })
+                .count();
+            if fits == rest.routes_len() {
+                updates.push(rest);
+                return updates;
+            }
+            let (head, tail) = rest.split_at(fits.max(1));
+            updates.push(head);
+            rest = tail;
+        }
+    }
+}
+
+/// ログなどに出すための1行の表現。空の項目は省く。
+/// `UPDATE withdrawn=[10.0.0.0/24] attributes=[Origin(Igp), NextHop(10.0.0.1)] nlri=[10.1.0.0/24]`
+impl fmt::Display for UpdateMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_end_of_rib() {
+            return write!(f, "UPDATE End-of-RIB");
+        }
+        write!(f, "UPDATE")?;
+        if !self.withdrawn_routes.is_empty() {
+            write!(f, " withdrawn=[{}]", join(&self.withdrawn_routes))?;
+        }
+        if !self.path_attributes.is_empty() {
+            let attributes: Vec<String> = self
+                .path_attributes
+                .iter()
+                .map(|p| format!("{p:?}"))
+                .collect();
+            write!(f, " attributes=[{}]", attributes.join(", "))?;
+        }
+        if !self.network_layer_reachability_information.is_empty() {
+            write!(
+                f,
+                " nlri=[{}]",
+                join(&self.network_layer_reachability_information)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn join(networks: &[Ipv4Network]) -> String {
+    networks
+        .iter()
+        .map(|n| n.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 impl From<UpdateMessage> for BytesMut {
     fn from(message: UpdateMessage) -> Self {
         let mut bytes = BytesMut::new();
@@ -79,34 +193,64 @@ impl From<UpdateMessage> for BytesMut {
     }
 }
 
-impl TryFrom<BytesMut> for UpdateMessage {
+impl TryFrom<&[u8]> for UpdateMessage {
     type Error = ConvertBytesToBgpMessageError;
-    fn try_from(bytes: BytesMut) -> Result<Self, Self::Error> {
-        let header = Header::try_from(BytesMut::from(&bytes[0..19]))?;
-        let withdrawn_routes_length: u16 =
-            u16::from_be_bytes(bytes[19..21].try_into().context(format!(
-                "Bytes: {:?} からwithdrawn_routes_lengthに変換できませんでした",
-                &bytes
-            ))?);
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let header = Header::try_from(bytes)?;
+        if bytes.len() < usize::from(MessageType::Update.min_length()) {
+            return Err(anyhow::Error::from(BadMessageLengthError {
+                type_name: MessageType::Update.name(),
+                length: u16::try_from(bytes.len()).unwrap_or(u16::MAX),
+            })
+            .into());
+        }
+        let withdrawn_routes_length = u16::from_be_bytes([bytes[19], bytes[20]]);
         let withdrawn_routes_end_index = 21 + withdrawn_routes_length as usize;
-        let withdrawn_routes_bytes = &bytes[21..withdrawn_routes_end_index];
-        let withdrawn_routes = Ipv4Network::from_u8_slice(withdrawn_routes_bytes)?;
+        // RFC 4271 Section 6.3: 長さがMessageに収まらなければMalformed Attribute List、
+        // NLRIの形式が不正ならInvalid Network Fieldとする。
+        let malformed_attribute_list = |reason: String| {
+            ConvertBytesToBgpMessageError::from(anyhow::Error::from(
+                UpdateMessageError::MalformedAttributeList(reason),
+            ))
+        };
+        let invalid_network_field = |e: ConvertBytesToBgpMessageError| {
+            ConvertBytesToBgpMessageError::from(anyhow::Error::from(
+                UpdateMessageError::InvalidNetworkField(format!("{e:#}")),
+            ))
+        };
+        let withdrawn_routes_bytes =
+            bytes.get(21..withdrawn_routes_end_index).ok_or_else(|| {
+                malformed_attribute_list(format!(
+                    "Withdrawn Routes Length {}がMessageの長さ{}を超えています。",
+                    withdrawn_routes_length,
+                    bytes.len()
+                ))
+            })?;
+        let withdrawn_routes =
+            Ipv4Network::from_u8_slice(withdrawn_routes_bytes).map_err(invalid_network_field)?;
         let path_attributes_start_index = withdrawn_routes_end_index + 2;
-        let total_path_attribute_length = u16::from_be_bytes(
-            bytes[withdrawn_routes_end_index..path_attributes_start_index]
-                .try_into()
-                .context(format!(
-                    "Bytes: {:?}からtotal_path_attribute_lengthに変換できませんでした",
-                    &bytes
-                ))?,
-        );
-
-        let path_attributes_bytes = &bytes[path_attributes_start_index
-            ..path_attributes_start_index + total_path_attribute_length as usize];
-        let path_attributes = Arc::new(PathAttribute::from_u8_slice(path_attributes_bytes)?);
+        let total_path_attribute_length = bytes
+            .get(withdrawn_routes_end_index..path_attributes_start_index)
+            .map(|length| u16::from_be_bytes([length[0], length[1]]))
+            .ok_or_else(|| {
+                malformed_attribute_list(
+                    "Total Path Attribute LengthがMessageに含まれていません。".to_owned(),
+                )
+            })?;
         let nlri_start_index = path_attributes_start_index + total_path_attribute_length as usize;
+        let path_attributes_bytes = bytes
+            .get(path_attributes_start_index..nlri_start_index)
+            .ok_or_else(|| {
+                malformed_attribute_list(format!(
+                    "Total Path Attribute Length {}がMessageの長さ{}を超えています。",
+                    total_path_attribute_length,
+                    bytes.len()
+                ))
+            })?;
+        let path_attributes = Arc::new(PathAttribute::from_u8_slice(path_attributes_bytes)?);
         let network_layer_reachability_information =
-            Ipv4Network::from_u8_slice(&bytes[nlri_start_index..])?;
+            Ipv4Network::from_u8_slice(&bytes[nlri_start_index..])
+                .map_err(invalid_network_field)?;
 
         Ok(Self {
             header,
@@ -119,14 +263,24 @@ impl TryFrom<BytesMut> for UpdateMessage {
     }
 }
 
+impl TryFrom<BytesMut> for UpdateMessage {
+    type Error = ConvertBytesToBgpMessageError;
+
+    fn try_from(bytes: BytesMut) -> Result<Self, Self::Error> {
+        Self::try_from(&bytes[..])
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::net::Ipv4Addr;
 
     use crate::{
         bgp_type::AutonomousSystemNumber,
-        path_attribute::{AsPath, Origin},
-        routing::{AdjRibOut, RibEntry},
+        bgp_type::RouteDistinguisher,
+        config::Config,
+        path_attribute::{AsPath, ExtendedCommunity, MpReachNlri, MpUnreachNlri, Origin},
+        routing::{AdjRibIn, AdjRibOut, RibEntry, VpnV4Network},
     };
 
     use super::*;
@@ -134,14 +288,13 @@ mod tests {
     #[test]
     fn convert_bytes_to_update_message_and_update_message_to_bytes() {
         let some_as: AutonomousSystemNumber = 64513.into();
-        let some_ip: Ipv4Addr = "10.0.100.3".parse().unwrap();
 
         let local_as: AutonomousSystemNumber = 64514.into();
         let local_ip: Ipv4Addr = "10.200.100.3".parse().unwrap();
 
         let update_message_path_attributes = Arc::new(vec![
             PathAttribute::Origin(Origin::Igp),
-            PathAttribute::AsPath(AsPath::AsSequence(vec![some_as, local_as])),
+            PathAttribute::AsPath(AsPath::sequence(vec![some_as, local_as])),
             PathAttribute::NextHop(local_ip),
         ]);
 
@@ -156,6 +309,357 @@ mod tests {
         assert_eq!(update_message, update_message2);
     }
 
+    #[test]
+    fn update_message_with_only_withdrawn_routes_is_parsed() {
+        let mut bytes = BytesMut::from(&[0xff; 16][..]);
+        bytes.extend_from_slice(&[0, 31, 2]);
+        bytes.extend_from_slice(&[0, 8, 24, 10, 100, 220, 24, 10, 100, 221]);
+        bytes.extend_from_slice(&[0, 0]);
+
+        let update = UpdateMessage::try_from(bytes).unwrap();
+        let withdrawn: Vec<Ipv4Network> = vec![
+            "10.100.220.0/24".parse().unwrap(),
+            "10.100.221.0/24".parse().unwrap(),
+        ];
+        assert_eq!(update.withdrawn_routes, withdrawn);
+        assert!(update.path_attributes.is_empty());
+        assert!(update.network_layer_reachability_information.is_empty());
+
+        let config: Config = "64513 10.200.100.3 64512 10.200.100.2 passive"
+            .parse()
+            .unwrap();
+        let mut adj_rib_in = AdjRibIn::from_config(&config);
+        adj_rib_in.install_from_update(
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::sequence(vec![64512.into()])),
+                    PathAttribute::NextHop("10.200.100.2".parse().unwrap()),
+                ]),
+                withdrawn.clone(),
+                vec![],
+            ),
+            &config,
+        );
+        assert_eq!(adj_rib_in.routes().count(), 2);
+        adj_rib_in.install_from_update(update, &config);
+        assert_eq!(adj_rib_in.routes().count(), 0);
+        assert_eq!(adj_rib_in.withdrawn_routes().count(), 2);
+    }
+
+    #[test]
+    fn update_longer_than_max_length_is_split() {
+        let networks: Vec<Ipv4Network> = (0..2000u32)
+            .map(|i| format!("10.{}.{}.0/24", i / 256, i % 256).parse().unwrap())
+            .collect();
+        let update = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::NextHop("10.200.100.3".parse().unwrap()),
+            ]),
+            networks.clone(),
+            vec![],
+        );
+
+        let updates = update.split_to_max_length();
+        assert_eq!(updates.len(), 2);
+        for update in &updates {
+            let bytes: BytesMut = update.clone().into();
+            assert!(bytes.len() <= usize::from(MAX_MESSAGE_LENGTH));
+            assert_eq!(usize::from(update.header.length()), bytes.len());
+        }
+        let split: Vec<Ipv4Network> = updates.iter().flat_map(|u| u.nlri().to_vec()).collect();
+        assert_eq!(split, networks);
+    }
+
+    #[test]
+    fn truncated_update_is_error_instead_of_panic() {
+        let update = |body: &[u8]| {
+            let mut bytes = vec![0xff; 16];
+            bytes.extend_from_slice(&(19 + body.len() as u16).to_be_bytes());
+            bytes.push(2);
+            bytes.extend_from_slice(body);
+            UpdateMessage::try_from(&bytes[..])
+        };
+        // Headerだけで、Withdrawn Routes Lengthが無い。
+        assert_eq!(update(&[]).unwrap_err().bad_message_length(), Some(19));
+        // Withdrawn Routes LengthがMessageの長さを超えている。
+        assert!(update(&[0x00, 0xff, 0x00, 0x00]).is_err());
+        // Total Path Attribute LengthがMessageの長さを超えている。
+        assert!(update(&[0x00, 0x00, 0x00, 0xff]).is_err());
+        // Withdrawn Routesの/24のprefixが途中で終わり、Total Path Attribute Lengthが無い。
+        assert!(update(&[0x00, 0x02, 24, 10]).is_err());
+        // NLRIの/24のprefixが途中で終わっている。
+        assert!(update(&[0x00, 0x00, 0x00, 0x00, 24, 10, 100]).is_err());
+        // prefixが32より長い。
+        assert!(update(&[0x00, 0x00, 0x00, 0x00, 33, 10, 100, 220, 0, 0]).is_err());
+        assert_eq!(
+            update(&[0x00, 0x00, 0x00, 0x00, 0, 24, 10, 100, 220])
+                .unwrap()
+                .nlri(),
+            &[
+                "0.0.0.0/0".parse().unwrap(),
+                "10.100.220.0/24".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_multi_exit_disc_is_attribute_length_error() {
+        let attribute_length_error = |bytes: &[u8]| {
+            let error = PathAttribute::from_u8_slice(bytes).unwrap_err();
+            error
+                .attribute_length_error()
+                .map(|e| (e.type_code, e.length))
+        };
+        // Attribute Lengthが残りのbytesより長い。
+        assert_eq!(attribute_length_error(&[0x80, 4, 4, 0, 0]), Some((4, 4)));
+        // Attribute Lengthが4より短く、次のORIGINを読んでしまわない。
+        assert_eq!(
+            attribute_length_error(&[0x80, 4, 2, 0, 1, 0x40, 1, 1, 0]),
+            Some((4, 2))
+        );
+        assert_eq!(
+            PathAttribute::from_u8_slice(&[0x80, 4, 4, 0, 0, 0, 100]).unwrap(),
+            vec![PathAttribute::MultiExitDisc(100)]
+        );
+        let error = PathAttribute::from_u8_slice(&[0x80, 4, 4, 0, 0])
+            .unwrap_err()
+            .message_error()
+            .unwrap();
+        assert_eq!((error.code(), error.subcode()), (3, 5));
+    }
+
+    #[test]
+    fn truncated_local_pref_is_attribute_length_error() {
+        let error = PathAttribute::from_u8_slice(&[0x40, 5, 3, 0, 0, 100]).unwrap_err();
+        let error = error.attribute_length_error().unwrap();
+        assert_eq!((error.type_code, error.length), (5, 3));
+        assert!(PathAttribute::from_u8_slice(&[0x40, 5, 4, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn truncated_aggregator_is_attribute_length_error() {
+        let attribute_length_error = |bytes: &[u8]| {
+            let error = PathAttribute::from_u8_slice(bytes).unwrap_err();
+            error
+                .attribute_length_error()
+                .map(|e| (e.type_code, e.length))
+        };
+        // AGGREGATORは6 bytes、AS4_AGGREGATORは8 bytes。
+        assert_eq!(
+            attribute_length_error(&[0xc0, 7, 4, 0xfc, 0x00, 10, 200]),
+            Some((7, 4))
+        );
+        assert_eq!(
+            attribute_length_error(&[0xc0, 7, 6, 0xfc, 0x00, 10, 200]),
+            Some((7, 6))
+        );
+        assert_eq!(
+            attribute_length_error(&[0xc0, 18, 6, 0, 0, 0xfc, 0x00, 10, 200, 100, 2]),
+            Some((18, 6))
+        );
+        assert_eq!(
+            PathAttribute::from_u8_slice(&[0xc0, 18, 8, 0, 1, 0, 0, 10, 200, 100, 2]).unwrap(),
+            vec![PathAttribute::As4Aggregator(
+                65536.into(),
+                "10.200.100.2".parse().unwrap()
+            )]
+        );
+    }
+
+    #[test]
+    fn community_length_not_multiple_of_element_is_attribute_length_error() {
+        let attribute_length_error = |bytes: &[u8]| {
+            let error = PathAttribute::from_u8_slice(bytes).unwrap_err();
+            error
+                .attribute_length_error()
+                .map(|e| (e.type_code, e.length))
+        };
+        // COMMUNITIESは4 bytes、EXTENDED COMMUNITIESは8 bytesの倍数。
+        assert_eq!(
+            attribute_length_error(&[0xc0, 8, 6, 0xfd, 0xe8, 0, 1, 0xfd, 0xe8]),
+            Some((8, 6))
+        );
+        assert_eq!(
+            attribute_length_error(&[0xc0, 16, 10, 0, 2, 0xfd, 0xe8, 0, 0, 0, 1, 0, 2]),
+            Some((16, 10))
+        );
+        assert_eq!(
+            PathAttribute::from_u8_slice(&[0xc0, 8, 4, 0xfd, 0xe8, 0, 1]).unwrap(),
+            vec![PathAttribute::Communities(vec![0xfde8_0001])]
+        );
+    }
+
+    #[test]
+    fn convert_vpnv4_update_message_to_bytes_and_bytes_to_update_message() {
+        let vpnv4_network = VpnV4Network::new(
+            24000,
+            "65000:1".parse().unwrap(),
+            "10.100.220.0/24".parse().unwrap(),
+        );
+        let update_message = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::sequence(vec![64513.into()])),
+                PathAttribute::MpReachNlri(MpReachNlri::VpnV4 {
+                    next_hop: "10.200.100.3".parse().unwrap(),
+                    nlri: vec![vpnv4_network],
+                }),
+            ]),
+            vec![],
+            vec![],
+        );
+
+        let update_message_bytes: BytesMut = update_message.clone().into();
+        let update_message2: UpdateMessage = update_message_bytes.try_into().unwrap();
+        assert_eq!(update_message, update_message2);
+        assert_eq!(
+            update_message2.path_attributes[2],
+            PathAttribute::MpReachNlri(MpReachNlri::VpnV4 {
+                next_hop: "10.200.100.3".parse().unwrap(),
+                nlri: vec![VpnV4Network::new(
+                    24000,
+                    RouteDistinguisher::Type0 {
+                        admin: 65000,
+                        assigned: 1
+                    },
+                    "10.100.220.0/24".parse().unwrap(),
+                )],
+            })
+        );
+    }
+
+    #[test]
+    fn convert_ipv6_unicast_update_message_to_bytes_and_bytes_to_update_message() {
+        let update_message = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::sequence(vec![64513.into()])),
+                PathAttribute::MpReachNlri(MpReachNlri::Ipv6Unicast {
+                    next_hop: "2001:db8::3".parse().unwrap(),
+                    nlri: vec![
+                        "2001:db8:100::/48".parse().unwrap(),
+                        "::/0".parse().unwrap(),
+                    ],
+                }),
+                PathAttribute::MpUnreachNlri(MpUnreachNlri::Ipv6Unicast {
+                    withdrawn_routes: vec!["2001:db8:200::/40".parse().unwrap()],
+                }),
+            ]),
+            vec![],
+            vec![],
+        );
+
+        let update_message_bytes: BytesMut = update_message.clone().into();
+        let update_message2: UpdateMessage = update_message_bytes.try_into().unwrap();
+        assert_eq!(update_message, update_message2);
+        assert!(!update_message2.is_end_of_rib());
+    }
+
+    #[test]
+    fn convert_extended_communities_to_bytes_and_bytes_to_extended_communities() {
+        let communities = [
+            ExtendedCommunity::RouteTarget {
+                global_admin: 65000,
+                local_admin: 100,
+            },
+            ExtendedCommunity::RouteOrigin {
+                global_admin: 65000,
+                local_admin: 200,
+            },
+            ExtendedCommunity::TwoOctetAsSpecific {
+                sub_type: 0x05,
+                global_admin: 65001,
+                local_admin: 300,
+            },
+            ExtendedCommunity::Other([0x03, 0x0b, 0, 0, 0, 0, 0, 10]),
+        ];
+        let update_message = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::sequence(vec![64513.into()])),
+                PathAttribute::NextHop("10.200.100.3".parse().unwrap()),
+                PathAttribute::ExtendedCommunities(communities.iter().map(|&c| c.into()).collect()),
+            ]),
+            vec!["10.100.220.0/24".parse().unwrap()],
+            vec![],
+        );
+
+        let update_message_bytes: BytesMut = update_message.clone().into();
+        let update_message2: UpdateMessage = update_message_bytes.try_into().unwrap();
+        assert_eq!(update_message, update_message2);
+        assert_eq!(
+            PathAttribute::extended_communities(&update_message2.path_attributes)
+                .collect::<Vec<_>>(),
+            communities
+        );
+        assert_eq!(
+            <[u8; 8]>::from(communities[0]),
+            [0x00, 0x02, 0xfd, 0xe8, 0, 0, 0, 100]
+        );
+    }
+
+    #[test]
+    fn accessors_and_display_expose_update_contents() {
+        let path_attributes = vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::NextHop("10.200.100.3".parse().unwrap()),
+        ];
+        let nlri: Vec<Ipv4Network> = vec!["10.100.220.0/24".parse().unwrap()];
+        let withdrawn: Vec<Ipv4Network> = vec![
+            "10.100.221.0/24".parse().unwrap(),
+            "10.100.222.0/24".parse().unwrap(),
+        ];
+        let update = UpdateMessage::new(
+            Arc::new(path_attributes.clone()),
+            nlri.clone(),
+            withdrawn.clone(),
+        );
+
+        assert_eq!(update.nlri(), nlri.as_slice());
+        assert_eq!(update.withdrawn_routes(), withdrawn.as_slice());
+        assert_eq!(update.path_attributes(), path_attributes.as_slice());
+        assert_eq!(
+            update.to_string(),
+            "UPDATE withdrawn=[10.100.221.0/24, 10.100.222.0/24] \
+             attributes=[Origin(Igp), NextHop(10.200.100.3)] nlri=[10.100.220.0/24]"
+        );
+        assert_eq!(
+            UpdateMessage::new_end_of_rib().to_string(),
+            "UPDATE End-of-RIB"
+        );
+    }
+
+    #[test]
+    fn end_of_rib_round_trips_and_is_detected() {
+        let end_of_rib = UpdateMessage::new_end_of_rib();
+        let bytes: BytesMut = end_of_rib.clone().into();
+        assert_eq!(bytes.len(), 23);
+
+        let end_of_rib2: UpdateMessage = bytes.try_into().unwrap();
+        assert!(end_of_rib2.is_end_of_rib());
+        assert_eq!(end_of_rib, end_of_rib2);
+    }
+
+    #[test]
+    fn split_at_counts_withdrawn_routes_before_nlri() {
+        let network = |n: &str| n.parse::<Ipv4Network>().unwrap();
+        let update = UpdateMessage::new(
+            Arc::new(vec![PathAttribute::Origin(Origin::Igp)]),
+            vec![network("10.100.1.0/24"), network("10.100.2.0/24")],
+            vec![network("10.100.3.0/24")],
+        );
+        assert_eq!(update.routes_len(), 3);
+
+        let (head, rest) = update.clone().split_at(2);
+        assert_eq!(head.withdrawn_routes(), &[network("10.100.3.0/24")]);
+        assert_eq!(head.nlri(), &[network("10.100.1.0/24")]);
+        assert_eq!(rest.withdrawn_routes(), &[]);
+        assert_eq!(rest.nlri(), &[network("10.100.2.0/24")]);
+        assert_eq!(rest.path_attributes(), update.path_attributes());
+    }
+
     #[tokio::test]
     async fn update_message_from_adj_rib_out() {
         let some_as: AutonomousSystemNumber = 64513.into();
@@ -164,15 +668,19 @@ mod tests {
         let local_as: AutonomousSystemNumber = 64514.into();
         let local_ip: Ipv4Addr = "10.200.100.3".parse().unwrap();
 
+        let config: Config = "64514 10.200.100.3 64513 10.0.100.3 active"
+            .parse()
+            .unwrap();
+
         let rib_path_attributes = Arc::new(vec![
             PathAttribute::Origin(Origin::Igp),
-            PathAttribute::AsPath(AsPath::AsSequence(vec![some_as])),
+            PathAttribute::AsPath(AsPath::sequence(vec![some_as])),
             PathAttribute::NextHop(some_ip),
         ]);
 
         let update_message_path_attributes = Arc::new(vec![
             PathAttribute::Origin(Origin::Igp),
-            PathAttribute::AsPath(AsPath::AsSequence(vec![some_as, local_as])),
+            PathAttribute::AsPath(AsPath::sequence(vec![some_as, local_as])),
             PathAttribute::NextHop(local_ip),
         ]);
         let mut adj_rib_out = AdjRibOut::new();
@@ -180,6 +688,7 @@ mod tests {
         adj_rib_out.insert(Arc::new(RibEntry {
             network_address: "10.100.220.0/24".parse().unwrap(),
             path_attributes: rib_path_attributes,
+            learned_from: None,
         }));
 
         let expected_update_message = UpdateMessage::new(
@@ -188,7 +697,7 @@ mod tests {
             vec![],
         );
         assert_eq!(
-            adj_rib_out.create_update_messages(local_ip, local_as),
+            adj_rib_out.create_update_messages(&config),
             vec![expected_update_message]
         );
     }
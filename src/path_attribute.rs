@@ -1,37 +1,318 @@
-use std::{collections::BTreeSet, net::Ipv4Addr};
+use std::{
+    collections::BTreeSet,
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
 
 use anyhow::{anyhow, Context};
 use bytes::{BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
 
-use crate::{bgp_type::AutonomousSystemNumber, error::ConvertBytesToBgpMessageError};
+use crate::{
+    bgp_type::AutonomousSystemNumber,
+    error::{
+        AttributeLengthError, ConfigParseError, ConvertBytesToBgpMessageError, UpdateMessageError,
+    },
+    flowspec::FlowSpecRule,
+    routing::{Ipv6Network, VpnV4Network},
+};
 
-#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
 pub enum PathAttribute {
     Origin(Origin),
     AsPath(AsPath),
     NextHop(Ipv4Addr),
-    DontKnow(Vec<u8>),
+    MultiExitDisc(u32),
+    /// LOCAL_PREF。AS内のneighborとの間でだけ交換する。
+    LocalPref(u32),
+    /// ATOMIC_AGGREGATE。経路の集約でAS_PATHの情報が失われたことを表す。
+    AtomicAggregate,
+    Aggregator(AutonomousSystemNumber, Ipv4Addr),
+    /// COMMUNITIES(RFC 1997)。各communityは上位16 bitがAS番号の32 bitの値。
+    Communities(Vec<u32>),
+    MpReachNlri(MpReachNlri),
+    MpUnreachNlri(MpUnreachNlri),
+    As4Path(AsPath),
+    As4Aggregator(AutonomousSystemNumber, Ipv4Addr),
+    ExtendedCommunities(#[serde(with = "hex_communities")] Vec<[u8; 8]>),
+    /// 対応していないattribute。flag, type code, lengthを含めてそのまま保持する。
+    DontKnow(#[serde(with = "hex_bytes")] Vec<u8>),
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+/// 解釈しないbytesを、JSONでは16進数の文字列として表す。
+pub(crate) mod hex_bytes {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    pub fn from_hex(s: &str) -> Result<Vec<u8>, String> {
+        if !s.len().is_multiple_of(2) {
+            return Err(format!("16進数の文字列{s}の長さが奇数です。"));
+        }
+        (0..s.len())
+            .step_by(2)
+            .map(|i| {
+                u8::from_str_radix(&s[i..i + 2], 16)
+                    .map_err(|e| format!("{s}を16進数として解釈できません。{e}"))
+            })
+            .collect()
+    }
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&to_hex(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        from_hex(&String::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+/// EXTENDED_COMMUNITIESの各communityを、JSONでは16進数の文字列として表す。
+mod hex_communities {
+    use serde::{de::Error, ser::SerializeSeq, Deserialize, Deserializer, Serializer};
+
+    use super::hex_bytes::{from_hex, to_hex};
+
+    pub fn serialize<S: Serializer>(
+        communities: &[[u8; 8]],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(communities.len()))?;
+        for community in communities {
+            seq.serialize_element(&to_hex(community))?;
+        }
+        seq.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<[u8; 8]>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|s| {
+                let bytes = from_hex(s).map_err(D::Error::custom)?;
+                <[u8; 8]>::try_from(bytes)
+                    .map_err(|_| D::Error::custom(format!("{s}は8 bytesではありません。")))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum Origin {
     Igp,
     Egp,
     Incomplete,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Hash)]
-pub enum AsPath {
+/// AS_PATHのpath segmentを受信した順に並べたもの。空のAS_PATHはsegmentを持たない。
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
+pub struct AsPath(pub Vec<AsPathSegment>);
+
+/// variant名はRFCのsegment type(AS_SEQUENCE, AS_CONFED_SET など)に合わせている。
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
+pub enum AsPathSegment {
     AsSequence(Vec<AutonomousSystemNumber>),
     AsSet(BTreeSet<AutonomousSystemNumber>),
+    /// confederation内のmember ASの並び(RFC 5065)。confederationの外には伝えない。
+    AsConfedSequence(Vec<AutonomousSystemNumber>),
+    /// confederation内のmember ASの集合(RFC 5065)。confederationの外には伝えない。
+    AsConfedSet(BTreeSet<AutonomousSystemNumber>),
+}
+
+/// MP_REACH_NLRI(RFC 4760)。対応しているAFI/SAFIのみをvariantとして持つ。
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
+pub enum MpReachNlri {
+    /// IPv6 Unicast(RFC 2545)。Next Hopはglobal addressのみ扱い、link-local addressは読み飛ばす。
+    Ipv6Unicast {
+        next_hop: Ipv6Addr,
+        nlri: Vec<Ipv6Network>,
+    },
+    VpnV4 {
+        next_hop: Ipv4Addr,
+        nlri: Vec<VpnV4Network>,
+    },
+    /// FlowSpec(RFC 8955)。Next Hopは持たない。
+    FlowSpec { rules: Vec<FlowSpecRule> },
+}
+
+/// MP_UNREACH_NLRI(RFC 4760)。
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
+pub enum MpUnreachNlri {
+    Ipv6Unicast { withdrawn_routes: Vec<Ipv6Network> },
+    VpnV4 { withdrawn_routes: Vec<VpnV4Network> },
+    FlowSpec { withdrawn_rules: Vec<FlowSpecRule> },
+}
+
+/// EXTENDED_COMMUNITIESの1つ(RFC 4360)。よく使われるsubtypeのみvariantとして持つ。
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum ExtendedCommunity {
+    RouteTarget {
+        global_admin: u16,
+        local_admin: u32,
+    },
+    RouteOrigin {
+        global_admin: u16,
+        local_admin: u32,
+    },
+    /// Route Target, Route Origin以外のTwo-Octet AS Specific Extended Community。
+    TwoOctetAsSpecific {
+        sub_type: u8,
+        global_admin: u16,
+        local_admin: u32,
+    },
+    /// FlowSpecのtraffic-rate(RFC 8955 Section 7.3)。rateはbytes/秒のIEEE浮動小数点数のbit列で、
+    /// 0は破棄を表す。
+    TrafficRate {
+        global_admin: u16,
+        rate: u32,
+    },
+    /// link-bandwidth(draft-ietf-idr-link-bandwidth)。bandwidthはbytes/秒のIEEE浮動小数点数のbit列。
+    /// ECMPで、next hopごとの重みに用いる。
+    LinkBandwidth {
+        global_admin: u16,
+        bandwidth: u32,
+    },
+    Other([u8; 8]),
+}
+
+const EXTENDED_COMMUNITY_TYPE_TWO_OCTET_AS_SPECIFIC: u8 = 0x00;
+const EXTENDED_COMMUNITY_SUB_TYPE_ROUTE_TARGET: u8 = 0x02;
+const EXTENDED_COMMUNITY_SUB_TYPE_ROUTE_ORIGIN: u8 = 0x03;
+
+const AFI_IPV4: u16 = 1;
+const AFI_IPV6: u16 = 2;
+const SAFI_UNICAST: u8 = 1;
+const SAFI_MPLS_VPN: u8 = 128;
+const SAFI_FLOWSPEC: u8 = 133;
+const EXTENDED_COMMUNITY_TYPE_FLOWSPEC: u8 = 0x80;
+const EXTENDED_COMMUNITY_SUB_TYPE_TRAFFIC_RATE: u8 = 0x06;
+const EXTENDED_COMMUNITY_TYPE_NON_TRANSITIVE_TWO_OCTET_AS_SPECIFIC: u8 = 0x40;
+const EXTENDED_COMMUNITY_SUB_TYPE_LINK_BANDWIDTH: u8 = 0x04;
+
+impl PathAttribute {
+    /// 解釈できる全てのattributeを1つずつ。値は代表値。
+    pub fn supported() -> Vec<PathAttribute> {
+        vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::sequence(vec![])),
+            PathAttribute::NextHop(Ipv4Addr::UNSPECIFIED),
+            PathAttribute::MultiExitDisc(0),
+            PathAttribute::LocalPref(0),
+            PathAttribute::AtomicAggregate,
+            PathAttribute::Aggregator(0.into(), Ipv4Addr::UNSPECIFIED),
+            PathAttribute::Communities(vec![]),
+            PathAttribute::MpReachNlri(MpReachNlri::VpnV4 {
+                next_hop: Ipv4Addr::UNSPECIFIED,
+                nlri: vec![],
+            }),
+            PathAttribute::MpUnreachNlri(MpUnreachNlri::VpnV4 {
+                withdrawn_routes: vec![],
+            }),
+            PathAttribute::ExtendedCommunities(vec![]),
+            PathAttribute::As4Path(AsPath::sequence(vec![])),
+            PathAttribute::As4Aggregator(0.into(), Ipv4Addr::UNSPECIFIED),
+        ]
+    }
+
+    /// Attribute Type Code。未知のattributeの場合はNone。
+    pub fn type_code(&self) -> Option<u8> {
+        match self {
+            PathAttribute::Origin(_) => Some(1),
+            PathAttribute::AsPath(_) => Some(2),
+            PathAttribute::NextHop(_) => Some(3),
+            PathAttribute::MultiExitDisc(_) => Some(4),
+            PathAttribute::LocalPref(_) => Some(5),
+            PathAttribute::AtomicAggregate => Some(6),
+            PathAttribute::Aggregator(..) => Some(7),
+            PathAttribute::Communities(_) => Some(8),
+            PathAttribute::MpReachNlri(_) => Some(14),
+            PathAttribute::MpUnreachNlri(_) => Some(15),
+            PathAttribute::ExtendedCommunities(_) => Some(16),
+            PathAttribute::As4Path(_) => Some(17),
+            PathAttribute::As4Aggregator(..) => Some(18),
+            PathAttribute::DontKnow(_) => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PathAttribute::Origin(_) => "ORIGIN",
+            PathAttribute::AsPath(_) => "AS_PATH",
+            PathAttribute::NextHop(_) => "NEXT_HOP",
+            PathAttribute::MultiExitDisc(_) => "MULTI_EXIT_DISC",
+            PathAttribute::LocalPref(_) => "LOCAL_PREF",
+            PathAttribute::AtomicAggregate => "ATOMIC_AGGREGATE",
+            PathAttribute::Aggregator(..) => "AGGREGATOR",
+            PathAttribute::Communities(_) => "COMMUNITIES",
+            PathAttribute::MpReachNlri(_) => "MP_REACH_NLRI",
+            PathAttribute::MpUnreachNlri(_) => "MP_UNREACH_NLRI",
+            PathAttribute::ExtendedCommunities(_) => "EXTENDED_COMMUNITIES",
+            PathAttribute::As4Path(_) => "AS4_PATH",
+            PathAttribute::As4Aggregator(..) => "AS4_AGGREGATOR",
+            PathAttribute::DontKnow(_) => "Unknown",
+        }
+    }
+}
+
+impl MpReachNlri {
+    /// 対応しているAFI/SAFIの組と名前。IPv4 Unicastは常に対応している。
+    pub fn supported_afi_safis() -> Vec<(u16, u8, &'static str)> {
+        let mp_reach_nlris = [
+            MpReachNlri::Ipv6Unicast {
+                next_hop: Ipv6Addr::UNSPECIFIED,
+                nlri: vec![],
+            },
+            MpReachNlri::VpnV4 {
+                next_hop: Ipv4Addr::UNSPECIFIED,
+                nlri: vec![],
+            },
+            MpReachNlri::FlowSpec { rules: vec![] },
+        ];
+        let mut afi_safis = vec![(AFI_IPV4, SAFI_UNICAST, "IPv4 Unicast")];
+        afi_safis.extend(mp_reach_nlris.iter().map(|nlri| {
+            let (afi, safi) = nlri.afi_safi();
+            (afi, safi, nlri.name())
+        }));
+        afi_safis
+    }
+
+    pub fn afi_safi(&self) -> (u16, u8) {
+        match self {
+            MpReachNlri::Ipv6Unicast { .. } => (AFI_IPV6, SAFI_UNICAST),
+            MpReachNlri::VpnV4 { .. } => (AFI_IPV4, SAFI_MPLS_VPN),
+            MpReachNlri::FlowSpec { .. } => (AFI_IPV4, SAFI_FLOWSPEC),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            MpReachNlri::Ipv6Unicast { .. } => "IPv6 Unicast",
+            MpReachNlri::VpnV4 { .. } => "VPNv4",
+            MpReachNlri::FlowSpec { .. } => "IPv4 FlowSpec",
+        }
+    }
 }
 
 impl PathAttribute {
     pub fn bytes_len(&self) -> usize {
         let path_attribute_value_length = match self {
-            PathAttribute::Origin(o) => 1,
+            PathAttribute::Origin(_) => 1,
             PathAttribute::AsPath(a) => a.bytes_len(),
             PathAttribute::NextHop(_) => 4,
+            PathAttribute::MultiExitDisc(_) => 4,
+            PathAttribute::LocalPref(_) => 4,
+            PathAttribute::AtomicAggregate => 0,
+            PathAttribute::Aggregator(..) => 6,
+            PathAttribute::Communities(c) => 4 * c.len(),
+            PathAttribute::MpReachNlri(m) => m.bytes_len(),
+            PathAttribute::MpUnreachNlri(m) => m.bytes_len(),
+            PathAttribute::As4Path(a) => a.four_octet_bytes_len(),
+            PathAttribute::As4Aggregator(..) => 8,
+            PathAttribute::ExtendedCommunities(c) => 8 * c.len(),
             PathAttribute::DontKnow(v) => v.len(),
         };
 
@@ -44,27 +325,185 @@ impl PathAttribute {
     }
 }
 
+impl MpReachNlri {
+    fn bytes_len(&self) -> usize {
+        match self {
+            // AFI(2) + SAFI(1) + Next Hopの長さ(1) + Next Hop(IPv6 16) + Reserved(1)
+            MpReachNlri::Ipv6Unicast { nlri, .. } => {
+                2 + 1 + 1 + 16 + 1 + nlri.iter().map(|n| n.bytes_len()).sum::<usize>()
+            }
+            // AFI(2) + SAFI(1) + Next Hopの長さ(1) + Next Hop(RD 8 + IPv4 4) + Reserved(1)
+            MpReachNlri::VpnV4 { nlri, .. } => {
+                2 + 1 + 1 + 12 + 1 + nlri.iter().map(|n| n.bytes_len()).sum::<usize>()
+            }
+            // AFI(2) + SAFI(1) + Next Hopの長さ(1) + Reserved(1)
+            MpReachNlri::FlowSpec { rules } => {
+                2 + 1 + 1 + 1 + rules.iter().map(|r| r.bytes_len()).sum::<usize>()
+            }
+        }
+    }
+}
+
+impl MpUnreachNlri {
+    fn bytes_len(&self) -> usize {
+        match self {
+            MpUnreachNlri::Ipv6Unicast { withdrawn_routes } => {
+                2 + 1
+                    + withdrawn_routes
+                        .iter()
+                        .map(|n| n.bytes_len())
+                        .sum::<usize>()
+            }
+            MpUnreachNlri::VpnV4 { withdrawn_routes } => {
+                2 + 1
+                    + withdrawn_routes
+                        .iter()
+                        .map(|n| n.bytes_len())
+                        .sum::<usize>()
+            }
+            MpUnreachNlri::FlowSpec { withdrawn_rules } => {
+                2 + 1 + withdrawn_rules.iter().map(|r| r.bytes_len()).sum::<usize>()
+            }
+        }
+    }
+}
+
 impl AsPath {
+    /// 1つのAS_SEQUENCEからなるAS_PATH。asesが空の場合は空のAS_PATHになる。
+    pub fn sequence(ases: Vec<AutonomousSystemNumber>) -> AsPath {
+        if ases.is_empty() {
+            return AsPath(vec![]);
+        }
+        AsPath(vec![AsPathSegment::AsSequence(ases)])
+    }
+
+    /// 1つのAS_SETからなるAS_PATH。
+    pub fn set(ases: BTreeSet<AutonomousSystemNumber>) -> AsPath {
+        AsPath(vec![AsPathSegment::AsSet(ases)])
+    }
+
     fn bytes_len(&self) -> usize {
-        let as_bytes_length = match self {
-            AsPath::AsSequence(v) => 2 * v.len(),
-            AsPath::AsSet(s) => 2 * s.len(),
-        };
+        self.0.iter().map(|s| 1 + 1 + 2 * s.number_of_ases()).sum()
+    }
+
+    /// 経路選択で用いるAS_PATHの長さ。AS_SETは1つとして数え、confederationのsegmentは数えない。
+    pub fn length(&self) -> usize {
+        self.0.iter().map(AsPathSegment::length).sum()
+    }
+
+    pub fn does_contain(&self, as_number: AutonomousSystemNumber) -> bool {
+        self.as_numbers().any(|a| a == as_number)
+    }
+
+    /// AS_PATHに現れるAS番号を、segmentの順に返す。
+    pub fn as_numbers(&self) -> impl Iterator<Item = AutonomousSystemNumber> + '_ {
+        self.0.iter().flat_map(AsPathSegment::as_numbers)
+    }
+
+    /// AS_PATHに`as_number`が現れる回数。AS_SETの中のものはsegmentごとに1回と数える。
+    pub fn count(&self, as_number: AutonomousSystemNumber) -> usize {
+        self.0.iter().map(|s| s.count(as_number)).sum()
+    }
+
+    /// AS_SEQUENCEに現れるAS番号を、segmentの順に返す。
+    pub fn sequence_as_numbers(&self) -> impl Iterator<Item = AutonomousSystemNumber> + '_ {
+        self.0
+            .iter()
+            .flat_map(|s| match s {
+                AsPathSegment::AsSequence(seq) => seq.as_slice(),
+                _ => &[],
+            })
+            .copied()
+    }
 
-        1 + 1 + as_bytes_length
+    /// `from`を`to`に置き換えたAS_PATHを返す。
+    pub fn replace(&self, from: AutonomousSystemNumber, to: AutonomousSystemNumber) -> AsPath {
+        AsPath(self.0.iter().map(|s| s.replace(from, to)).collect())
     }
-    pub fn does_contain(&self, as_path: AutonomousSystemNumber) -> bool {
+
+    /// 最後のsegmentにas_numberを加える。segmentが無い場合はAS_SEQUENCEを作る。
+    pub fn push(&mut self, as_number: AutonomousSystemNumber) {
+        match self.0.last_mut() {
+            Some(AsPathSegment::AsSequence(seq) | AsPathSegment::AsConfedSequence(seq)) => {
+                seq.push(as_number)
+            }
+            Some(AsPathSegment::AsSet(set) | AsPathSegment::AsConfedSet(set)) => {
+                set.insert(as_number);
+            }
+            None => self.0.push(AsPathSegment::AsSequence(vec![as_number])),
+        }
+    }
+
+    /// confederationのsegmentを取り除いたAS_PATHを返す。
+    /// confederationの外のpeerに広告するときに用いる。
+    pub fn without_confederation_segments(&self) -> AsPath {
+        AsPath(
+            self.0
+                .iter()
+                .filter(|s| !s.is_confederation())
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+impl AsPathSegment {
+    fn number_of_ases(&self) -> usize {
+        match self {
+            AsPathSegment::AsSequence(seq) | AsPathSegment::AsConfedSequence(seq) => seq.len(),
+            AsPathSegment::AsSet(set) | AsPathSegment::AsConfedSet(set) => set.len(),
+        }
+    }
+
+    fn length(&self) -> usize {
         match self {
-            AsPath::AsSequence(seq) => seq.contains(&as_path),
-            AsPath::AsSet(set) => set.contains(&as_path),
+            AsPathSegment::AsSequence(seq) => seq.len(),
+            AsPathSegment::AsSet(_) => 1,
+            AsPathSegment::AsConfedSequence(_) | AsPathSegment::AsConfedSet(_) => 0,
         }
     }
 
-    pub fn push(&mut self, as_path: AutonomousSystemNumber) {
+    fn is_confederation(&self) -> bool {
+        matches!(
+            self,
+            AsPathSegment::AsConfedSequence(_) | AsPathSegment::AsConfedSet(_)
+        )
+    }
+
+    fn as_numbers(&self) -> Box<dyn Iterator<Item = AutonomousSystemNumber> + '_> {
         match self {
-            AsPath::AsSequence(seq) => seq.push(as_path),
-            AsPath::AsSet(set) => {
-                set.insert(as_path);
+            AsPathSegment::AsSequence(seq) | AsPathSegment::AsConfedSequence(seq) => {
+                Box::new(seq.iter().copied())
+            }
+            AsPathSegment::AsSet(set) | AsPathSegment::AsConfedSet(set) => {
+                Box::new(set.iter().copied())
+            }
+        }
+    }
+
+    fn count(&self, as_number: AutonomousSystemNumber) -> usize {
+        match self {
+            AsPathSegment::AsSequence(seq) | AsPathSegment::AsConfedSequence(seq) => {
+                seq.iter().filter(|a| **a == as_number).count()
+            }
+            AsPathSegment::AsSet(set) | AsPathSegment::AsConfedSet(set) => {
+                usize::from(set.contains(&as_number))
+            }
+        }
+    }
+
+    fn replace(&self, from: AutonomousSystemNumber, to: AutonomousSystemNumber) -> Self {
+        let replace = |a: &AutonomousSystemNumber| if *a == from { to } else { *a };
+        match self {
+            AsPathSegment::AsSequence(seq) => {
+                AsPathSegment::AsSequence(seq.iter().map(replace).collect())
+            }
+            AsPathSegment::AsSet(set) => AsPathSegment::AsSet(set.iter().map(replace).collect()),
+            AsPathSegment::AsConfedSequence(seq) => {
+                AsPathSegment::AsConfedSequence(seq.iter().map(replace).collect())
+            }
+            AsPathSegment::AsConfedSet(set) => {
+                AsPathSegment::AsConfedSet(set.iter().map(replace).collect())
             }
         }
     }
@@ -109,16 +548,90 @@ impl From<&PathAttribute> for BytesMut {
                 bytes.put(attribute);
             }
             PathAttribute::NextHop(n) => {
-                let mut attribute_flag = 0b0100_0000;
+                let attribute_flag = 0b0100_0000;
                 let attribute_type_code = 3;
                 let attribute_length = 4;
-                let mut attribute = n.octets();
+                let attribute = n.octets();
 
                 bytes.put_u8(attribute_flag);
                 bytes.put_u8(attribute_type_code);
                 bytes.put_u8(attribute_length);
                 bytes.put(&attribute[..]);
             }
+            PathAttribute::MultiExitDisc(med) => {
+                let attribute_flag = 0b1000_0000;
+                let attribute_type_code = 4;
+                let mut attribute = BytesMut::new();
+                attribute.put_u32(*med);
+                put_attribute(&mut bytes, attribute_flag, attribute_type_code, attribute);
+            }
+            PathAttribute::LocalPref(local_pref) => {
+                let attribute_flag = 0b0100_0000;
+                let attribute_type_code = 5;
+                let mut attribute = BytesMut::new();
+                attribute.put_u32(*local_pref);
+                put_attribute(&mut bytes, attribute_flag, attribute_type_code, attribute);
+            }
+            PathAttribute::AtomicAggregate => {
+                let attribute_flag = 0b0100_0000;
+                let attribute_type_code = 6;
+                put_attribute(
+                    &mut bytes,
+                    attribute_flag,
+                    attribute_type_code,
+                    BytesMut::new(),
+                );
+            }
+            PathAttribute::Aggregator(as_number, addr) => {
+                let attribute_flag = 0b1100_0000;
+                let attribute_type_code = 7;
+                let mut attribute = BytesMut::new();
+                attribute.put_u16((*as_number).into());
+                attribute.put(&addr.octets()[..]);
+                put_attribute(&mut bytes, attribute_flag, attribute_type_code, attribute);
+            }
+            PathAttribute::Communities(communities) => {
+                let attribute_flag = 0b1100_0000;
+                let attribute_type_code = 8;
+                let mut attribute = BytesMut::new();
+                communities.iter().for_each(|c| attribute.put_u32(*c));
+                put_attribute(&mut bytes, attribute_flag, attribute_type_code, attribute);
+            }
+            PathAttribute::As4Path(a) => {
+                let attribute_flag = 0b1100_0000;
+                let attribute_type_code = 17;
+                put_attribute(
+                    &mut bytes,
+                    attribute_flag,
+                    attribute_type_code,
+                    a.to_bytes(true),
+                );
+            }
+            PathAttribute::As4Aggregator(as_number, addr) => {
+                let attribute_flag = 0b1100_0000;
+                let attribute_type_code = 18;
+                let mut attribute = BytesMut::new();
+                attribute.put_u32((*as_number).into());
+                attribute.put(&addr.octets()[..]);
+                put_attribute(&mut bytes, attribute_flag, attribute_type_code, attribute);
+            }
+            PathAttribute::ExtendedCommunities(communities) => {
+                let attribute_flag = 0b1100_0000;
+                let attribute_type_code = 16;
+                let mut attribute = BytesMut::new();
+                communities.iter().for_each(|c| attribute.put(&c[..]));
+                put_attribute(&mut bytes, attribute_flag, attribute_type_code, attribute);
+            }
+            PathAttribute::MpReachNlri(m) => {
+                let attribute_flag = 0b1000_0000;
+                let attribute_type_code = 14;
+                put_attribute(&mut bytes, attribute_flag, attribute_type_code, m.into());
+            }
+            PathAttribute::MpUnreachNlri(m) => {
+                let attribute_flag = 0b1000_0000;
+                let attribute_type_code = 15;
+                put_attribute(&mut bytes, attribute_flag, attribute_type_code, m.into());
+            }
             PathAttribute::DontKnow(v) => bytes.put(&v[..]),
         }
 
@@ -126,39 +639,387 @@ impl From<&PathAttribute> for BytesMut {
     }
 }
 
-impl From<&AsPath> for BytesMut {
-    fn from(as_path: &AsPath) -> BytesMut {
-        match as_path {
-            AsPath::AsSet(s) => {
-                let mut bytes = BytesMut::new();
-
-                let path_segment_type = 1;
-                let number_of_ases = s.len();
-                bytes.put_u8(path_segment_type);
-                bytes.put_u8(number_of_ases as u8);
-                bytes.put(
-                    &s.iter()
-                        .flat_map(|a| u16::from(*a).to_be_bytes())
-                        .collect::<Vec<u8>>()[..],
-                );
-                bytes
-            }
-            AsPath::AsSequence(s) => {
-                let mut bytes = BytesMut::new();
-
-                let path_segment_type = 2;
-                let number_of_ases = s.len();
-                bytes.put_u8(path_segment_type);
-                bytes.put_u8(number_of_ases as u8);
-                bytes.put(
-                    &s.iter()
-                        .flat_map(|a| u16::from(*a).to_be_bytes())
-                        .collect::<Vec<u8>>()[..],
-                );
-                bytes
+impl PathAttribute {
+    /// AS番号を全て4 octetで表したbytes。MRT(RFC 6396 Section 4.3.4)のように、
+    /// 4 octetのAS番号を扱えることが分かっている場合に用いる。
+    /// AS4_PATH, AS4_AGGREGATORは不要なので空になる。
+    pub fn to_four_octet_bytes(&self) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        match self {
+            PathAttribute::AsPath(a) => put_attribute(&mut bytes, 0b0100_0000, 2, a.to_bytes(true)),
+            PathAttribute::Aggregator(as_number, addr) => {
+                let mut attribute = BytesMut::new();
+                attribute.put_u32((*as_number).into());
+                attribute.put(&addr.octets()[..]);
+                put_attribute(&mut bytes, 0b1100_0000, 7, attribute);
+            }
+            PathAttribute::As4Path(_) | PathAttribute::As4Aggregator(..) => {}
+            p => bytes.put(BytesMut::from(p)),
+        }
+        bytes
+    }
+}
+
+/// attributeの長さに応じてExtended Lengthのflagを立てて、attributeをbytesに書き込む。
+fn put_attribute(
+    bytes: &mut BytesMut,
+    attribute_flag: u8,
+    attribute_type_code: u8,
+    value: BytesMut,
+) {
+    if value.len() < 256 {
+        bytes.put_u8(attribute_flag);
+        bytes.put_u8(attribute_type_code);
+        bytes.put_u8(value.len() as u8);
+    } else {
+        bytes.put_u8(attribute_flag | 0b0001_0000);
+        bytes.put_u8(attribute_type_code);
+        bytes.put_u16(value.len() as u16);
+    }
+    bytes.put(value);
+}
+
+impl From<&MpReachNlri> for BytesMut {
+    fn from(m: &MpReachNlri) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        match m {
+            MpReachNlri::Ipv6Unicast { next_hop, nlri } => {
+                bytes.put_u16(AFI_IPV6);
+                bytes.put_u8(SAFI_UNICAST);
+                bytes.put_u8(16);
+                bytes.put(&next_hop.octets()[..]);
+                bytes.put_u8(0);
+                nlri.iter().for_each(|n| bytes.put::<BytesMut>(n.into()));
+            }
+            MpReachNlri::VpnV4 { next_hop, nlri } => {
+                let next_hop_route_distinguisher = [0u8; 8];
+                bytes.put_u16(AFI_IPV4);
+                bytes.put_u8(SAFI_MPLS_VPN);
+                bytes.put_u8(12);
+                bytes.put(&next_hop_route_distinguisher[..]);
+                bytes.put(&next_hop.octets()[..]);
+                bytes.put_u8(0);
+                nlri.iter().for_each(|n| bytes.put::<BytesMut>(n.into()));
+            }
+            MpReachNlri::FlowSpec { rules } => {
+                bytes.put_u16(AFI_IPV4);
+                bytes.put_u8(SAFI_FLOWSPEC);
+                bytes.put_u8(0);
+                bytes.put_u8(0);
+                rules.iter().for_each(|r| bytes.put::<BytesMut>(r.into()));
+            }
+        }
+        bytes
+    }
+}
+
+impl From<&MpUnreachNlri> for BytesMut {
+    fn from(m: &MpUnreachNlri) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        match m {
+            MpUnreachNlri::Ipv6Unicast { withdrawn_routes } => {
+                bytes.put_u16(AFI_IPV6);
+                bytes.put_u8(SAFI_UNICAST);
+                withdrawn_routes
+                    .iter()
+                    .for_each(|n| bytes.put::<BytesMut>(n.into()));
+            }
+            MpUnreachNlri::VpnV4 { withdrawn_routes } => {
+                bytes.put_u16(AFI_IPV4);
+                bytes.put_u8(SAFI_MPLS_VPN);
+                withdrawn_routes
+                    .iter()
+                    .for_each(|n| bytes.put::<BytesMut>(n.into()));
+            }
+            MpUnreachNlri::FlowSpec { withdrawn_rules } => {
+                bytes.put_u16(AFI_IPV4);
+                bytes.put_u8(SAFI_FLOWSPEC);
+                withdrawn_rules
+                    .iter()
+                    .for_each(|r| bytes.put::<BytesMut>(r.into()));
+            }
+        }
+        bytes
+    }
+}
+
+impl TryFrom<&[u8]> for MpReachNlri {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        if value.len() < 5 {
+            return Err(anyhow!(
+                "MP_REACH_NLRIの長さが足りません。value: {:?}",
+                value
+            ));
+        }
+        let afi = u16::from_be_bytes([value[0], value[1]]);
+        let safi = value[2];
+        let next_hop_length = value[3] as usize;
+        let nlri_start_index = 4 + next_hop_length + 1;
+        if value.len() < nlri_start_index {
+            return Err(anyhow!(
+                "MP_REACH_NLRIの長さが足りません。value: {:?}",
+                value
+            ));
+        }
+        match (afi, safi, next_hop_length) {
+            (AFI_IPV6, SAFI_UNICAST, 16 | 32) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&value[4..20]);
+                Ok(MpReachNlri::Ipv6Unicast {
+                    next_hop: Ipv6Addr::from(octets),
+                    nlri: Ipv6Network::from_u8_slice(&value[nlri_start_index..])?,
+                })
+            }
+            (AFI_IPV4, SAFI_MPLS_VPN, 12) => Ok(MpReachNlri::VpnV4 {
+                next_hop: Ipv4Addr::new(value[12], value[13], value[14], value[15]),
+                nlri: VpnV4Network::from_u8_slice(&value[nlri_start_index..])?,
+            }),
+            (AFI_IPV4, SAFI_FLOWSPEC, 0) => Ok(MpReachNlri::FlowSpec {
+                rules: FlowSpecRule::from_u8_slice(&value[nlri_start_index..])?,
+            }),
+            _ => Err(anyhow!(
+                "AFI: {}, SAFI: {}, Next Hopの長さ: {} のMP_REACH_NLRIには対応していません。",
+                afi,
+                safi,
+                next_hop_length
+            )),
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for MpUnreachNlri {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        if value.len() < 3 {
+            return Err(anyhow!(
+                "MP_UNREACH_NLRIの長さが足りません。value: {:?}",
+                value
+            ));
+        }
+        let afi = u16::from_be_bytes([value[0], value[1]]);
+        let safi = value[2];
+        match (afi, safi) {
+            (AFI_IPV6, SAFI_UNICAST) => Ok(MpUnreachNlri::Ipv6Unicast {
+                withdrawn_routes: Ipv6Network::from_u8_slice(&value[3..])?,
+            }),
+            (AFI_IPV4, SAFI_MPLS_VPN) => Ok(MpUnreachNlri::VpnV4 {
+                withdrawn_routes: VpnV4Network::from_u8_slice(&value[3..])?,
+            }),
+            (AFI_IPV4, SAFI_FLOWSPEC) => Ok(MpUnreachNlri::FlowSpec {
+                withdrawn_rules: FlowSpecRule::from_u8_slice(&value[3..])?,
+            }),
+            _ => Err(anyhow!(
+                "AFI: {}, SAFI: {} のMP_UNREACH_NLRIには対応していません。",
+                afi,
+                safi
+            )),
+        }
+    }
+}
+
+impl ExtendedCommunity {
+    /// rateはbytes/秒。0.0は破棄を表す。
+    pub fn traffic_rate(global_admin: u16, rate: f32) -> Self {
+        ExtendedCommunity::TrafficRate {
+            global_admin,
+            rate: rate.to_bits(),
+        }
+    }
+
+    /// bandwidthはbytes/秒。
+    pub fn link_bandwidth(global_admin: u16, bandwidth: f32) -> Self {
+        ExtendedCommunity::LinkBandwidth {
+            global_admin,
+            bandwidth: bandwidth.to_bits(),
+        }
+    }
+}
+
+impl From<[u8; 8]> for ExtendedCommunity {
+    fn from(c: [u8; 8]) -> Self {
+        if c[..2]
+            == [
+                EXTENDED_COMMUNITY_TYPE_FLOWSPEC,
+                EXTENDED_COMMUNITY_SUB_TYPE_TRAFFIC_RATE,
+            ]
+        {
+            return ExtendedCommunity::TrafficRate {
+                global_admin: u16::from_be_bytes([c[2], c[3]]),
+                rate: u32::from_be_bytes([c[4], c[5], c[6], c[7]]),
+            };
+        }
+        if c[..2]
+            == [
+                EXTENDED_COMMUNITY_TYPE_NON_TRANSITIVE_TWO_OCTET_AS_SPECIFIC,
+                EXTENDED_COMMUNITY_SUB_TYPE_LINK_BANDWIDTH,
+            ]
+        {
+            return ExtendedCommunity::LinkBandwidth {
+                global_admin: u16::from_be_bytes([c[2], c[3]]),
+                bandwidth: u32::from_be_bytes([c[4], c[5], c[6], c[7]]),
+            };
+        }
+        if c[0] != EXTENDED_COMMUNITY_TYPE_TWO_OCTET_AS_SPECIFIC {
+            return ExtendedCommunity::Other(c);
+        }
+        let global_admin = u16::from_be_bytes([c[2], c[3]]);
+        let local_admin = u32::from_be_bytes([c[4], c[5], c[6], c[7]]);
+        match c[1] {
+            EXTENDED_COMMUNITY_SUB_TYPE_ROUTE_TARGET => ExtendedCommunity::RouteTarget {
+                global_admin,
+                local_admin,
+            },
+            EXTENDED_COMMUNITY_SUB_TYPE_ROUTE_ORIGIN => ExtendedCommunity::RouteOrigin {
+                global_admin,
+                local_admin,
+            },
+            sub_type => ExtendedCommunity::TwoOctetAsSpecific {
+                sub_type,
+                global_admin,
+                local_admin,
+            },
+        }
+    }
+}
+
+impl From<ExtendedCommunity> for [u8; 8] {
+    fn from(c: ExtendedCommunity) -> [u8; 8] {
+        let (sub_type, global_admin, local_admin) = match c {
+            ExtendedCommunity::RouteTarget {
+                global_admin,
+                local_admin,
+            } => (
+                EXTENDED_COMMUNITY_SUB_TYPE_ROUTE_TARGET,
+                global_admin,
+                local_admin,
+            ),
+            ExtendedCommunity::RouteOrigin {
+                global_admin,
+                local_admin,
+            } => (
+                EXTENDED_COMMUNITY_SUB_TYPE_ROUTE_ORIGIN,
+                global_admin,
+                local_admin,
+            ),
+            ExtendedCommunity::TwoOctetAsSpecific {
+                sub_type,
+                global_admin,
+                local_admin,
+            } => (sub_type, global_admin, local_admin),
+            ExtendedCommunity::TrafficRate { global_admin, rate } => {
+                let mut bytes = [0u8; 8];
+                bytes[0] = EXTENDED_COMMUNITY_TYPE_FLOWSPEC;
+                bytes[1] = EXTENDED_COMMUNITY_SUB_TYPE_TRAFFIC_RATE;
+                bytes[2..4].copy_from_slice(&global_admin.to_be_bytes());
+                bytes[4..8].copy_from_slice(&rate.to_be_bytes());
+                return bytes;
             }
+            ExtendedCommunity::LinkBandwidth {
+                global_admin,
+                bandwidth,
+            } => {
+                let mut bytes = [0u8; 8];
+                bytes[0] = EXTENDED_COMMUNITY_TYPE_NON_TRANSITIVE_TWO_OCTET_AS_SPECIFIC;
+                bytes[1] = EXTENDED_COMMUNITY_SUB_TYPE_LINK_BANDWIDTH;
+                bytes[2..4].copy_from_slice(&global_admin.to_be_bytes());
+                bytes[4..8].copy_from_slice(&bandwidth.to_be_bytes());
+                return bytes;
+            }
+            ExtendedCommunity::Other(c) => return c,
+        };
+        let mut bytes = [0u8; 8];
+        bytes[0] = EXTENDED_COMMUNITY_TYPE_TWO_OCTET_AS_SPECIFIC;
+        bytes[1] = sub_type;
+        bytes[2..4].copy_from_slice(&global_admin.to_be_bytes());
+        bytes[4..8].copy_from_slice(&local_admin.to_be_bytes());
+        bytes
+    }
+}
+
+/// configの`igp`, `egp`, `incomplete`をparseする。
+impl FromStr for Origin {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "igp" => Ok(Origin::Igp),
+            "egp" => Ok(Origin::Egp),
+            "incomplete" => Ok(Origin::Incomplete),
+            _ => Err(anyhow!("cannot parse {s} as origin").into()),
+        }
+    }
+}
+
+/// `<AS番号>:<値>`の形式のRoute Targetとしてparseする。
+impl FromStr for ExtendedCommunity {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (global_admin, local_admin) = s
+            .split_once(':')
+            .context(format!("cannot parse {s} as route target"))?;
+        Ok(ExtendedCommunity::RouteTarget {
+            global_admin: global_admin
+                .parse()
+                .context(format!("cannot parse global administrator of {s}"))?,
+            local_admin: local_admin
+                .parse()
+                .context(format!("cannot parse local administrator of {s}"))?,
+        })
+    }
+}
+
+/// 保守のために経路を使われなくするよう求めるcommunity(RFC 8326)。
+/// 受信した経路はLOCAL_PREFが0であるものとして扱う。
+pub const GRACEFUL_SHUTDOWN: u32 = 0xFFFF_0000;
+
+impl PathAttribute {
+    /// COMMUNITIESに含まれるcommunityを返す。
+    pub fn communities(path_attributes: &[PathAttribute]) -> impl Iterator<Item = u32> + '_ {
+        path_attributes
+            .iter()
+            .filter_map(|p| match p {
+                PathAttribute::Communities(c) => Some(c),
+                _ => None,
+            })
+            .flatten()
+            .copied()
+    }
+
+    /// path_attributesのCOMMUNITIESにcommunityを加える。COMMUNITIESが無ければ作る。
+    pub fn add_community(path_attributes: &mut Vec<PathAttribute>, community: u32) {
+        match path_attributes.iter_mut().find_map(|p| match p {
+            PathAttribute::Communities(c) => Some(c),
+            _ => None,
+        }) {
+            Some(communities) if communities.contains(&community) => {}
+            Some(communities) => communities.push(community),
+            None => path_attributes.push(PathAttribute::Communities(vec![community])),
         }
     }
+
+    /// EXTENDED_COMMUNITIESに含まれるExtended Communityを返す。
+    pub fn extended_communities(
+        path_attributes: &[PathAttribute],
+    ) -> impl Iterator<Item = ExtendedCommunity> + '_ {
+        path_attributes
+            .iter()
+            .filter_map(|p| match p {
+                PathAttribute::ExtendedCommunities(c) => Some(c),
+                _ => None,
+            })
+            .flatten()
+            .map(|c| ExtendedCommunity::from(*c))
+    }
+}
+
+impl From<&AsPath> for BytesMut {
+    fn from(as_path: &AsPath) -> BytesMut {
+        as_path.to_bytes(false)
+    }
 }
 
 impl TryFrom<u8> for Origin {
@@ -181,31 +1042,182 @@ impl TryFrom<&[u8]> for AsPath {
     type Error = anyhow::Error;
 
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        match value[0] {
-            1 => {
-                let mut ases = BTreeSet::new();
-                let mut i = 2;
-                while i < value.len() {
-                    ases.insert(u16::from_be_bytes(value[i..i + 2].try_into()?).into());
-                    i += 2;
+        AsPath::from_bytes(value, false)
+    }
+}
+
+impl AsPath {
+    /// four_octetがtrueのときはAS4_PATHとして、AS番号を4 octetで表現する。
+    fn to_bytes(&self, four_octet: bool) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        for segment in &self.0 {
+            let (path_segment_type, ases): (u8, Vec<AutonomousSystemNumber>) = match segment {
+                AsPathSegment::AsSet(s) => (1, s.iter().copied().collect()),
+                AsPathSegment::AsSequence(s) => (2, s.clone()),
+                AsPathSegment::AsConfedSequence(s) => (3, s.clone()),
+                AsPathSegment::AsConfedSet(s) => (4, s.iter().copied().collect()),
+            };
+            bytes.put_u8(path_segment_type);
+            bytes.put_u8(ases.len() as u8);
+            for a in ases {
+                if four_octet {
+                    bytes.put_u32(a.into());
+                } else {
+                    bytes.put_u16(a.into());
                 }
-                Ok(AsPath::AsSet(ases))
-            }
-            2 => {
-                let mut ases = vec![];
-                let mut i = 2;
-                while i < value.len() {
-                    ases.push(u16::from_be_bytes(value[i..i + 2].try_into()?).into());
-                    i += 2;
+            }
+        }
+        bytes
+    }
+
+    /// path segmentごとに、Path Segment Lengthの数だけAS番号を読む。
+    /// AS番号の無いsegmentは空のAS_PATHと区別しないので取り除く。
+    fn from_bytes(value: &[u8], four_octet: bool) -> Result<Self, anyhow::Error> {
+        let as_octets = if four_octet { 4 } else { 2 };
+        let mut segments = vec![];
+        let mut i = 0;
+        while i < value.len() {
+            let (Some(&path_segment_type), Some(&path_segment_length)) =
+                (value.get(i), value.get(i + 1))
+            else {
+                return Err(anyhow::anyhow!(
+                    "value: {:?} のpath segmentのheaderが途中で終わっています。",
+                    &value
+                ));
+            };
+            let end = i + 2 + as_octets * usize::from(path_segment_length);
+            let ases: Vec<AutonomousSystemNumber> = value
+                .get(i + 2..end)
+                .with_context(|| {
+                    format!(
+                        "value: {:?} のpath segmentのAS番号が途中で終わっています。",
+                        &value
+                    )
+                })?
+                .chunks_exact(as_octets)
+                .map(|a| {
+                    if four_octet {
+                        u32::from_be_bytes([a[0], a[1], a[2], a[3]])
+                    } else {
+                        u32::from(u16::from_be_bytes([a[0], a[1]]))
+                    }
+                    .into()
+                })
+                .collect();
+            i = end;
+            if ases.is_empty() {
+                continue;
+            }
+            segments.push(match path_segment_type {
+                1 => AsPathSegment::AsSet(ases.into_iter().collect()),
+                2 => AsPathSegment::AsSequence(ases),
+                3 => AsPathSegment::AsConfedSequence(ases),
+                4 => AsPathSegment::AsConfedSet(ases.into_iter().collect()),
+                _ => {
+                    return Err(anyhow::anyhow!(format!(
+                        "value: {:?} をAsPathに変換できませんでした。",
+                        &value
+                    )))
                 }
-                Ok(AsPath::AsSequence(ases))
+            });
+        }
+        Ok(AsPath(segments))
+    }
+
+    fn merge_as4_path(&self, as4_path: &AsPath) -> AsPath {
+        if self.length() < as4_path.length() {
+            return self.clone();
+        }
+        match (self.0.as_slice(), as4_path.0.as_slice()) {
+            ([AsPathSegment::AsSequence(seq)], [AsPathSegment::AsSequence(seq4)]) => {
+                let mut merged = seq[..seq.len() - seq4.len()].to_vec();
+                merged.extend(seq4.iter().copied());
+                AsPath::sequence(merged)
             }
-            _ => Err(anyhow::anyhow!(format!(
-                "value: {:?} をAsPathに変換できませんでした。",
-                &value
-            ))),
+            ([AsPathSegment::AsSet(_)], [AsPathSegment::AsSet(_)]) => as4_path.clone(),
+            _ => self.clone(),
         }
     }
+
+    fn four_octet_bytes_len(&self) -> usize {
+        self.0.iter().map(|s| 1 + 1 + 4 * s.number_of_ases()).sum()
+    }
+}
+
+impl PathAttribute {
+    /// 2 octetのAS番号しか扱えないspeakerを経由した経路について、
+    /// AS4_PATH, AS4_AGGREGATORからAS_PATH, AGGREGATORを復元する(RFC 6793 Section 4.2.3)。
+    /// 復元後はAS4_PATH, AS4_AGGREGATORを取り除く。
+    pub fn reconstruct_four_octet_as_path(path_attributes: &[PathAttribute]) -> Vec<PathAttribute> {
+        let aggregator = path_attributes.iter().find_map(|p| match p {
+            PathAttribute::Aggregator(as_number, _) => Some(*as_number),
+            _ => None,
+        });
+        let as4_aggregator = path_attributes.iter().find_map(|p| match p {
+            PathAttribute::As4Aggregator(as_number, addr) => Some((*as_number, *addr)),
+            _ => None,
+        });
+        let as4_path = path_attributes.iter().find_map(|p| match p {
+            PathAttribute::As4Path(as_path) => Some(as_path),
+            _ => None,
+        });
+
+        // AGGREGATORがAS_TRANSでなければ、AS4_PATH, AS4_AGGREGATORは無視する。
+        let is_aggregator_valid = aggregator.is_none_or(|a| a == AutonomousSystemNumber::AS_TRANS);
+
+        path_attributes
+            .iter()
+            .filter(|p| {
+                !matches!(
+                    p,
+                    PathAttribute::As4Path(_) | PathAttribute::As4Aggregator(..)
+                )
+            })
+            .map(|p| match (p, as4_path, as4_aggregator) {
+                (PathAttribute::AsPath(as_path), Some(as4_path), _) if is_aggregator_valid => {
+                    PathAttribute::AsPath(as_path.merge_as4_path(as4_path))
+                }
+                (PathAttribute::Aggregator(..), _, Some((as_number, addr)))
+                    if is_aggregator_valid =>
+                {
+                    PathAttribute::Aggregator(as_number, addr)
+                }
+                _ => p.clone(),
+            })
+            .collect()
+    }
+}
+
+/// 長さが決まっているpath attributeの値。長さが異なる場合はAttribute Length Errorにする。
+fn fixed_length_value<const N: usize>(
+    type_code: u8,
+    value: &[u8],
+) -> Result<[u8; N], ConvertBytesToBgpMessageError> {
+    value.try_into().map_err(|_| {
+        anyhow::Error::new(AttributeLengthError {
+            type_code,
+            length: value.len(),
+        })
+        .into()
+    })
+}
+
+/// 決まった長さの要素を並べたpath attributeの値。長さが要素の倍数でない場合はAttribute Length Errorにする。
+fn fixed_length_elements<const N: usize>(
+    type_code: u8,
+    value: &[u8],
+) -> Result<Vec<[u8; N]>, ConvertBytesToBgpMessageError> {
+    if !value.len().is_multiple_of(N) {
+        return Err(anyhow::Error::new(AttributeLengthError {
+            type_code,
+            length: value.len(),
+        })
+        .into());
+    }
+    Ok(value
+        .chunks_exact(N)
+        .map(|c| <[u8; N]>::try_from(c).expect("chunks_exactでN bytesずつ取り出しています。"))
+        .collect())
 }
 
 impl PathAttribute {
@@ -217,29 +1229,97 @@ impl PathAttribute {
         while bytes.len() > i {
             let attribute_flag = bytes[i];
             let attribute_length_octets = ((attribute_flag & 0b0001_0000) >> 4) + 1;
-            let attribute_type_code = bytes[i + 1];
-            let attribute_length = if attribute_length_octets == 1 {
-                bytes[i + 2] as usize
-            } else {
-                u16::from_be_bytes(bytes[i + 2..i + 4].try_into().context("aaa")?) as usize
+            let malformed_attribute_list = |reason: &str| {
+                ConvertBytesToBgpMessageError::from(anyhow::Error::from(
+                    UpdateMessageError::MalformedAttributeList(reason.to_owned()),
+                ))
+            };
+            let attribute_type_code = *bytes.get(i + 1).ok_or_else(|| {
+                malformed_attribute_list(
+                    "path attributeのAttribute Type Codeを取得できませんでした。",
+                )
+            })?;
+            let attribute_length = match bytes.get(i + 2..i + 2 + attribute_length_octets as usize)
+            {
+                Some(&[length]) => usize::from(length),
+                Some(&[high, low]) => usize::from(u16::from_be_bytes([high, low])),
+                _ => {
+                    return Err(malformed_attribute_list(
+                        "path attributeのAttribute Lengthを取得できませんでした。",
+                    ))
+                }
             };
 
             let attribute_start_index = i + 1 + attribute_length_octets as usize + 1;
             let attribute_end_index = attribute_start_index + attribute_length;
+            if attribute_end_index > bytes.len() {
+                return Err(anyhow::Error::new(AttributeLengthError {
+                    type_code: attribute_type_code,
+                    length: attribute_length,
+                })
+                .into());
+            }
+            let value = &bytes[attribute_start_index..attribute_end_index];
             let path_attribute = match attribute_type_code {
-                1 => PathAttribute::Origin(Origin::try_from(bytes[attribute_start_index])?),
-                2 => PathAttribute::AsPath(AsPath::try_from(
-                    &bytes[attribute_start_index..attribute_end_index],
+                1 => {
+                    let [origin] = fixed_length_value(attribute_type_code, value)?;
+                    PathAttribute::Origin(Origin::try_from(origin).map_err(|e| {
+                        anyhow::Error::from(UpdateMessageError::InvalidOrigin(format!("{e:#}")))
+                    })?)
+                }
+                2 => PathAttribute::AsPath(AsPath::try_from(value).map_err(|e| {
+                    anyhow::Error::from(UpdateMessageError::MalformedAsPath(format!("{e:#}")))
+                })?),
+                3 => PathAttribute::NextHop(Ipv4Addr::from(fixed_length_value::<4>(
+                    attribute_type_code,
+                    value,
+                )?)),
+                4 => PathAttribute::MultiExitDisc(u32::from_be_bytes(fixed_length_value(
+                    attribute_type_code,
+                    value,
+                )?)),
+                5 => PathAttribute::LocalPref(u32::from_be_bytes(fixed_length_value(
+                    attribute_type_code,
+                    value,
+                )?)),
+                6 => PathAttribute::AtomicAggregate,
+                7 => {
+                    let [as_0, as_1, a, b, c, d] = fixed_length_value(attribute_type_code, value)?;
+                    PathAttribute::Aggregator(
+                        u32::from(u16::from_be_bytes([as_0, as_1])).into(),
+                        Ipv4Addr::new(a, b, c, d),
+                    )
+                }
+                8 => PathAttribute::Communities(
+                    fixed_length_elements(attribute_type_code, value)?
+                        .into_iter()
+                        .map(u32::from_be_bytes)
+                        .collect(),
+                ),
+                16 => PathAttribute::ExtendedCommunities(fixed_length_elements(
+                    attribute_type_code,
+                    value,
                 )?),
-                3 => {
-                    let addr = Ipv4Addr::new(
-                        bytes[attribute_start_index],
-                        bytes[attribute_start_index + 1],
-                        bytes[attribute_start_index + 2],
-                        bytes[attribute_start_index + 3],
-                    );
-                    PathAttribute::NextHop(addr)
+                17 => PathAttribute::As4Path(AsPath::from_bytes(value, true)?),
+                18 => {
+                    let [as_0, as_1, as_2, as_3, a, b, c, d] =
+                        fixed_length_value(attribute_type_code, value)?;
+                    PathAttribute::As4Aggregator(
+                        u32::from_be_bytes([as_0, as_1, as_2, as_3]).into(),
+                        Ipv4Addr::new(a, b, c, d),
+                    )
                 }
+                // 対応していないAFI/SAFIのものは未知のattributeとして保持する。
+                14 => MpReachNlri::try_from(value)
+                    .map(PathAttribute::MpReachNlri)
+                    .unwrap_or_else(|_| {
+                        PathAttribute::DontKnow(bytes[i..attribute_end_index].to_owned())
+                    }),
+                15 => MpUnreachNlri::try_from(value)
+                    .map(PathAttribute::MpUnreachNlri)
+                    .unwrap_or_else(|_| {
+                        PathAttribute::DontKnow(bytes[i..attribute_end_index].to_owned())
+                    }),
                 _ => PathAttribute::DontKnow(bytes[i..attribute_end_index].to_owned()),
             };
             path_attributes.push(path_attribute);
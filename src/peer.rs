@@ -1,286 +1,4480 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::net::Ipv4Addr;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crate::admission::{AdmissionControl, AdmissionTicket};
+use crate::bmp::{BmpClient, BmpPeer, PeerDownReason};
+use crate::clock::{Clock, TokioClock};
+use crate::config::{Config, Mode};
 use crate::connection::Connection;
+use crate::error::{MessageError, PeerError};
 use crate::event::Event;
 use crate::event_queue::EventQueue;
-use crate::packets::keepalive;
+#[cfg(test)]
+use crate::fault::FaultInjector;
+use crate::fib::{Fib, KernelFib};
+use crate::flowspec::FlowSpecRib;
+use crate::ipv6_unicast::Ipv6UnicastRib;
+use crate::packets::message::Message;
+use crate::packets::open::{Capability, OpenMessage};
+use crate::packets::route_refresh::{
+    RouteRefreshMessage, RouteRefreshSubtype, AFI_IPV4, AFI_IPV6, SAFI_UNICAST,
+};
 use crate::packets::update::UpdateMessage;
-use crate::routing::{AdjRibIn, AdjRibOut, LocRib};
+use crate::routing::{
+    AdjRibIn, AdjRibOut, Ipv4Network, LocRib, PrefixChurn, Rib, RibDiff, RibEntry,
+};
 use crate::state::State;
-use crate::{config::Config, packets::message::Message};
-use tokio::sync::Mutex;
-use tracing::{debug, info, instrument};
+use crate::status::{AdjRibInStatus, ChurningPrefix, DeadLetterLog, NegotiatedParams, PeerStatus};
+use bytes::{BufMut, BytesMut};
+use futures::future::{join_all, select_all};
+use tokio::sync::{watch, Mutex};
+use tracing::{debug, info, instrument, warn};
+
+/// `initial_advertisement_window`で、最初の経路を分けて送る間隔。
+const ADVERTISEMENT_PACING_INTERVAL: Duration = Duration::from_millis(100);
+/// 受信した経路をLocRibに反映するとき、1回のlockで反映する経路の数。
+const LOC_RIB_INSTALL_CHUNK: usize = 1000;
+/// 解釈できずに捨てたMessageを、peerごとに保持する数。
+const DEAD_LETTER_CAPACITY: usize = 16;
+/// statusに含める、best pathの変化回数が多いprefixの数。
+const STATUS_TOP_CHURNING_PREFIXES: usize = 10;
+/// セッションを閉じるとき、送信したNOTIFICATIONが相手に届くのを待つ時間の上限。
+const CONNECTION_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+const FINITE_STATE_MACHINE_ERROR: u8 = 5;
+/// FSM ErrorのError Subcode(RFC 6608)。
+const RECEIVE_UNEXPECTED_MESSAGE_IN_ESTABLISHED_STATE: u8 = 3;
+const HOLD_TIMER_EXPIRED: u8 = 4;
+const CEASE: u8 = 6;
+const ADMINISTRATIVE_SHUTDOWN: u8 = 2;
+const ADMINISTRATIVE_RESET: u8 = 4;
+const CONNECTION_REJECTED: u8 = 5;
+const MAXIMUM_NUMBER_OF_PREFIXES_REACHED: u8 = 1;
+
+/// `Peer::poll`で、処理を1回進めた間に起きたこと。
+/// 複数のことが起きた場合は、状態の遷移、Messageの受信、eventの処理の順に1つを返す。
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum PeerProgress {
+    /// Idleのまま、`start`されるまで何も起こらない。
+    Idle,
+    ProcessedEvent(Event),
+    ReceivedMessage(Message),
+    /// 遷移した後の状態。
+    StateChanged(State),
+    /// 処理することが無く、相手からのデータかtimerの満了を待っている。
+    WouldBlock,
+}
+
+/// セッションを閉じる理由。Graceful Restartで経路を保持するかどうかが変わる。
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum CloseCause {
+    ConnectionLost,
+    Notification,
+    HardReset,
+}
+
+type TransitionCallback = dyn Fn(State, State, &Event) + Send + Sync;
+
+/// `Peer::set_transition_observer`で登録するcallback。
+struct TransitionObserver(Box<TransitionCallback>);
+
+impl std::fmt::Debug for TransitionObserver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TransitionObserver")
+    }
+}
+
+/// 運用者の対応が必要な状況を知らせる警報。
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum PeerAlarm {
+    /// 接続の確立に`connect_failure_alarm`回続けて失敗した。
+    NeighborUnreachable {
+        remote_ip: Ipv4Addr,
+        consecutive_failures: u32,
+    },
+}
+
+type AlarmCallback = dyn Fn(&PeerAlarm) + Send + Sync;
+
+/// `Peer::set_alarm_observer`で登録するcallback。
+struct AlarmObserver(Box<AlarmCallback>);
+
+impl std::fmt::Debug for AlarmObserver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AlarmObserver")
+    }
+}
 
 #[derive(Debug)]
 pub struct Peer {
     state: State,
     event_queue: EventQueue,
     tcp_connection: Option<Connection>,
+    /// listen_rangeで、peerの外でacceptした接続。次に接続を確立するときに用いる。
+    accepted_connection: Option<Connection>,
     config: Config,
     loc_rib: Arc<Mutex<LocRib>>,
     adj_rib_out: AdjRibOut,
     adj_rib_in: AdjRibIn,
+    end_of_rib_sent: bool,
+    end_of_rib_received: bool,
+    loc_rib_generation: u64,
+    /// LocRibのgenerationが進むのを待つためのReceiver。Establishedで初めてLocRibを見たときに作る。
+    loc_rib_changes: Option<watch::Receiver<u64>>,
+    /// loc_rib_generationの時点で、best pathの変化回数が多かったprefix。
+    top_churning_prefixes: Vec<(Ipv4Network, PrefixChurn)>,
+    notifications_received: u64,
+    last_announcement_sent: Option<Instant>,
+    /// 前回の広告に適用しているMRAI。timer_jitterで増減させている。
+    mrai_interval: Duration,
+    announcement_held: bool,
+    /// `initial_advertisement_window`で、まだ送っていない最初の経路のUPDATE。
+    /// 分けて送っている間の変更は、順序を保つため後ろに並べる。
+    paced_updates: VecDeque<UpdateMessage>,
+    /// 1回に送る経路の数。
+    paced_batch_size: usize,
+    /// 次に`paced_updates`を送る時刻。
+    paced_send_deadline: Option<Instant>,
+    /// 双方がGraceful Restart Capabilityを広告した場合の、相手のrestart time。
+    graceful_restart_time: Option<Duration>,
+    /// 双方がGraceful RestartのN bitを立てた場合はtrue。
+    graceful_restart_notification: bool,
+    /// staleな経路を保持する期限。
+    stale_routes_deadline: Option<Instant>,
+    clock: Arc<dyn Clock>,
+    /// OPENの交換で合意したHold Time。0の場合はNone。
+    hold_time: Option<Duration>,
+    hold_timer_deadline: Option<Instant>,
+    keepalive_timer_deadline: Option<Instant>,
+    /// 双方がRoute Refresh Capabilityを広告した場合はtrue。
+    route_refresh: bool,
+    /// 双方がEnhanced Route Refresh Capabilityも広告した場合はtrue。
+    enhanced_route_refresh: bool,
+    /// 双方がIPv6 UnicastのMultiprotocol Extensions Capabilityを広告した場合はtrue。
+    ipv6_unicast: bool,
+    /// エラーでIdleに戻った後、再接続を試みる時刻。
+    connect_retry_deadline: Option<Instant>,
+    /// graceful shutdownで、セッションを管理上停止する時刻。
+    graceful_shutdown_deadline: Option<Instant>,
+    /// Establishedになった時刻。LocRibの時刻と比べるので、clockではなく実際の時刻を用いる。
+    established_at: Option<Instant>,
+    /// Establishedになってから、LocRibのbest pathが最後に変化するまでの時間。
+    /// 経路の変化が収まった後は、収束にかかった時間になる。
+    convergence_time: Option<Duration>,
+    transition_observer: Option<TransitionObserver>,
+    alarm_observer: Option<AlarmObserver>,
+    /// ConnectRetryCounter(RFC 4271 Section 8)。TcpConnectionFailsのたびに増やし、
+    /// OPENの交換に成功してOpenConfirmになると0に戻す。
+    connect_retry_counter: u32,
+    /// 安定して動かないまま続いた、NOTIFICATIONによる切断の回数。`notification_dampening`に用いる。
+    notification_resets: u32,
+    /// clockの時刻でEstablishedになった時刻。`notification_dampening`で安定したかを判断する。
+    stable_since: Option<Instant>,
+    /// 安定して動かないまま続いた、Idleへの自動的な遷移の回数。`damp_peer_oscillations`に用いる。
+    idle_hold_count: u32,
+    /// IdleHoldTimer(RFC 4271 Section 8)の満了時刻。満了するまではIdleから自動的に再接続しない。
+    idle_hold_deadline: Option<Instant>,
+    /// 同時にEstablishedにできるpeerの数を制限する、全てのpeerで共有するもの。
+    admission: Arc<AdmissionControl>,
+    /// Establishedの間、admissionに数えられていることを表すticket。
+    admission_ticket: Option<AdmissionTicket>,
+    /// soft reconfiguration inboundのために保持している、policyの適用前の経路。
+    /// 無効な場合、または上限を超えて無効にした場合はNone。
+    pre_policy_routes: Option<Rib>,
+    /// 解釈できずに捨てた直近のMessage。セッションをまたいで保持する。
+    dead_letters: DeadLetterLog,
+    /// 相手から受信したFlowSpecの規則。
+    flowspec_rib: FlowSpecRib,
+    /// 相手から受信したIPv6 Unicastの経路。
+    ipv6_adj_rib_in: Ipv6UnicastRib,
+    /// 相手に広告しているIPv6 Unicastの経路。
+    ipv6_adj_rib_out: Ipv6UnicastRib,
+    /// 今回の`next`で、まだ処理できる受信経路の数。
+    route_budget: usize,
+    /// `max_routes_per_iteration`を超えて、次の`next`以降に持ち越したUPDATE。受信した順に処理する。
+    inbound_backlog: VecDeque<UpdateMessage>,
+    /// timerのjitterに用いる乱数。
+    rng: fastrand::Rng,
+    /// LocRibのbest pathを書き込む転送テーブル。
+    fib: Arc<dyn Fib>,
+    /// OPENの交換で決まったパラメータ。
+    negotiated: Option<NegotiatedParams>,
+    /// 設定されている場合、セッションの状態と受信したUPDATEをBMP collectorへ送る。
+    bmp: Option<Arc<BmpClient>>,
+    /// BMPのPeer Upで送るために保持している、送信したOPENと受信したOPEN。
+    sent_open: Option<OpenMessage>,
+    received_open: Option<OpenMessage>,
+    /// テストで、以降に確立する接続に障害を注入する。
+    #[cfg(test)]
+    fault_injector: Option<Arc<FaultInjector>>,
 }
 
 impl Peer {
     pub fn new(config: Config, loc_rib: Arc<Mutex<LocRib>>) -> Self {
+        Self::with_clock(config, loc_rib, Arc::new(TokioClock))
+    }
+
+    /// timerがclockの時刻に従うpeerを作る。
+    pub fn with_clock(config: Config, loc_rib: Arc<Mutex<LocRib>>, clock: Arc<dyn Clock>) -> Self {
         let state = State::Idle;
         let event_queue = EventQueue::new();
         let adj_rib_out = AdjRibOut::new();
-        let adj_rib_in = AdjRibIn::new();
+        let adj_rib_in = AdjRibIn::from_config(&config);
+        let pre_policy_routes = config.soft_reconfiguration_inbound.then(Rib::new);
+        let mrai_interval = config.mrai;
         Self {
             state,
             event_queue,
             tcp_connection: None,
+            accepted_connection: None,
             config,
             loc_rib,
             adj_rib_out,
             adj_rib_in,
+            end_of_rib_sent: false,
+            end_of_rib_received: false,
+            loc_rib_generation: 0,
+            loc_rib_changes: None,
+            top_churning_prefixes: vec![],
+            notifications_received: 0,
+            last_announcement_sent: None,
+            mrai_interval,
+            announcement_held: false,
+            paced_updates: VecDeque::new(),
+            paced_batch_size: 0,
+            paced_send_deadline: None,
+            graceful_restart_time: None,
+            graceful_restart_notification: false,
+            stale_routes_deadline: None,
+            clock,
+            hold_time: None,
+            hold_timer_deadline: None,
+            keepalive_timer_deadline: None,
+            route_refresh: false,
+            enhanced_route_refresh: false,
+            ipv6_unicast: false,
+            connect_retry_deadline: None,
+            graceful_shutdown_deadline: None,
+            established_at: None,
+            convergence_time: None,
+            transition_observer: None,
+            alarm_observer: None,
+            connect_retry_counter: 0,
+            notification_resets: 0,
+            stable_since: None,
+            idle_hold_count: 0,
+            idle_hold_deadline: None,
+            admission: Arc::new(AdmissionControl::default()),
+            admission_ticket: None,
+            pre_policy_routes,
+            dead_letters: DeadLetterLog::new(DEAD_LETTER_CAPACITY),
+            flowspec_rib: FlowSpecRib::new(),
+            ipv6_adj_rib_in: Ipv6UnicastRib::new(),
+            ipv6_adj_rib_out: Ipv6UnicastRib::new(),
+            route_budget: usize::MAX,
+            inbound_backlog: VecDeque::new(),
+            rng: fastrand::Rng::new(),
+            fib: Arc::new(KernelFib),
+            negotiated: None,
+            bmp: None,
+            sent_open: None,
+            received_open: None,
+            #[cfg(test)]
+            fault_injector: None,
         }
     }
-    #[instrument]
+    #[instrument(fields(description = self.config.description.as_deref()))]
     pub fn start(&mut self) {
+        if self.config.admin_shutdown {
+            info!("peer is not started because it is administratively shut down.");
+            return;
+        }
         info!("peer is started.");
         self.event_queue.enqueue(Event::ManualStart);
     }
 
-    #[instrument]
+    /// neighborを管理上停止する、または停止を解除する。
+    /// 停止するときは、セッションがあればCeaseを送って閉じ、受信した経路を取り除く。
+    /// 停止を解除するとIdleからセッションを確立し直す。
+    #[instrument(fields(description = self.config.description.as_deref()))]
+    pub async fn set_admin_shutdown(&mut self, shutdown: bool) {
+        if self.config.admin_shutdown == shutdown {
+            return;
+        }
+        self.config.admin_shutdown = shutdown;
+        if !shutdown {
+            self.start();
+            return;
+        }
+        info!("peer is administratively shut down.");
+        if let Some(conn) = &mut self.tcp_connection {
+            conn.send(Message::new_notification(
+                CEASE,
+                ADMINISTRATIVE_SHUTDOWN,
+                BytesMut::new(),
+            ))
+            .await;
+        }
+        self.close_session(CloseCause::HardReset, &Event::ManualStop)
+            .await;
+        self.connect_retry_deadline = None;
+        self.event_queue = EventQueue::new();
+    }
+
+    /// 広告している全ての経路にGRACEFUL_SHUTDOWN community(RFC 8326)を付けて広告し直し、
+    /// delayの後に管理上停止する。相手はその間に他の経路へ切り替えられる。
+    #[instrument(fields(description = self.config.description.as_deref()))]
+    pub async fn graceful_shutdown(&mut self, delay: Duration) {
+        info!("peer is gracefully shut down after {:?}.", delay);
+        self.config.graceful_shutdown = true;
+        if self.state == State::Established {
+            let updates = self.adj_rib_out.create_refresh_messages(&self.config);
+            if let Some(conn) = &mut self.tcp_connection {
+                for update in updates {
+                    conn.send(Message::Update(update)).await;
+                }
+            }
+        }
+        self.graceful_shutdown_deadline = Some(self.clock.now() + delay);
+    }
+
+    /// 相手からのデータかtimerの満了を待ってから、処理を1回進める。
+    #[instrument(fields(description = self.config.description.as_deref()))]
     pub async fn next(&mut self) {
+        self.wait_for_activity().await;
+        self.poll_once().await;
+    }
+
+    /// 待たずに処理を進め、何が起きたかを返す。
+    /// 全てのpeerが`WouldBlock`か`Idle`を返す間は、呼び出し側は待ってよい。
+    #[instrument(fields(description = self.config.description.as_deref()))]
+    pub async fn poll(&mut self) -> PeerProgress {
+        loop {
+            let progress = self.poll_once().await;
+            // timerの満了で積んだeventがあれば、続けて処理する。
+            if progress != PeerProgress::WouldBlock
+                || (self.event_queue.is_empty() && self.inbound_backlog.is_empty())
+            {
+                return progress;
+            }
+        }
+    }
+
+    async fn poll_once(&mut self) -> PeerProgress {
+        let previous_state = self.state;
+        let mut processed_event = None;
+        let mut received_message = None;
+        self.route_budget = self.routes_per_iteration();
+        self.process_inbound_backlog();
         if let Some(event) = self.event_queue.dequeue() {
             info!("event is occurred, event={:?}.", event);
-            self.handle_event(event).await;
+            processed_event = Some(event.clone());
+            if let Err(e) = self.handle_event(event).await {
+                warn!(
+                    "{}, peer is reset and retries after {:?}.",
+                    e, self.config.connect_retry_time
+                );
+                self.record_connection_failure();
+                self.close_session(CloseCause::ConnectionLost, &Event::TcpConnectionFails)
+                    .await;
+                self.connect_retry_deadline =
+                    Some(self.clock.now() + self.config.connect_retry_time);
+            }
         }
 
-        if let Some(conn) = &mut self.tcp_connection {
-            if let Some(message) = conn.get_message().await {
-                info!("message is received, message={:?}.", message);
-                self.handle_message(message);
+        let now = self.clock.now();
+        if self
+            .connect_retry_deadline
+            .is_some_and(|deadline| deadline <= now)
+            && self
+                .idle_hold_deadline
+                .is_none_or(|deadline| deadline <= now)
+        {
+            self.connect_retry_deadline = None;
+            self.event_queue.enqueue(Event::ConnectRetryTimerExpires);
+        }
+        // 受信した経路の処理などが積まれていても、timerの処理を遅らせない。
+        // 後に積んだものが先に取り出されるので、Hold Timerの満了を最後に積む。
+        if self
+            .keepalive_timer_deadline
+            .is_some_and(|deadline| deadline <= now)
+        {
+            self.keepalive_timer_deadline = None;
+            self.event_queue.enqueue_first(Event::KeepaliveTimerExpires);
+        }
+        if self
+            .hold_timer_deadline
+            .is_some_and(|deadline| deadline <= now)
+        {
+            self.hold_timer_deadline = None;
+            self.event_queue.enqueue_first(Event::HoldTimerExpires);
+        }
+        if self
+            .paced_send_deadline
+            .is_some_and(|deadline| deadline <= now)
+        {
+            if let Err(e) = self.send_paced_updates().await {
+                warn!("cannot send paced updates, {}.", e);
             }
         }
-    }
+        if self
+            .stale_routes_deadline
+            .is_some_and(|deadline| deadline <= now)
+        {
+            warn!("graceful restart time is expired.");
+            self.purge_stale_routes().await;
+        }
+        if self
+            .graceful_shutdown_deadline
+            .is_some_and(|deadline| deadline <= now)
+        {
+            self.graceful_shutdown_deadline = None;
+            self.set_admin_shutdown(true).await;
+        }
 
-    fn handle_message(&mut self, message: Message) {
-        match message {
-            Message::Open(open) => self.event_queue.enqueue(Event::BgpOpen(open)),
-            Message::Keepalive(keepalive) => {
-                self.event_queue.enqueue(Event::KeepAliveMsg(keepalive))
+        if self.state == State::Established {
+            if self.observe_loc_rib().await {
+                self.event_queue.enqueue(Event::LocRibChanged);
+            }
+            if self.announcement_held && !self.is_mrai_running() {
+                self.announcement_held = false;
+                self.event_queue.enqueue(Event::AdjRibOutChanged);
             }
-            Message::Update(update) => self.event_queue.enqueue(Event::UpdateMsg(update)),
         }
-    }
 
-    #[instrument]
-    async fn handle_event(&mut self, event: Event) {
-        match &self.state {
-            State::Idle => match event {
-                Event::ManualStart => {
-                    self.tcp_connection = Connection::connect(&self.config).await.ok();
-                    if self.tcp_connection.is_some() {
-                        self.event_queue.enqueue(Event::TcpConnectionConfirmed)
-                    } else {
-                        panic!("TCP Connectionの確立ができませんでした。{:?}", self.config)
-                    }
-                    self.state = State::Connect;
-                }
-                _ => {}
-            },
-            State::Connect => match event {
-                Event::TcpConnectionConfirmed => {
-                    self.tcp_connection
-                        .as_mut()
-                        .expect("TCP Connectionが確立できていません。")
-                        .send(Message::new_open(
-                            self.config.local_as,
-                            self.config.local_ip,
-                        ))
-                        .await;
-                    self.state = State::OpenSent
+        if let Some(conn) = &mut self.tcp_connection {
+            match conn.get_message(&mut self.dead_letters).await {
+                Ok(Some(message)) => {
+                    info!("message is received, message={:?}.", message);
+                    received_message = Some(message.clone());
+                    self.handle_message(message);
                 }
-                _ => {}
-            },
-            State::OpenSent => match event {
-                Event::BgpOpen(open) => {
-                    self.tcp_connection
-                        .as_mut()
-                        .expect("TCP Connection が確立できていません。")
-                        .send(Message::new_keepalive())
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("message error is detected, {:?}.", e);
+                    conn.send(Message::new_notification(e.code(), e.subcode(), e.data()))
                         .await;
-                    self.state = State::OpenConfirm;
-                }
-                _ => {}
-            },
-            State::OpenConfirm => match event {
-                Event::KeepAliveMsg(keepalive) => {
-                    self.state = State::Established;
-                    self.event_queue.enqueue(Event::Established);
-                }
-                _ => {}
-            },
-            State::Established => match event {
-                Event::Established | Event::LocRibChanged => {
-                    let loc_rib = self.loc_rib.lock().await;
-                    self.adj_rib_out
-                        .install_from_loc_rib(&loc_rib, &self.config);
-                    if self.adj_rib_out.does_contain_new_route() {
-                        self.event_queue.enqueue(Event::AdjRibOutChanged);
-                        self.adj_rib_out.update_to_all_changed();
-                    }
+                    let event = match e {
+                        MessageError::Header(_) => Event::BgpHeaderErr,
+                        MessageError::Open(_) => Event::BgpOpenMsgErr,
+                        MessageError::Update(_) => Event::UpdateMsgErr,
+                    };
+                    self.close_session(CloseCause::Notification, &event).await;
+                    self.schedule_dampened_reconnect();
                 }
-                Event::AdjRibOutChanged => {
-                    let updates: Vec<UpdateMessage> = self
-                        .adj_rib_out
-                        .create_update_messages(self.config.local_ip, self.config.local_as);
-                    for update in updates {
-                        self.tcp_connection
-                            .as_mut()
-                            .expect("TCP Connectionが確立できていません。")
-                            .send(Message::Update(update))
-                            .await;
-                    }
-                }
-                Event::UpdateMsg(update) => {
-                    self.adj_rib_in.install_from_update(update, &self.config);
-                    if self.adj_rib_in.does_contain_new_route() {
-                        debug!("abj_rib in is updated.");
-                        self.event_queue.enqueue(Event::AdjRibInChanged);
-                        self.adj_rib_in.update_to_all_changed();
-                    }
-                }
-                Event::AdjRibInChanged => {
-                    self.loc_rib
-                        .lock()
-                        .await
-                        .intsall_from_adj_rib_in(&self.adj_rib_in);
-                    if self.loc_rib.lock().await.does_contain_new_route() {
-                        self.loc_rib
-                            .lock()
-                            .await
-                            .write_to_kernel_routing_table()
-                            .await;
-                        self.event_queue.enqueue(Event::LocRibChanged);
-                        self.loc_rib.lock().await.update_to_all_changed();
-                    }
-                }
-                _ => {}
-            },
-            _ => {}
+            }
+        }
+
+        if self.state != previous_state {
+            PeerProgress::StateChanged(self.state)
+        } else if let Some(message) = received_message {
+            PeerProgress::ReceivedMessage(message)
+        } else if let Some(event) = processed_event {
+            PeerProgress::ProcessedEvent(event)
+        } else if self.state == State::Idle
+            && self.connect_retry_deadline.is_none()
+            && self.event_queue.is_empty()
+        {
+            PeerProgress::Idle
+        } else {
+            PeerProgress::WouldBlock
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
+    /// `paced_updates`から`paced_batch_size`個の経路を送る。全て送り終えればEnd-of-RIBを送る。
+    async fn send_paced_updates(&mut self) -> Result<(), PeerError> {
+        let mut budget = self.paced_batch_size;
+        while budget > 0 {
+            let Some(update) = self.paced_updates.pop_front() else {
+                break;
+            };
+            let update = if update.routes_len() > budget {
+                let (head, rest) = update.split_at(budget);
+                self.paced_updates.push_front(rest);
+                head
+            } else {
+                update
+            };
+            budget = budget.saturating_sub(update.routes_len().max(1));
+            self.connection()?.send(Message::Update(update)).await;
+        }
+        if !self.paced_updates.is_empty() {
+            self.paced_send_deadline = Some(self.clock.now() + ADVERTISEMENT_PACING_INTERVAL);
+            return Ok(());
+        }
+        self.paced_send_deadline = None;
+        if !self.end_of_rib_sent {
+            self.send_end_of_rib().await?;
+        }
+        Ok(())
+    }
 
-    use super::*;
-    use tokio::time::{sleep, Duration};
+    /// 相手からのデータの到着か、いずれかのtimerの満了か、LocRibの変更まで待つ。
+    /// 処理していないeventや受信したMessageが残っていれば、待たずに返る。
+    pub async fn wait_for_activity(&mut self) {
+        if !self.event_queue.is_empty()
+            || !self.inbound_backlog.is_empty()
+            || self
+                .tcp_connection
+                .as_ref()
+                .is_some_and(|conn| conn.has_buffered_message())
+        {
+            return;
+        }
+        let mrai_expires = self
+            .announcement_held
+            .then_some(self.last_announcement_sent)
+            .flatten()
+            .map(|sent| sent + self.mrai_interval);
+        // idle_hold_deadlineより前にconnect_retry_deadlineが来ても再接続しないので、
+        // 遅い方まで待つ。
+        let connect_retry = self
+            .connect_retry_deadline
+            .map(|deadline| deadline.max(self.idle_hold_deadline.unwrap_or(deadline)));
+        let deadline = [
+            connect_retry,
+            self.hold_timer_deadline,
+            self.keepalive_timer_deadline,
+            self.stale_routes_deadline,
+            self.paced_send_deadline,
+            self.graceful_shutdown_deadline,
+            mrai_expires,
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let clock = &self.clock;
+        let timer = async {
+            match deadline {
+                Some(deadline) => clock.sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        let tcp_connection = &mut self.tcp_connection;
+        let readable = async {
+            match tcp_connection {
+                Some(conn) => conn.wait_readable().await,
+                None => std::future::pending().await,
+            }
+        };
+        // LocRibの変更はEstablishedのときだけ広告する。
+        let loc_rib_changes = self
+            .loc_rib_changes
+            .as_mut()
+            .filter(|_| self.state == State::Established);
+        let loc_rib_changed = async {
+            match loc_rib_changes {
+                // Senderを持つLocRibはpeerより先に無くならないので、エラーにはならない。
+                Some(changes) => changes.changed().await.unwrap_or(()),
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = readable => {}
+            _ = timer => {}
+            _ = loc_rib_changed => {}
+        }
+    }
 
-    #[tokio::test]
-    async fn peer_can_transition_to_open_sent_state() {
-        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
-        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
-        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
-        peer.start();
+    /// Establishedに達し、相手からEnd-of-RIBを受信して、
+    /// 受信した経路の処理がすべて終わっていればtrueを返す。
+    pub fn is_converged(&self) -> bool {
+        self.state == State::Established
+            && self.end_of_rib_received
+            && self.event_queue.is_empty()
+            && self.inbound_backlog.is_empty()
+    }
 
-        tokio::spawn(async move {
-            let remote_config = "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
-            let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
-            let mut remote_peer = Peer::new(remote_config, Arc::clone(&remote_loc_rib));
-            remote_peer.start();
-            remote_peer.next().await;
-            remote_peer.next().await;
-        });
+    #[instrument(fields(description = self.config.description.as_deref()))]
+    pub fn stop(&mut self) {
+        info!("peer is stopped.");
+        self.connect_retry_deadline = None;
+        self.reset_session(&Event::ManualStop);
+    }
 
-        tokio::time::sleep(Duration::from_secs(1)).await;
-        peer.next().await;
-        peer.next().await;
-        assert_eq!(peer.state, State::OpenSent);
+    /// まだ処理していないeventを、処理される順に返す。
+    #[cfg(test)]
+    pub fn pending_events(&self) -> Vec<Event> {
+        self.event_queue.peek_all()
     }
 
-    #[tokio::test]
-    async fn peer_can_transition_to_connect_state() {
-        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
-        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
-        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
-        peer.start();
+    /// 相手から受信したNOTIFICATIONの数。
+    pub fn notifications_received(&self) -> u64 {
+        self.notifications_received
+    }
 
-        tokio::spawn(async move {
-            let remote_config = "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
-            let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
-            let mut remote_peer = Peer::new(remote_config, Arc::clone(&remote_loc_rib));
-            remote_peer.start();
-            remote_peer.next().await;
-        });
+    /// LocRibのgenerationが進んでいればtrueを返し、statusに含める情報を取り直す。
+    async fn observe_loc_rib(&mut self) -> bool {
+        let loc_rib = self.loc_rib.lock().await;
+        self.loc_rib_changes
+            .get_or_insert_with(|| loc_rib.subscribe())
+            .borrow_and_update();
+        if let (Some(established_at), Some(changed)) =
+            (self.established_at, loc_rib.last_best_path_change())
+        {
+            if changed > established_at {
+                self.convergence_time = Some(changed - established_at);
+            }
+        }
+        if loc_rib.generation() == self.loc_rib_generation {
+            return false;
+        }
+        self.loc_rib_generation = loc_rib.generation();
+        self.top_churning_prefixes = loc_rib.top_churning_prefixes(STATUS_TOP_CHURNING_PREFIXES);
+        true
+    }
 
-        tokio::time::sleep(Duration::from_secs(1)).await;
-        peer.next().await;
-        assert_eq!(peer.state, State::Connect);
+    pub fn status(&self) -> PeerStatus {
+        PeerStatus {
+            description: self.config.description.clone(),
+            local_as: self.config.local_as.into(),
+            remote_as: self.config.remote_as.into(),
+            remote_ip: self.config.remote_ip,
+            state: self.state,
+            adj_rib_in_routes: self.adj_rib_in.len(),
+            adj_rib_out_routes: self.adj_rib_out.len(),
+            notifications_received: self.notifications_received,
+            stale_routes: self
+                .adj_rib_in
+                .stale_routes()
+                .map(|e| e.network_address.to_string())
+                .collect::<BTreeSet<String>>()
+                .into_iter()
+                .collect(),
+            dead_letters: self.dead_letters.dead_letters(),
+            negotiated: self.negotiated.clone(),
+            convergence_time_millis: self.convergence_time.map(|t| t.as_millis() as u64),
+            connect_retry_counter: self.connect_retry_counter,
+            bogon_routes_dropped: self.adj_rib_in.bogon_routes_dropped(),
+            duplicate_nlri: self.adj_rib_in.duplicate_nlri(),
+            top_churning_prefixes: self
+                .top_churning_prefixes
+                .iter()
+                .map(|(network, churn)| ChurningPrefix {
+                    network: network.to_string(),
+                    count: churn.count,
+                    millis_since_last_change: churn.last_changed.elapsed().as_millis() as u64,
+                })
+                .collect(),
+        }
     }
 
-    #[tokio::test]
-    async fn peer_can_transition_to_open_confirm_state() {
-        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
-        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
-        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
-        peer.start();
+    pub fn remote_ip(&self) -> Ipv4Addr {
+        self.config.remote_ip
+    }
 
-        tokio::spawn(async move {
-            let remote_config = "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
-            let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
-            let mut remote_peer = Peer::new(remote_config, Arc::clone(&remote_loc_rib));
-            remote_peer.start();
-            let max_step = 50;
-            for _ in 0..max_step {
-                remote_peer.next().await;
-                if remote_peer.state == State::OpenConfirm {
-                    break;
-                }
-                tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
-            }
-        });
+    /// 今のセッションで、相手からEnd-of-RIBを受信していればtrue。
+    pub fn is_end_of_rib_received(&self) -> bool {
+        self.end_of_rib_received
+    }
 
-        tokio::time::sleep(Duration::from_secs(1)).await;
-        let max_step = 50;
-        for _ in 0..max_step {
-            peer.next().await;
-            if peer.state == State::OpenConfirm {
-                break;
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// listen_rangeでacceptした接続を、次に接続を確立するときに用いる。
+    pub(crate) fn set_accepted_connection(&mut self, connection: Connection) {
+        self.accepted_connection = Some(connection);
+    }
+
+    /// OPENの交換で決まったパラメータ。OPENを受信するまではNone。
+    pub fn negotiated_params(&self) -> Option<&NegotiatedParams> {
+        self.negotiated.as_ref()
+    }
+
+    /// 受信した経路を、全てのPath Attributeとともにnetworkの順に返す。
+    pub fn adj_rib_in_status(&self) -> AdjRibInStatus {
+        let mut routes: Vec<RibEntry> = self
+            .adj_rib_in
+            .routes()
+            .map(|e| RibEntry::clone(e))
+            .collect();
+        routes.sort_by_key(|e| e.network_address);
+        AdjRibInStatus {
+            remote_ip: self.config.remote_ip,
+            routes,
+        }
+    }
+
+    /// daemonの終了時に、セッションがあればCeaseを送り、相手に届くのを待って閉じる。
+    /// 相手から受信した経路はLocRibとkernelに残したままにする。
+    /// 全てのpeerに送り終えるまでは、相手から届く通信を転送し続けるため。
+    #[instrument(fields(description = self.config.description.as_deref()))]
+    pub async fn notify_shutdown(&mut self) {
+        info!("peer is shut down.");
+        if let Some(mut conn) = self.tcp_connection.take() {
+            conn.send(Message::new_notification(
+                CEASE,
+                ADMINISTRATIVE_SHUTDOWN,
+                BytesMut::new(),
+            ))
+            .await;
+            conn.close(CONNECTION_CLOSE_TIMEOUT).await;
+        }
+        self.stop();
+    }
+
+    /// Hard Reset(RFC 8538)を送ってセッションを閉じる。
+    /// Graceful Restartを合意していても、相手から受信した経路は保持せずに取り除く。
+    #[instrument(fields(description = self.config.description.as_deref()))]
+    pub async fn hard_reset(&mut self) {
+        info!("peer is hard reset.");
+        if let Some(conn) = &mut self.tcp_connection {
+            conn.send(Message::new_hard_reset(
+                CEASE,
+                ADMINISTRATIVE_RESET,
+                BytesMut::new(),
+            ))
+            .await;
+        }
+        self.close_session(CloseCause::HardReset, &Event::ManualStop)
+            .await;
+    }
+
+    /// 1つのneighborのセッションをやり直す(clear neighbor)。
+    /// Ceaseを送ってセッションを閉じ、相手から受信した経路をLocRibとkernelから取り除いた上で、
+    /// ConnectRetry Timerが満了した後にIdleから再接続する。
+    #[instrument(fields(description = self.config.description.as_deref()))]
+    pub async fn reset(&mut self) {
+        info!("peer is reset.");
+        if let Some(conn) = &mut self.tcp_connection {
+            conn.send(Message::new_notification(
+                CEASE,
+                ADMINISTRATIVE_RESET,
+                BytesMut::new(),
+            ))
+            .await;
+        }
+        self.close_session(CloseCause::HardReset, &Event::ManualStop)
+            .await;
+        self.stale_routes_deadline = None;
+        self.connect_retry_deadline = Some(self.clock.now() + self.config.connect_retry_time);
+    }
+
+    /// セッションを閉じ、相手から受信した経路を取り除く。
+    /// Graceful Restartを合意していた場合は、経路をstaleとして保持する。
+    /// NOTIFICATIONによる切断では、N bitも合意していた場合のみ保持する。
+    async fn close_session(&mut self, cause: CloseCause, event: &Event) {
+        let was_established = self.state == State::Established;
+        if was_established {
+            self.report_peer_down(event).await;
+        }
+        if let Some(conn) = self.tcp_connection.take() {
+            conn.close(CONNECTION_CLOSE_TIMEOUT).await;
+        }
+        self.reset_session(event);
+        if *event != Event::ManualStop {
+            self.start_idle_hold_timer(was_established);
+        }
+        // FlowSpecの規則とIPv6 Unicastの経路はGraceful Restartでも保持しない。
+        self.flowspec_rib.clear();
+        self.ipv6_adj_rib_in.clear();
+        let retains_routes = match cause {
+            CloseCause::ConnectionLost => true,
+            CloseCause::Notification => self.graceful_restart_notification,
+            CloseCause::HardReset => false,
+        };
+        self.graceful_restart_notification = false;
+        match self.graceful_restart_time.take() {
+            Some(restart_time) if was_established && retains_routes => {
+                self.adj_rib_in.mark_all_stale();
+                warn!(
+                    "{} routes from {} are retained as stale for {:?}.",
+                    self.adj_rib_in.stale_routes().count(),
+                    self.config.remote_ip,
+                    restart_time
+                );
+                self.loc_rib
+                    .lock()
+                    .await
+                    .mark_stale_from_adj_rib_in(&self.adj_rib_in);
+                self.stale_routes_deadline = Some(self.clock.now() + restart_time);
+            }
+            // rib_storeから読み込んだ経路などstaleな経路を保持している間は、
+            // セッションを確立できなくても保持期限まで残す。
+            _ if !was_established && self.stale_routes_deadline.is_some() => {}
+            _ => {
+                self.adj_rib_in.withdraw_all();
+                self.install_adj_rib_in_to_loc_rib().await;
             }
-            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
         }
-        assert_eq!(peer.state, State::OpenConfirm);
     }
 
-    #[tokio::test]
-    async fn peer_can_transition_to_established_state() {
-        let config: Config = "64512 127.0.0.1 64513 127.0.0.2 active".parse().unwrap();
-        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
-        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
-        peer.start();
+    /// 接続に関する状態を初期化する。受信した経路はそのまま残す。
+    fn reset_session(&mut self, event: &Event) {
+        self.tcp_connection = None;
+        self.transition(State::Idle, event);
+        self.end_of_rib_sent = false;
+        self.end_of_rib_received = false;
+        self.last_announcement_sent = None;
+        self.announcement_held = false;
+        self.paced_updates.clear();
+        self.paced_send_deadline = None;
+        self.adj_rib_out = AdjRibOut::new();
+        self.hold_time = None;
+        self.hold_timer_deadline = None;
+        self.keepalive_timer_deadline = None;
+        self.route_refresh = false;
+        self.enhanced_route_refresh = false;
+        self.ipv6_unicast = false;
+        self.ipv6_adj_rib_out.clear();
+        self.admission_ticket = None;
+        self.negotiated = None;
+        self.established_at = None;
+        self.sent_open = None;
+        self.received_open = None;
+        self.inbound_backlog.clear();
+        if let Some(pre_policy_routes) = &mut self.pre_policy_routes {
+            *pre_policy_routes = Rib::new();
+        }
+    }
 
-        tokio::spawn(async move {
-            let remote_config = "64513 127.0.0.2 64512 127.0.0.1 passive".parse().unwrap();
-            let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
-            let mut remote_peer = Peer::new(remote_config, Arc::clone(&remote_loc_rib));
-            remote_peer.start();
-            let max_step = 50;
-            for _ in 0..max_step {
-                remote_peer.next().await;
-                if remote_peer.state == State::Established {
-                    break;
+    /// 状態を遷移させる。状態の変更は全てここを通し、遷移前後の状態と契機のeventを記録する。
+    fn transition(&mut self, new: State, cause: &Event) {
+        let old = self.state;
+        if old == new {
+            return;
+        }
+        info!(
+            "state is changed, neighbor={}, {:?} -> {:?}, event={:?}.",
+            self.config.remote_ip, old, new, cause
+        );
+        self.state = new;
+        if let Some(observer) = &self.transition_observer {
+            (observer.0)(old, new, cause);
+        }
+    }
+
+    /// 警報が出るたびに呼ばれるcallbackを登録する。警報はcallbackに関わらずログにも出す。
+    pub fn set_alarm_observer(&mut self, observer: impl Fn(&PeerAlarm) + Send + Sync + 'static) {
+        self.alarm_observer = Some(AlarmObserver(Box::new(observer)));
+    }
+
+    fn raise_alarm(&self, alarm: PeerAlarm) {
+        warn!("alarm is raised, {:?}.", alarm);
+        if let Some(observer) = &self.alarm_observer {
+            (observer.0)(&alarm);
+        }
+    }
+
+    /// TcpConnectionFailsの回数を数え、`connect_failure_alarm`に達したときに一度だけ警報を出す。
+    fn record_connection_failure(&mut self) {
+        self.connect_retry_counter += 1;
+        if self.config.connect_failure_alarm == Some(self.connect_retry_counter) {
+            self.raise_alarm(PeerAlarm::NeighborUnreachable {
+                remote_ip: self.config.remote_ip,
+                consecutive_failures: self.connect_retry_counter,
+            });
+        }
+    }
+
+    /// NOTIFICATIONによる切断の後、`notification_dampening`が設定されていれば再接続を予約してtrueを返す。
+    /// 再接続までの時間は、安定して動かないまま切断が続くたびにconnect_retry_timeから倍にする。
+    /// TCPの接続の失敗による再接続(connect_retry_time)とは別に数える。
+    fn schedule_dampened_reconnect(&mut self) -> bool {
+        let Some(max_delay) = self.config.notification_dampening else {
+            return false;
+        };
+        let now = self.clock.now();
+        if self
+            .stable_since
+            .take()
+            .is_some_and(|since| since + max_delay <= now)
+        {
+            self.notification_resets = 0;
+        }
+        let delay = self
+            .config
+            .connect_retry_time
+            .saturating_mul(2u32.saturating_pow(self.notification_resets))
+            .min(max_delay);
+        self.notification_resets = self.notification_resets.saturating_add(1);
+        info!(
+            "peer retries after {:?}, {} notification resets are occurred in a row.",
+            delay, self.notification_resets
+        );
+        self.connect_retry_deadline = Some(now + delay);
+        true
+    }
+
+    /// `idle_hold_time`が設定されていれば、自動的にIdleへ戻ったときにIdleHoldTimerを始める。
+    /// `damp_peer_oscillations`も設定されていれば、安定して動かないままIdleへ戻るたびに
+    /// 間隔を倍にする(DampPeerOscillations)。
+    fn start_idle_hold_timer(&mut self, was_established: bool) {
+        let Some(idle_hold_time) = self.config.idle_hold_time else {
+            return;
+        };
+        let now = self.clock.now();
+        let interval = match self.config.damp_peer_oscillations {
+            Some(max_interval) => {
+                // 上限の時間よりも長くEstablishedだった場合は、安定したとみなして数え直す。
+                if was_established
+                    && self
+                        .stable_since
+                        .is_some_and(|since| since + max_interval <= now)
+                {
+                    self.idle_hold_count = 0;
                 }
-                tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+                let interval = idle_hold_time
+                    .saturating_mul(2u32.saturating_pow(self.idle_hold_count))
+                    .min(max_interval);
+                self.idle_hold_count = self.idle_hold_count.saturating_add(1);
+                interval
             }
-        });
+            None => idle_hold_time,
+        };
+        info!("peer is held in idle for {:?}.", interval);
+        self.idle_hold_deadline = Some(now + interval);
+    }
 
-        tokio::time::sleep(Duration::from_secs(1)).await;
-        let max_step = 50;
-        for _ in 0..max_step {
-            peer.next().await;
-            if peer.state == State::Established {
-                break;
-            }
-            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+    /// 状態が遷移するたびに`(遷移前, 遷移後, 契機のevent)`で呼ばれるcallbackを登録する。
+    pub fn set_transition_observer(
+        &mut self,
+        observer: impl Fn(State, State, &Event) + Send + Sync + 'static,
+    ) {
+        self.transition_observer = Some(TransitionObserver(Box::new(observer)));
+    }
+
+    /// configを読み込み直し、自身が広告元の経路の差分だけをLocRibに反映する。
+    /// 差分はLocRibの変更としてAdjRibOutに伝わり、広告と取り消しのUPDATEだけが送られる。
+    /// セッションは維持するので、接続先などセッションに関わる設定の変更は再接続まで反映されない。
+    pub async fn reload_config(&mut self, config: Config) -> anyhow::Result<RibDiff> {
+        let diff = self.loc_rib.lock().await.reload(&config).await?;
+        self.config = config;
+        Ok(diff)
+    }
+
+    /// 同時にEstablishedにできるpeerの数を、他のpeerと共有するadmissionで制限する。
+    pub fn set_admission_control(&mut self, admission: Arc<AdmissionControl>) {
+        self.admission = admission;
+    }
+
+    /// 受信した経路の書き込み先を変える。既定ではカーネルのルーティングテーブルに書き込む。
+    pub fn set_fib(&mut self, fib: Arc<dyn Fib>) {
+        self.fib = fib;
+    }
+
+    /// 以降に確立する接続で送受信するMessageに、faultsの障害を注入する。
+    #[cfg(test)]
+    pub fn set_fault_injector(&mut self, faults: Arc<FaultInjector>) {
+        self.fault_injector = Some(faults);
+    }
+
+    /// セッションの状態と受信したUPDATEを、clientのBMP collectorへ送るようにする。
+    pub fn set_bmp_client(&mut self, client: Arc<BmpClient>) {
+        self.bmp = Some(client);
+    }
+
+    fn bmp_peer(&self) -> BmpPeer {
+        BmpPeer {
+            address: self.config.remote_ip,
+            as_number: self.config.remote_as,
+            bgp_identifier: self
+                .received_open
+                .as_ref()
+                .map_or(Ipv4Addr::UNSPECIFIED, |open| open.bgp_identifier()),
         }
-        assert_eq!(peer.state, State::Established);
+    }
+
+    /// BMP collectorへPeer Upを送る。
+    /// collectorへの送信に失敗しても、警告するだけでセッションには影響させない。
+    async fn report_peer_up(&self) {
+        let (Some(bmp), Some(sent_open), Some(received_open), Some(conn)) = (
+            &self.bmp,
+            &self.sent_open,
+            &self.received_open,
+            &self.tcp_connection,
+        ) else {
+            return;
+        };
+        let (Ok(local), Ok(remote)) = (conn.local_addr(), conn.peer_addr()) else {
+            return;
+        };
+        if let Err(e) = bmp
+            .peer_up(&self.bmp_peer(), local, remote, sent_open, received_open)
+            .await
+        {
+            warn!("failed to send bmp peer up, {:?}.", e);
+        }
+    }
+
+    /// 受信したUPDATEをRoute MonitoringとしてBMP collectorへ送る。
+    async fn report_route_monitoring(&self, update: &UpdateMessage) {
+        if let Some(bmp) = &self.bmp {
+            if let Err(e) = bmp.route_monitoring(&self.bmp_peer(), update).await {
+                warn!("failed to send bmp route monitoring, {:?}.", e);
+            }
+        }
+    }
+
+    /// eventを契機にEstablishedのセッションが閉じたことを、BMP collectorへ送る。
+    async fn report_peer_down(&self, event: &Event) {
+        if let Some(bmp) = &self.bmp {
+            let reason = PeerDownReason::from_event(event);
+            if let Err(e) = bmp.peer_down(&self.bmp_peer(), &reason).await {
+                warn!("failed to send bmp peer down, {:?}.", e);
+            }
+        }
+    }
+
+    /// Cease(Connection Rejected)を送ってセッションを拒否し、後で再接続を試みる。
+    async fn reject_connection(&mut self, event: &Event) {
+        warn!(
+            "session with {} is rejected because {} peers are already established.",
+            self.config.remote_ip,
+            self.admission.established()
+        );
+        if let Some(conn) = &mut self.tcp_connection {
+            conn.send(Message::new_notification(
+                CEASE,
+                CONNECTION_REJECTED,
+                BytesMut::new(),
+            ))
+            .await;
+        }
+        self.close_session(CloseCause::HardReset, event).await;
+        self.connect_retry_deadline = Some(self.clock.now() + self.config.connect_retry_time);
+    }
+
+    /// 受信した経路の数が`max_prefix`を超えていて、セッションを閉じるべきならtrueを返す。
+    /// `max_prefix_warning_only`の場合は警告するだけでfalseを返す。
+    fn exceeds_max_prefix(&self) -> bool {
+        let Some(max_prefix) = self.config.max_prefix else {
+            return false;
+        };
+        let received = self.adj_rib_in.len();
+        if received <= max_prefix {
+            return false;
+        }
+        warn!(
+            "{} routes are received from {}, which exceeds max_prefix {}.",
+            received, self.config.remote_ip, max_prefix
+        );
+        !self.config.max_prefix_warning_only
+    }
+
+    /// Cease(Maximum Number of Prefixes Reached)を送ってセッションを閉じる。
+    /// 再接続してまた上限を超えることを繰り返さないよう、`max_prefix_restart`の間はIdleに留まる。
+    async fn close_session_by_max_prefix(&mut self, event: &Event) {
+        // RFC 4486 Section 4: AFI, SAFI, 上限の経路数。
+        let mut data = BytesMut::new();
+        data.put_u16(1);
+        data.put_u8(1);
+        let max_prefix = self.config.max_prefix.unwrap_or_default();
+        data.put_u32(u32::try_from(max_prefix).unwrap_or(u32::MAX));
+        if let Some(conn) = &mut self.tcp_connection {
+            conn.send(Message::new_notification(
+                CEASE,
+                MAXIMUM_NUMBER_OF_PREFIXES_REACHED,
+                data,
+            ))
+            .await;
+        }
+        self.close_session(CloseCause::HardReset, event).await;
+        self.connect_retry_deadline = self
+            .config
+            .max_prefix_restart
+            .map(|restart| self.clock.now() + restart);
+        match self.config.max_prefix_restart {
+            Some(restart) => info!(
+                "peer stays idle for {:?} after max_prefix is exceeded.",
+                restart
+            ),
+            None => {
+                info!("peer stays idle until it is started again after max_prefix is exceeded.")
+            }
+        }
+    }
+
+    /// 受信した経路を書き出す。次の起動時に`load_adj_rib_in`で読み込む。
+    pub fn persist_adj_rib_in(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        self.adj_rib_in.persist(path)
+    }
+
+    /// 前回の起動で書き出した受信経路をstaleとして読み込み、読み込んだ数を返す。
+    /// Graceful Restartと同じく、End-of-RIBを受信するかstale_timeが経つまでに
+    /// 受信し直さなかった経路は取り除く。LocRibには先に`LocRib::load`で読み込んでおく。
+    pub async fn load_adj_rib_in(
+        &mut self,
+        path: impl AsRef<Path>,
+        stale_time: Duration,
+    ) -> anyhow::Result<usize> {
+        let loaded = self.adj_rib_in.load(path)?;
+        if loaded > 0 {
+            self.loc_rib
+                .lock()
+                .await
+                .mark_stale_from_adj_rib_in(&self.adj_rib_in);
+            self.stale_routes_deadline = Some(self.clock.now() + stale_time);
+        }
+        Ok(loaded)
+    }
+
+    /// Graceful Restart中に保持していたstaleな経路を取り除く。
+    async fn purge_stale_routes(&mut self) {
+        self.stale_routes_deadline = None;
+        let stale_routes = self.adj_rib_in.stale_routes().count();
+        if stale_routes > 0 {
+            info!("{} stale routes are purged.", stale_routes);
+        }
+        self.adj_rib_in.withdraw_stale_routes();
+        self.install_adj_rib_in_to_loc_rib().await;
+    }
+
+    async fn install_adj_rib_in_to_loc_rib(&mut self) {
+        {
+            let mut loc_rib = self.loc_rib.lock().await;
+            if self.config.is_ibgp() {
+                loc_rib.add_ibgp_neighbor(self.config.remote_ip);
+            }
+            self.adj_rib_in.check_own_prefixes(&loc_rib, &self.config);
+        }
+
+        // 前回の反映から変わった経路だけを反映する。
+        // 大量の経路を一度に反映すると、その間LocRibのlockを握り続けて同じrun loopの他のpeerの
+        // keepaliveなどが遅れるため、LOC_RIB_INSTALL_CHUNK件ずつlockを取り直し、間で他のtaskに譲る。
+        let routes: Vec<Arc<RibEntry>> = self.adj_rib_in.new_routes().cloned().collect();
+        for chunk in routes.chunks(LOC_RIB_INSTALL_CHUNK) {
+            self.loc_rib
+                .lock()
+                .await
+                .install_routes(&self.adj_rib_in, chunk, &[]);
+            tokio::task::yield_now().await;
+        }
+
+        let withdrawn: Vec<Arc<RibEntry>> = self.adj_rib_in.withdrawn_routes().cloned().collect();
+        let mut loc_rib = self.loc_rib.lock().await;
+        loc_rib.install_routes(&self.adj_rib_in, &[], &withdrawn);
+        loc_rib.finish_install();
+        self.adj_rib_in.update_to_all_changed();
+        if loc_rib.does_contain_changed_route() {
+            if let Err(e) = loc_rib.write_to_fib(self.fib.as_ref()).await {
+                warn!("cannot write loc-rib to fib, {:?}.", e);
+            }
+            loc_rib.update_to_all_changed();
+        }
+    }
+
+    fn capabilities(&self) -> Vec<Capability> {
+        let mut capabilities: Vec<Capability> = self
+            .config
+            .graceful_restart_time
+            .map(|restart_time| Capability::GracefulRestart {
+                restart_time,
+                notification: true,
+            })
+            .into_iter()
+            .collect();
+        if self.config.route_refresh || self.config.enhanced_route_refresh {
+            capabilities.push(Capability::RouteRefresh);
+        }
+        if self.config.enhanced_route_refresh {
+            capabilities.push(Capability::EnhancedRouteRefresh);
+        }
+        // Multiprotocol Extensions Capabilityを広告する場合は、IPv4 Unicastも含める(RFC 4760)。
+        if self.config.ipv6_unicast {
+            capabilities.push(Capability::Multiprotocol {
+                afi: AFI_IPV4,
+                safi: SAFI_UNICAST,
+            });
+            capabilities.push(Capability::Multiprotocol {
+                afi: AFI_IPV6,
+                safi: SAFI_UNICAST,
+            });
+        }
+        #[cfg(feature = "update-compression")]
+        if self.config.update_compression {
+            capabilities.push(Capability::UpdateCompression);
+        }
+        capabilities
+    }
+
+    /// 相手に、合意した全てのaddress familyの経路の再送を要求する。
+    /// Route Refreshを合意していなければ何もしない。
+    pub async fn request_route_refresh(&mut self) -> Result<(), PeerError> {
+        self.send_route_refresh(AFI_IPV4, SAFI_UNICAST).await?;
+        if self.ipv6_unicast {
+            self.send_route_refresh(AFI_IPV6, SAFI_UNICAST).await?;
+        }
+        Ok(())
+    }
+
+    /// 相手に、afi, safiで指定したaddress familyの経路の再送を要求する。
+    /// Route Refreshを合意していなければ何もしない。
+    pub async fn send_route_refresh(&mut self, afi: u16, safi: u8) -> Result<(), PeerError> {
+        if !self.route_refresh {
+            warn!("route refresh is not negotiated.");
+            return Ok(());
+        }
+        self.connection()?
+            .send(Message::RouteRefresh(RouteRefreshMessage::for_afi_safi(
+                afi,
+                safi,
+                RouteRefreshSubtype::Request,
+            )))
+            .await;
+        Ok(())
+    }
+
+    /// soft reconfiguration inboundのために、policyの適用前の経路を保持していればtrue。
+    pub fn is_soft_reconfiguration_enabled(&self) -> bool {
+        self.pre_policy_routes.is_some()
+    }
+
+    /// 受信側のpolicyを適用し直す。policyの適用前の経路を保持していればそれを用い、
+    /// 保持していなければ相手にRoute Refreshを要求して再送してもらう。
+    pub async fn soft_reset_inbound(&mut self) -> Result<(), PeerError> {
+        let Some(pre_policy_routes) = &self.pre_policy_routes else {
+            return self.request_route_refresh().await;
+        };
+        let updates: Vec<UpdateMessage> = pre_policy_routes
+            .routes()
+            .map(|e| {
+                UpdateMessage::new(
+                    Arc::clone(&e.path_attributes),
+                    vec![e.network_address],
+                    vec![],
+                )
+            })
+            .collect();
+        self.adj_rib_in.withdraw_all();
+        for update in updates {
+            self.adj_rib_in.install_from_update(update, &self.config);
+        }
+        self.install_adj_rib_in_to_loc_rib().await;
+        Ok(())
+    }
+
+    /// updateが、この相手から受信してbest pathになっている経路を取り消すならtrue。
+    async fn withdraws_best_path(&self, update: &UpdateMessage) -> bool {
+        let loc_rib = self.loc_rib.lock().await;
+        update.withdrawn_routes().iter().any(|network| {
+            loc_rib
+                .best_path(*network)
+                .is_some_and(|best| best.learned_from == Some(self.config.remote_ip))
+        })
+    }
+
+    fn routes_per_iteration(&self) -> usize {
+        self.config
+            .max_routes_per_iteration
+            .map_or(usize::MAX, |n| n.max(1))
+    }
+
+    /// 受信したUPDATEをAdj-RIB-Inに反映する。今回の`next`で処理できる数を超える経路は、
+    /// 分けてinbound_backlogに持ち越す。先に持ち越したUPDATEがあれば、その後ろに並べる。
+    fn receive_routes(&mut self, update: UpdateMessage) {
+        if self.inbound_backlog.is_empty() && self.route_budget > 0 {
+            self.install_update(update);
+        } else {
+            self.inbound_backlog.push_back(update);
+        }
+        self.notify_adj_rib_in_changed();
+    }
+
+    /// 持ち越したUPDATEを、今回の`next`で処理できる数だけ反映する。
+    fn process_inbound_backlog(&mut self) {
+        if self.inbound_backlog.is_empty() {
+            return;
+        }
+        while self.route_budget > 0 {
+            let Some(update) = self.inbound_backlog.pop_front() else {
+                break;
+            };
+            self.install_update(update);
+        }
+        debug!(
+            "{} updates are held in inbound backlog.",
+            self.inbound_backlog.len()
+        );
+        self.notify_adj_rib_in_changed();
+    }
+
+    /// Adj-RIB-Inが変化していれば、LocRibへの反映を1つだけ積む。
+    /// 経路を分けて処理しても、反映のeventでtimerのeventが後回しにならないようにする。
+    fn notify_adj_rib_in_changed(&mut self) {
+        if self.adj_rib_in.does_contain_changed_route()
+            && !self.event_queue.contains(&Event::AdjRibInChanged)
+        {
+            debug!("abj_rib in is updated.");
+            self.event_queue.enqueue(Event::AdjRibInChanged);
+        }
+    }
+
+    fn install_update(&mut self, update: UpdateMessage) {
+        let update = if update.routes_len() > self.route_budget {
+            let (head, rest) = update.split_at(self.route_budget);
+            self.inbound_backlog.push_front(rest);
+            head
+        } else {
+            update
+        };
+        self.route_budget = self.route_budget.saturating_sub(update.routes_len().max(1));
+        self.store_pre_policy_routes(&update);
+        self.adj_rib_in.install_from_update(update, &self.config);
+    }
+
+    /// policyの適用前の経路を保持する。上限を超えた場合は保持をやめ、Route Refreshに切り替える。
+    fn store_pre_policy_routes(&mut self, update: &UpdateMessage) {
+        let Some(pre_policy_routes) = &mut self.pre_policy_routes else {
+            return;
+        };
+        for network in update
+            .withdrawn_routes
+            .iter()
+            .chain(&update.network_layer_reachability_information)
+        {
+            pre_policy_routes.remove(*network);
+        }
+        for network in &update.network_layer_reachability_information {
+            pre_policy_routes.insert(Arc::new(RibEntry {
+                network_address: *network,
+                path_attributes: Arc::clone(&update.path_attributes),
+                learned_from: Some(self.config.remote_ip),
+            }));
+        }
+        if let Some(limit) = self.config.soft_reconfiguration_limit {
+            if pre_policy_routes.len() > limit {
+                warn!(
+                    "pre-policy routes from {} exceed the limit {}, \
+                    soft reconfiguration is disabled and route refresh is used instead.",
+                    self.config.remote_ip, limit
+                );
+                self.pre_policy_routes = None;
+            }
+        }
+    }
+
+    /// 前回の経路の広告からMRAIが経過していなければtrueを返す。
+    /// MRAIが0の場合は常にfalseなので、広告は保留されずtimerも設定されない。
+    fn is_mrai_running(&self) -> bool {
+        !self.mrai_interval.is_zero()
+            && self.last_announcement_sent.is_some_and(|sent| {
+                self.clock.now().saturating_duration_since(sent) < self.mrai_interval
+            })
+    }
+
+    /// 広告している全ての経路を再送する。
+    /// Enhanced Route Refreshを合意していれば、BoRRとEoRRで囲む。
+    async fn send_route_refresh_response(&mut self, afi: u16, safi: u8) -> Result<(), PeerError> {
+        let updates = if (afi, safi) == (AFI_IPV6, SAFI_UNICAST) {
+            self.ipv6_adj_rib_out.announcement_messages(&self.config)
+        } else {
+            self.adj_rib_out.create_refresh_messages(&self.config)
+        };
+        let enhanced_route_refresh = self.enhanced_route_refresh;
+        let conn = self.connection()?;
+        if enhanced_route_refresh {
+            conn.send(Message::RouteRefresh(RouteRefreshMessage::for_afi_safi(
+                afi,
+                safi,
+                RouteRefreshSubtype::BeginOfRouteRefresh,
+            )))
+            .await;
+        }
+        for update in updates {
+            conn.send(Message::Update(update)).await;
+        }
+        if enhanced_route_refresh {
+            conn.send(Message::RouteRefresh(RouteRefreshMessage::for_afi_safi(
+                afi,
+                safi,
+                RouteRefreshSubtype::EndOfRouteRefresh,
+            )))
+            .await;
+        }
+        Ok(())
+    }
+
+    /// afi, safiが、相手と経路を交換しているaddress familyであればtrue。
+    fn is_exchanged_address_family(&self, afi: u16, safi: u8) -> bool {
+        match (afi, safi) {
+            (AFI_IPV4, SAFI_UNICAST) => true,
+            (AFI_IPV6, SAFI_UNICAST) => self.ipv6_unicast,
+            _ => false,
+        }
+    }
+
+    /// 合意した全てのaddress familyのEnd-of-RIBを送る。
+    async fn send_end_of_rib(&mut self) -> Result<(), PeerError> {
+        let ipv6_unicast = self.ipv6_unicast;
+        let conn = self.connection()?;
+        conn.send(Message::Update(UpdateMessage::new_end_of_rib()))
+            .await;
+        if ipv6_unicast {
+            conn.send(Message::Update(Ipv6UnicastRib::end_of_rib()))
+                .await;
+        }
+        self.end_of_rib_sent = true;
+        Ok(())
+    }
+
+    fn connection(&mut self) -> Result<&mut Connection, PeerError> {
+        self.tcp_connection.as_mut().ok_or(PeerError::NotConnected)
+    }
+
+    fn restart_hold_timer(&mut self) {
+        self.hold_timer_deadline = self.hold_time.map(|hold_time| self.clock.now() + hold_time);
+    }
+
+    /// Keepalive Timerの間隔はHold Timeの1/3にする(RFC 4271 Section 10)。
+    /// jitterで増減させても、Hold Timeの1/2は超えないようにする。
+    fn restart_keepalive_timer(&mut self) {
+        self.keepalive_timer_deadline = self.hold_time.map(|hold_time| {
+            let interval = self.jittered(hold_time / 3).min(hold_time / 2);
+            self.clock.now() + interval
+        });
+    }
+
+    /// intervalをtimer_jitterの範囲でランダムに増減させる。
+    fn jittered(&mut self, interval: Duration) -> Duration {
+        let jitter = f64::from(self.config.timer_jitter) / 100.0;
+        interval.mul_f64(1.0 + jitter * (2.0 * self.rng.f64() - 1.0))
+    }
+
+    fn handle_message(&mut self, message: Message) {
+        match message {
+            Message::Open(open) => self.event_queue.enqueue(Event::BgpOpen(open)),
+            Message::Keepalive(keepalive) => {
+                self.restart_hold_timer();
+                self.event_queue.enqueue(Event::KeepAliveMsg(keepalive))
+            }
+            Message::Update(update) => {
+                self.restart_hold_timer();
+                self.event_queue.enqueue(Event::UpdateMsg(update))
+            }
+            Message::Notification(notification) => {
+                self.notifications_received += 1;
+                warn!(
+                    "notification is received from {} (AS {:?}), error={}.",
+                    self.config.remote_ip,
+                    self.config.remote_as,
+                    notification.description()
+                );
+                debug!("notification data={}.", notification.data_hex());
+                self.event_queue.enqueue(Event::NotifMsg(notification))
+            }
+            Message::RouteRefresh(route_refresh) => self
+                .event_queue
+                .enqueue(Event::RouteRefreshMsg(route_refresh)),
+        }
+    }
+
+    #[instrument(fields(description = self.config.description.as_deref()))]
+    async fn handle_event(&mut self, event: Event) -> Result<(), PeerError> {
+        if let Event::NotifMsg(notification) = &event {
+            // どの状態でもNOTIFICATIONを受信したらセッションを閉じてIdleに戻る。
+            let cause = if notification.is_hard_reset() {
+                CloseCause::HardReset
+            } else {
+                CloseCause::Notification
+            };
+            let collision_dump = notification.is_collision_resolution();
+            self.close_session(cause, &event).await;
+            // OpenCollisionDump: 相手は別の接続を残したので、IdleHoldTimerの満了後に接続し直す。
+            if !self.schedule_dampened_reconnect() && collision_dump {
+                info!("connection is dumped by collision resolution.");
+                self.connect_retry_deadline = Some(self.clock.now());
+            }
+            return Ok(());
+        }
+        match event {
+            Event::HoldTimerExpires => {
+                warn!("hold timer is expired.");
+                if let Some(conn) = &mut self.tcp_connection {
+                    conn.send(Message::new_notification(
+                        HOLD_TIMER_EXPIRED,
+                        0,
+                        BytesMut::new(),
+                    ))
+                    .await;
+                }
+                self.close_session(CloseCause::Notification, &event).await;
+                if !self.schedule_dampened_reconnect() {
+                    self.connect_retry_deadline =
+                        Some(self.clock.now() + self.config.connect_retry_time);
+                }
+                return Ok(());
+            }
+            Event::KeepaliveTimerExpires => {
+                if let Some(conn) = &mut self.tcp_connection {
+                    conn.send(Message::new_keepalive()).await;
+                }
+                self.restart_keepalive_timer();
+                return Ok(());
+            }
+            _ => {}
+        }
+        match &self.state {
+            State::Idle => match event {
+                Event::ManualStart | Event::ConnectRetryTimerExpires
+                    if !self.config.admin_shutdown =>
+                {
+                    #[cfg_attr(not(test), allow(unused_mut))]
+                    let mut conn = match self.accepted_connection.take() {
+                        Some(conn) => conn,
+                        None => Connection::connect(&self.config).await?,
+                    };
+                    #[cfg(test)]
+                    if let Some(faults) = &self.fault_injector {
+                        conn.set_fault_injector(Arc::clone(faults));
+                    }
+                    self.tcp_connection = Some(conn);
+                    if self.config.mode == Mode::Passive && self.admission.is_full() {
+                        self.reject_connection(&event).await;
+                        return Ok(());
+                    }
+                    self.event_queue.enqueue(Event::TcpConnectionConfirmed);
+                    self.transition(State::Connect, &event);
+                }
+                _ => {}
+            },
+            State::Connect => {
+                if event == Event::TcpConnectionConfirmed {
+                    let open =
+                        OpenMessage::new(self.config.advertised_local_as(), self.config.local_ip)
+                            .with_hold_time(self.config.hold_time.into())
+                            .with_capabilities(&self.capabilities());
+                    self.sent_open = Some(open.clone());
+                    self.connection()?.send(Message::Open(open)).await;
+                    self.transition(State::OpenSent, &event);
+                }
+            }
+            State::OpenSent => {
+                if let Event::BgpOpen(ref open) = event {
+                    let graceful_restart = self.config.graceful_restart_time.and_then(|_| {
+                        open.capabilities().into_iter().find_map(|c| match c {
+                            Capability::GracefulRestart {
+                                restart_time,
+                                notification,
+                            } => Some((Duration::from_secs(restart_time.into()), notification)),
+                            _ => None,
+                        })
+                    });
+                    self.graceful_restart_time = graceful_restart.map(|(time, _)| time);
+                    self.graceful_restart_notification =
+                        graceful_restart.is_some_and(|(_, notification)| notification);
+                    let capabilities = open.capabilities();
+                    self.route_refresh = (self.config.route_refresh
+                        || self.config.enhanced_route_refresh)
+                        && capabilities.contains(&Capability::RouteRefresh);
+                    self.enhanced_route_refresh = self.route_refresh
+                        && self.config.enhanced_route_refresh
+                        && capabilities.contains(&Capability::EnhancedRouteRefresh);
+                    self.ipv6_unicast = self.config.ipv6_unicast
+                        && capabilities.contains(&Capability::Multiprotocol {
+                            afi: AFI_IPV6,
+                            safi: SAFI_UNICAST,
+                        });
+                    let hold_time = self.config.hold_time.min(open.hold_time().into());
+                    self.hold_time = (hold_time > 0).then(|| Duration::from_secs(hold_time.into()));
+                    let local_capabilities = self.capabilities();
+                    self.negotiated = Some(NegotiatedParams {
+                        hold_time,
+                        keepalive_interval: hold_time / 3,
+                        capabilities: capabilities
+                            .iter()
+                            .filter(|c| local_capabilities.iter().any(|l| l.code() == c.code()))
+                            .map(|c| c.name().to_owned())
+                            .collect(),
+                        remote_router_id: open.bgp_identifier(),
+                        remote_as: open.my_as_number().into(),
+                    });
+                    self.received_open = Some(open.clone());
+                    // 相手はKEEPALIVEを受信してからUPDATEを送るので、その前に切り替える。
+                    #[cfg(feature = "update-compression")]
+                    if self.config.update_compression
+                        && capabilities.contains(&Capability::UpdateCompression)
+                    {
+                        self.connection()?.enable_update_compression();
+                    }
+                    self.connection()?.send(Message::new_keepalive()).await;
+                    self.restart_hold_timer();
+                    self.restart_keepalive_timer();
+                    self.connect_retry_counter = 0;
+                    self.connect_retry_deadline = None;
+                    self.transition(State::OpenConfirm, &event);
+                }
+            }
+            State::OpenConfirm => {
+                if let Event::KeepAliveMsg(_) = event {
+                    self.admission_ticket = self.admission.try_admit();
+                    if self.admission_ticket.is_none() {
+                        self.reject_connection(&event).await;
+                        return Ok(());
+                    }
+                    self.transition(State::Established, &event);
+                    self.established_at = Some(Instant::now());
+                    self.stable_since = Some(self.clock.now());
+                    self.convergence_time = None;
+                    self.report_peer_up().await;
+                    self.event_queue.enqueue(Event::Established);
+                }
+            }
+            State::Established => match event {
+                Event::Established | Event::LocRibChanged => {
+                    let loc_rib = self.loc_rib.lock().await;
+                    self.adj_rib_out
+                        .install_from_loc_rib(&loc_rib, &self.config);
+                    if self.adj_rib_out.does_contain_changed_route() || !self.end_of_rib_sent {
+                        self.event_queue.enqueue(Event::AdjRibOutChanged);
+                    }
+                    let ipv6_updates = if self.ipv6_unicast {
+                        self.ipv6_adj_rib_out
+                            .update_from(loc_rib.ipv6_unicast_rib(), &self.config)
+                    } else {
+                        vec![]
+                    };
+                    drop(loc_rib);
+                    for update in ipv6_updates {
+                        self.connection()?.send(Message::Update(update)).await;
+                    }
+                }
+                Event::AdjRibOutChanged => {
+                    // 経路の取り消しを遅らせるとブラックホールになるので、MRAIに関わらずすぐに送る。
+                    let withdrawals = self.adj_rib_out.create_withdrawal_messages();
+                    if self.paced_updates.is_empty() {
+                        for update in withdrawals {
+                            self.connection()?.send(Message::Update(update)).await;
+                        }
+                    } else {
+                        self.paced_updates.extend(withdrawals);
+                    }
+                    self.adj_rib_out.remove_withdrawn_routes();
+
+                    if self.is_mrai_running() {
+                        debug!("announcements are held until mrai expires.");
+                        self.announcement_held = true;
+                        return Ok(());
+                    }
+                    let announcements = self.adj_rib_out.create_announcement_messages(&self.config);
+                    if !announcements.is_empty() {
+                        self.last_announcement_sent = Some(self.clock.now());
+                        self.mrai_interval = self.jittered(self.config.mrai);
+                    }
+                    let paces = !self.end_of_rib_sent && self.paced_updates.is_empty();
+                    match self.config.initial_advertisement_window {
+                        Some(window) if paces => {
+                            self.paced_updates.extend(announcements);
+                            let routes: usize =
+                                self.paced_updates.iter().map(|u| u.routes_len()).sum();
+                            let batches = (window.as_millis()
+                                / ADVERTISEMENT_PACING_INTERVAL.as_millis())
+                            .max(1) as usize;
+                            self.paced_batch_size = routes.div_ceil(batches).max(1);
+                            info!(
+                                "{} routes are advertised in {} batches over {:?}.",
+                                routes, batches, window
+                            );
+                            self.send_paced_updates().await?;
+                        }
+                        _ if !self.paced_updates.is_empty() => {
+                            self.paced_updates.extend(announcements)
+                        }
+                        _ => {
+                            for update in announcements {
+                                self.connection()?.send(Message::Update(update)).await;
+                            }
+                        }
+                    }
+                    self.adj_rib_out.mark_new_routes_unchanged();
+                    if !self.end_of_rib_sent && self.paced_updates.is_empty() {
+                        self.send_end_of_rib().await?;
+                    }
+                }
+                Event::BgpOpen(_) => {
+                    warn!("unexpected open is received in established state.");
+                    self.connection()?
+                        .send(Message::new_notification(
+                            FINITE_STATE_MACHINE_ERROR,
+                            RECEIVE_UNEXPECTED_MESSAGE_IN_ESTABLISHED_STATE,
+                            BytesMut::new(),
+                        ))
+                        .await;
+                    self.close_session(CloseCause::Notification, &event).await;
+                    self.schedule_dampened_reconnect();
+                }
+                // 持ち越した経路を全て反映するまで、End-of-RIBとRoute Refreshの処理を遅らせる。
+                Event::UpdateMsg(ref update)
+                    if update.is_end_of_rib() && !self.inbound_backlog.is_empty() =>
+                {
+                    self.event_queue.enqueue(event);
+                }
+                Event::RouteRefreshMsg(_) if !self.inbound_backlog.is_empty() => {
+                    self.event_queue.enqueue(event);
+                }
+                Event::UpdateMsg(update) if update.is_end_of_rib() => {
+                    info!("end-of-rib is received.");
+                    self.report_route_monitoring(&update).await;
+                    self.end_of_rib_received = true;
+                    if self.stale_routes_deadline.is_some() {
+                        self.purge_stale_routes().await;
+                    }
+                }
+                Event::UpdateMsg(update) => {
+                    debug!("{} is received.", update);
+                    self.report_route_monitoring(&update).await;
+                    self.flowspec_rib.install_from_update(&update);
+                    if self.ipv6_unicast {
+                        self.ipv6_adj_rib_in.install_from_update(&update);
+                    }
+                    let withdraws_best_path =
+                        self.config.fast_reroute && self.withdraws_best_path(&update).await;
+                    self.receive_routes(update);
+                    if withdraws_best_path && !self.exceeds_max_prefix() {
+                        self.install_adj_rib_in_to_loc_rib().await;
+                    }
+                }
+                Event::AdjRibInChanged => {
+                    if self.exceeds_max_prefix() {
+                        self.close_session_by_max_prefix(&event).await;
+                        return Ok(());
+                    }
+                    self.install_adj_rib_in_to_loc_rib().await;
+                }
+                // 要求や、BoRR, EoRRの対象のaddress familyの経路だけを再送したりstaleにしたりする。
+                Event::RouteRefreshMsg(route_refresh) if self.route_refresh => {
+                    let (afi, safi) = route_refresh.afi_safi();
+                    if !self.is_exchanged_address_family(afi, safi) {
+                        warn!(
+                            "route refresh for unsupported afi/safi {:?} is ignored.",
+                            (afi, safi)
+                        );
+                        return Ok(());
+                    }
+                    let ipv6_unicast = (afi, safi) == (AFI_IPV6, SAFI_UNICAST);
+                    match route_refresh.subtype {
+                        RouteRefreshSubtype::Request => {
+                            self.send_route_refresh_response(afi, safi).await?
+                        }
+                        // 再送される経路で置き換えるまでは、受信済みの経路をstaleとして使い続ける。
+                        RouteRefreshSubtype::BeginOfRouteRefresh if self.enhanced_route_refresh => {
+                            info!(
+                                "begin-of-route-refresh is received, afi/safi={:?}.",
+                                (afi, safi)
+                            );
+                            if ipv6_unicast {
+                                self.ipv6_adj_rib_in.mark_all_stale();
+                            } else {
+                                self.adj_rib_in.mark_all_stale();
+                            }
+                        }
+                        // 再送されなかった経路を取り除く。
+                        RouteRefreshSubtype::EndOfRouteRefresh if self.enhanced_route_refresh => {
+                            info!(
+                                "end-of-route-refresh is received, afi/safi={:?}.",
+                                (afi, safi)
+                            );
+                            if ipv6_unicast {
+                                self.ipv6_adj_rib_in.purge_stale_routes();
+                            } else {
+                                self.purge_stale_routes().await;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            },
+        }
+        Ok(())
+    }
+}
+
+/// いずれかのpeerに処理することができるまで待つ。peerが無ければ待ち続ける。
+/// 同じtaskで動かす複数のpeerを、1つずつ待って他のpeerの処理を止めないようにする。
+pub async fn wait_for_any_activity(peers: &mut [Peer]) {
+    if peers.is_empty() {
+        return std::future::pending().await;
+    }
+    select_all(
+        peers
+            .iter_mut()
+            .map(|peer| Box::pin(peer.wait_for_activity())),
+    )
+    .await;
+}
+
+/// 全てのpeerが収束する(`Peer::is_converged`)かtimeoutするまでpeerを動かし、
+/// 最後に全てのpeerへCeaseを送って停止する。収束した場合はtrueを返す。
+pub async fn run_until_converged(peers: &mut [Peer], timeout: Duration) -> bool {
+    let converged = tokio::time::timeout(timeout, async {
+        while !peers.iter().all(|peer| peer.is_converged()) {
+            wait_for_any_activity(peers).await;
+            for peer in peers.iter_mut() {
+                peer.poll().await;
+            }
+        }
+    })
+    .await
+    .is_ok();
+    join_all(peers.iter_mut().map(|peer| peer.notify_shutdown())).await;
+    converged
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::bmp::{BmpMessageType, PER_PEER_HEADER_LENGTH};
+    use crate::clock::MockClock;
+    use crate::fault::{Direction, Fault, Trigger};
+    use crate::fib::{FibBarrier, InMemoryFib};
+    use crate::packets::header::MessageType;
+    use crate::packets::keepalive::KeepaliveMessage;
+    use crate::packets::notification::NotificationMessage;
+    use crate::path_attribute::{AsPath, PathAttribute};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::sleep;
+
+    /// `step`で、peerに処理することが無いときに待つ時間の上限。
+    const STEP_WAIT: Duration = Duration::from_millis(100);
+
+    /// 相手の処理や時計が進むのを待つテストで、peerに処理することが無くても止まらないよう、
+    /// 待つ時間を区切ってから処理を1回進める。
+    async fn step(peer: &mut Peer) {
+        let _ = tokio::time::timeout(STEP_WAIT, peer.wait_for_activity()).await;
+        peer.poll_once().await;
+    }
+
+    /// 特権ポート179を使わずにテストできるよう、空いている高位ポートを探す。
+    fn unused_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    fn loopback_configs() -> (Config, Config) {
+        let port = unused_port();
+        let config = format!("64512 127.0.0.2 64513 127.0.0.3 active port={port}")
+            .parse()
+            .unwrap();
+        let remote_config = format!("64513 127.0.0.3 64512 127.0.0.2 passive port={port}")
+            .parse()
+            .unwrap();
+        (config, remote_config)
+    }
+
+    #[tokio::test]
+    async fn peer_can_transition_to_open_sent_state() {
+        let (config, remote_config) = loopback_configs();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.start();
+
+        tokio::spawn(async move {
+            let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+            let mut remote_peer = Peer::new(remote_config, Arc::clone(&remote_loc_rib));
+            remote_peer.start();
+            remote_peer.next().await;
+            remote_peer.next().await;
+        });
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        peer.next().await;
+        peer.next().await;
+        assert_eq!(peer.state, State::OpenSent);
+    }
+
+    #[tokio::test]
+    async fn peer_can_transition_to_connect_state() {
+        let (config, remote_config) = loopback_configs();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.start();
+        assert_eq!(peer.pending_events(), vec![Event::ManualStart]);
+
+        tokio::spawn(async move {
+            let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+            let mut remote_peer = Peer::new(remote_config, Arc::clone(&remote_loc_rib));
+            remote_peer.start();
+            remote_peer.next().await;
+        });
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        peer.next().await;
+        assert_eq!(peer.state, State::Connect);
+        assert_eq!(peer.pending_events(), vec![Event::TcpConnectionConfirmed]);
+    }
+
+    #[tokio::test]
+    async fn poll_reports_state_change_from_idle_to_connect() {
+        let (config, remote_config) = loopback_configs();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        assert_eq!(peer.poll().await, PeerProgress::Idle);
+
+        tokio::spawn(async move {
+            let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+            let mut remote_peer = Peer::new(remote_config, Arc::clone(&remote_loc_rib));
+            remote_peer.start();
+            remote_peer.next().await;
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        peer.start();
+        assert_eq!(
+            peer.poll().await,
+            PeerProgress::StateChanged(State::Connect)
+        );
+        assert_eq!(
+            peer.poll().await,
+            PeerProgress::StateChanged(State::OpenSent)
+        );
+    }
+
+    #[tokio::test]
+    async fn peer_can_transition_to_open_confirm_state() {
+        let (config, remote_config) = loopback_configs();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.start();
+
+        tokio::spawn(async move {
+            let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+            let mut remote_peer = Peer::new(remote_config, Arc::clone(&remote_loc_rib));
+            remote_peer.start();
+            let max_step = 50;
+            for _ in 0..max_step {
+                remote_peer.next().await;
+                if remote_peer.state == State::OpenConfirm {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+            }
+        });
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let max_step = 50;
+        for _ in 0..max_step {
+            peer.next().await;
+            if peer.state == State::OpenConfirm {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+        assert_eq!(peer.state, State::OpenConfirm);
+    }
+
+    #[tokio::test]
+    async fn peer_can_transition_to_established_state() {
+        let (config, remote_config) = loopback_configs();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.start();
+
+        tokio::spawn(async move {
+            let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+            let mut remote_peer = Peer::new(remote_config, Arc::clone(&remote_loc_rib));
+            remote_peer.start();
+            let max_step = 50;
+            for _ in 0..max_step {
+                remote_peer.next().await;
+                if remote_peer.state == State::Established {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+            }
+        });
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let max_step = 50;
+        for _ in 0..max_step {
+            peer.next().await;
+            if peer.state == State::Established {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+        assert_eq!(peer.state, State::Established);
+    }
+
+    #[tokio::test]
+    async fn passive_then_active_peer_connects_actively_after_timeout() {
+        let (mut config, remote_config) = loopback_configs();
+        config.mode = "passive_then_active:1".parse().unwrap();
+        assert_eq!(config.mode, Mode::PassiveThenActive(Duration::from_secs(1)));
+        let port = config.port;
+        // 相手はpassiveなので、こちらへ接続してくることは無い。
+        tokio::spawn(async move {
+            let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+            let mut remote_peer = Peer::new(remote_config, remote_loc_rib);
+            remote_peer.start();
+            loop {
+                remote_peer.next().await;
+            }
+        });
+
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, loc_rib);
+        peer.start();
+        let started = Instant::now();
+        let established = tokio::time::timeout(Duration::from_secs(10), async {
+            while peer.state != State::Established {
+                peer.next().await;
+            }
+        })
+        .await;
+        assert!(established.is_ok());
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        // 自分から相手のBGPのportへ接続している。
+        let conn = peer.tcp_connection.as_ref().unwrap();
+        assert_eq!(conn.peer_addr().unwrap().port(), port);
+        assert_ne!(conn.local_addr().unwrap().port(), port);
+    }
+
+    #[tokio::test]
+    async fn negotiated_hold_time_is_smaller_of_local_and_remote() {
+        let (mut config, mut remote_config) = loopback_configs();
+        config.hold_time = 90;
+        config.enhanced_route_refresh = true;
+        remote_config.hold_time = 30;
+        remote_config.enhanced_route_refresh = true;
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.start();
+        assert_eq!(peer.negotiated_params(), None);
+
+        tokio::spawn(async move {
+            let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+            let mut remote_peer = Peer::new(remote_config, Arc::clone(&remote_loc_rib));
+            remote_peer.start();
+            for _ in 0..50 {
+                remote_peer.next().await;
+                if remote_peer.state == State::Established {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+            }
+        });
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        for _ in 0..50 {
+            peer.next().await;
+            if peer.state == State::Established {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+        assert_eq!(peer.state, State::Established);
+        let negotiated = peer.negotiated_params().unwrap();
+        assert_eq!(negotiated.hold_time, 30);
+        assert_eq!(negotiated.keepalive_interval, 10);
+        assert_eq!(negotiated.remote_as, 64513);
+        assert_eq!(
+            negotiated.remote_router_id,
+            std::net::Ipv4Addr::new(127, 0, 0, 3)
+        );
+        assert_eq!(
+            negotiated.capabilities,
+            vec!["Route Refresh", "Enhanced Route Refresh"]
+        );
+        assert_eq!(peer.status().negotiated.as_ref(), Some(negotiated));
+
+        peer.stop();
+        assert_eq!(peer.negotiated_params(), None);
+    }
+
+    #[tokio::test]
+    async fn admin_shutdown_peer_stays_idle_until_cleared() {
+        let (_, remote_config) = loopback_configs();
+        let config: Config = format!(
+            "64512 127.0.0.2 64513 127.0.0.3 active port={} admin_shutdown=true",
+            remote_config.port
+        )
+        .parse()
+        .unwrap();
+        assert!(config.admin_shutdown);
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.start();
+
+        tokio::spawn(async move {
+            let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+            let mut remote_peer = Peer::new(remote_config, Arc::clone(&remote_loc_rib));
+            remote_peer.start();
+            let max_step = 100;
+            for _ in 0..max_step {
+                remote_peer.next().await;
+                if remote_peer.state == State::Established {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+            }
+        });
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        // 管理上停止している間は何も起こらないので、next()は待ち続ける。
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), peer.next())
+                .await
+                .is_err()
+        );
+        assert_eq!(peer.state, State::Idle);
+
+        peer.set_admin_shutdown(false).await;
+        let max_step = 50;
+        for _ in 0..max_step {
+            peer.next().await;
+            if peer.state == State::Established {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+        assert_eq!(peer.state, State::Established);
+    }
+
+    #[tokio::test]
+    async fn cease_reaches_remote_before_connection_is_closed() {
+        let config: Config = format!(
+            "64512 127.0.0.2 64513 127.0.0.3 active port={}",
+            unused_port()
+        )
+        .parse()
+        .unwrap();
+        let listener = tokio::net::TcpListener::bind((config.remote_ip, config.port))
+            .await
+            .unwrap();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config.clone(), loc_rib);
+        peer.set_fib(Arc::new(InMemoryFib::new()));
+        peer.tcp_connection = Some(Connection::connect(&config).await.unwrap());
+        let (mut remote, _) = listener.accept().await.unwrap();
+        peer.state = State::Established;
+
+        // peerが読み切れないほどのKEEPALIVEを送り、未読のデータを残したまま停止させる。
+        let keepalive: BytesMut = Message::new_keepalive().into();
+        for _ in 0..5000 {
+            remote.write_all(&keepalive).await.unwrap();
+        }
+        sleep(Duration::from_millis(100)).await;
+        peer.set_admin_shutdown(true).await;
+
+        let mut received = vec![];
+        let closed_cleanly = loop {
+            let mut buf = [0; 4096];
+            match remote.read(&mut buf).await {
+                Ok(0) => break true,
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+                Err(_) => break false,
+            }
+        };
+        assert!(closed_cleanly);
+        assert_eq!(
+            Message::try_from(&received[..]).unwrap(),
+            Message::new_notification(CEASE, ADMINISTRATIVE_SHUTDOWN, BytesMut::new())
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_update_is_answered_with_update_message_error() {
+        let config: Config = format!(
+            "64512 127.0.0.2 64513 127.0.0.3 active port={}",
+            unused_port()
+        )
+        .parse()
+        .unwrap();
+        let listener = tokio::net::TcpListener::bind((config.remote_ip, config.port))
+            .await
+            .unwrap();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config.clone(), loc_rib);
+        peer.set_fib(Arc::new(InMemoryFib::new()));
+        peer.tcp_connection = Some(Connection::connect(&config).await.unwrap());
+        let (mut remote, _) = listener.accept().await.unwrap();
+        peer.state = State::Established;
+
+        // ORIGINの値が3のUPDATE。
+        let mut malformed = vec![0xff; 16];
+        malformed.extend([
+            0x00, 0x1b, 0x02, 0x00, 0x00, 0x00, 0x04, 0x40, 0x01, 0x01, 0x03,
+        ]);
+        remote.write_all(&malformed).await.unwrap();
+        for _ in 0..10 {
+            peer.next().await;
+            if peer.state == State::Idle {
+                break;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(peer.state, State::Idle);
+        assert_eq!(peer.dead_letters.dead_letters().len(), 1);
+
+        let mut received = vec![];
+        while let Ok(n) = remote.read_buf(&mut received).await {
+            if n == 0 {
+                break;
+            }
+        }
+        assert_eq!(
+            Message::try_from(&received[..]).unwrap(),
+            Message::new_notification(3, 6, BytesMut::new())
+        );
+    }
+
+    #[tokio::test]
+    async fn initial_advertisement_is_spread_over_window() {
+        let config: Config = format!(
+            "64512 127.0.0.2 64513 127.0.0.3 active port={} initial_advertisement_window=1",
+            unused_port()
+        )
+        .parse()
+        .unwrap();
+        let listener = tokio::net::TcpListener::bind((config.remote_ip, config.port))
+            .await
+            .unwrap();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        for i in 0..1000u32 {
+            let network: Ipv4Network = format!("10.{}.{}.0/24", 100 + i / 256, i % 256)
+                .parse()
+                .unwrap();
+            loc_rib
+                .lock()
+                .await
+                .originate(network, config.local_ip, vec![], &InMemoryFib::new())
+                .await
+                .unwrap();
+        }
+        let clock = Arc::new(MockClock::new());
+        let mut peer = Peer::with_clock(config.clone(), loc_rib, clock.clone());
+        peer.set_fib(Arc::new(InMemoryFib::new()));
+        peer.tcp_connection = Some(Connection::connect(&config).await.unwrap());
+        let (mut remote, _) = listener.accept().await.unwrap();
+        peer.state = State::Established;
+
+        let pending_routes =
+            |peer: &Peer| -> usize { peer.paced_updates.iter().map(|u| u.routes_len()).sum() };
+        peer.handle_event(Event::Established).await.unwrap();
+        peer.handle_event(Event::AdjRibOutChanged).await.unwrap();
+        let mut pending = vec![pending_routes(&peer)];
+        while !peer.end_of_rib_sent {
+            assert!(pending.len() <= 10, "{pending:?}");
+            clock.advance(ADVERTISEMENT_PACING_INTERVAL);
+            peer.poll_once().await;
+            pending.push(pending_routes(&peer));
+        }
+        // 1秒を100msごとに10回に分け、100経路ずつ送る。
+        assert_eq!(pending, (0..10).rev().map(|i| i * 100).collect::<Vec<_>>());
+
+        let mut received = vec![];
+        let mut advertised = 0;
+        loop {
+            let mut buf = [0; 4096];
+            let n = remote.read(&mut buf).await.unwrap();
+            received.extend_from_slice(&buf[..n]);
+            while received.len() >= 19 {
+                let length = usize::from(u16::from_be_bytes([received[16], received[17]]));
+                if received.len() < length {
+                    break;
+                }
+                let message: Vec<u8> = received.drain(..length).collect();
+                if let Message::Update(update) = Message::try_from(&message[..]).unwrap() {
+                    if update.is_end_of_rib() {
+                        assert_eq!(advertised, 1000);
+                        return;
+                    }
+                    advertised += update.network_layer_reachability_information.len();
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn inbound_session_is_rejected_once_max_peers_are_established() {
+        async fn drive_until_established(mut peer: Peer) -> Peer {
+            for _ in 0..50 {
+                step(&mut peer).await;
+                if peer.state == State::Established {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+            }
+            peer
+        }
+
+        let (first_port, second_port) = (unused_port(), unused_port());
+        let config = |local: &str, remote: &str, mode: &str, port: u16| -> Config {
+            format!("{local} {remote} {mode} port={port}")
+                .parse()
+                .unwrap()
+        };
+        let admission = Arc::new(AdmissionControl::new(Some(1)));
+        let local_config = config("64512 127.0.0.2", "64513 127.0.0.3", "passive", first_port);
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&local_config).await.unwrap()));
+        let new_peer = |config: Config| {
+            let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+            peer.set_admission_control(Arc::clone(&admission));
+            peer.start();
+            peer
+        };
+        let first = new_peer(local_config);
+        let second = new_peer(config(
+            "64512 127.0.0.2",
+            "64514 127.0.0.4",
+            "passive",
+            second_port,
+        ));
+
+        let first_remote_config =
+            config("64513 127.0.0.3", "64512 127.0.0.2", "active", first_port);
+        let first_remote_loc_rib =
+            Arc::new(Mutex::new(LocRib::new(&first_remote_config).await.unwrap()));
+        let mut first_remote = Peer::new(first_remote_config, first_remote_loc_rib);
+        first_remote.start();
+        let first = tokio::spawn(drive_until_established(first));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let first_remote = tokio::spawn(drive_until_established(first_remote));
+        let first = first.await.unwrap();
+        let _first_remote = first_remote.await.unwrap();
+        assert_eq!(first.state, State::Established);
+        assert_eq!(admission.established(), 1);
+
+        let second = tokio::spawn(async move {
+            let mut second = second;
+            let mut states = vec![];
+            for _ in 0..20 {
+                step(&mut second).await;
+                states.push(second.state);
+                tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+            }
+            states
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let second_remote_config =
+            config("64514 127.0.0.4", "64512 127.0.0.2", "active", second_port);
+        let second_remote_loc_rib = Arc::new(Mutex::new(
+            LocRib::new(&second_remote_config).await.unwrap(),
+        ));
+        let mut second_remote = Peer::new(second_remote_config, second_remote_loc_rib);
+        second_remote.start();
+        for _ in 0..20 {
+            step(&mut second_remote).await;
+            if second_remote.notifications_received() > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+
+        assert_eq!(second_remote.notifications_received(), 1);
+        assert_ne!(second_remote.state, State::Established);
+        assert!(!second.await.unwrap().contains(&State::Established));
+        assert_eq!(first.state, State::Established);
+        assert_eq!(admission.established(), 1);
+    }
+
+    #[tokio::test]
+    async fn peers_exit_cleanly_after_convergence_in_once_mode() {
+        let (config, remote_config) = loopback_configs();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.start();
+
+        let remote = tokio::spawn(async move {
+            let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+            let mut remote_peer = Peer::new(remote_config, Arc::clone(&remote_loc_rib));
+            remote_peer.start();
+            loop {
+                if let PeerProgress::ReceivedMessage(Message::Notification(notification)) =
+                    remote_peer.poll().await
+                {
+                    return notification;
+                }
+                tokio::task::yield_now().await;
+            }
+        });
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let mut peers = [peer];
+        assert!(run_until_converged(&mut peers, Duration::from_secs(10)).await);
+        assert_eq!(peers[0].state, State::Idle);
+        let notification = tokio::time::timeout(Duration::from_secs(10), remote)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (notification.error_code, notification.error_subcode),
+            (CEASE, ADMINISTRATIVE_SHUTDOWN)
+        );
+    }
+
+    #[tokio::test]
+    async fn originated_route_is_advertised_and_withdrawn_after_establishment() {
+        let (config, remote_config) = loopback_configs();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.start();
+
+        let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+        let remote = {
+            let remote_loc_rib = Arc::clone(&remote_loc_rib);
+            tokio::spawn(async move {
+                let mut remote_peer = Peer::new(remote_config, remote_loc_rib);
+                remote_peer.start();
+                loop {
+                    remote_peer.next().await;
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let max_step = 50;
+        for _ in 0..max_step {
+            step(&mut peer).await;
+            if peer.state == State::Established {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+        assert_eq!(peer.state, State::Established);
+
+        let network: Ipv4Network = "10.100.230.0/24".parse().unwrap();
+        let is_advertised = || async {
+            remote_loc_rib
+                .lock()
+                .await
+                .routes()
+                .any(|e| e.network_address == network)
+        };
+        loc_rib
+            .lock()
+            .await
+            .originate(
+                network,
+                "127.0.0.2".parse().unwrap(),
+                vec![],
+                &InMemoryFib::new(),
+            )
+            .await
+            .unwrap();
+        for _ in 0..max_step {
+            step(&mut peer).await;
+            if is_advertised().await {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+        assert!(is_advertised().await);
+
+        loc_rib
+            .lock()
+            .await
+            .withdraw(network, &InMemoryFib::new())
+            .await
+            .unwrap();
+        for _ in 0..max_step {
+            step(&mut peer).await;
+            if !is_advertised().await {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+        assert!(!is_advertised().await);
+        remote.abort();
+    }
+
+    #[tokio::test]
+    async fn originated_route_reaches_remote_fib_through_update_pipeline() {
+        let (mut config, remote_config) = loopback_configs();
+        let network: Ipv4Network = "10.100.220.0/24".parse().unwrap();
+        config.networks = vec![network.to_string().parse().unwrap()];
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        assert!(loc_rib
+            .lock()
+            .await
+            .routes()
+            .any(|e| e.network_address == network));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+
+        let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+        let remote_fib = Arc::new(InMemoryFib::new());
+        // passiveのpeerは接続を待つ間next()から戻らないので、別のtaskで動かす。
+        let remote = {
+            let remote_loc_rib = Arc::clone(&remote_loc_rib);
+            let remote_fib = Arc::clone(&remote_fib);
+            tokio::spawn(async move {
+                let mut remote_peer = Peer::new(remote_config, remote_loc_rib);
+                remote_peer.set_fib(Arc::clone(&remote_fib) as Arc<dyn Fib>);
+                remote_peer.start();
+                while remote_fib.gateway(network).is_none() {
+                    remote_peer.next().await;
+                    tokio::task::yield_now().await;
+                }
+                remote_peer
+            })
+        };
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        peer.start();
+        let installed = tokio::time::timeout(Duration::from_secs(10), async {
+            while !remote.is_finished() {
+                peer.next().await;
+                tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+            }
+        })
+        .await;
+        assert!(installed.is_ok());
+        let remote_peer = remote.await.unwrap();
+        assert_eq!(peer.state, State::Established);
+        assert_eq!(remote_peer.state, State::Established);
+
+        let expected_as_path = PathAttribute::AsPath(AsPath::sequence(vec![64512.into()]));
+        let expected_next_hop = PathAttribute::NextHop("127.0.0.2".parse().unwrap());
+        let received: Vec<_> = remote_peer
+            .adj_rib_in
+            .routes()
+            .filter(|e| e.network_address == network)
+            .cloned()
+            .collect();
+        assert_eq!(received.len(), 1);
+        assert!(received[0].path_attributes.contains(&expected_as_path));
+        assert!(received[0].path_attributes.contains(&expected_next_hop));
+
+        let remote_loc_rib = remote_loc_rib.lock().await;
+        let best = remote_loc_rib.best_path(network).unwrap();
+        assert_eq!(best, &received[0]);
+
+        assert_eq!(
+            remote_fib.routes(),
+            vec![(network, "127.0.0.2".parse().unwrap())]
+        );
+    }
+
+    #[tokio::test]
+    async fn fib_barrier_holds_writes_until_lifted() {
+        let (mut config, remote_config) = loopback_configs();
+        let network: Ipv4Network = "10.100.220.0/24".parse().unwrap();
+        config.static_routes = vec![network.to_string().parse().unwrap()];
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+
+        let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+        let remote_fib = Arc::new(InMemoryFib::new());
+        let barrier = Arc::new(FibBarrier::new(Arc::clone(&remote_fib) as Arc<dyn Fib>));
+        // passiveのpeerは接続を待つ間next()から戻らないので、別のtaskで動かす。
+        let remote = {
+            let barrier = Arc::clone(&barrier);
+            let remote_loc_rib = Arc::clone(&remote_loc_rib);
+            tokio::spawn(async move {
+                let mut remote_peer = Peer::new(remote_config, remote_loc_rib);
+                remote_peer.set_fib(barrier as Arc<dyn Fib>);
+                remote_peer.start();
+                while !remote_peer.is_converged() {
+                    remote_peer.next().await;
+                    tokio::task::yield_now().await;
+                }
+                remote_peer
+            })
+        };
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        peer.start();
+        let converged = tokio::time::timeout(Duration::from_secs(10), async {
+            while !remote.is_finished() {
+                peer.next().await;
+                tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+            }
+        })
+        .await;
+        assert!(converged.is_ok());
+        assert!(remote_loc_rib.lock().await.best_path(network).is_some());
+        assert_eq!(remote_fib.routes(), vec![]);
+
+        barrier.lift().await.unwrap();
+        assert!(barrier.is_lifted());
+        assert_eq!(
+            remote_fib.routes(),
+            vec![(network, "127.0.0.2".parse().unwrap())]
+        );
+    }
+
+    #[tokio::test]
+    async fn received_update_is_exported_to_bmp_collector_as_route_monitoring() {
+        let (mut config, remote_config) = loopback_configs();
+        let network: Ipv4Network = "10.100.220.0/24".parse().unwrap();
+        config.static_routes = vec![network.to_string().parse().unwrap()];
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+
+        let collector = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bmp = Arc::new(
+            BmpClient::connect(collector.local_addr().unwrap())
+                .await
+                .unwrap(),
+        );
+        let (mut collector, _) = collector.accept().await.unwrap();
+
+        let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+        let remote_fib = Arc::new(InMemoryFib::new());
+        // passiveのpeerは接続を待つ間next()から戻らないので、別のtaskで動かす。
+        let remote = {
+            let remote_fib = Arc::clone(&remote_fib);
+            tokio::spawn(async move {
+                let mut remote_peer = Peer::new(remote_config, remote_loc_rib);
+                remote_peer.set_fib(Arc::clone(&remote_fib) as Arc<dyn Fib>);
+                remote_peer.set_bmp_client(bmp);
+                remote_peer.start();
+                while remote_fib.gateway(network).is_none() {
+                    remote_peer.next().await;
+                    tokio::task::yield_now().await;
+                }
+                remote_peer
+            })
+        };
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        peer.start();
+        let installed = tokio::time::timeout(Duration::from_secs(10), async {
+            while !remote.is_finished() {
+                peer.next().await;
+                tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+            }
+        })
+        .await;
+        assert!(installed.is_ok());
+
+        // Initiation, Peer Up, Route Monitoringの順に届く。
+        let mut message_types = vec![];
+        let update = loop {
+            let mut header = [0; 6];
+            collector.read_exact(&mut header).await.unwrap();
+            assert_eq!(header[0], 3);
+            let length = u32::from_be_bytes(header[1..5].try_into().unwrap()) as usize;
+            let mut body = vec![0; length - header.len()];
+            collector.read_exact(&mut body).await.unwrap();
+            message_types.push(header[5]);
+            if header[5] == BmpMessageType::RouteMonitoring as u8 {
+                let bgp_message = BytesMut::from(&body[PER_PEER_HEADER_LENGTH..]);
+                match Message::try_from(bgp_message).unwrap() {
+                    Message::Update(update) => break update,
+                    message => panic!("unexpected message {:?}", message),
+                }
+            }
+        };
+        assert_eq!(
+            message_types,
+            vec![
+                BmpMessageType::Initiation as u8,
+                BmpMessageType::PeerUp as u8,
+                BmpMessageType::RouteMonitoring as u8
+            ]
+        );
+        assert_eq!(update.network_layer_reachability_information, vec![network]);
+    }
+
+    #[tokio::test]
+    async fn peer_counts_and_decodes_received_notifications() {
+        let (config, _) = loopback_configs();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+
+        let expected = [
+            (1, 1, "Message Header Error / Connection Not Synchronized"),
+            (2, 2, "OPEN Message Error / Bad Peer AS"),
+            (3, 11, "UPDATE Message Error / Malformed AS_PATH"),
+            (4, 0, "Hold Timer Expired"),
+            (5, 0, "Finite State Machine Error"),
+            (6, 2, "Cease / Administrative Shutdown"),
+        ];
+        for (i, (error_code, error_subcode, description)) in expected.into_iter().enumerate() {
+            let notification = NotificationMessage::new(error_code, error_subcode, BytesMut::new());
+            assert_eq!(notification.description(), description);
+            peer.handle_message(Message::Notification(notification));
+            assert_eq!(peer.notifications_received(), i as u64 + 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn peer_resets_when_connection_is_not_synchronized() {
+        let (_, remote_config) = loopback_configs();
+        let port = remote_config.port;
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+        let mut peer = Peer::new(remote_config, Arc::clone(&loc_rib));
+        peer.start();
+
+        let garbage_sender = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            // passiveのpeerはremote_ipからの接続だけを受け入れる。
+            let socket = tokio::net::TcpSocket::new_v4().unwrap();
+            socket.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+            let mut stream = socket
+                .connect(format!("127.0.0.3:{port}").parse().unwrap())
+                .await
+                .unwrap();
+            stream.write_all(&[0u8; 70 * 1024]).await.unwrap();
+            let mut received = vec![];
+            stream.read_to_end(&mut received).await.unwrap();
+            received
+        });
+
+        let max_step = 50;
+        for _ in 0..max_step {
+            peer.next().await;
+            if peer.state == State::Idle && peer.tcp_connection.is_none() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+        assert_eq!(peer.state, State::Idle);
+
+        let received = garbage_sender.await.unwrap();
+        let open_length = usize::from(u16::from_be_bytes([received[16], received[17]]));
+        let notification = Message::try_from(&received[open_length..]).unwrap();
+        assert_eq!(
+            notification,
+            Message::new_notification(1, 1, BytesMut::new())
+        );
+    }
+
+    #[tokio::test]
+    async fn withdrawal_is_sent_without_waiting_for_mrai() {
+        let port = unused_port();
+        let config: Config = format!("64512 127.0.0.2 64513 127.0.0.3 active port={port} mrai=60")
+            .parse()
+            .unwrap();
+        let remote_config: Config = format!("64513 127.0.0.3 64512 127.0.0.2 passive port={port}")
+            .parse()
+            .unwrap();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.start();
+
+        let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+        let remote = {
+            let remote_loc_rib = Arc::clone(&remote_loc_rib);
+            tokio::spawn(async move {
+                let mut remote_peer = Peer::new(remote_config, remote_loc_rib);
+                remote_peer.start();
+                loop {
+                    remote_peer.next().await;
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let max_step = 50;
+        for _ in 0..max_step {
+            step(&mut peer).await;
+            if peer.state == State::Established {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+        assert_eq!(peer.state, State::Established);
+
+        let withdrawn: Ipv4Network = "10.100.230.0/24".parse().unwrap();
+        let held: Ipv4Network = "10.100.231.0/24".parse().unwrap();
+        let is_advertised = |network: Ipv4Network| {
+            let remote_loc_rib = Arc::clone(&remote_loc_rib);
+            async move {
+                remote_loc_rib
+                    .lock()
+                    .await
+                    .routes()
+                    .any(|e| e.network_address == network)
+            }
+        };
+        loc_rib
+            .lock()
+            .await
+            .originate(
+                withdrawn,
+                "127.0.0.2".parse().unwrap(),
+                vec![],
+                &InMemoryFib::new(),
+            )
+            .await
+            .unwrap();
+        for _ in 0..max_step {
+            step(&mut peer).await;
+            if is_advertised(withdrawn).await {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+        assert!(is_advertised(withdrawn).await);
+
+        // 最初の広告からMRAIの間は、新しい経路の広告は保留されるが取り消しは送られる。
+        {
+            let mut loc_rib = loc_rib.lock().await;
+            loc_rib
+                .originate(
+                    held,
+                    "127.0.0.2".parse().unwrap(),
+                    vec![],
+                    &InMemoryFib::new(),
+                )
+                .await
+                .unwrap();
+            loc_rib
+                .withdraw(withdrawn, &InMemoryFib::new())
+                .await
+                .unwrap();
+        }
+        for _ in 0..max_step {
+            step(&mut peer).await;
+            if !is_advertised(withdrawn).await {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+        assert!(!is_advertised(withdrawn).await);
+        assert!(!is_advertised(held).await);
+        assert!(peer.announcement_held);
+        remote.abort();
+    }
+
+    #[tokio::test]
+    async fn every_announcement_is_sent_immediately_with_zero_advertisement_interval() {
+        let port = unused_port();
+        let config: Config =
+            format!("64512 127.0.0.2 64513 127.0.0.3 active port={port} advertisement_interval=0")
+                .parse()
+                .unwrap();
+        assert_eq!(config.mrai, Duration::ZERO);
+        let remote_config: Config = format!("64513 127.0.0.3 64512 127.0.0.2 passive port={port}")
+            .parse()
+            .unwrap();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.start();
+
+        let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+        let remote = {
+            let remote_loc_rib = Arc::clone(&remote_loc_rib);
+            tokio::spawn(async move {
+                let mut remote_peer = Peer::new(remote_config, remote_loc_rib);
+                remote_peer.start();
+                loop {
+                    remote_peer.next().await;
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let max_step = 50;
+        for _ in 0..max_step {
+            peer.next().await;
+            if peer.state == State::Established {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+        assert_eq!(peer.state, State::Established);
+
+        let is_advertised = |network: Ipv4Network| {
+            let remote_loc_rib = Arc::clone(&remote_loc_rib);
+            async move {
+                remote_loc_rib
+                    .lock()
+                    .await
+                    .routes()
+                    .any(|e| e.network_address == network)
+            }
+        };
+        // 前の広告の直後に経路を追加しても、保留されずにすぐに広告される。
+        for network in ["10.100.230.0/24", "10.100.231.0/24", "10.100.232.0/24"] {
+            let network: Ipv4Network = network.parse().unwrap();
+            let sent_before = peer.last_announcement_sent;
+            loc_rib
+                .lock()
+                .await
+                .originate(
+                    network,
+                    "127.0.0.2".parse().unwrap(),
+                    vec![],
+                    &InMemoryFib::new(),
+                )
+                .await
+                .unwrap();
+            for _ in 0..max_step {
+                peer.next().await;
+                assert!(!peer.announcement_held);
+                if peer.last_announcement_sent != sent_before {
+                    break;
+                }
+            }
+            assert_ne!(peer.last_announcement_sent, sent_before);
+            for _ in 0..max_step {
+                if is_advertised(network).await {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_secs_f32(0.01)).await;
+            }
+            assert!(is_advertised(network).await);
+        }
+        remote.abort();
+    }
+
+    #[tokio::test]
+    async fn status_contains_neighbor_description() {
+        let config: Config = "64512 127.0.0.2 64513 127.0.0.3 active description=upstream-tokyo"
+            .parse()
+            .unwrap();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.close_session(CloseCause::ConnectionLost, &Event::TcpConnectionFails)
+            .await;
+
+        let status = peer.status();
+        assert_eq!(status.description.as_deref(), Some("upstream-tokyo"));
+        assert!(status
+            .to_json()
+            .contains(r#""description":"upstream-tokyo""#));
+    }
+
+    #[tokio::test]
+    async fn status_contains_top_churning_prefixes_of_loc_rib() {
+        let config: Config = "64512 127.0.0.2 64513 127.0.0.3 active".parse().unwrap();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        let flapping: Ipv4Network = "10.100.220.0/24".parse().unwrap();
+        let stable: Ipv4Network = "10.100.221.0/24".parse().unwrap();
+        {
+            let mut loc_rib = loc_rib.lock().await;
+            let fib = InMemoryFib::new();
+            loc_rib
+                .originate(stable, "127.0.0.2".parse().unwrap(), vec![], &fib)
+                .await
+                .unwrap();
+            for next_hop in ["127.0.0.2", "127.0.0.4", "127.0.0.2"] {
+                loc_rib
+                    .originate(flapping, next_hop.parse().unwrap(), vec![], &fib)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        assert!(peer.observe_loc_rib().await);
+        assert!(!peer.observe_loc_rib().await);
+
+        let status = peer.status();
+        let top: Vec<(&str, u64)> = status
+            .top_churning_prefixes
+            .iter()
+            .map(|p| (p.network.as_str(), p.count))
+            .collect();
+        assert_eq!(top, vec![("10.100.220.0/24", 3), ("10.100.221.0/24", 1)]);
+        assert!(status
+            .to_json()
+            .contains(r#""top_churning_prefixes":[{"network":"10.100.220.0/24","count":3,"#));
+    }
+
+    #[tokio::test]
+    async fn established_peer_resets_with_fsm_error_on_unexpected_open() {
+        let (config, remote_config) = loopback_configs();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.start();
+
+        let remote = tokio::spawn(async move {
+            let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+            let mut remote_peer = Peer::new(remote_config, remote_loc_rib);
+            remote_peer.start();
+            loop {
+                if let PeerProgress::ReceivedMessage(Message::Notification(notification)) =
+                    remote_peer.poll().await
+                {
+                    return notification;
+                }
+                tokio::task::yield_now().await;
+            }
+        });
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let max_step = 50;
+        for _ in 0..max_step {
+            peer.next().await;
+            if peer.state == State::Established {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+        assert_eq!(peer.state, State::Established);
+
+        let Message::Open(open) = Message::new_open(64513.into(), "127.0.0.3".parse().unwrap())
+        else {
+            unreachable!()
+        };
+        peer.event_queue.enqueue(Event::BgpOpen(open));
+        for _ in 0..max_step {
+            peer.next().await;
+            if peer.state == State::Idle {
+                break;
+            }
+        }
+        assert_eq!(peer.state, State::Idle);
+        assert!(peer.tcp_connection.is_none());
+        let notification = tokio::time::timeout(Duration::from_secs(10), remote)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (notification.error_code, notification.error_subcode),
+            (
+                FINITE_STATE_MACHINE_ERROR,
+                RECEIVE_UNEXPECTED_MESSAGE_IN_ESTABLISHED_STATE
+            )
+        );
+    }
+
+    fn update_from(network: &str, as_path: Vec<u32>, next_hop: &str) -> UpdateMessage {
+        use crate::path_attribute::{AsPath, Origin, PathAttribute};
+        UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::sequence(
+                    as_path.into_iter().map(Into::into).collect(),
+                )),
+                PathAttribute::NextHop(next_hop.parse().unwrap()),
+            ]),
+            vec![network.parse().unwrap()],
+            vec![],
+        )
+    }
+
+    #[tokio::test]
+    async fn graceful_restart_retains_routes_as_stale_and_depreferred() {
+        let (mut config, _) = loopback_configs();
+        config.graceful_restart_time = Some(120);
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config.clone(), Arc::clone(&loc_rib));
+        peer.state = State::Established;
+        peer.graceful_restart_time = Some(Duration::from_secs(120));
+
+        let network: Ipv4Network = "10.100.240.0/24".parse().unwrap();
+        peer.adj_rib_in.install_from_update(
+            update_from("10.100.240.0/24", vec![64513], "127.0.0.3"),
+            &config,
+        );
+        loc_rib
+            .lock()
+            .await
+            .intsall_from_adj_rib_in(&peer.adj_rib_in);
+        peer.adj_rib_in.update_to_all_changed();
+
+        peer.close_session(CloseCause::ConnectionLost, &Event::TcpConnectionFails)
+            .await;
+        assert_eq!(peer.state, State::Idle);
+        assert_eq!(peer.status().stale_routes, vec!["10.100.240.0/24"]);
+        assert!(peer
+            .status()
+            .to_json()
+            .contains(r#""stale_routes":["10.100.240.0/24"]"#));
+
+        // 別のpeerから受信した、AS_PATHは長いがstaleでない経路が優先される。
+        let mut other_adj_rib_in = AdjRibIn::new();
+        other_adj_rib_in.install_from_update(
+            update_from("10.100.240.0/24", vec![64514, 64515], "127.0.0.4"),
+            &config,
+        );
+        let mut loc_rib = loc_rib.lock().await;
+        loc_rib.intsall_from_adj_rib_in(&other_adj_rib_in);
+        assert_eq!(loc_rib.routes().filter(|e| loc_rib.is_stale(e)).count(), 1);
+        assert_eq!(
+            loc_rib.best_path(network).unwrap().path_attributes,
+            other_adj_rib_in.routes().next().unwrap().path_attributes
+        );
+    }
+
+    #[tokio::test]
+    async fn session_without_graceful_restart_purges_routes() {
+        let (config, _) = loopback_configs();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config.clone(), Arc::clone(&loc_rib));
+        peer.state = State::Established;
+
+        peer.adj_rib_in.install_from_update(
+            update_from("10.100.240.0/24", vec![64513], "127.0.0.3"),
+            &config,
+        );
+        peer.install_adj_rib_in_to_loc_rib().await;
+        assert_eq!(loc_rib.lock().await.len(), 1);
+
+        peer.close_session(CloseCause::ConnectionLost, &Event::TcpConnectionFails)
+            .await;
+        assert!(peer.status().stale_routes.is_empty());
+        assert!(peer.adj_rib_in.is_empty());
+        assert!(loc_rib.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn hard_reset_purges_routes_while_cease_retains_them_as_stale() {
+        let (mut config, _) = loopback_configs();
+        config.graceful_restart_time = Some(120);
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config.clone(), Arc::clone(&loc_rib));
+        let establish_with_routes = async |peer: &mut Peer| {
+            peer.state = State::Established;
+            peer.graceful_restart_time = Some(Duration::from_secs(120));
+            peer.graceful_restart_notification = true;
+            peer.adj_rib_in.install_from_update(
+                update_from("10.100.240.0/24", vec![64513], "127.0.0.3"),
+                &config,
+            );
+            peer.install_adj_rib_in_to_loc_rib().await;
+        };
+
+        establish_with_routes(&mut peer).await;
+        let cease = NotificationMessage::new(6, 2, BytesMut::new());
+        peer.handle_event(Event::NotifMsg(cease)).await.unwrap();
+        assert_eq!(peer.state, State::Idle);
+        assert_eq!(peer.status().stale_routes, vec!["10.100.240.0/24"]);
+        assert_eq!(loc_rib.lock().await.len(), 1);
+
+        establish_with_routes(&mut peer).await;
+        let hard_reset = NotificationMessage::new_hard_reset(6, 4, BytesMut::new());
+        assert_eq!(
+            hard_reset.description(),
+            "Cease / Hard Reset (Cease / Administrative Reset)"
+        );
+        let bytes: BytesMut = hard_reset.clone().into();
+        assert!(NotificationMessage::try_from(bytes)
+            .unwrap()
+            .is_hard_reset());
+        peer.handle_event(Event::NotifMsg(hard_reset))
+            .await
+            .unwrap();
+        assert_eq!(peer.state, State::Idle);
+        assert!(peer.status().stale_routes.is_empty());
+        assert!(peer.adj_rib_in.is_empty());
+        assert!(loc_rib.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn hold_timer_expires_on_mock_clock_without_keepalive() {
+        let (mut config, _) = loopback_configs();
+        config.hold_time = 9;
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let clock = Arc::new(MockClock::new());
+        let mut peer = Peer::with_clock(config, loc_rib, clock.clone());
+        peer.state = State::Established;
+        peer.hold_time = Some(Duration::from_secs(9));
+        peer.restart_hold_timer();
+
+        clock.advance(Duration::from_secs(5));
+        step(&mut peer).await;
+        peer.handle_message(Message::Keepalive(KeepaliveMessage::new()));
+        step(&mut peer).await;
+        clock.advance(Duration::from_secs(5));
+        step(&mut peer).await;
+        assert_eq!(peer.state, State::Established);
+
+        clock.advance(Duration::from_secs(5));
+        step(&mut peer).await;
+        step(&mut peer).await;
+        assert_eq!(peer.state, State::Idle);
+        assert_eq!(peer.hold_timer_deadline, None);
+    }
+
+    #[tokio::test]
+    async fn hold_timer_recovers_open_confirm_when_keepalives_are_dropped() {
+        let (mut config, mut remote_config) = loopback_configs();
+        config.hold_time = 9;
+        remote_config.hold_time = 9;
+        let faults = Arc::new(FaultInjector::new().with_fault(
+            Direction::Send,
+            Trigger::Type(MessageType::Keepalive),
+            Fault::Drop,
+        ));
+        let remote = {
+            let faults = Arc::clone(&faults);
+            tokio::spawn(async move {
+                let remote_loc_rib =
+                    Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+                let mut remote_peer = Peer::new(remote_config, remote_loc_rib);
+                remote_peer.set_fault_injector(faults);
+                remote_peer.start();
+                while remote_peer.notifications_received() == 0 {
+                    remote_peer.next().await;
+                }
+                remote_peer
+            })
+        };
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let clock = Arc::new(MockClock::new());
+        let mut peer = Peer::with_clock(config, loc_rib, clock.clone());
+        peer.start();
+        let open_confirm = tokio::time::timeout(Duration::from_secs(5), async {
+            while peer.state != State::OpenConfirm {
+                step(&mut peer).await;
+            }
+        })
+        .await;
+        assert!(open_confirm.is_ok());
+        // 相手のKEEPALIVEは届かないので、OpenConfirmに留まる。
+        for _ in 0..5 {
+            step(&mut peer).await;
+        }
+        assert_eq!(peer.state, State::OpenConfirm);
+        assert!(faults.injected() > 0);
+
+        clock.advance(Duration::from_secs(10));
+        step(&mut peer).await;
+        step(&mut peer).await;
+        assert_eq!(peer.state, State::Idle);
+        let remote_peer = tokio::time::timeout(Duration::from_secs(5), remote)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(remote_peer.notifications_received(), 1);
+
+        clock.advance(peer.config.connect_retry_time);
+        step(&mut peer).await;
+        assert_eq!(peer.pending_events(), vec![Event::ConnectRetryTimerExpires]);
+    }
+
+    #[tokio::test]
+    async fn neighbor_unreachable_alarm_fires_once_at_connect_failure_threshold() {
+        let (mut config, _) = loopback_configs();
+        config.connect_failure_alarm = Some(3);
+        let connect_retry_time = config.connect_retry_time;
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let clock = Arc::new(MockClock::new());
+        let mut peer = Peer::with_clock(config, loc_rib, clock.clone());
+        let alarms = Arc::new(std::sync::Mutex::new(vec![]));
+        let observed = Arc::clone(&alarms);
+        peer.set_alarm_observer(move |alarm| observed.lock().unwrap().push(alarm.clone()));
+
+        // 相手は接続を待っていないので、接続の確立は毎回失敗する。
+        peer.start();
+        for failures in 1..=5 {
+            peer.next().await;
+            assert_eq!(peer.connect_retry_counter, failures);
+            assert_eq!(peer.state, State::Idle);
+            if failures == 2 {
+                assert!(alarms.lock().unwrap().is_empty());
+            }
+            clock.advance(connect_retry_time);
+            peer.next().await;
+        }
+        assert_eq!(
+            *alarms.lock().unwrap(),
+            vec![PeerAlarm::NeighborUnreachable {
+                remote_ip: "127.0.0.3".parse().unwrap(),
+                consecutive_failures: 3,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_retry_counter_increments_on_failure_and_resets_on_open_exchange() {
+        let (config, remote_config) = loopback_configs();
+        let connect_retry_time = config.connect_retry_time;
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let clock = Arc::new(MockClock::new());
+        let mut peer = Peer::with_clock(config, loc_rib, clock.clone());
+
+        // 相手がまだ接続を待っていないので、最初の接続は失敗する。
+        peer.start();
+        peer.next().await;
+        assert_eq!(peer.state, State::Idle);
+        assert_eq!(peer.status().connect_retry_counter, 1);
+
+        tokio::spawn(async move {
+            let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+            let mut remote_peer = Peer::new(remote_config, remote_loc_rib);
+            remote_peer.start();
+            loop {
+                remote_peer.next().await;
+            }
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        clock.advance(connect_retry_time);
+        let open_confirm = tokio::time::timeout(Duration::from_secs(5), async {
+            while peer.state != State::OpenConfirm {
+                peer.next().await;
+                if peer.state == State::OpenSent {
+                    assert_eq!(peer.status().connect_retry_counter, 1);
+                }
+            }
+        })
+        .await;
+        assert!(open_confirm.is_ok());
+        assert_eq!(peer.status().connect_retry_counter, 0);
+        assert!(peer.connect_retry_deadline.is_none());
+    }
+
+    #[tokio::test]
+    async fn keepalive_interval_varies_within_jitter_and_below_half_hold_time() {
+        let keepalive_intervals = |timer_jitter: u8| async move {
+            let (mut config, _) = loopback_configs();
+            config.timer_jitter = timer_jitter;
+            let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+            let clock = Arc::new(MockClock::new());
+            let mut peer = Peer::with_clock(config, loc_rib, clock.clone());
+            peer.hold_time = Some(Duration::from_secs(90));
+            (0..100)
+                .map(|_| {
+                    peer.restart_keepalive_timer();
+                    peer.keepalive_timer_deadline.unwrap() - clock.now()
+                })
+                .collect::<Vec<Duration>>()
+        };
+
+        let intervals = keepalive_intervals(10).await;
+        assert!(intervals
+            .iter()
+            .all(|i| Duration::from_secs(27) <= *i && *i <= Duration::from_secs(33)));
+        assert!(intervals.iter().any(|i| *i != intervals[0]));
+
+        let intervals = keepalive_intervals(100).await;
+        assert!(intervals.iter().all(|i| *i <= Duration::from_secs(45)));
+        assert!(intervals.iter().any(|i| *i != intervals[0]));
+    }
+
+    #[tokio::test]
+    async fn peer_stays_idle_for_max_prefix_restart_after_exceeding_max_prefix() {
+        let port = unused_port();
+        let config: Config = format!(
+            "64512 127.0.0.2 64513 127.0.0.3 active port={port} \
+            max_prefix=1 max_prefix_restart=30"
+        )
+        .parse()
+        .unwrap();
+        let remote_config: Config = format!("64513 127.0.0.3 64512 127.0.0.2 passive port={port}")
+            .parse()
+            .unwrap();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let clock = Arc::new(MockClock::new());
+        let mut peer = Peer::with_clock(config, Arc::clone(&loc_rib), clock.clone());
+        peer.start();
+
+        let spawn_remote = |remote_config: Config| {
+            tokio::spawn(async move {
+                let mut remote_loc_rib = LocRib::new(&remote_config).await.unwrap();
+                for network in ["10.100.230.0/24", "10.100.231.0/24"] {
+                    remote_loc_rib
+                        .originate(
+                            network.parse().unwrap(),
+                            "127.0.0.3".parse().unwrap(),
+                            vec![],
+                            &InMemoryFib::new(),
+                        )
+                        .await
+                        .unwrap();
+                }
+                let mut remote_peer =
+                    Peer::new(remote_config, Arc::new(Mutex::new(remote_loc_rib)));
+                remote_peer.start();
+                loop {
+                    remote_peer.next().await;
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+        let remote = spawn_remote(remote_config.clone());
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let max_step = 50;
+        let mut established = false;
+        for _ in 0..max_step {
+            step(&mut peer).await;
+            established |= peer.state == State::Established;
+            if established && peer.state == State::Idle {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+        assert!(established);
+        assert_eq!(peer.state, State::Idle);
+        assert!(!loc_rib
+            .lock()
+            .await
+            .routes()
+            .any(|e| e.network_address == "10.100.230.0/24".parse().unwrap()));
+        remote.abort();
+
+        // restartの間は、相手が待ち受けていても再接続しない。
+        let remote = spawn_remote(remote_config);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        clock.advance(Duration::from_secs(29));
+        for _ in 0..3 {
+            step(&mut peer).await;
+        }
+        assert_eq!(peer.state, State::Idle);
+
+        clock.advance(Duration::from_secs(1));
+        step(&mut peer).await;
+        step(&mut peer).await;
+        assert_eq!(peer.state, State::Connect);
+        remote.abort();
+    }
+
+    #[tokio::test]
+    async fn max_prefix_warning_only_does_not_close_session() {
+        let config: Config =
+            "64512 127.0.0.2 64513 127.0.0.3 active max_prefix=1 max_prefix_warning_only=true"
+                .parse()
+                .unwrap();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, loc_rib);
+        for network in ["10.100.230.0/24", "10.100.231.0/24"] {
+            peer.adj_rib_in.insert(Arc::new(RibEntry {
+                network_address: network.parse().unwrap(),
+                path_attributes: Arc::new(vec![]),
+                learned_from: None,
+            }));
+        }
+        assert!(!peer.exceeds_max_prefix());
+        peer.config.max_prefix_warning_only = false;
+        assert!(peer.exceeds_max_prefix());
+    }
+
+    #[tokio::test]
+    async fn peer_returns_to_idle_and_retries_when_connection_fails() {
+        let (mut config, remote_config) = loopback_configs();
+        config.connect_retry_time = Duration::from_secs(30);
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let clock = Arc::new(MockClock::new());
+        let mut peer = Peer::with_clock(config, loc_rib, clock.clone());
+        peer.start();
+
+        // 相手がまだ待ち受けていないので、接続に失敗する。
+        step(&mut peer).await;
+        step(&mut peer).await;
+        assert_eq!(peer.state, State::Idle);
+        assert!(peer.tcp_connection.is_none());
+
+        tokio::spawn(async move {
+            let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+            let mut remote_peer = Peer::new(remote_config, Arc::clone(&remote_loc_rib));
+            remote_peer.start();
+            remote_peer.next().await;
+        });
+        sleep(Duration::from_secs(1)).await;
+
+        clock.advance(Duration::from_secs(30));
+        step(&mut peer).await;
+        step(&mut peer).await;
+        assert_eq!(peer.state, State::Connect);
+    }
+
+    /// NOTIFICATIONを受信してIdleに戻し、再接続までの時間を返す。
+    async fn reset_by_notification(peer: &mut Peer, clock: &MockClock) -> Duration {
+        let cease = NotificationMessage::new(6, 2, BytesMut::new());
+        peer.handle_message(Message::Notification(cease));
+        step(peer).await;
+        assert_eq!(peer.state, State::Idle);
+        peer.connect_retry_deadline.unwrap() - clock.now()
+    }
+
+    #[tokio::test]
+    async fn reconnect_delay_grows_while_notification_resets_repeat() {
+        let (mut config, _) = loopback_configs();
+        config.connect_retry_time = Duration::from_secs(1);
+        config.notification_dampening = Some(Duration::from_secs(8));
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let clock = Arc::new(MockClock::new());
+        let mut peer = Peer::with_clock(config, loc_rib, clock.clone());
+        let mut delays = vec![];
+        for _ in 0..5 {
+            delays.push(reset_by_notification(&mut peer, &clock).await.as_secs());
+        }
+        assert_eq!(delays, vec![1, 2, 4, 8, 8]);
+
+        // Establishedのまま上限の時間が経てば、connect_retry_timeに戻る。
+        peer.stable_since = Some(clock.now());
+        clock.advance(Duration::from_secs(8));
+        assert_eq!(
+            reset_by_notification(&mut peer, &clock).await,
+            Duration::from_secs(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn idle_hold_time_grows_while_collision_dumps_repeat() {
+        let (mut config, _) = loopback_configs();
+        config.idle_hold_time = Some(Duration::from_secs(1));
+        config.damp_peer_oscillations = Some(Duration::from_secs(8));
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let clock = Arc::new(MockClock::new());
+        let mut peer = Peer::with_clock(config, loc_rib, clock.clone());
+        let mut intervals = vec![];
+        for _ in 0..5 {
+            let dump = NotificationMessage::new(6, 7, BytesMut::new());
+            peer.handle_message(Message::Notification(dump));
+            step(&mut peer).await;
+            assert_eq!(peer.state, State::Idle);
+            let interval = peer.idle_hold_deadline.unwrap() - clock.now();
+            intervals.push(interval.as_secs());
+
+            // IdleHoldTimerが満了するまでは再接続しない。
+            clock.advance(interval - Duration::from_millis(1));
+            step(&mut peer).await;
+            assert!(!peer
+                .pending_events()
+                .contains(&Event::ConnectRetryTimerExpires));
+            peer.event_queue = EventQueue::new();
+        }
+        assert_eq!(intervals, vec![1, 2, 4, 8, 8]);
+
+        clock.advance(Duration::from_millis(1));
+        step(&mut peer).await;
+        assert!(peer
+            .pending_events()
+            .contains(&Event::ConnectRetryTimerExpires));
+    }
+
+    #[tokio::test]
+    async fn reset_peer_purges_routes_and_reestablishes_session() {
+        let (mut config, remote_config) = loopback_configs();
+        config.connect_retry_time = Duration::from_secs(1);
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.start();
+
+        let network: Ipv4Network = "10.100.231.0/24".parse().unwrap();
+        let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+        remote_loc_rib
+            .lock()
+            .await
+            .originate(
+                network,
+                "127.0.0.3".parse().unwrap(),
+                vec![],
+                &InMemoryFib::new(),
+            )
+            .await
+            .unwrap();
+        let remote = tokio::spawn(async move {
+            let mut remote_peer = Peer::new(remote_config, remote_loc_rib);
+            remote_peer.start();
+            let mut restarted = false;
+            loop {
+                remote_peer.next().await;
+                // Ceaseを受信してIdleに戻ったら、もう一度接続を待ち受ける。
+                if !restarted
+                    && remote_peer.state == State::Idle
+                    && remote_peer.notifications_received() > 0
+                {
+                    restarted = true;
+                    remote_peer.start();
+                }
+                tokio::task::yield_now().await;
+            }
+        });
+
+        let is_learned = || async {
+            loc_rib
+                .lock()
+                .await
+                .routes()
+                .any(|e| e.network_address == network)
+        };
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let max_step = 50;
+        for _ in 0..max_step {
+            peer.next().await;
+            if peer.state == State::Established && is_learned().await {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+        assert!(is_learned().await);
+
+        peer.reset().await;
+        assert_eq!(peer.state, State::Idle);
+        assert_eq!(peer.adj_rib_in.len(), 0);
+        assert_eq!(peer.adj_rib_out.len(), 0);
+        assert!(!is_learned().await);
+
+        for _ in 0..max_step {
+            peer.next().await;
+            if peer.state == State::Established && is_learned().await {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+        }
+        assert_eq!(peer.state, State::Established);
+        assert!(is_learned().await);
+        remote.abort();
+    }
+
+    #[tokio::test]
+    async fn routes_absent_from_enhanced_route_refresh_are_purged_at_eorr() {
+        let (config, _) = loopback_configs();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.state = State::Established;
+        peer.route_refresh = true;
+        peer.enhanced_route_refresh = true;
+        let loc_rib_networks = || async {
+            loc_rib
+                .lock()
+                .await
+                .routes()
+                .map(|e| e.network_address.to_string())
+                .collect::<BTreeSet<String>>()
+        };
+
+        for network in ["10.100.250.0/24", "10.100.251.0/24"] {
+            peer.handle_event(Event::UpdateMsg(update_from(
+                network,
+                vec![64513],
+                "127.0.0.3",
+            )))
+            .await
+            .unwrap();
+        }
+        peer.handle_event(Event::AdjRibInChanged).await.unwrap();
+        assert_eq!(loc_rib_networks().await.len(), 2);
+
+        peer.handle_event(Event::RouteRefreshMsg(RouteRefreshMessage::new(
+            RouteRefreshSubtype::BeginOfRouteRefresh,
+        )))
+        .await
+        .unwrap();
+        peer.handle_event(Event::UpdateMsg(update_from(
+            "10.100.250.0/24",
+            vec![64513],
+            "127.0.0.3",
+        )))
+        .await
+        .unwrap();
+        // EoRRを受信するまでは、再送されていない経路も使い続ける。
+        peer.handle_event(Event::AdjRibInChanged).await.unwrap();
+        assert_eq!(loc_rib_networks().await.len(), 2);
+
+        peer.handle_event(Event::RouteRefreshMsg(RouteRefreshMessage::new(
+            RouteRefreshSubtype::EndOfRouteRefresh,
+        )))
+        .await
+        .unwrap();
+        assert_eq!(peer.adj_rib_in.stale_routes().count(), 0);
+        assert_eq!(
+            loc_rib_networks().await,
+            BTreeSet::from(["10.100.250.0/24".to_owned()])
+        );
+    }
+
+    #[tokio::test]
+    async fn route_refresh_is_negotiated_without_enhanced_route_refresh() {
+        let (config, remote_config) = loopback_configs();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        peer.set_fib(Arc::new(InMemoryFib::new()));
+        let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+        remote_loc_rib
+            .lock()
+            .await
+            .originate(
+                "10.100.240.0/24".parse().unwrap(),
+                remote_config.local_ip,
+                vec![],
+                &InMemoryFib::new(),
+            )
+            .await
+            .unwrap();
+        let remote = tokio::spawn(async move {
+            let mut remote_peer = Peer::new(remote_config, remote_loc_rib);
+            remote_peer.set_fib(Arc::new(InMemoryFib::new()));
+            remote_peer.start();
+            loop {
+                remote_peer.next().await;
+            }
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        peer.start();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !peer.is_end_of_rib_received() {
+                peer.next().await;
+            }
+        })
+        .await
+        .unwrap();
+        // 双方が広告した場合だけ合意するので、相手もRoute Refreshを広告している。
+        assert!(peer.route_refresh);
+        assert!(!peer.enhanced_route_refresh);
+
+        // soft reconfiguration inboundが無効なので、相手に経路の再送を要求する。
+        peer.soft_reset_inbound().await.unwrap();
+        let resent = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let PeerProgress::ReceivedMessage(Message::Update(update)) = peer.poll().await {
+                    return update;
+                }
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        remote.abort();
+        assert_eq!(resent.nlri(), &["10.100.240.0/24".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn soft_reconfiguration_falls_back_to_route_refresh_over_limit() {
+        let config: Config = format!(
+            "64512 127.0.0.2 64513 127.0.0.3 active port={} enhanced_route_refresh=true \
+            soft_reconfiguration_inbound=true soft_reconfiguration_limit=2",
+            unused_port()
+        )
+        .parse()
+        .unwrap();
+        let listener = tokio::net::TcpListener::bind((config.remote_ip, config.port))
+            .await
+            .unwrap();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config.clone(), loc_rib);
+        peer.tcp_connection = Some(Connection::connect(&config).await.unwrap());
+        let (mut remote, _) = listener.accept().await.unwrap();
+        peer.state = State::Established;
+        peer.route_refresh = true;
+
+        for network in ["10.100.250.0/24", "10.100.251.0/24"] {
+            peer.handle_event(Event::UpdateMsg(update_from(
+                network,
+                vec![64513],
+                "127.0.0.3",
+            )))
+            .await
+            .unwrap();
+        }
+        assert!(peer.is_soft_reconfiguration_enabled());
+        // 保持している経路から適用し直すので、相手には何も送らない。
+        peer.soft_reset_inbound().await.unwrap();
+        assert_eq!(peer.adj_rib_in.len(), 2);
+
+        peer.handle_event(Event::UpdateMsg(update_from(
+            "10.100.252.0/24",
+            vec![64513],
+            "127.0.0.3",
+        )))
+        .await
+        .unwrap();
+        assert!(!peer.is_soft_reconfiguration_enabled());
+        assert_eq!(peer.adj_rib_in.len(), 3);
+
+        peer.soft_reset_inbound().await.unwrap();
+        let mut buf = [0u8; 23];
+        tokio::time::timeout(Duration::from_secs(1), remote.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let message = Message::try_from(&buf[..]).unwrap();
+        assert!(matches!(
+            message,
+            Message::RouteRefresh(r) if r.subtype == RouteRefreshSubtype::Request
+        ));
+    }
+
+    #[tokio::test]
+    async fn keepalives_are_sent_while_route_burst_is_paced() {
+        let mut config: Config = format!(
+            "64512 127.0.0.2 64513 127.0.0.3 active port={} max_routes_per_iteration=500",
+            unused_port()
+        )
+        .parse()
+        .unwrap();
+        config.hold_time = 9;
+        let listener = tokio::net::TcpListener::bind((config.remote_ip, config.port))
+            .await
+            .unwrap();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let clock = Arc::new(MockClock::new());
+        let mut peer = Peer::with_clock(config.clone(), loc_rib, clock.clone());
+        peer.set_fib(Arc::new(InMemoryFib::new()));
+        peer.tcp_connection = Some(Connection::connect(&config).await.unwrap());
+        let (mut remote, _) = listener.accept().await.unwrap();
+        peer.state = State::Established;
+        peer.hold_time = Some(Duration::from_secs(9));
+        peer.restart_hold_timer();
+        peer.restart_keepalive_timer();
+
+        // 1000経路ずつのUPDATEを10個、一度に受信する。
+        let networks: Vec<Ipv4Network> = (0..10_000u32)
+            .map(|i| Ipv4Network::new(Ipv4Addr::new(10, 60 + (i / 256) as u8, i as u8, 0), 24))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        for chunk in networks.chunks(1000) {
+            let path_attributes =
+                update_from("10.60.0.0/24", vec![64513], "127.0.0.3").path_attributes;
+            let update = UpdateMessage::new(path_attributes, chunk.to_vec(), vec![]);
+            let bytes: BytesMut = Message::Update(update).into();
+            remote.write_all(&bytes).await.unwrap();
+        }
+
+        let mut received = BytesMut::new();
+        let mut keepalives_during_ingestion = 0;
+        let mut iterations = 0;
+        while peer.adj_rib_in.len() < networks.len() {
+            iterations += 1;
+            assert!(iterations < 1000, "routes are not installed.");
+            clock.advance(Duration::from_secs(2));
+            if iterations % 3 == 0 {
+                peer.handle_message(Message::Keepalive(KeepaliveMessage::new()));
+            }
+            peer.next().await;
+            let processed = peer.adj_rib_in.len();
+            assert!(processed <= iterations * 500);
+
+            let mut buf = [0u8; 4096];
+            while let Ok(Ok(n)) =
+                tokio::time::timeout(Duration::from_millis(10), remote.read(&mut buf)).await
+            {
+                if n == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buf[..n]);
+            }
+            while received.len() >= 19 {
+                let length = u16::from_be_bytes([received[16], received[17]]) as usize;
+                if received.len() < length {
+                    break;
+                }
+                let message = Message::try_from(received.split_to(length)).unwrap();
+                if matches!(message, Message::Keepalive(_)) && processed < networks.len() {
+                    keepalives_during_ingestion += 1;
+                }
+            }
+        }
+        assert_eq!(peer.state, State::Established);
+        assert!(iterations >= networks.len() / 500);
+        assert!(
+            keepalives_during_ingestion >= 5,
+            "only {keepalives_during_ingestion} keepalives are sent during ingestion."
+        );
+    }
+
+    #[tokio::test]
+    async fn idle_session_parks_instead_of_busy_polling() {
+        let (mut config, _) = loopback_configs();
+        config.timer_jitter = 0;
+        let listener = tokio::net::TcpListener::bind((config.remote_ip, config.port))
+            .await
+            .unwrap();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let clock = Arc::new(MockClock::new());
+        let mut peer = Peer::with_clock(config.clone(), Arc::clone(&loc_rib), clock.clone());
+        peer.tcp_connection = Some(Connection::connect(&config).await.unwrap());
+        let (_remote, _) = listener.accept().await.unwrap();
+        peer.state = State::Established;
+        peer.end_of_rib_sent = true;
+        peer.hold_time = Some(Duration::from_secs(3));
+        peer.restart_keepalive_timer();
+
+        // 相手から何も届かず、時計も進まない間は、next()はデータかtimerを待って止まる。
+        let parked = Duration::from_millis(200);
+        let woken = Duration::from_secs(5);
+        assert!(tokio::time::timeout(parked, peer.next()).await.is_err());
+
+        // keepaliveを送る時刻になると、待つのをやめる。
+        clock.advance(Duration::from_secs(1));
+        tokio::time::timeout(woken, peer.next()).await.unwrap();
+        tokio::time::timeout(woken, peer.next()).await.unwrap();
+        assert!(peer.event_queue.is_empty());
+        assert!(tokio::time::timeout(parked, peer.next()).await.is_err());
+
+        // 他のpeerなどがLocRibを変更すると、待つのをやめる。
+        loc_rib.lock().await.originate_ipv6(
+            "2001:db8::/32".parse().unwrap(),
+            std::net::Ipv6Addr::LOCALHOST,
+        );
+        tokio::time::timeout(woken, peer.next()).await.unwrap();
+    }
+
+    /// テスト中に出力されたlogを貯めておくwriter。
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn state_transitions_are_logged_from_idle_to_open_sent() {
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (config, remote_config) = loopback_configs();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, Arc::clone(&loc_rib));
+        let transitions = Arc::new(std::sync::Mutex::new(vec![]));
+        let observed = Arc::clone(&transitions);
+        peer.set_transition_observer(move |old, new, _| observed.lock().unwrap().push((old, new)));
+        peer.start();
+
+        tokio::spawn(async move {
+            let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+            let mut remote_peer = Peer::new(remote_config, Arc::clone(&remote_loc_rib));
+            remote_peer.start();
+            remote_peer.next().await;
+            remote_peer.next().await;
+        });
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        peer.next().await;
+        peer.next().await;
+        assert_eq!(peer.state, State::OpenSent);
+
+        assert_eq!(
+            *transitions.lock().unwrap(),
+            vec![
+                (State::Idle, State::Connect),
+                (State::Connect, State::OpenSent)
+            ]
+        );
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs
+            .contains("state is changed, neighbor=127.0.0.3, Idle -> Connect, event=ManualStart."));
+        assert!(logs.contains(
+            "state is changed, neighbor=127.0.0.3, Connect -> OpenSent, event=TcpConnectionConfirmed."
+        ));
+    }
+
+    #[tokio::test]
+    async fn quiet_peer_keeps_sending_keepalives_while_another_peer_churns() {
+        let (config, _) = loopback_configs();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+
+        // 10000経路の受信と取り消しを繰り返すpeer。
+        let mut churning_config = config.clone();
+        churning_config.remote_ip = "127.0.0.4".parse().unwrap();
+        churning_config.remote_as = 64514.into();
+        let mut churning = Peer::new(churning_config.clone(), Arc::clone(&loc_rib));
+        churning.set_fib(Arc::new(InMemoryFib::new()));
+        churning.state = State::Established;
+        let networks: Vec<Ipv4Network> = (0..10_000u32)
+            .map(|i| Ipv4Network::new(Ipv4Addr::new(10, 60 + (i / 256) as u8, i as u8, 0), 24))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let path_attributes = update_from("10.60.0.0/24", vec![64514], "127.0.0.4").path_attributes;
+        let mut announced = churning.adj_rib_in.clone();
+        announced.install_from_update(
+            UpdateMessage::new(path_attributes, networks.clone(), vec![]),
+            &churning_config,
+        );
+        let mut withdrawn = announced.clone();
+        withdrawn.update_to_all_changed();
+        withdrawn.install_from_update(
+            UpdateMessage::new(Arc::new(vec![]), vec![], networks),
+            &churning_config,
+        );
+        tokio::spawn(async move {
+            loop {
+                churning.adj_rib_in = announced.clone();
+                churning.install_adj_rib_in_to_loc_rib().await;
+                churning.adj_rib_in = withdrawn.clone();
+                churning.install_adj_rib_in_to_loc_rib().await;
+            }
+        });
+
+        // 何も送受信しないpeer。Hold Timeが3秒なので、時計が1秒進むごとにKEEPALIVEを送るはず。
+        let mut config: Config = format!(
+            "64512 127.0.0.2 64513 127.0.0.3 active port={}",
+            unused_port()
+        )
+        .parse()
+        .unwrap();
+        config.route_map_out = Some(Arc::new("route-map 10 deny".parse().unwrap()));
+        config.timer_jitter = 0;
+        let listener = tokio::net::TcpListener::bind((config.remote_ip, config.port))
+            .await
+            .unwrap();
+        let clock = Arc::new(MockClock::new());
+        let mut quiet = Peer::with_clock(config.clone(), Arc::clone(&loc_rib), clock.clone());
+        quiet.set_fib(Arc::new(InMemoryFib::new()));
+        quiet.tcp_connection = Some(Connection::connect(&config).await.unwrap());
+        let (mut remote, _) = listener.accept().await.unwrap();
+        quiet.state = State::Established;
+        quiet.hold_time = Some(Duration::from_secs(3));
+        quiet.restart_keepalive_timer();
+        tokio::spawn(async move {
+            loop {
+                quiet.next().await;
+            }
+        });
+
+        let mut received = BytesMut::new();
+        for i in 0..3 {
+            clock.advance(Duration::from_secs(1));
+            let keepalive = async {
+                loop {
+                    while received.len() >= 19 {
+                        let length = u16::from_be_bytes([received[16], received[17]]) as usize;
+                        if received.len() < length {
+                            break;
+                        }
+                        let message = received.split_to(length);
+                        if let Message::Keepalive(_) = Message::try_from(&message[..]).unwrap() {
+                            return;
+                        }
+                    }
+                    let mut buf = [0u8; 4096];
+                    let n = remote.read(&mut buf).await.unwrap();
+                    assert!(n > 0, "connection is closed.");
+                    received.extend_from_slice(&buf[..n]);
+                }
+            };
+            assert!(
+                tokio::time::timeout(Duration::from_secs(5), keepalive)
+                    .await
+                    .is_ok(),
+                "keepalive {i} is not sent while another peer churns."
+            );
+        }
+    }
+
+    #[cfg(feature = "update-compression")]
+    #[tokio::test]
+    async fn large_update_round_trips_compressed_between_two_instances() {
+        let (mut config, mut remote_config) = loopback_configs();
+        config.update_compression = true;
+        remote_config.update_compression = true;
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let remote_loc_rib = Arc::new(Mutex::new(LocRib::new(&remote_config).await.unwrap()));
+            let mut remote = Peer::new(remote_config, remote_loc_rib);
+            remote.set_fib(Arc::new(InMemoryFib::new()));
+            remote.start();
+            loop {
+                match remote.poll().await {
+                    PeerProgress::ProcessedEvent(Event::UpdateMsg(update))
+                        if !update.nlri().is_empty() =>
+                    {
+                        let _ = sender.send((remote.negotiated_params().cloned(), update));
+                        return;
+                    }
+                    _ => sleep(Duration::from_millis(10)).await,
+                }
+            }
+        });
+
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peer = Peer::new(config, loc_rib);
+        peer.set_fib(Arc::new(InMemoryFib::new()));
+        sleep(Duration::from_secs(1)).await;
+        peer.start();
+        let established = tokio::time::timeout(Duration::from_secs(10), async {
+            while peer.state != State::Established {
+                peer.next().await;
+            }
+        })
+        .await;
+        assert!(established.is_ok());
+
+        let networks: Vec<Ipv4Network> = (0..1000u32)
+            .map(|i| Ipv4Network::new(Ipv4Addr::new(10, 60 + (i / 256) as u8, i as u8, 0), 24))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let path_attributes = update_from("10.60.0.0/24", vec![64512], "127.0.0.2").path_attributes;
+        let update = UpdateMessage::new(path_attributes, networks, vec![]);
+        let bytes: BytesMut = Message::Update(update.clone()).into();
+        assert!(crate::compression::compress_update(bytes.clone()).len() < bytes.len());
+        peer.connection()
+            .unwrap()
+            .send(Message::Update(update))
+            .await;
+
+        let (remote_negotiated, received) = tokio::time::timeout(Duration::from_secs(5), receiver)
+            .await
+            .unwrap()
+            .unwrap();
+        for negotiated in [peer.negotiated_params().cloned(), remote_negotiated] {
+            assert!(negotiated
+                .unwrap()
+                .capabilities
+                .contains(&"Update Compression".to_owned()));
+        }
+        assert_eq!(
+            Message::Update(received),
+            Message::try_from(&bytes[..]).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn withdrawing_best_path_installs_next_best_in_same_cycle() {
+        async fn gateway_after_withdrawal(fast_reroute: bool) -> Option<Ipv4Addr> {
+            let (mut config, _) = loopback_configs();
+            config.fast_reroute = fast_reroute;
+            let mut backup_config = config.clone();
+            backup_config.remote_ip = "127.0.0.4".parse().unwrap();
+            backup_config.remote_as = 64514.into();
+            let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+            let fib = Arc::new(InMemoryFib::new());
+            let network: Ipv4Network = "10.100.240.0/24".parse().unwrap();
+
+            let mut primary = Peer::new(config.clone(), Arc::clone(&loc_rib));
+            let mut backup = Peer::new(backup_config.clone(), Arc::clone(&loc_rib));
+            for (peer, config, as_path, next_hop) in [
+                (&mut primary, &config, vec![64513], "127.0.0.3"),
+                (&mut backup, &backup_config, vec![64514, 64515], "127.0.0.4"),
+            ] {
+                peer.set_fib(fib.clone());
+                peer.state = State::Established;
+                peer.adj_rib_in
+                    .install_from_update(update_from("10.100.240.0/24", as_path, next_hop), config);
+                peer.install_adj_rib_in_to_loc_rib().await;
+            }
+            assert_eq!(fib.gateway(network), Some("127.0.0.3".parse().unwrap()));
+
+            let withdrawal = UpdateMessage::new(Arc::new(vec![]), vec![], vec![network]);
+            primary.event_queue.enqueue(Event::UpdateMsg(withdrawal));
+            primary.next().await;
+            fib.gateway(network)
+        }
+
+        assert_eq!(
+            gateway_after_withdrawal(true).await,
+            Some("127.0.0.4".parse().unwrap())
+        );
+        // 有効にしなければ、LocRibへの反映は次の`next`まで持ち越す。
+        assert_eq!(
+            gateway_after_withdrawal(false).await,
+            Some("127.0.0.3".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn route_refresh_for_other_address_family_does_not_touch_ipv4_routes() {
+        let config: Config = format!(
+            "64512 127.0.0.2 64513 127.0.0.3 active port={} enhanced_route_refresh=true",
+            unused_port()
+        )
+        .parse()
+        .unwrap();
+        let listener = tokio::net::TcpListener::bind((config.remote_ip, config.port))
+            .await
+            .unwrap();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        loc_rib
+            .lock()
+            .await
+            .originate(
+                "10.100.240.0/24".parse().unwrap(),
+                config.local_ip,
+                vec![],
+                &InMemoryFib::new(),
+            )
+            .await
+            .unwrap();
+        let mut peer = Peer::new(config.clone(), loc_rib);
+        peer.set_fib(Arc::new(InMemoryFib::new()));
+        peer.tcp_connection = Some(Connection::connect(&config).await.unwrap());
+        let (mut remote, _) = listener.accept().await.unwrap();
+        peer.state = State::Established;
+        peer.route_refresh = true;
+        peer.enhanced_route_refresh = true;
+        peer.handle_event(Event::Established).await.unwrap();
+        peer.handle_event(Event::AdjRibOutChanged).await.unwrap();
+        peer.handle_event(Event::UpdateMsg(update_from(
+            "10.100.250.0/24",
+            vec![64513],
+            "127.0.0.3",
+        )))
+        .await
+        .unwrap();
+
+        let ipv6_unicast = |subtype| {
+            Event::RouteRefreshMsg(RouteRefreshMessage::for_afi_safi(
+                AFI_IPV6,
+                SAFI_UNICAST,
+                subtype,
+            ))
+        };
+        // IPv6 UnicastのBoRRで、IPv4 Unicastの経路をstaleにしない。
+        peer.handle_event(ipv6_unicast(RouteRefreshSubtype::BeginOfRouteRefresh))
+            .await
+            .unwrap();
+        assert_eq!(peer.adj_rib_in.stale_routes().count(), 0);
+        peer.handle_event(ipv6_unicast(RouteRefreshSubtype::Request))
+            .await
+            .unwrap();
+        peer.handle_event(Event::RouteRefreshMsg(RouteRefreshMessage::new(
+            RouteRefreshSubtype::Request,
+        )))
+        .await
+        .unwrap();
+        drop(peer);
+
+        let mut received = vec![];
+        remote.read_to_end(&mut received).await.unwrap();
+        let mut messages = vec![];
+        let mut bytes = &received[..];
+        while !bytes.is_empty() {
+            let length = usize::from(u16::from_be_bytes([bytes[16], bytes[17]]));
+            messages.push(Message::try_from(&bytes[..length]).unwrap());
+            bytes = &bytes[length..];
+        }
+        // 最初の広告と、IPv4 Unicastの要求に応じた再送だけを送る。
+        let updates = messages
+            .iter()
+            .filter(|m| matches!(m, Message::Update(u) if !u.nlri().is_empty()))
+            .count();
+        assert_eq!(updates, 2);
+        let route_refreshes: Vec<&RouteRefreshMessage> = messages
+            .iter()
+            .filter_map(|m| match m {
+                Message::RouteRefresh(route_refresh) => Some(route_refresh),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(route_refreshes.len(), 2);
+        assert!(route_refreshes.iter().all(|r| r.is_ipv4_unicast()));
+    }
+
+    #[tokio::test]
+    async fn ipv6_route_refresh_resends_and_refreshes_only_ipv6_routes() {
+        use crate::path_attribute::{MpReachNlri, MpUnreachNlri, Origin};
+        let config: Config = format!(
+            "64512 127.0.0.2 64513 127.0.0.3 active port={} enhanced_route_refresh=true \
+             ipv6_unicast=true",
+            unused_port()
+        )
+        .parse()
+        .unwrap();
+        let listener = tokio::net::TcpListener::bind((config.remote_ip, config.port))
+            .await
+            .unwrap();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        {
+            let mut loc_rib = loc_rib.lock().await;
+            loc_rib
+                .originate(
+                    "10.100.240.0/24".parse().unwrap(),
+                    config.local_ip,
+                    vec![],
+                    &InMemoryFib::new(),
+                )
+                .await
+                .unwrap();
+            loc_rib.originate_ipv6(
+                "2001:db8:240::/48".parse().unwrap(),
+                "2001:db8::2".parse().unwrap(),
+            );
+        }
+        let mut peer = Peer::new(config.clone(), loc_rib);
+        peer.set_fib(Arc::new(InMemoryFib::new()));
+        peer.tcp_connection = Some(Connection::connect(&config).await.unwrap());
+        let (mut remote, _) = listener.accept().await.unwrap();
+        peer.state = State::Established;
+        peer.route_refresh = true;
+        peer.enhanced_route_refresh = true;
+        peer.ipv6_unicast = true;
+        peer.handle_event(Event::Established).await.unwrap();
+        peer.handle_event(Event::AdjRibOutChanged).await.unwrap();
+        peer.handle_event(Event::UpdateMsg(update_from(
+            "10.100.250.0/24",
+            vec![64513],
+            "127.0.0.3",
+        )))
+        .await
+        .unwrap();
+        let received_ipv6_network = "2001:db8:250::/48".parse().unwrap();
+        peer.handle_event(Event::UpdateMsg(UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::sequence(vec![64513.into()])),
+                PathAttribute::MpReachNlri(MpReachNlri::Ipv6Unicast {
+                    next_hop: "2001:db8::3".parse().unwrap(),
+                    nlri: vec![received_ipv6_network],
+                }),
+            ]),
+            vec![],
+            vec![],
+        )))
+        .await
+        .unwrap();
+        assert_eq!(peer.ipv6_adj_rib_in.len(), 1);
+
+        let ipv6_unicast = |subtype| {
+            Event::RouteRefreshMsg(RouteRefreshMessage::for_afi_safi(
+                AFI_IPV6,
+                SAFI_UNICAST,
+                subtype,
+            ))
+        };
+        // IPv6 UnicastのBoRR, EoRRで、IPv6 Unicastの経路だけをstaleにして取り除く。
+        peer.handle_event(ipv6_unicast(RouteRefreshSubtype::BeginOfRouteRefresh))
+            .await
+            .unwrap();
+        assert_eq!(peer.adj_rib_in.stale_routes().count(), 0);
+        peer.handle_event(ipv6_unicast(RouteRefreshSubtype::EndOfRouteRefresh))
+            .await
+            .unwrap();
+        assert!(peer.ipv6_adj_rib_in.get(&received_ipv6_network).is_none());
+        assert_eq!(peer.adj_rib_in.routes().count(), 1);
+        peer.handle_event(ipv6_unicast(RouteRefreshSubtype::Request))
+            .await
+            .unwrap();
+        drop(peer);
+
+        let mut received = vec![];
+        remote.read_to_end(&mut received).await.unwrap();
+        let mut messages = vec![];
+        let mut bytes = &received[..];
+        while !bytes.is_empty() {
+            let length = usize::from(u16::from_be_bytes([bytes[16], bytes[17]]));
+            messages.push(Message::try_from(&bytes[..length]).unwrap());
+            bytes = &bytes[length..];
+        }
+        let ipv6_end_of_rib = Message::Update(Ipv6UnicastRib::end_of_rib());
+        assert!(messages.contains(&Message::Update(UpdateMessage::new_end_of_rib())));
+        assert!(messages.contains(&ipv6_end_of_rib));
+        // 要求に応じた再送は、BoRRとEoRRの間のIPv6 Unicastの経路だけになる。
+        let borr = messages
+            .iter()
+            .position(|m| matches!(m, Message::RouteRefresh(_)))
+            .unwrap();
+        let resent = &messages[borr..];
+        assert_eq!(resent.len(), 3);
+        assert_eq!(
+            resent[0],
+            Message::RouteRefresh(RouteRefreshMessage::for_afi_safi(
+                AFI_IPV6,
+                SAFI_UNICAST,
+                RouteRefreshSubtype::BeginOfRouteRefresh
+            ))
+        );
+        let Message::Update(update) = &resent[1] else {
+            panic!("unexpected message {:?}", resent[1]);
+        };
+        assert!(update.nlri().is_empty());
+        assert!(update.path_attributes.contains(&PathAttribute::MpReachNlri(
+            MpReachNlri::Ipv6Unicast {
+                next_hop: "2001:db8::2".parse().unwrap(),
+                nlri: vec!["2001:db8:240::/48".parse().unwrap()],
+            }
+        )));
+        assert!(update
+            .path_attributes
+            .contains(&PathAttribute::AsPath(AsPath::sequence(vec![64512.into()]))));
+        assert!(!update.path_attributes.iter().any(|p| matches!(
+            p,
+            PathAttribute::MpUnreachNlri(MpUnreachNlri::Ipv6Unicast { .. })
+        )));
+        assert_eq!(
+            resent[2],
+            Message::RouteRefresh(RouteRefreshMessage::for_afi_safi(
+                AFI_IPV6,
+                SAFI_UNICAST,
+                RouteRefreshSubtype::EndOfRouteRefresh
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_sends_cease_to_peers_before_removing_kernel_routes() {
+        use std::future::Future;
+        use std::pin::Pin;
+
+        use crate::peer_manager::PeerManager;
+
+        /// 経路を取り除いた順番をlogに記録する転送テーブル。
+        #[derive(Debug)]
+        struct RecordingFib(Arc<std::sync::Mutex<Vec<String>>>);
+
+        impl Fib for RecordingFib {
+            fn install(
+                &self,
+                _: Ipv4Network,
+                _: Ipv4Addr,
+            ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + '_>> {
+                Box::pin(async { Ok(()) })
+            }
+
+            fn remove(
+                &self,
+                network: Ipv4Network,
+            ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + '_>> {
+                self.0.lock().unwrap().push(format!("remove {}", *network));
+                Box::pin(async { Ok(()) })
+            }
+        }
+
+        let log = Arc::new(std::sync::Mutex::new(vec![]));
+        let fib = Arc::new(RecordingFib(Arc::clone(&log)));
+        let (config, _) = loopback_configs();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+        let mut peers = vec![];
+        for (remote_ip, remote_as) in [("127.0.0.3", 64513), ("127.0.0.4", 64514)] {
+            let config: Config = format!(
+                "64512 127.0.0.2 {remote_as} {remote_ip} active port={}",
+                unused_port()
+            )
+            .parse()
+            .unwrap();
+            let listener = tokio::net::TcpListener::bind((config.remote_ip, config.port))
+                .await
+                .unwrap();
+            let mut peer = Peer::new(config.clone(), Arc::clone(&loc_rib));
+            peer.set_fib(fib.clone());
+            peer.tcp_connection = Some(Connection::connect(&config).await.unwrap());
+            let (mut remote, _) = listener.accept().await.unwrap();
+            peer.state = State::Established;
+            peer.adj_rib_in.install_from_update(
+                update_from(
+                    &format!("10.100.{}.0/24", 240 + peers.len()),
+                    vec![remote_as],
+                    remote_ip,
+                ),
+                &config,
+            );
+            peer.install_adj_rib_in_to_loc_rib().await;
+            peers.push(peer);
+
+            // Ceaseを受信したらlogに記録して閉じる。
+            let log = Arc::clone(&log);
+            tokio::spawn(async move {
+                let mut received = BytesMut::new();
+                while remote.read_buf(&mut received).await.unwrap() > 0 {
+                    if received.len() >= 19 {
+                        let length = usize::from(u16::from_be_bytes([received[16], received[17]]));
+                        if received.len() >= length {
+                            if let Ok(Message::Notification(_)) =
+                                Message::try_from(&received[..length])
+                            {
+                                log.lock().unwrap().push(format!("cease to {remote_ip}"));
+                                return;
+                            }
+                            let _ = received.split_to(length);
+                        }
+                    }
+                }
+            });
+        }
+
+        let mut manager = PeerManager::new(peers);
+        manager.shutdown(&loc_rib, fib.as_ref()).await.unwrap();
+
+        let log = log.lock().unwrap().clone();
+        assert_eq!(log.len(), 4, "{log:?}");
+        assert!(
+            log[..2].iter().all(|entry| entry.starts_with("cease")),
+            "{log:?}"
+        );
+        assert_eq!(
+            log[2..].iter().collect::<BTreeSet<_>>(),
+            BTreeSet::from([
+                &"remove 10.100.240.0/24".to_owned(),
+                &"remove 10.100.241.0/24".to_owned()
+            ])
+        );
+        assert!(manager
+            .states()
+            .iter()
+            .all(|(_, state)| *state == State::Idle));
     }
 }
@@ -0,0 +1,161 @@
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::future::join_all;
+use tokio::sync::Mutex;
+
+use crate::fib::Fib;
+use crate::peer::{self, Peer};
+use crate::routing::LocRib;
+use crate::state::State;
+
+/// 静的に設定したneighborのpeerをまとめて動かし、全体の状態を確かめる。
+#[derive(Debug, Default)]
+pub struct PeerManager {
+    peers: Vec<Peer>,
+}
+
+impl PeerManager {
+    pub fn new(peers: Vec<Peer>) -> Self {
+        Self { peers }
+    }
+
+    pub fn peers(&self) -> &[Peer] {
+        &self.peers
+    }
+
+    pub fn peers_mut(&mut self) -> &mut [Peer] {
+        &mut self.peers
+    }
+
+    pub fn start(&mut self) {
+        for peer in &mut self.peers {
+            peer.start();
+        }
+    }
+
+    /// いずれかのpeerに処理することができるまで待ち、全てのpeerの処理を1回ずつ進める。
+    pub async fn next(&mut self) {
+        self.wait_for_activity().await;
+        self.poll().await;
+    }
+
+    /// いずれかのpeerに処理することができるまで待つ。
+    pub async fn wait_for_activity(&mut self) {
+        peer::wait_for_any_activity(&mut self.peers).await;
+    }
+
+    /// 待たずに、全てのpeerの処理を1回ずつ進める。
+    pub async fn poll(&mut self) {
+        for peer in &mut self.peers {
+            peer.poll().await;
+        }
+    }
+
+    /// 全てのpeerが収束していればtrueを返す(`Peer::is_converged`)。
+    pub fn all_converged(&self) -> bool {
+        self.peers.iter().all(|peer| peer.is_converged())
+    }
+
+    /// peerごとの、相手のaddressと状態。
+    pub fn states(&self) -> Vec<(Ipv4Addr, State)> {
+        self.peers
+            .iter()
+            .map(|peer| (peer.remote_ip(), peer.state()))
+            .collect()
+    }
+
+    /// 全てのpeerへ並行してCeaseを送り、届いてからkernelに書き込んだ経路を取り除く。
+    /// 先に経路を取り除くと、相手が他の経路へ切り替えるまでに届いた通信を捨ててしまう。
+    pub async fn shutdown(&mut self, loc_rib: &Mutex<LocRib>, fib: &dyn Fib) -> Result<()> {
+        join_all(self.peers.iter_mut().map(|peer| peer.notify_shutdown())).await;
+        loc_rib.lock().await.remove_from_fib(fib).await
+    }
+
+    /// `peer::run_until_converged`を全てのpeerに対して行う。
+    pub async fn run_until_converged(&mut self, timeout: Duration) -> bool {
+        peer::run_until_converged(&mut self.peers, timeout).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::connection::Connection;
+
+    /// 接続を待ち受け始めてから戻り、届いた接続でpeerを動かし続ける。
+    async fn spawn_passive_peer(local_ip: &str, local_as: u32, port: u16) {
+        let config: Config = format!("{local_as} {local_ip} 64512 127.0.0.2 passive port={port}")
+            .parse()
+            .unwrap();
+        let listener = Connection::listen(&config).await.unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let loc_rib = Arc::new(Mutex::new(LocRib::new(&config).await.unwrap()));
+            let mut peer = Peer::new(config, loc_rib);
+            peer.set_accepted_connection(Connection::from_accepted(stream).unwrap());
+            peer.start();
+            loop {
+                peer.next().await;
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn all_converged_flips_once_every_peer_has_converged() {
+        let port = TcpListener::bind(("127.0.0.2", 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        spawn_passive_peer("127.0.0.3", 64513, port).await;
+        spawn_passive_peer("127.0.0.4", 64514, port).await;
+
+        let configs: Vec<Config> = [("64513", "127.0.0.3"), ("64514", "127.0.0.4")]
+            .into_iter()
+            .map(|(remote_as, remote_ip)| {
+                format!("64512 127.0.0.2 {remote_as} {remote_ip} active port={port}")
+                    .parse()
+                    .unwrap()
+            })
+            .collect();
+        let loc_rib = Arc::new(Mutex::new(LocRib::new(&configs[0]).await.unwrap()));
+        let mut manager = PeerManager::new(
+            configs
+                .into_iter()
+                .map(|config| Peer::new(config, Arc::clone(&loc_rib)))
+                .collect(),
+        );
+        assert!(!manager.all_converged());
+        assert_eq!(
+            manager.states(),
+            vec![
+                ("127.0.0.3".parse().unwrap(), State::Idle),
+                ("127.0.0.4".parse().unwrap(), State::Idle)
+            ]
+        );
+
+        manager.start();
+        let converged = tokio::time::timeout(Duration::from_secs(10), async {
+            while !manager.all_converged() {
+                manager.next().await;
+            }
+        })
+        .await;
+        assert!(converged.is_ok());
+        assert_eq!(
+            manager.states(),
+            vec![
+                ("127.0.0.3".parse().unwrap(), State::Established),
+                ("127.0.0.4".parse().unwrap(), State::Established)
+            ]
+        );
+    }
+}
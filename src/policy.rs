@@ -0,0 +1,67 @@
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::path_attribute::{ExtendedCommunity, PathAttribute};
+use crate::route_map::RouteMap;
+use crate::routing::{Ipv4Network, RibEntry};
+
+/// 受信した経路を受け入れるかどうかの判断。
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct ImportPolicy {
+    route_targets: Vec<ExtendedCommunity>,
+    route_map: Option<Arc<RouteMap>>,
+}
+
+impl ImportPolicy {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            route_targets: config.import_route_targets.clone(),
+            route_map: config.route_map_in.clone(),
+        }
+    }
+
+    pub fn accepts(&self, path_attributes: &[PathAttribute]) -> bool {
+        self.route_targets.is_empty()
+            || PathAttribute::extended_communities(path_attributes)
+                .any(|c| self.route_targets.contains(&c))
+    }
+
+    /// networkへの経路にroute-mapを適用する。route-mapで捨てる場合はNoneを返す。
+    pub fn apply(
+        &self,
+        network: Ipv4Network,
+        path_attributes: &Arc<Vec<PathAttribute>>,
+    ) -> Option<Arc<Vec<PathAttribute>>> {
+        match &self.route_map {
+            Some(route_map) => route_map.apply(network, path_attributes).map(Arc::new),
+            None => Some(Arc::clone(path_attributes)),
+        }
+    }
+}
+
+/// 広告する経路の選択と書き換え。
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct ExportPolicy {
+    route_map: Option<Arc<RouteMap>>,
+}
+
+impl ExportPolicy {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            route_map: config.route_map_out.clone(),
+        }
+    }
+
+    /// 経路にroute-mapを適用する。route-mapで捨てる場合はNoneを返す。
+    pub fn apply(&self, entry: Arc<RibEntry>) -> Option<Arc<RibEntry>> {
+        let Some(route_map) = &self.route_map else {
+            return Some(entry);
+        };
+        let path_attributes = route_map.apply(entry.network_address, &entry.path_attributes)?;
+        Some(Arc::new(RibEntry {
+            network_address: entry.network_address,
+            path_attributes: Arc::new(path_attributes),
+            learned_from: entry.learned_from,
+        }))
+    }
+}
@@ -0,0 +1,362 @@
+//! neighborごとに受信・広告する経路を選び、attributeを書き換えるroute-map。
+//!
+//! 次のような行からなるテキストとして書く。`#`から行末まではコメントとして読み飛ばす。
+//!
+//! ```text
+//! prefix-list CUSTOMERS 10.100.0.0/16 le 24
+//! prefix-list CUSTOMERS 10.101.0.0/16
+//!
+//! route-map 10 permit
+//!   match prefix-list CUSTOMERS
+//!   match community 64512:100
+//!   set local-pref 200
+//! route-map 20 permit
+//!   match as-path 64513
+//!   set med 10
+//!   set community 64512:200
+//!   set next-hop 10.200.100.1
+//! ```
+//!
+//! 経路はsequence numberの小さいentryから順に照合し、最初に全てのmatchを満たしたentryに従う。
+//! permitであればsetを適用して受け入れ、denyであれば捨てる。どのentryにも一致しなければ捨てる。
+//! matchの無いentryは全ての経路に一致する。
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context};
+
+use crate::bgp_type::AutonomousSystemNumber;
+use crate::error::ConfigParseError;
+use crate::path_attribute::PathAttribute;
+use crate::routing::Ipv4Network;
+
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+pub struct RouteMap {
+    /// sequence numberの順に並べたentry。
+    entries: Vec<RouteMapEntry>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+struct RouteMapEntry {
+    sequence: u32,
+    action: RouteMapAction,
+    matches: Vec<MatchClause>,
+    sets: Vec<SetClause>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+enum RouteMapAction {
+    Permit,
+    Deny,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+enum MatchClause {
+    /// prefix-listのいずれかの行に一致する。
+    PrefixList(Vec<PrefixListEntry>),
+    /// AS_PATHにAS番号が含まれる。
+    AsPath(AutonomousSystemNumber),
+    /// COMMUNITIESにcommunityが含まれる。
+    Community(u32),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Hash, PartialOrd, Ord)]
+enum SetClause {
+    LocalPref(u32),
+    Med(u32),
+    /// COMMUNITIESにcommunityを加える。
+    Community(u32),
+    /// `set next-hop`は受信した経路と、NEXT_HOPを自身に書き換えないneighborへの広告にだけ効く。
+    NextHop(Ipv4Addr),
+}
+
+/// prefix-listの1行。`le`, `ge`が無ければnetworkと同じprefixにだけ一致する。
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord)]
+struct PrefixListEntry {
+    network: Ipv4Network,
+    ge: Option<u8>,
+    le: Option<u8>,
+}
+
+impl RouteMap {
+    /// route-mapを書いたファイルを読み込む。
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigParseError> {
+        let path = path.as_ref();
+        std::fs::read_to_string(path)
+            .context(format!("cannot read route-map {}", path.display()))?
+            .parse()
+    }
+
+    /// networkへの経路にroute-mapを適用する。
+    /// 受け入れる場合はsetを適用したpath attributeを、捨てる場合はNoneを返す。
+    pub fn apply(
+        &self,
+        network: Ipv4Network,
+        path_attributes: &[PathAttribute],
+    ) -> Option<Vec<PathAttribute>> {
+        let entry = self.entries.iter().find(|entry| {
+            entry
+                .matches
+                .iter()
+                .all(|clause| clause.matches(network, path_attributes))
+        })?;
+        if entry.action == RouteMapAction::Deny {
+            return None;
+        }
+        let mut path_attributes = path_attributes.to_vec();
+        for set in &entry.sets {
+            set.apply(&mut path_attributes);
+        }
+        Some(path_attributes)
+    }
+}
+
+impl MatchClause {
+    fn matches(&self, network: Ipv4Network, path_attributes: &[PathAttribute]) -> bool {
+        match self {
+            MatchClause::PrefixList(entries) => entries.iter().any(|e| e.matches(network)),
+            MatchClause::AsPath(as_number) => path_attributes.iter().any(|p| match p {
+                PathAttribute::AsPath(as_path) => as_path.count(*as_number) > 0,
+                _ => false,
+            }),
+            MatchClause::Community(community) => {
+                PathAttribute::communities(path_attributes).any(|c| c == *community)
+            }
+        }
+    }
+}
+
+impl SetClause {
+    fn apply(&self, path_attributes: &mut Vec<PathAttribute>) {
+        match self {
+            SetClause::LocalPref(local_pref) => {
+                path_attributes.retain(|p| !matches!(p, PathAttribute::LocalPref(_)));
+                path_attributes.push(PathAttribute::LocalPref(*local_pref));
+            }
+            SetClause::Med(med) => {
+                path_attributes.retain(|p| !matches!(p, PathAttribute::MultiExitDisc(_)));
+                path_attributes.push(PathAttribute::MultiExitDisc(*med));
+            }
+            SetClause::Community(community) => {
+                PathAttribute::add_community(path_attributes, *community)
+            }
+            SetClause::NextHop(next_hop) => {
+                for p in path_attributes.iter_mut() {
+                    if let PathAttribute::NextHop(n) = p {
+                        *n = *next_hop;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl PrefixListEntry {
+    fn matches(&self, network: Ipv4Network) -> bool {
+        let prefix = network.prefix();
+        let (ge, le) = match (self.ge, self.le) {
+            (None, None) => (self.network.prefix(), self.network.prefix()),
+            (ge, le) => (ge.unwrap_or(self.network.prefix()), le.unwrap_or(32)),
+        };
+        network.is_subnet_of(*self.network) && ge <= prefix && prefix <= le
+    }
+}
+
+/// 読み込み途中のentry。prefix-listは参照より後に書いてもよいので、名前のまま覚えておく。
+struct ParsedEntry<'a> {
+    action: RouteMapAction,
+    matches: Vec<Result<MatchClause, &'a str>>,
+    sets: Vec<SetClause>,
+}
+
+impl FromStr for RouteMap {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut prefix_lists: HashMap<&str, Vec<PrefixListEntry>> = HashMap::new();
+        let mut entries: BTreeMap<u32, ParsedEntry> = BTreeMap::new();
+        let mut current = None;
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let context = || format!("cannot parse line {} of route-map, `{}`", i + 1, line);
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            match tokens.as_slice() {
+                [] => {}
+                ["prefix-list", name, network, range @ ..] => {
+                    let entry = PrefixListEntry::parse(network, range).context(context())?;
+                    prefix_lists.entry(name).or_default().push(entry);
+                }
+                ["route-map", sequence, action] => {
+                    let sequence: u32 = sequence.parse().context(context())?;
+                    let action = match *action {
+                        "permit" => RouteMapAction::Permit,
+                        "deny" => RouteMapAction::Deny,
+                        _ => return Err(anyhow!("{}, unknown action", context()).into()),
+                    };
+                    let entry = ParsedEntry {
+                        action,
+                        matches: vec![],
+                        sets: vec![],
+                    };
+                    if entries.insert(sequence, entry).is_some() {
+                        return Err(anyhow!("{}, sequence is duplicated", context()).into());
+                    }
+                    current = Some(sequence);
+                }
+                ["match" | "set", clause, value] => {
+                    let entry = current
+                        .and_then(|sequence| entries.get_mut(&sequence))
+                        .with_context(|| format!("{}, clause is outside route-map", context()))?;
+                    match tokens[0] {
+                        "match" if *clause == "prefix-list" => entry.matches.push(Err(value)),
+                        "match" => entry
+                            .matches
+                            .push(Ok(MatchClause::parse(clause, value).context(context())?)),
+                        _ => entry
+                            .sets
+                            .push(SetClause::parse(clause, value).context(context())?),
+                    }
+                }
+                _ => return Err(anyhow!("{}, unknown statement", context()).into()),
+            }
+        }
+
+        let entries = entries
+            .into_iter()
+            .map(|(sequence, entry)| {
+                let matches = entry
+                    .matches
+                    .into_iter()
+                    .map(|clause| {
+                        clause.or_else(|name| {
+                            prefix_lists
+                                .get(name)
+                                .map(|list| MatchClause::PrefixList(list.clone()))
+                                .with_context(|| format!("prefix-list {name} is not defined"))
+                        })
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                Ok(RouteMapEntry {
+                    sequence,
+                    action: entry.action,
+                    matches,
+                    sets: entry.sets,
+                })
+            })
+            .collect::<Result<_, ConfigParseError>>()?;
+        Ok(Self { entries })
+    }
+}
+
+impl MatchClause {
+    fn parse(clause: &str, value: &str) -> anyhow::Result<Self> {
+        match clause {
+            "as-path" => Ok(MatchClause::AsPath(value.parse::<u32>()?.into())),
+            "community" => Ok(MatchClause::Community(parse_community(value)?)),
+            _ => Err(anyhow!("unknown match clause `{clause}`")),
+        }
+    }
+}
+
+impl SetClause {
+    fn parse(clause: &str, value: &str) -> anyhow::Result<Self> {
+        match clause {
+            "local-pref" => Ok(SetClause::LocalPref(value.parse()?)),
+            "med" => Ok(SetClause::Med(value.parse()?)),
+            "community" => Ok(SetClause::Community(parse_community(value)?)),
+            "next-hop" => Ok(SetClause::NextHop(value.parse()?)),
+            _ => Err(anyhow!("unknown set clause `{clause}`")),
+        }
+    }
+}
+
+impl PrefixListEntry {
+    /// `10.100.0.0/16`に続く`ge 20`, `le 24`を読む。
+    fn parse(network: &str, range: &[&str]) -> anyhow::Result<Self> {
+        let mut entry = Self {
+            network: network.parse()?,
+            ge: None,
+            le: None,
+        };
+        for bound in range.chunks(2) {
+            match bound {
+                ["ge", length] => entry.ge = Some(length.parse()?),
+                ["le", length] => entry.le = Some(length.parse()?),
+                _ => {
+                    return Err(anyhow!(
+                        "cannot parse `{}` as prefix length range",
+                        bound.join(" ")
+                    ))
+                }
+            }
+        }
+        Ok(entry)
+    }
+}
+
+/// `64512:100`の形式のcommunityを、上位16 bitをAS番号とする値にする。
+fn parse_community(s: &str) -> anyhow::Result<u32> {
+    let (as_number, value) = s
+        .split_once(':')
+        .with_context(|| format!("cannot parse {s} as community"))?;
+    let as_number: u16 = as_number.parse()?;
+    let value: u16 = value.parse()?;
+    Ok((u32::from(as_number) << 16) | u32::from(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path_attribute::{AsPath, Origin};
+
+    fn path_attributes(as_number: u32) -> Vec<PathAttribute> {
+        vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::sequence(vec![as_number.into()])),
+            PathAttribute::NextHop("10.200.100.3".parse().unwrap()),
+        ]
+    }
+
+    #[test]
+    fn route_map_sets_attributes_of_matching_routes_and_denies_the_rest() {
+        let route_map: RouteMap = "
+            route-map 20 permit
+              match as-path 64514
+              set med 10
+              set community 64512:200
+            route-map 10 permit
+              match prefix-list CUSTOMERS # 後に定義したprefix-listも参照できる
+              set local-pref 200
+            prefix-list CUSTOMERS 10.100.0.0/16 le 24
+        "
+        .parse()
+        .unwrap();
+
+        let network: Ipv4Network = "10.100.220.0/24".parse().unwrap();
+        let applied = route_map.apply(network, &path_attributes(64513)).unwrap();
+        assert!(applied.contains(&PathAttribute::LocalPref(200)));
+        assert!(!applied.contains(&PathAttribute::MultiExitDisc(10)));
+
+        let network: Ipv4Network = "10.100.220.128/25".parse().unwrap();
+        let applied = route_map.apply(network, &path_attributes(64514)).unwrap();
+        assert!(applied.contains(&PathAttribute::MultiExitDisc(10)));
+        assert_eq!(
+            PathAttribute::communities(&applied).collect::<Vec<_>>(),
+            vec![(64512 << 16) | 200]
+        );
+
+        assert_eq!(route_map.apply(network, &path_attributes(64513)), None);
+    }
+
+    #[test]
+    fn route_map_with_undefined_prefix_list_is_rejected() {
+        assert!("route-map 10 permit\n match prefix-list UNKNOWN"
+            .parse::<RouteMap>()
+            .is_err());
+        assert!("match as-path 64513".parse::<RouteMap>().is_err());
+        assert!("route-map 10 allow".parse::<RouteMap>().is_err());
+    }
+}
@@ -1,26 +1,162 @@
-use std::collections::hash_map::Keys;
-use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::ops::{Deref, DerefMut};
+use std::path::Path;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
 use bytes::{BufMut, BytesMut};
-use futures::TryStreamExt;
 use ipnetwork;
-use rtnetlink::new_connection;
+use rtnetlink::packet::constants::RT_TABLE_MAIN;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
 
-use crate::bgp_type::AutonomousSystemNumber;
-use crate::config::Config;
+use crate::bgp_type::{AutonomousSystemNumber, RouteDistinguisher};
+use crate::config::{
+    AggregateAddress, Config, DefaultOriginate, InvalidRoutePolicy, OwnPrefixReaction,
+    RibLimitPolicy,
+};
 use crate::error::{ConfigParseError, ConstructIpv4NetworkError, ConvertBytesToBgpMessageError};
+use crate::fib::{Fib, KernelRoute, WeightedNextHop};
+use crate::ipv6_unicast::Ipv6UnicastRib;
+use crate::mrt::{self, MrtPeer};
 use crate::packets::update::UpdateMessage;
-use crate::path_attribute::{self, AsPath, Origin, PathAttribute};
+use crate::path_attribute::{
+    AsPath, ExtendedCommunity, MpReachNlri, Origin, PathAttribute, GRACEFUL_SHUTDOWN,
+};
+use crate::policy::{ExportPolicy, ImportPolicy};
+use crate::rpki::{RoaTable, ValidationState};
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+/// LOCAL_PREFの無い経路には、この値を用いる。
+const DEFAULT_LOCAL_PREF: u32 = 100;
+
+#[derive(Debug)]
 pub struct LocRib {
     rib: Rib,
     local_as_number: AutonomousSystemNumber,
+    /// 変更されるたびに増加する値。`subscribe`したpeerは、これが進むのを待てる。
+    generation: watch::Sender<u64>,
+    best_paths: HashMap<Ipv4Network, Arc<RibEntry>>,
+    churn: HashMap<Ipv4Network, PrefixChurn>,
+    best_path_metrics: BestPathMetrics,
+    med_comparison: MedComparison,
+    /// FIBに書き込むECMPのnext hopの数の上限。
+    maximum_paths: usize,
+    /// 1回の書き込みで減らしてよい、FIBの経路の数の割合(%)。Noneの場合は確認しない。
+    fib_max_shrink_percent: Option<u8>,
+    /// 前回FIBに書き込んだ後の経路の数。まだ書き込んでいなければNone。
+    fib_routes: Option<usize>,
+    /// 到達できないnext hop。これをnext hopとする経路はbest pathにも、FIBにも、広告にも用いない。
+    unreachable_next_hops: BTreeSet<Ipv4Addr>,
+    /// 経路を受信したIBGPのneighbor。best-externalで、IBGPで受信した経路を見分けるのに用いる。
+    ibgp_neighbors: BTreeSet<Ipv4Addr>,
+    /// configのstatic routeから作った経路。reloadで差分を取るのに用いる。
+    static_routes: HashSet<RibEntry>,
+    /// origin validationに用いるROA。Noneの場合は検証しない。
+    roa_table: Option<RoaTable>,
+    invalid_route_policy: InvalidRoutePolicy,
+    /// 集約した経路のNEXT_HOPとAGGREGATORに用いる、自身のaddress。
+    local_ip: Ipv4Addr,
+    aggregate_addresses: Vec<AggregateAddress>,
+    /// `aggregate_addresses`から作り、LocRibに入れている集約した経路。
+    aggregates: HashSet<Arc<RibEntry>>,
+    /// 自身が生成したIPv6 Unicastの経路。IPv6 Unicastを合意したpeerにだけ広告する。
+    ipv6_unicast_rib: Ipv6UnicastRib,
+}
+
+/// best path選択でのMEDの比較方法。
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct MedComparison {
+    /// 隣接ASが異なる経路の間でもMEDを比較する。
+    pub always_compare_med: bool,
+    /// 隣接ASごとにbest pathを選んでから、それらを比較する。
+    pub deterministic_med: bool,
+}
+
+impl From<&Config> for MedComparison {
+    fn from(config: &Config) -> Self {
+        Self {
+            always_compare_med: config.always_compare_med,
+            deterministic_med: config.deterministic_med,
+        }
+    }
+}
+
+/// 経路をbest path選択での優先度で比較する。より優先される経路ほど大きい。
+/// MEDは隣接ASに関わらず比較するので、全順序になる。
+/// `RibEntry`自体の`Eq`, `Hash`はRibのkeyとして経路の同一性を表すので、優先度の比較とは分けている。
+#[derive(Debug, Clone)]
+pub struct ByPreference(pub Arc<RibEntry>);
+
+impl ByPreference {
+    const MED_COMPARISON: MedComparison = MedComparison {
+        always_compare_med: true,
+        deterministic_med: false,
+    };
+}
+
+impl Ord for ByPreference {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.0.compare_preference(&self.0, Self::MED_COMPARISON)
+    }
+}
+
+impl PartialOrd for ByPreference {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// 優先度が等しい場合に等しいとする。同じ経路であるとは限らない。
+impl PartialEq for ByPreference {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ByPreference {}
+
+/// prefixごとのbest pathの変化の記録。
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PrefixChurn {
+    /// best pathが変化した回数。経路が無くなった場合も1回と数える。
+    pub count: u64,
+    pub last_changed: Instant,
+}
+
+/// best pathの計算時間のhistogramの境界。
+pub const BEST_PATH_DURATION_BUCKETS: [Duration; 5] = [
+    Duration::from_micros(100),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+];
+
+/// LocRibの変更でbest pathを計算し直した回数と、その所要時間。
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize)]
+pub struct BestPathMetrics {
+    pub computations: u64,
+    /// i番目は所要時間が`BEST_PATH_DURATION_BUCKETS[i]`以下だった回数。
+    /// 最後の要素は、全ての境界を超えた回数。
+    pub duration_histogram: [u64; BEST_PATH_DURATION_BUCKETS.len() + 1],
+    pub total_duration: Duration,
+}
+
+impl BestPathMetrics {
+    fn record(&mut self, duration: Duration) {
+        self.computations += 1;
+        self.total_duration += duration;
+        let bucket = BEST_PATH_DURATION_BUCKETS
+            .iter()
+            .position(|bound| duration <= *bound)
+            .unwrap_or(BEST_PATH_DURATION_BUCKETS.len());
+        self.duration_histogram[bucket] += 1;
+    }
 }
 
 impl Deref for LocRib {
@@ -41,54 +177,433 @@ impl DerefMut for LocRib {
 pub enum RibEntryStatus {
     New,
     UnChanged,
+    Withdrawn,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
 pub struct RibEntry {
     pub network_address: Ipv4Network,
     pub path_attributes: Arc<Vec<PathAttribute>>,
+    /// 経路を受信したneighborのaddress。自身が広告元の経路ではNone。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub learned_from: Option<Ipv4Addr>,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
-pub struct Rib(HashMap<Arc<RibEntry>, RibEntryStatus>);
+pub struct Rib {
+    entries: HashMap<Arc<RibEntry>, RibEntryStatus>,
+    /// Withdrawnでない経路の数。`len`で数え直さないよう、entriesの変更に合わせて更新する。
+    routes_len: usize,
+    /// Graceful Restart中に保持している、古くなった経路。
+    stale: HashSet<Arc<RibEntry>>,
+    max_entries: Option<usize>,
+    limit_policy: RibLimitPolicy,
+}
+
+impl Default for Rib {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Rib {
     pub fn new() -> Self {
-        Self(HashMap::new())
+        Self::with_limit(None, RibLimitPolicy::default())
+    }
+
+    pub fn with_limit(max_entries: Option<usize>, limit_policy: RibLimitPolicy) -> Self {
+        Self {
+            entries: HashMap::new(),
+            routes_len: 0,
+            stale: HashSet::new(),
+            max_entries,
+            limit_policy,
+        }
+    }
+
+    /// entryをRibに追加する。`max_entries`を超える場合は`limit_policy`に従い、
+    /// entryが追加されなかった場合はfalseを返す。
+    /// staleな経路を再び受け取った場合は、新しい経路として扱う。
+    pub fn insert(&mut self, entry: Arc<RibEntry>) -> bool {
+        if let Some(status) = self.entries.get_mut(&entry) {
+            if *status == RibEntryStatus::Withdrawn {
+                self.routes_len += 1;
+            }
+            if *status == RibEntryStatus::Withdrawn || self.stale.remove(&entry) {
+                *status = RibEntryStatus::New;
+            }
+            return true;
+        }
+        if let Some(max_entries) = self.max_entries {
+            if self.len() >= max_entries && !self.make_room_for(&entry) {
+                warn!(
+                    "rib entry limit {} is reached, refuse new route {:?}.",
+                    max_entries, entry.network_address
+                );
+                return false;
+            }
+        }
+        self.entries.insert(entry, RibEntryStatus::New);
+        self.routes_len += 1;
+        true
+    }
+
+    /// best path選択で最も優先されない経路が、entryよりも優先されなければ取り除く。
+    fn make_room_for(&mut self, entry: &Arc<RibEntry>) -> bool {
+        match self.limit_policy {
+            RibLimitPolicy::RefuseNew => false,
+            RibLimitPolicy::EvictLeastPreferred => {
+                let least_preferred = self.routes().map(|e| ByPreference(Arc::clone(e))).min();
+                match least_preferred {
+                    Some(least_preferred) if least_preferred < ByPreference(Arc::clone(entry)) => {
+                        warn!(
+                            "rib entry limit is reached, evict route {:?}.",
+                            least_preferred.0.network_address
+                        );
+                        self.remove_entry(&least_preferred.0)
+                    }
+                    _ => false,
+                }
+            }
+        }
+    }
+
+    /// 同じnetworkへの他の経路をWithdrawnにしてから、entryを追加する(implicit withdraw)。
+    /// 同じ経路を受け取り直した場合は、その経路の状態を変えない。
+    pub fn replace(&mut self, entry: Arc<RibEntry>) -> bool {
+        let replaced: Vec<Arc<RibEntry>> = self
+            .routes()
+            .filter(|e| e.network_address == entry.network_address && **e != entry)
+            .cloned()
+            .collect();
+        replaced.iter().for_each(|e| self.withdraw_entry(e));
+        self.insert(entry)
+    }
+
+    /// networkへの経路を全てWithdrawnにする。該当する経路があった場合はtrueを返す。
+    pub fn withdraw(&mut self, network: Ipv4Network) -> bool {
+        let mut withdrawn = false;
+        for (entry, status) in self.entries.iter_mut() {
+            if entry.network_address == network && *status != RibEntryStatus::Withdrawn {
+                *status = RibEntryStatus::Withdrawn;
+                self.routes_len -= 1;
+                withdrawn = true;
+            }
+        }
+        withdrawn
+    }
+
+    fn withdraw_entry(&mut self, entry: &RibEntry) {
+        if let Some(status) = self.entries.get_mut(entry) {
+            if *status != RibEntryStatus::Withdrawn {
+                self.routes_len -= 1;
+            }
+            *status = RibEntryStatus::Withdrawn;
+        }
+    }
+
+    /// networkへの経路をWithdrawnを経由せずに取り除く。
+    pub fn remove(&mut self, network: Ipv4Network) -> bool {
+        let len = self.entries.len();
+        let mut removed_routes = 0;
+        self.entries.retain(|entry, status| {
+            let removed = entry.network_address == network;
+            if removed && *status != RibEntryStatus::Withdrawn {
+                removed_routes += 1;
+            }
+            !removed
+        });
+        self.routes_len -= removed_routes;
+        self.stale.retain(|entry| entry.network_address != network);
+        len != self.entries.len()
+    }
+
+    /// entryをWithdrawnを経由せずに取り除く。
+    pub fn remove_entry(&mut self, entry: &RibEntry) -> bool {
+        self.stale.remove(entry);
+        match self.entries.remove(entry) {
+            Some(status) => {
+                if status != RibEntryStatus::Withdrawn {
+                    self.routes_len -= 1;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 全ての経路をWithdrawnにする。
+    pub fn withdraw_all(&mut self) {
+        self.entries
+            .values_mut()
+            .for_each(|status| *status = RibEntryStatus::Withdrawn);
+        self.routes_len = 0;
+        self.stale.clear();
+    }
+
+    pub fn mark_all_stale(&mut self) {
+        self.stale = self.routes().cloned().collect();
+    }
+
+    /// 経路をstaleにする。Ribに無い経路は無視する。
+    pub fn mark_stale(&mut self, entry: &Arc<RibEntry>) {
+        if self.contains(entry) {
+            self.stale.insert(Arc::clone(entry));
+        }
+    }
+
+    pub fn is_stale(&self, entry: &RibEntry) -> bool {
+        self.stale.contains(entry)
+    }
+
+    pub fn stale_routes(&self) -> impl Iterator<Item = &Arc<RibEntry>> {
+        self.routes().filter(|entry| self.is_stale(entry))
+    }
+
+    /// staleな経路を全てWithdrawnにする。
+    pub fn withdraw_stale_routes(&mut self) {
+        let stale: Vec<Arc<RibEntry>> = self.stale.drain().collect();
+        stale.iter().for_each(|entry| self.withdraw_entry(entry));
+    }
+
+    /// `write_routes`で書き出した経路を、staleとして加える。既にある経路はそのままにする。
+    /// 初めての起動ではファイルが無いので、何もしない。加えた経路の数を返す。
+    pub fn load_stale(&mut self, path: &Path) -> Result<usize> {
+        if !path.exists() {
+            return Ok(0);
+        }
+        let json = std::fs::read_to_string(path)
+            .context(format!("cannot read rib store {}", path.display()))?;
+        let entries: Vec<RibEntry> = serde_json::from_str(&json).context(format!(
+            "cannot parse rib store {} as rib entries",
+            path.display()
+        ))?;
+        let mut loaded = 0;
+        for entry in entries {
+            let entry = Arc::new(entry);
+            if !self.contains(&entry) && self.insert(Arc::clone(&entry)) {
+                self.mark_stale(&entry);
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+
+    pub fn contains(&self, entry: &RibEntry) -> bool {
+        matches!(
+            self.entries.get(entry),
+            Some(status) if *status != RibEntryStatus::Withdrawn
+        )
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes_len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Withdrawnでない経路を返す。
+    pub fn routes(&self) -> impl Iterator<Item = &Arc<RibEntry>> {
+        self.entries
+            .iter()
+            .filter(|(_, status)| **status != RibEntryStatus::Withdrawn)
+            .map(|(entry, _)| entry)
     }
 
-    pub fn insert(&mut self, entry: Arc<RibEntry>) {
-        self.0.entry(entry).or_insert(RibEntryStatus::New);
+    pub fn new_routes(&self) -> impl Iterator<Item = &Arc<RibEntry>> {
+        self.entries
+            .iter()
+            .filter(|(_, status)| **status == RibEntryStatus::New)
+            .map(|(entry, _)| entry)
     }
 
-    pub fn routes(&self) -> Keys<'_, Arc<RibEntry>, RibEntryStatus> {
-        self.0.keys()
+    pub fn withdrawn_routes(&self) -> impl Iterator<Item = &Arc<RibEntry>> {
+        self.entries
+            .iter()
+            .filter(|(_, status)| **status == RibEntryStatus::Withdrawn)
+            .map(|(entry, _)| entry)
     }
+
+    /// Withdrawnの経路を取り除き、残りをUnChangedにする。
     pub fn update_to_all_changed(&mut self) {
-        self.0
-            .iter_mut()
-            .for_each(|(_, v)| *v = RibEntryStatus::UnChanged);
+        self.remove_withdrawn_routes();
+        self.mark_new_routes_unchanged();
+    }
+
+    pub fn remove_withdrawn_routes(&mut self) {
+        self.entries.retain(|_, v| *v != RibEntryStatus::Withdrawn);
+        let entries = &self.entries;
+        self.stale.retain(|entry| entries.contains_key(entry));
+    }
+
+    pub fn mark_new_routes_unchanged(&mut self) {
+        self.entries
+            .values_mut()
+            .filter(|v| **v == RibEntryStatus::New)
+            .for_each(|v| *v = RibEntryStatus::UnChanged);
     }
     pub fn does_contain_new_route(&self) -> bool {
-        self.0
+        self.entries.values().any(|v| &RibEntryStatus::New == v)
+    }
+    pub fn does_contain_withdrawn_route(&self) -> bool {
+        self.entries
             .values()
-            .map(|v| &RibEntryStatus::New == v)
-            .any(|v| v)
+            .any(|v| &RibEntryStatus::Withdrawn == v)
+    }
+    pub fn does_contain_changed_route(&self) -> bool {
+        self.does_contain_new_route() || self.does_contain_withdrawn_route()
+    }
+
+    /// `self`から`other`への差分を、networkとpath attributeで比較して返す。
+    /// Withdrawnの経路は含めない。
+    pub fn diff(&self, other: &Rib) -> RibDiff {
+        let by_network = |rib: &Rib| {
+            let mut networks: BTreeMap<Ipv4Network, HashSet<RibEntry>> = BTreeMap::new();
+            for entry in rib.routes() {
+                networks
+                    .entry(entry.network_address)
+                    .or_default()
+                    .insert(RibEntry::clone(entry));
+            }
+            networks
+        };
+        let before = by_network(self);
+        let after = by_network(other);
+        let mut diff = RibDiff::default();
+        for (network, entries) in &after {
+            match before.get(network) {
+                None => diff.added.extend(entries.iter().cloned()),
+                Some(before_entries) => diff.changed.extend(
+                    entries
+                        .iter()
+                        .filter(|entry| !before_entries.contains(*entry))
+                        .cloned(),
+                ),
+            }
+        }
+        for (network, entries) in before {
+            if !after.contains_key(&network) {
+                diff.removed.extend(entries);
+            }
+        }
+        diff
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+/// `Rib::diff`で求めた2つのRibの差分。経路はnetworkの順に並ぶ。
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct RibDiff {
+    /// 新しく現れたnetworkへの経路。
+    pub added: Vec<RibEntry>,
+    /// 無くなったnetworkへの経路。
+    pub removed: Vec<RibEntry>,
+    /// 両方にあるnetworkで、path attributeが変わった経路。変更後の経路を持つ。
+    pub changed: Vec<RibEntry>,
+}
+
+impl RibDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
 pub struct AdjRibOut(Rib);
 
 impl AdjRibOut {
     pub fn new() -> Self {
-        Self(Rib::new())
+        Default::default()
     }
     pub fn install_from_loc_rib(&mut self, loc_rib: &LocRib, config: &Config) {
-        loc_rib
+        let default_route = Self::default_route(loc_rib, config);
+        let originates_default_route = default_route.is_some();
+        let best_external_paths = if config.best_external && config.is_ibgp() {
+            loc_rib.best_external_paths()
+        } else {
+            HashMap::new()
+        };
+        let export_policy = ExportPolicy::from_config(config);
+        // prefixごとにbest pathを1つだけ広告する。IBGPで受信した経路はIBGPのneighborに
+        // 広告しない(split horizon)。best-externalが有効ならEBGPで受信した経路で代える。
+        let exported: HashSet<Arc<RibEntry>> = loc_rib
+            .best_paths
+            .iter()
+            .filter_map(|(network, best)| match best_external_paths.get(network) {
+                Some(external) => Some(external),
+                None if config.is_ibgp() && loc_rib.is_ibgp_learned(best) => None,
+                None => Some(best),
+            })
+            .filter(|entry| config.as_override || !entry.does_contain_as(config.remote_as))
+            .filter(|entry| loc_rib.is_next_hop_reachable(entry))
+            .filter(|entry| Self::is_in_origination_scope(entry, config))
+            .filter(|entry| Self::satisfies_advertise_conditions(entry, loc_rib, config))
+            .filter(|entry| !(originates_default_route && entry.is_default_route()))
+            .map(|entry| {
+                if config.as_override {
+                    entry.replace_as(config.remote_as, config.advertised_local_as())
+                } else {
+                    Arc::clone(entry)
+                }
+            })
+            .filter_map(|entry| export_policy.apply(entry))
+            .chain(default_route)
+            .collect();
+        exported.iter().for_each(|r| {
+            self.insert(Arc::clone(r));
+        });
+        let withdrawn: Vec<Arc<RibEntry>> = self
             .routes()
-            .filter(|entry| !entry.does_contain_as(config.remote_as))
-            .for_each(|r| self.insert(Arc::clone(r)));
+            .filter(|entry| !exported.contains(*entry))
+            .cloned()
+            .collect();
+        withdrawn
+            .iter()
+            .for_each(|entry| self.withdraw_entry(entry));
+    }
+
+    /// `advertise_if`の指定があれば、その全ての条件をLocRibのbest pathが満たしているか調べる。
+    fn satisfies_advertise_conditions(entry: &RibEntry, loc_rib: &LocRib, config: &Config) -> bool {
+        config
+            .conditional_advertisements
+            .iter()
+            .filter(|rule| rule.network == entry.network_address)
+            .all(|rule| rule.is_satisfied(loc_rib.best_path(rule.monitored).is_some()))
+    }
+
+    /// 自身が広告元の経路に`originate_to`の指定があれば、このneighborが含まれるか調べる。
+    fn is_in_origination_scope(entry: &RibEntry, config: &Config) -> bool {
+        if !entry.is_locally_originated() {
+            return true;
+        }
+        let mut scopes = config
+            .origination_scopes
+            .iter()
+            .filter(|scope| scope.network == entry.network_address)
+            .peekable();
+        scopes.peek().is_none() || scopes.any(|scope| scope.neighbor.matches(config))
+    }
+
+    /// `default_originate`の条件を満たしていれば、自身をnext hopとするdefault routeを返す。
+    fn default_route(loc_rib: &LocRib, config: &Config) -> Option<Arc<RibEntry>> {
+        let originates = match config.default_originate? {
+            DefaultOriginate::Always => true,
+            DefaultOriginate::IfPresent(network) => {
+                loc_rib.routes().any(|e| e.network_address == network)
+            }
+        };
+        originates.then(|| {
+            Arc::new(RibEntry {
+                network_address: "0.0.0.0/0".parse().unwrap(),
+                path_attributes: Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::sequence(vec![])),
+                    PathAttribute::NextHop(config.local_ip),
+                ]),
+                learned_from: None,
+            })
+        })
     }
 }
 
@@ -106,7 +621,8 @@ impl DerefMut for AdjRibOut {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct Ipv4Network(ipnetwork::Ipv4Network);
 
 impl Deref for Ipv4Network {
@@ -142,287 +658,3336 @@ impl FromStr for Ipv4Network {
 
 impl LocRib {
     pub async fn new(config: &Config) -> Result<Self> {
-        let path_attributes = Arc::new(vec![
-            PathAttribute::Origin(Origin::Igp),
-            PathAttribute::AsPath(AsPath::AsSequence(vec![])),
-            PathAttribute::NextHop(config.local_ip),
-        ]);
-        let mut rib = Rib::new();
+        let kernel_routes = Self::lookup_kernel_routes(config).await?;
+        Self::with_kernel_routes(config, &kernel_routes)
+    }
+
+    /// kernel_routesをカーネルのルーティングテーブルとして、network statementとredistributeの経路を作る。
+    pub fn with_kernel_routes(config: &Config, kernel_routes: &[KernelRoute]) -> Result<Self> {
+        let mut rib = Self::originated_rib(config, kernel_routes);
+        // カーネルから読み込んだ経路なので、カーネルへの書き込み対象にはしない。
+        rib.update_to_all_changed();
+        let med_comparison = MedComparison::from(config);
+        let unreachable_next_hops = BTreeSet::new();
+        let roa_table = config.roa_file.as_ref().map(RoaTable::load).transpose()?;
+        let invalid_route_policy = config.invalid_route_policy;
+        let static_routes = Self::static_routes(config).collect();
+        let best_paths = Self::select_best_paths(
+            &rib,
+            med_comparison,
+            &unreachable_next_hops,
+            &static_routes,
+            Self::depreferring_roa_table(roa_table.as_ref(), invalid_route_policy),
+        );
+        let mut loc_rib = Self {
+            rib,
+            local_as_number: config.local_as,
+            generation: watch::channel(0).0,
+            best_paths,
+            churn: HashMap::new(),
+            best_path_metrics: BestPathMetrics::default(),
+            med_comparison,
+            maximum_paths: config.maximum_paths.max(1),
+            fib_max_shrink_percent: config.fib_max_shrink_percent,
+            fib_routes: None,
+            unreachable_next_hops,
+            ibgp_neighbors: BTreeSet::new(),
+            static_routes,
+            roa_table,
+            invalid_route_policy,
+            local_ip: config.local_ip,
+            aggregate_addresses: config.aggregate_addresses.clone(),
+            aggregates: HashSet::new(),
+            ipv6_unicast_rib: Ipv6UnicastRib::new(),
+        };
+        if loc_rib.update_aggregates() {
+            loc_rib.mark_changed();
+        }
+        Ok(loc_rib)
+    }
+
+    /// network statementもredistributeも無ければ、カーネルの経路は読まない。
+    async fn lookup_kernel_routes(config: &Config) -> Result<Vec<KernelRoute>> {
+        if config.networks.is_empty() && config.redistribute.is_empty() {
+            return Ok(vec![]);
+        }
+        KernelRoute::lookup_all().await
+    }
+
+    /// configのnetworkに一致するカーネルの経路、redistributeするカーネルの経路と、
+    /// static routeから、自身が広告元の経路を作る。
+    fn originated_rib(config: &Config, kernel_routes: &[KernelRoute]) -> Rib {
+        let path_attributes_with = |origin: Origin| {
+            Arc::new(vec![
+                PathAttribute::Origin(origin),
+                PathAttribute::AsPath(AsPath::sequence(vec![])),
+                PathAttribute::NextHop(config.local_ip),
+            ])
+        };
+        let path_attributes = path_attributes_with(config.default_origin);
+        let mut rib = Rib::with_limit(config.max_rib_entries, config.rib_limit_policy);
         for network in &config.networks {
-            let routes = Self::lookup_kernel_routing_table(*network).await?;
+            let path_attributes = match network.origin {
+                Some(origin) => path_attributes_with(origin),
+                None => Arc::clone(&path_attributes),
+            };
+            let routes = kernel_routes
+                .iter()
+                .filter(|route| network.matches(route.destination));
             for route in routes {
                 rib.insert(Arc::new(RibEntry {
-                    network_address: route,
+                    network_address: route.destination,
                     path_attributes: Arc::clone(&path_attributes),
-                }))
+                    learned_from: None,
+                }));
             }
         }
-        Ok(Self {
-            rib,
-            local_as_number: config.local_as,
+        // main tableの経路のうち、protocolがredistributeのいずれかであるもの。
+        let redistributed = kernel_routes.iter().filter(|route| {
+            route.table == RT_TABLE_MAIN
+                && config
+                    .redistribute
+                    .iter()
+                    .any(|protocol| protocol.rtm_protocol() == route.protocol)
+        });
+        for route in redistributed {
+            rib.insert(Arc::new(RibEntry {
+                network_address: route.destination,
+                path_attributes: Arc::clone(&path_attributes),
+                learned_from: None,
+            }));
+        }
+        for entry in Self::static_routes(config) {
+            rib.insert(Arc::new(entry));
+        }
+        rib
+    }
+
+    fn static_routes(config: &Config) -> impl Iterator<Item = RibEntry> + '_ {
+        config.static_routes.iter().map(|route| RibEntry {
+            network_address: route.network,
+            path_attributes: Arc::new(
+                route.path_attributes(config.local_ip, config.default_origin),
+            ),
+            learned_from: None,
         })
     }
 
-    async fn lookup_kernel_routing_table(
-        network_address: Ipv4Network,
-    ) -> Result<(Vec<Ipv4Network>)> {
-        let (connection, handle, _) = new_connection()?;
-        tokio::spawn(connection);
-        let mut routes = handle.route().get(rtnetlink::IpVersion::V4).execute();
-        let mut results = vec![];
-        while let Some(route) = routes.try_next().await? {
-            let destination = if let Some((IpAddr::V4(addr), prefix)) = route.destination_prefix() {
-                ipnetwork::Ipv4Network::new(addr, prefix)?.into()
-            } else {
-                continue;
-            };
-            if destination != network_address {
-                continue;
-            }
+    /// 新しいconfigから自身が広告元の経路を作り直し、今の経路との差分だけをLocRibに反映する。
+    /// 反映した差分を返す。経路はカーネル由来なので、カーネルへは書き込まない。
+    pub async fn reload(&mut self, config: &Config) -> Result<RibDiff> {
+        let kernel_routes = Self::lookup_kernel_routes(config).await?;
+        let originated = Self::originated_rib(config, &kernel_routes);
+        let mut running = Rib::new();
+        self.rib
+            .routes()
+            .filter(|e| self.is_originated(e) && !self.aggregates.contains(*e))
+            .for_each(|e| {
+                running.insert(Arc::clone(e));
+            });
+        let diff = running.diff(&originated);
+        self.static_routes = Self::static_routes(config).collect();
 
-            results.push(destination);
-        }
-        Ok(results)
-    }
-    pub async fn write_to_kernel_routing_table(&self) -> Result<()> {
-        let (connection, handle, _) = new_connection()?;
-        tokio::spawn(connection);
-        for e in self.routes() {
-            for p in e.path_attributes.iter() {
-                if let PathAttribute::NextHop(gateway) = p {
-                    let dest = e.network_address;
-                    handle
-                        .route()
-                        .add()
-                        .v4()
-                        .destination_prefix(dest.ip(), dest.prefix())
-                        .gateway(*gateway)
-                        .execute()
-                        .await?;
-                    break;
-                }
+        let stale_networks: BTreeSet<Ipv4Network> = diff
+            .removed
+            .iter()
+            .chain(&diff.changed)
+            .map(|e| e.network_address)
+            .collect();
+        for entry in running.routes() {
+            if stale_networks.contains(&entry.network_address) && !originated.contains(entry) {
+                self.rib.remove_entry(entry);
             }
         }
-        Ok(())
+        for entry in diff.added.iter().chain(&diff.changed) {
+            self.rib.insert(Arc::new(entry.clone()));
+        }
+        self.rib.mark_new_routes_unchanged();
+        let aggregates_changed = self.update_aggregates();
+        if aggregates_changed && diff.is_empty() {
+            self.mark_changed();
+        }
+        if !diff.is_empty() {
+            info!(
+                "config is reloaded, {} routes are added, {} removed and {} changed.",
+                diff.added.len(),
+                diff.removed.len(),
+                diff.changed.len()
+            );
+            self.mark_changed();
+        }
+        Ok(diff)
     }
 
-    pub fn intsall_from_adj_rib_in(&mut self, adj_rib_in: &AdjRibIn) {
-        let local_as = self.local_as_number;
-
-        adj_rib_in
-            .routes()
-            .filter(|entry| !entry.does_contain_as(local_as))
-            .for_each(|entry| self.insert(Arc::clone(&entry)));
+    /// LocRibが変更されるたびに増加する値。
+    /// peerはこれを見てLocRibChangedを発生させる。
+    pub fn generation(&self) -> u64 {
+        *self.generation.borrow()
     }
-}
 
-impl RibEntry {
-    fn does_contain_as(&self, as_number: AutonomousSystemNumber) -> bool {
-        for path_attribute in self.path_attributes.iter() {
-            if let PathAttribute::AsPath(as_path) = path_attribute {
-                return as_path.does_contain(as_number);
-            }
-        }
-        false
+    /// generationが進んだことを、LocRibのlockを取らずに待てるReceiverを返す。
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.generation.subscribe()
     }
-}
 
-impl Ipv4Network {
-    pub fn new(addr: Ipv4Addr, prefix: u8) -> Result<Self, ConstructIpv4NetworkError> {
-        let net = ipnetwork::Ipv4Network::new(addr, prefix).context(format!(
-            "Ipv4NetworkをConstructできませんでしたaddr:{}, prefix: {}
-            ",
-            addr, prefix
-        ))?;
-        Ok(Self(net))
+    fn increment_generation(&mut self) {
+        self.generation.send_modify(|generation| *generation += 1);
     }
-    pub fn from_u8_slice(bytes: &[u8]) -> Result<Vec<Self>, ConvertBytesToBgpMessageError> {
-        let mut networks = vec![];
-        let mut i = 0;
-        while bytes.len() > i {
-            let prefix = bytes[i];
-            i += 1;
-            if prefix == 0 {
-                networks.push(Ipv4Network::new(Ipv4Addr::new(0, 0, 0, 0), prefix).context("")?);
-                i += 1;
-            } else if (1..=8).contains(&prefix) {
-                networks
-                    .push(Ipv4Network::new(Ipv4Addr::new(bytes[i], 0, 0, 0), prefix).context("")?);
-                i += 1;
-            } else if (9..=16).contains(&prefix) {
-                networks.push(
-                    Ipv4Network::new(Ipv4Addr::new(bytes[i], bytes[i + 1], 0, 0), prefix)
-                        .context("")?,
-                );
-                i += 2;
-            } else if (17..=24).contains(&prefix) {
-                networks.push(
-                    Ipv4Network::new(
-                        Ipv4Addr::new(bytes[i], bytes[i + 1], bytes[i + 2], 0),
-                        prefix,
-                    )
-                    .context("bytes -> Ipv4に変換できませんでした。")?,
-                );
-                i += 3;
-            } else if (24..=32).contains(&prefix) {
-                networks.push(
-                    Ipv4Network::new(
-                        Ipv4Addr::new(bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]),
-                        prefix,
-                    )
-                    .context("bytes -> Ipv4に変換できませんでした。")?,
-                );
-                i += 4;
-            } else {
-                return Err(ConvertBytesToBgpMessageError::from(anyhow::anyhow!(
-                    "bytes -> Ipv4に変換できませんでした。 \
-                    Prefixが0-32の間ではありません。
-                    "
-                )));
-            };
+
+    /// LocRibの変更を記録する。generationを進め、
+    /// best pathが変化したprefixのchurnを数える。
+    fn mark_changed(&mut self) {
+        self.increment_generation();
+        let started = Instant::now();
+        let best_paths = Self::select_best_paths(
+            &self.rib,
+            self.med_comparison,
+            &self.unreachable_next_hops,
+            &self.static_routes,
+            Self::depreferring_roa_table(self.roa_table.as_ref(), self.invalid_route_policy),
+        );
+        self.best_path_metrics.record(started.elapsed());
+        let networks: BTreeSet<Ipv4Network> = best_paths
+            .keys()
+            .chain(self.best_paths.keys())
+            .copied()
+            .collect();
+        let now = Instant::now();
+        for network in networks {
+            if best_paths.get(&network) != self.best_paths.get(&network) {
+                let churn = self.churn.entry(network).or_insert(PrefixChurn {
+                    count: 0,
+                    last_changed: now,
+                });
+                churn.count += 1;
+                churn.last_changed = now;
+            }
         }
-        Ok(networks)
+        self.best_paths = best_paths;
     }
 
-    pub fn bytes_len(&self) -> usize {
-        match self.prefix() {
-            0 => 1,
-            1..9 => 2,
-            9..17 => 3,
-            17..25 => 4,
-            25..33 => 5,
-            _ => panic!("prefixが0..32の間ではありません！"),
+    /// prefixごとにbest pathを選ぶ。
+    /// 比較する順番を再現できるよう、経路はNEXT_HOPの順に比較する。
+    /// staleな経路はそうでない経路より優先されない。
+    /// 到達できないnext hopの経路は選ばない。
+    /// 自身が広告元の経路は、受信した経路より常に優先する。
+    /// roa_tableが渡された場合、origin validationでInvalidの経路はそうでない経路より優先されない。
+    fn select_best_paths(
+        rib: &Rib,
+        med_comparison: MedComparison,
+        unreachable_next_hops: &BTreeSet<Ipv4Addr>,
+        static_routes: &HashSet<RibEntry>,
+        roa_table: Option<&RoaTable>,
+    ) -> HashMap<Ipv4Network, Arc<RibEntry>> {
+        let mut candidates: HashMap<Ipv4Network, Vec<&Arc<RibEntry>>> = HashMap::new();
+        for entry in rib
+            .routes()
+            .filter(|e| !e.has_unreachable_next_hop(unreachable_next_hops))
+        {
+            candidates
+                .entry(entry.network_address)
+                .or_default()
+                .push(entry);
         }
+        candidates
+            .into_iter()
+            .filter_map(|(network, mut entries)| {
+                // staleな経路は、そうでない経路が無いときだけ選ぶ。
+                if entries.iter().any(|e| !rib.is_stale(e)) {
+                    entries.retain(|e| !rib.is_stale(e));
+                }
+                let is_originated =
+                    |e: &RibEntry| e.is_locally_originated() || static_routes.contains(e);
+                if entries.iter().any(|e| is_originated(e)) {
+                    entries.retain(|e| is_originated(e));
+                }
+                if let Some(roa_table) = roa_table {
+                    let is_invalid =
+                        |e: &RibEntry| roa_table.validate(e) == ValidationState::Invalid;
+                    if entries.iter().any(|e| !is_invalid(e)) {
+                        entries.retain(|e| !is_invalid(e));
+                    }
+                }
+                entries.sort_by_key(|e| e.next_hop());
+                Self::select_best_path(&entries, med_comparison)
+                    .map(|best| (network, Arc::clone(best)))
+            })
+            .collect()
     }
-}
-
-impl From<&Ipv4Network> for BytesMut {
-    fn from(network: &Ipv4Network) -> BytesMut {
-        let prefix = network.prefix();
 
-        let n = network.network().octets();
-        let network_bytes = match prefix {
-            0 => vec![],
-            1..9 => n[0..1].into(),
-            9..17 => n[0..2].into(),
-            17..25 => n[0..3].into(),
-            25..33 => n[0..4].into(),
-            _ => panic!("prefixが0..32の間ではありません！"),
-        };
-        let mut bytes = BytesMut::new();
-        bytes.put_u8(prefix);
-        bytes.put(&network_bytes[..]);
-        bytes
+    fn select_best_path<'a>(
+        entries: &[&'a Arc<RibEntry>],
+        med_comparison: MedComparison,
+    ) -> Option<&'a Arc<RibEntry>> {
+        if !med_comparison.deterministic_med {
+            return Self::fold_best_path(entries.iter().copied(), med_comparison);
+        }
+        let mut groups: BTreeMap<Option<AutonomousSystemNumber>, Vec<&Arc<RibEntry>>> =
+            BTreeMap::new();
+        for entry in entries {
+            groups.entry(entry.neighbor_as()).or_default().push(entry);
+        }
+        Self::fold_best_path(
+            groups
+                .into_values()
+                .filter_map(|group| Self::fold_best_path(group.into_iter(), med_comparison)),
+            med_comparison,
+        )
     }
-}
 
-impl AdjRibOut {
-    pub fn create_update_messages(
-        &self,
-        local_ip: Ipv4Addr,
-        local_as: AutonomousSystemNumber,
-    ) -> Vec<UpdateMessage> {
-        let mut hash_map: HashMap<Arc<Vec<PathAttribute>>, Vec<Ipv4Network>> = HashMap::new();
-        for entry in self.routes() {
-            if let Some(routes) = hash_map.get_mut(&entry.path_attributes) {
-                routes.push(entry.network_address);
+    /// 順番に比較して、より優先される経路を残していく。
+    fn fold_best_path<'a>(
+        entries: impl Iterator<Item = &'a Arc<RibEntry>>,
+        med_comparison: MedComparison,
+    ) -> Option<&'a Arc<RibEntry>> {
+        entries.reduce(|best, entry| {
+            if entry.compare_preference(best, med_comparison) == Ordering::Less {
+                entry
             } else {
-                hash_map.insert(
-                    Arc::clone(&entry.path_attributes),
-                    vec![entry.network_address],
-                );
+                best
             }
-        }
+        })
+    }
 
-        let mut updates = vec![];
-        for (path_attribute, routes) in hash_map.into_iter() {
-            let mut path_attributes = Arc::<Vec<PathAttribute>>::unwrap_or_clone(path_attribute);
-            for p in path_attributes.iter_mut() {
-                if let PathAttribute::NextHop(n) = p {
-                    *n = local_ip
+    /// InvalidRoutePolicy::DePreferの場合だけ、best path選択に用いるROAを返す。
+    fn depreferring_roa_table(
+        roa_table: Option<&RoaTable>,
+        invalid_route_policy: InvalidRoutePolicy,
+    ) -> Option<&RoaTable> {
+        roa_table.filter(|_| invalid_route_policy == InvalidRoutePolicy::DePrefer)
+    }
+
+    /// 経路のorigin validationの結果。ROAが設定されていなければNoneを返す。
+    pub fn validation_state(&self, entry: &RibEntry) -> Option<ValidationState> {
+        self.roa_table.as_ref().map(|table| table.validate(entry))
+    }
+
+    pub fn best_path(&self, network: Ipv4Network) -> Option<&Arc<RibEntry>> {
+        self.best_paths.get(&network)
+    }
+
+    /// best pathがIBGPで受信した経路のprefixごとに、EBGPで受信した経路の中で
+    /// 最も優先される経路(best-external)を返す。
+    pub fn best_external_paths(&self) -> HashMap<Ipv4Network, Arc<RibEntry>> {
+        self.best_paths
+            .iter()
+            .filter(|(_, best)| self.is_ibgp_learned(best))
+            .filter_map(|(network, _)| {
+                let mut candidates: Vec<&Arc<RibEntry>> = self
+                    .rib
+                    .routes()
+                    .filter(|e| e.network_address == *network)
+                    .filter(|e| e.learned_from.is_some() && !self.is_ibgp_learned(e))
+                    .filter(|e| !self.rib.is_stale(e) && self.is_next_hop_reachable(e))
+                    .collect();
+                candidates.sort_by_key(|e| e.next_hop());
+                Self::select_best_path(&candidates, self.med_comparison)
+                    .map(|best| (*network, Arc::clone(best)))
+            })
+            .collect()
+    }
+
+    /// neighborをIBGPのneighborとして記録する。
+    pub fn add_ibgp_neighbor(&mut self, neighbor: Ipv4Addr) {
+        self.ibgp_neighbors.insert(neighbor);
+    }
+
+    fn is_ibgp_learned(&self, entry: &RibEntry) -> bool {
+        entry
+            .learned_from
+            .is_some_and(|neighbor| self.ibgp_neighbors.contains(&neighbor))
+    }
+
+    pub fn best_path_metrics(&self) -> &BestPathMetrics {
+        &self.best_path_metrics
+    }
+
+    /// いずれかのprefixのbest pathが最後に変化した時刻。
+    pub fn last_best_path_change(&self) -> Option<Instant> {
+        self.churn.values().map(|churn| churn.last_changed).max()
+    }
+
+    pub fn churn(&self, network: Ipv4Network) -> Option<PrefixChurn> {
+        self.churn.get(&network).copied()
+    }
+
+    /// best pathの変化回数が多い順にn個のprefixを返す。
+    pub fn top_churning_prefixes(&self, n: usize) -> Vec<(Ipv4Network, PrefixChurn)> {
+        let mut prefixes: Vec<(Ipv4Network, PrefixChurn)> =
+            self.churn.iter().map(|(k, v)| (*k, *v)).collect();
+        prefixes.sort_by(|a, b| b.1.count.cmp(&a.1.count).then(a.0.cmp(&b.0)));
+        prefixes.truncate(n);
+        prefixes
+    }
+
+    /// networkへのIPv6 Unicastの経路をnext_hopで生成する。同じnetworkへの経路は置き換える。
+    /// 転送テーブルへの書き込みには対応していない。
+    pub fn originate_ipv6(&mut self, network: Ipv6Network, next_hop: Ipv6Addr) -> bool {
+        let path_attributes = Arc::new(vec![
+            PathAttribute::Origin(Origin::Igp),
+            PathAttribute::AsPath(AsPath::sequence(vec![])),
+        ]);
+        let changed = self
+            .ipv6_unicast_rib
+            .insert(network, next_hop, path_attributes);
+        if changed {
+            self.increment_generation();
+        }
+        changed
+    }
+
+    /// `originate_ipv6`で生成した経路を取り除く。
+    pub fn withdraw_ipv6(&mut self, network: &Ipv6Network) -> bool {
+        let removed = self.ipv6_unicast_rib.remove(network);
+        if removed {
+            self.increment_generation();
+        }
+        removed
+    }
+
+    pub fn ipv6_unicast_rib(&self) -> &Ipv6UnicastRib {
+        &self.ipv6_unicast_rib
+    }
+
+    /// networkへの経路をnext_hopとattributesで生成してLocRibに追加し、転送テーブルに書き込む。
+    /// 同じnetworkに対して自身で生成した経路があれば置き換える。peerから受信した経路はそのまま残す。
+    /// ORIGIN, AS_PATHがattributesに無い場合はIGP, 空のAS_SEQUENCEを用いる。
+    pub async fn originate(
+        &mut self,
+        network: Ipv4Network,
+        next_hop: Ipv4Addr,
+        attributes: Vec<PathAttribute>,
+        fib: &dyn Fib,
+    ) -> Result<bool> {
+        let mut path_attributes: Vec<PathAttribute> = attributes
+            .into_iter()
+            .filter(|p| !matches!(p, PathAttribute::NextHop(_)))
+            .collect();
+        if !path_attributes
+            .iter()
+            .any(|p| matches!(p, PathAttribute::Origin(_)))
+        {
+            path_attributes.insert(0, PathAttribute::Origin(Origin::Igp));
+        }
+        if !path_attributes
+            .iter()
+            .any(|p| matches!(p, PathAttribute::AsPath(_)))
+        {
+            path_attributes.push(PathAttribute::AsPath(AsPath::sequence(vec![])));
+        }
+        path_attributes.push(PathAttribute::NextHop(next_hop));
+        let entry = Arc::new(RibEntry {
+            network_address: network,
+            path_attributes: Arc::new(path_attributes),
+            learned_from: None,
+        });
+
+        let replaced: Vec<Arc<RibEntry>> = self
+            .originated_entries(network)
+            .filter(|e| **e != entry)
+            .cloned()
+            .collect();
+        replaced.iter().for_each(|e| self.withdraw_entry(e));
+        let inserted = self.rib.insert(entry);
+        self.update_aggregates();
+        self.mark_changed();
+        self.write_changes_to_fib(fib).await?;
+        Ok(inserted)
+    }
+
+    /// networkへの自身で生成した経路をLocRibから取り除き、転送テーブルに反映する。
+    /// peerから受信した同じnetworkの経路は残り、それがbest pathになれば転送テーブルに書き込む。
+    pub async fn withdraw(&mut self, network: Ipv4Network, fib: &dyn Fib) -> Result<bool> {
+        let withdrawn: Vec<Arc<RibEntry>> = self.originated_entries(network).cloned().collect();
+        if withdrawn.is_empty() {
+            return Ok(false);
+        }
+        withdrawn.iter().for_each(|e| self.withdraw_entry(e));
+        self.update_aggregates();
+        self.mark_changed();
+        self.write_changes_to_fib(fib).await?;
+        Ok(true)
+    }
+
+    /// networkへの経路のうち、peerから受信したものでも集約したものでもない経路。
+    fn originated_entries(&self, network: Ipv4Network) -> impl Iterator<Item = &Arc<RibEntry>> {
+        self.rib.routes().filter(move |e| {
+            e.network_address == network
+                && e.learned_from.is_none()
+                && !self.aggregates.contains(*e)
+        })
+    }
+
+    /// 変更された経路を転送テーブルに書き込み、Withdrawnの経路を取り除く。
+    /// 書き込みに失敗しても、LocRibの変更は取り消さない。
+    async fn write_changes_to_fib(&mut self, fib: &dyn Fib) -> Result<()> {
+        let written = self.write_to_fib(fib).await;
+        self.update_to_all_changed();
+        written
+    }
+
+    /// `aggregate_addresses`ごとに、LocRibにあるより長いprefixの経路から集約した経路を作り直す。
+    /// 集約できる経路が無くなれば、集約した経路も取り除く。集約した経路が変わればtrueを返す。
+    fn update_aggregates(&mut self) -> bool {
+        let mut changed = false;
+        for aggregate in self.aggregate_addresses.clone() {
+            let contributors: Vec<&Arc<RibEntry>> = self
+                .rib
+                .routes()
+                .filter(|e| {
+                    e.network_address != aggregate.network
+                        && e.network_address.is_subnet_of(*aggregate.network)
+                })
+                .filter(|e| !self.aggregates.contains(*e) && self.is_next_hop_reachable(e))
+                .collect();
+            let entry = (!contributors.is_empty()).then(|| {
+                Arc::new(RibEntry {
+                    network_address: aggregate.network,
+                    path_attributes: Arc::new(
+                        self.aggregate_path_attributes(aggregate, &contributors),
+                    ),
+                    learned_from: None,
+                })
+            });
+            let previous = self
+                .aggregates
+                .iter()
+                .find(|e| e.network_address == aggregate.network)
+                .cloned();
+            if previous == entry {
+                continue;
+            }
+            changed = true;
+            if let Some(previous) = previous {
+                self.aggregates.remove(&previous);
+                self.rib.remove_entry(&previous);
+            }
+            if let Some(entry) = entry {
+                info!(
+                    "routes are aggregated, network={:?}.",
+                    entry.network_address
+                );
+                self.aggregates.insert(Arc::clone(&entry));
+                self.rib.insert(entry);
+            }
+        }
+        changed
+    }
+
+    /// 集約した経路のpath attribute(RFC 4271 Section 9.2.2.2)。
+    /// ORIGINは集約した経路の中で最も優先されないものにする。
+    fn aggregate_path_attributes(
+        &self,
+        aggregate: AggregateAddress,
+        contributors: &[&Arc<RibEntry>],
+    ) -> Vec<PathAttribute> {
+        let origin = contributors
+            .iter()
+            .filter_map(|e| {
+                e.path_attributes.iter().find_map(|p| match p {
+                    PathAttribute::Origin(origin) => Some(*origin),
+                    _ => None,
+                })
+            })
+            .max()
+            .unwrap_or(Origin::Igp);
+        let mut path_attributes = vec![PathAttribute::Origin(origin)];
+        if aggregate.as_set {
+            let as_set = contributors
+                .iter()
+                .flat_map(|e| e.path_attributes.iter())
+                .filter_map(|p| match p {
+                    PathAttribute::AsPath(as_path) => Some(as_path.as_numbers()),
+                    _ => None,
+                })
+                .flatten()
+                .collect();
+            path_attributes.push(PathAttribute::AsPath(AsPath::set(as_set)));
+        } else {
+            path_attributes.push(PathAttribute::AsPath(AsPath::sequence(vec![])));
+            path_attributes.push(PathAttribute::AtomicAggregate);
+        }
+        path_attributes.push(PathAttribute::NextHop(self.local_ip));
+        path_attributes.push(PathAttribute::Aggregator(
+            self.local_as_number,
+            self.local_ip,
+        ));
+        path_attributes
+    }
+
+    /// 変更された経路を転送テーブルに反映する。
+    /// 経路の数が`fib_max_shrink_percent`を超えて減る場合は、警報を出して何も書き込まない。
+    pub async fn write_to_fib(&mut self, fib: &dyn Fib) -> Result<()> {
+        let routes = self.fib_route_count();
+        if let Some((installed, minimum)) = self.fib_shrink_limit() {
+            if routes < minimum {
+                warn!(
+                    "alarm is raised, fib would shrink from {} to {} routes, \
+                    write is refused.",
+                    installed, routes
+                );
+                return Err(anyhow::anyhow!(
+                    "転送テーブルの経路が{}から{}に減るので、書き込みを拒否しました。\
+                    fib_max_shrink_percentは{:?}です。",
+                    installed,
+                    routes,
+                    self.fib_max_shrink_percent
+                ));
+            }
+        }
+        if self.maximum_paths > 1 {
+            self.write_multipaths_to_fib(fib).await?;
+        } else {
+            self.write_best_paths_to_fib(fib).await?;
+        }
+        self.fib_routes = Some(routes);
+        Ok(())
+    }
+
+    /// 前回書き込んだ経路の数と、`fib_max_shrink_percent`から求めた書き込みを許す最小の数。
+    fn fib_shrink_limit(&self) -> Option<(usize, usize)> {
+        let max_shrink = usize::from(self.fib_max_shrink_percent?);
+        let installed = self.fib_routes?;
+        Some((installed, (installed * (100 - max_shrink)).div_ceil(100)))
+    }
+
+    /// 転送テーブルに書き込んだ経路を全て取り除く。終了時に、全てのpeerへCeaseを送った後で用いる。
+    pub async fn remove_from_fib(&mut self, fib: &dyn Fib) -> Result<()> {
+        for (network, e) in &self.best_paths {
+            if self.is_next_hop_reachable(e)
+                && e.next_hop().is_some()
+                && !self.aggregates.contains(e)
+            {
+                fib.remove(*network).await?;
+            }
+        }
+        self.fib_routes = Some(0);
+        Ok(())
+    }
+
+    /// 変更を反映した後に、転送テーブルに書き込まれているprefixの数。
+    fn fib_route_count(&self) -> usize {
+        self.best_paths
+            .values()
+            .filter(|e| self.is_next_hop_reachable(e) && e.next_hop().is_some())
+            .filter(|e| !self.aggregates.contains(*e))
+            .count()
+    }
+
+    async fn write_best_paths_to_fib(&self, fib: &dyn Fib) -> Result<()> {
+        let networks: BTreeSet<Ipv4Network> = self
+            .withdrawn_routes()
+            .chain(self.new_routes())
+            .map(|e| e.network_address)
+            .collect();
+        for network in networks {
+            // best pathが取り消された場合は、消さずに次善の経路へ書き換える。
+            match self.best_paths.get(&network) {
+                Some(e) if self.aggregates.contains(e) => {}
+                Some(e) if self.is_next_hop_reachable(e) => {
+                    if let Some(gateway) = e.next_hop() {
+                        fib.install(network, gateway).await?;
+                    }
                 }
-                if let PathAttribute::AsPath(ases) = p {
-                    ases.push(local_as);
+                _ => fib.remove(network).await?,
+            }
+        }
+        Ok(())
+    }
+
+    /// 経路が変更されたprefixについて、ECMPの経路を選び直して転送テーブルに反映する。
+    async fn write_multipaths_to_fib(&self, fib: &dyn Fib) -> Result<()> {
+        let networks: BTreeSet<Ipv4Network> = self
+            .withdrawn_routes()
+            .chain(self.new_routes())
+            .map(|e| e.network_address)
+            .collect();
+        for network in networks {
+            if self
+                .best_paths
+                .get(&network)
+                .is_some_and(|e| self.aggregates.contains(e))
+            {
+                continue;
+            }
+            let paths = self.multipath(network);
+            match paths.as_slice() {
+                [] => fib.remove(network).await?,
+                [path] => {
+                    if let Some(gateway) = path.next_hop() {
+                        fib.install(network, gateway).await?;
+                    }
+                }
+                paths => {
+                    fib.install_multipath(network, weighted_next_hops(paths))
+                        .await?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// best pathと、それとNEXT_HOP以外の優先度が等しい経路。
+    /// next hopの異なる経路を、best pathを先頭に`maximum_paths`個まで返す。
+    pub fn multipath(&self, network: Ipv4Network) -> Vec<Arc<RibEntry>> {
+        let Some(best) = self.best_paths.get(&network) else {
+            return vec![];
+        };
+        let mut candidates: Vec<&Arc<RibEntry>> = self
+            .rib
+            .routes()
+            .filter(|e| e.network_address == network && e != &best)
+            .filter(|e| self.is_next_hop_reachable(e) && !self.rib.is_stale(e))
+            .filter(|e| self.is_originated(e) == self.is_originated(best))
+            .filter(|e| {
+                e.compare_multipath_preference(best, self.med_comparison) == Ordering::Equal
+            })
+            .collect();
+        candidates.sort_by_key(|e| (e.next_hop(), e.learned_from));
+        let mut paths = vec![Arc::clone(best)];
+        for candidate in candidates {
+            if paths.len() >= self.maximum_paths {
+                break;
+            }
+            if paths.iter().all(|p| p.next_hop() != candidate.next_hop()) {
+                paths.push(Arc::clone(candidate));
+            }
+        }
+        paths
+    }
+
+    /// 追跡すべきnext hop。自身が広告元の経路のnext hopは自身なので含めない。
+    pub fn tracked_next_hops(&self) -> BTreeSet<Ipv4Addr> {
+        self.rib
+            .routes()
+            .filter(|e| !e.is_locally_originated())
+            .filter_map(|e| e.next_hop())
+            .collect()
+    }
+
+    /// 自身が広告元の経路、つまりAS_PATHが空の経路かstatic routeならtrueを返す。
+    pub fn is_originated(&self, entry: &RibEntry) -> bool {
+        entry.is_locally_originated() || self.static_routes.contains(entry)
+    }
+
+    /// 自身が広告元の経路、または集約した経路がnetworkにあればtrueを返す。
+    pub fn is_own_prefix(&self, network: Ipv4Network) -> bool {
+        self.rib.routes().any(|e| {
+            e.network_address == network && (self.is_originated(e) || self.aggregates.contains(e))
+        })
+    }
+
+    pub fn is_next_hop_reachable(&self, entry: &RibEntry) -> bool {
+        !entry.has_unreachable_next_hop(&self.unreachable_next_hops)
+    }
+
+    /// next hopの到達性を更新する。到達性が変わった場合は、
+    /// そのnext hopを用いる経路を返し、best pathを選び直す。
+    pub fn set_next_hop_reachability(
+        &mut self,
+        next_hop: Ipv4Addr,
+        reachable: bool,
+    ) -> Vec<Arc<RibEntry>> {
+        let changed = if reachable {
+            self.unreachable_next_hops.remove(&next_hop)
+        } else {
+            self.unreachable_next_hops.insert(next_hop)
+        };
+        if !changed {
+            return vec![];
+        }
+        info!(
+            "next hop {} becomes {}.",
+            next_hop,
+            if reachable {
+                "reachable"
+            } else {
+                "unreachable"
+            }
+        );
+        let affected = self
+            .rib
+            .routes()
+            .filter(|e| !e.is_locally_originated() && e.next_hop() == Some(next_hop))
+            .cloned()
+            .collect();
+        self.mark_changed();
+        affected
+    }
+
+    /// 他のneighborから受信した経路を、`load`で読み込めるJSONとして書き出す。
+    /// 自身が広告元の経路は、起動時にconfigとカーネルから作り直すので含めない。
+    pub fn persist(&self, path: impl AsRef<Path>) -> Result<()> {
+        write_routes(
+            path.as_ref(),
+            self.rib.routes().filter(|e| !e.is_locally_originated()),
+        )
+    }
+
+    /// `persist`で書き出した経路をstaleとして読み込み、読み込んだ数を返す。
+    /// 受信し直した経路はstaleでなくなり、受信し直さなかった経路は`purge_stale_routes`で取り除く。
+    pub fn load(&mut self, path: impl AsRef<Path>) -> Result<usize> {
+        let loaded = self.rib.load_stale(path.as_ref())?;
+        if loaded > 0 {
+            self.mark_changed();
+        }
+        Ok(loaded)
+    }
+
+    /// staleな経路を全て取り除き、取り除いた数を返す。
+    pub fn purge_stale_routes(&mut self) -> usize {
+        let stale = self.rib.stale_routes().count();
+        if stale > 0 {
+            self.rib.withdraw_stale_routes();
+            self.mark_changed();
+        }
+        stale
+    }
+
+    /// adj_rib_inでstaleになっている経路をLocRibでもstaleにする。
+    pub fn mark_stale_from_adj_rib_in(&mut self, adj_rib_in: &AdjRibIn) {
+        adj_rib_in
+            .stale_routes()
+            .for_each(|entry| self.rib.mark_stale(entry));
+        self.mark_changed();
+    }
+
+    pub fn intsall_from_adj_rib_in(&mut self, adj_rib_in: &AdjRibIn) {
+        let routes: Vec<Arc<RibEntry>> = adj_rib_in.routes().cloned().collect();
+        let withdrawn: Vec<Arc<RibEntry>> = adj_rib_in.withdrawn_routes().cloned().collect();
+        self.install_routes(adj_rib_in, &routes, &withdrawn);
+        self.finish_install();
+    }
+
+    /// adj_rib_inの経路のうちroutesを加え、withdrawnを取り除く。
+    /// best pathは選び直さないので、全ての経路を反映した後に`finish_install`を呼ぶ。
+    pub fn install_routes(
+        &mut self,
+        adj_rib_in: &AdjRibIn,
+        routes: &[Arc<RibEntry>],
+        withdrawn: &[Arc<RibEntry>],
+    ) {
+        let local_as = self.local_as_number;
+        let dropping_roa_table = self
+            .roa_table
+            .as_ref()
+            .filter(|_| self.invalid_route_policy == InvalidRoutePolicy::Drop);
+
+        routes
+            .iter()
+            .filter(|entry| !entry.does_contain_as(local_as))
+            .filter(|entry| !adj_rib_in.is_stale(entry))
+            .filter(|entry| {
+                dropping_roa_table
+                    .is_none_or(|table| table.validate(entry) != ValidationState::Invalid)
+            })
+            .for_each(|entry| {
+                self.rib.insert(Arc::clone(entry));
+            });
+        withdrawn
+            .iter()
+            .for_each(|entry| self.withdraw_entry(entry));
+    }
+
+    /// `install_routes`で反映した変更から、集約した経路とbest pathを作り直す。
+    pub fn finish_install(&mut self) {
+        if self.update_aggregates() || self.does_contain_changed_route() {
+            self.mark_changed();
+        }
+    }
+}
+
+/// 経路を`RibEntry`のJSONの配列として書き出す。`AdjRibIn::load_fixture`と同じ形式。
+/// 書き出しの途中で終了しても前回のファイルが壊れないよう、一時ファイルに書いてから置き換える。
+fn write_routes<'a>(path: &Path, routes: impl Iterator<Item = &'a Arc<RibEntry>>) -> Result<()> {
+    let routes: Vec<&RibEntry> = routes.map(|e| e.as_ref()).collect();
+    let json = serde_json::to_string(&routes).context("cannot serialize rib entries")?;
+    let temporary = path.with_extension("tmp");
+    std::fs::write(&temporary, json)
+        .context(format!("cannot write rib store {}", temporary.display()))?;
+    std::fs::rename(&temporary, path).context(format!("cannot write rib store {}", path.display()))
+}
+
+impl RibEntry {
+    fn does_contain_as(&self, as_number: AutonomousSystemNumber) -> bool {
+        for path_attribute in self.path_attributes.iter() {
+            if let PathAttribute::AsPath(as_path) = path_attribute {
+                return as_path.does_contain(as_number);
+            }
+        }
+        false
+    }
+
+    fn as_path_length(&self) -> usize {
+        for path_attribute in self.path_attributes.iter() {
+            if let PathAttribute::AsPath(as_path) = path_attribute {
+                return as_path.length();
+            }
+        }
+        0
+    }
+
+    fn next_hop(&self) -> Option<Ipv4Addr> {
+        self.path_attributes.iter().find_map(|p| match p {
+            PathAttribute::NextHop(next_hop) => Some(*next_hop),
+            _ => None,
+        })
+    }
+
+    /// AS_PATH中の`from`を`to`に置き換えた経路を返す(as-override)。
+    fn replace_as(
+        self: &Arc<Self>,
+        from: AutonomousSystemNumber,
+        to: AutonomousSystemNumber,
+    ) -> Arc<RibEntry> {
+        if !self.does_contain_as(from) {
+            return Arc::clone(self);
+        }
+        let path_attributes = self
+            .path_attributes
+            .iter()
+            .map(|p| match p {
+                PathAttribute::AsPath(as_path) => PathAttribute::AsPath(as_path.replace(from, to)),
+                p => p.clone(),
+            })
+            .collect();
+        Arc::new(RibEntry {
+            network_address: self.network_address,
+            path_attributes: Arc::new(path_attributes),
+            learned_from: self.learned_from,
+        })
+    }
+
+    /// peerから受信したのではなく、自身が広告元の経路ならtrueを返す。
+    /// IBGPのpeerが広告元の経路もAS_PATHが空なので、AS_PATHでは判断しない。
+    fn is_locally_originated(&self) -> bool {
+        self.learned_from.is_none()
+    }
+
+    /// 自身が広告元の経路は、next hopの到達性に関わらず用いる。
+    fn has_unreachable_next_hop(&self, unreachable_next_hops: &BTreeSet<Ipv4Addr>) -> bool {
+        !self.is_locally_originated()
+            && self
+                .next_hop()
+                .is_some_and(|next_hop| unreachable_next_hops.contains(&next_hop))
+    }
+
+    /// LOCAL_PREFが無ければ既定値を用いる。
+    /// GRACEFUL_SHUTDOWN communityの付いた経路は0として扱う(RFC 8326 Section 4)。
+    fn local_pref(&self) -> u32 {
+        if PathAttribute::communities(&self.path_attributes).any(|c| c == GRACEFUL_SHUTDOWN) {
+            return 0;
+        }
+        self.path_attributes
+            .iter()
+            .find_map(|p| match p {
+                PathAttribute::LocalPref(local_pref) => Some(*local_pref),
+                _ => None,
+            })
+            .unwrap_or(DEFAULT_LOCAL_PREF)
+    }
+
+    fn is_default_route(&self) -> bool {
+        self.network_address.prefix() == 0
+    }
+
+    fn multi_exit_disc(&self) -> u32 {
+        self.path_attributes
+            .iter()
+            .find_map(|p| match p {
+                PathAttribute::MultiExitDisc(med) => Some(*med),
+                _ => None,
+            })
+            .unwrap_or(0)
+    }
+
+    /// 経路を広告してきた隣接AS。AS_SEQUENCEの先頭のAS番号で、自身で生成した経路ではNoneになる。
+    fn neighbor_as(&self) -> Option<AutonomousSystemNumber> {
+        self.path_attributes.iter().find_map(|p| match p {
+            PathAttribute::AsPath(as_path) => as_path.sequence_as_numbers().next(),
+            _ => None,
+        })
+    }
+
+    /// ORIGINの優先度。IGP, EGP, INCOMPLETEの順に小さく、小さいほど優先される。
+    fn origin_rank(&self) -> u8 {
+        self.path_attributes
+            .iter()
+            .find_map(|p| match p {
+                PathAttribute::Origin(Origin::Igp) => Some(0),
+                PathAttribute::Origin(Origin::Egp) => Some(1),
+                PathAttribute::Origin(Origin::Incomplete) => Some(2),
+                _ => None,
+            })
+            .unwrap_or(2)
+    }
+
+    /// link-bandwidth extended communityのbandwidth(bytes/秒)。
+    fn link_bandwidth(&self) -> Option<f32> {
+        self.path_attributes.iter().find_map(|p| match p {
+            PathAttribute::ExtendedCommunities(communities) => {
+                communities
+                    .iter()
+                    .find_map(|c| match ExtendedCommunity::from(*c) {
+                        ExtendedCommunity::LinkBandwidth { bandwidth, .. } => {
+                            Some(f32::from_bits(bandwidth))
+                        }
+                        _ => None,
+                    })
+            }
+            _ => None,
+        })
+    }
+
+    /// ECMPで同じ優先度として扱うかを比べる。NEXT_HOPと受信したneighborは比較しない。
+    fn compare_multipath_preference(
+        &self,
+        other: &RibEntry,
+        med_comparison: MedComparison,
+    ) -> Ordering {
+        let compare_med =
+            med_comparison.always_compare_med || self.neighbor_as() == other.neighbor_as();
+        other
+            .local_pref()
+            .cmp(&self.local_pref())
+            .then_with(|| self.as_path_length().cmp(&other.as_path_length()))
+            .then_with(|| self.origin_rank().cmp(&other.origin_rank()))
+            .then_with(|| {
+                if compare_med {
+                    self.multi_exit_disc().cmp(&other.multi_exit_disc())
+                } else {
+                    Ordering::Equal
                 }
+            })
+    }
+
+    /// selfがotherより優先される場合にLessを返す。
+    /// LOCAL_PREF、AS_PATHの長さ、ORIGIN、MED、NEXT_HOP、受信したneighborのaddressの順に比較し、
+    /// MEDは隣接ASが同じ場合のみ比較する。最後はaddressの小さい方を選ぶ(RFC 4271 Section 9.1.2.2 g)。
+    fn compare_preference(&self, other: &RibEntry, med_comparison: MedComparison) -> Ordering {
+        self.compare_multipath_preference(other, med_comparison)
+            .then_with(|| self.next_hop().cmp(&other.next_hop()))
+            .then_with(|| self.learned_from.cmp(&other.learned_from))
+    }
+}
+
+/// link-bandwidthに比例した重みを付けたnext hop。最も小さいbandwidthの経路の重みを1とする。
+/// link-bandwidthの無い経路があれば、全て等しい重みにする。
+fn weighted_next_hops(paths: &[Arc<RibEntry>]) -> Vec<WeightedNextHop> {
+    let bandwidths: Option<Vec<f32>> = paths
+        .iter()
+        .map(|p| p.link_bandwidth().filter(|b| *b > 0.0))
+        .collect();
+    let minimum = bandwidths
+        .iter()
+        .flatten()
+        .copied()
+        .fold(f32::INFINITY, f32::min);
+    paths
+        .iter()
+        .enumerate()
+        .filter_map(|(i, path)| {
+            let weight = match &bandwidths {
+                Some(bandwidths) => (bandwidths[i] / minimum).round().clamp(1.0, 255.0) as u8,
+                None => 1,
+            };
+            Some(WeightedNextHop {
+                gateway: path.next_hop()?,
+                weight,
+            })
+        })
+        .collect()
+}
+
+impl Ipv4Network {
+    pub fn new(addr: Ipv4Addr, prefix: u8) -> Result<Self, ConstructIpv4NetworkError> {
+        let net = ipnetwork::Ipv4Network::new(addr, prefix).context(format!(
+            "Ipv4NetworkをConstructできませんでしたaddr:{}, prefix: {}
+            ",
+            addr, prefix
+        ))?;
+        Ok(Self(net))
+    }
+    pub fn from_u8_slice(bytes: &[u8]) -> Result<Vec<Self>, ConvertBytesToBgpMessageError> {
+        let mut networks = vec![];
+        let mut i = 0;
+        while bytes.len() > i {
+            let prefix = bytes[i];
+            if prefix > 32 {
+                return Err(ConvertBytesToBgpMessageError::from(anyhow::anyhow!(
+                    "bytes -> Ipv4に変換できませんでした。Prefix {}が0-32の間ではありません。",
+                    prefix
+                )));
             }
+            let prefix_bytes_len = (prefix as usize).div_ceil(8);
+            let end = i + 1 + prefix_bytes_len;
+            let prefix_bytes = bytes.get(i + 1..end).context(format!(
+                "Prefixが{}のNLRIのbytes列が途中で終わっています。",
+                prefix
+            ))?;
+            let mut octets = [0u8; 4];
+            octets[..prefix_bytes_len].copy_from_slice(prefix_bytes);
+            networks.push(
+                Ipv4Network::new(Ipv4Addr::from(octets), prefix)
+                    .context("bytes -> Ipv4に変換できませんでした。")?,
+            );
+            i = end;
+        }
+        Ok(networks)
+    }
+
+    pub fn bytes_len(&self) -> usize {
+        match self.prefix() {
+            0 => 1,
+            1..9 => 2,
+            9..17 => 3,
+            17..25 => 4,
+            25..33 => 5,
+            _ => panic!("prefixが0..32の間ではありません！"),
+        }
+    }
+}
+
+impl From<&Ipv4Network> for BytesMut {
+    fn from(network: &Ipv4Network) -> BytesMut {
+        let prefix = network.prefix();
+
+        let n = network.network().octets();
+        let network_bytes = match prefix {
+            0 => vec![],
+            1..9 => n[0..1].into(),
+            9..17 => n[0..2].into(),
+            17..25 => n[0..3].into(),
+            25..33 => n[0..4].into(),
+            _ => panic!("prefixが0..32の間ではありません！"),
+        };
+        let mut bytes = BytesMut::new();
+        bytes.put_u8(prefix);
+        bytes.put(&network_bytes[..]);
+        bytes
+    }
+}
+
+/// IPv6 Unicastの経路のnetwork(AFI=2, SAFI=1)。MP_REACH_NLRIとMP_UNREACH_NLRIで送受信する。
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Ipv6Network(ipnetwork::Ipv6Network);
+
+impl Deref for Ipv6Network {
+    type Target = ipnetwork::Ipv6Network;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<ipnetwork::Ipv6Network> for Ipv6Network {
+    fn from(ip_network: ipnetwork::Ipv6Network) -> Self {
+        Self(ip_network)
+    }
+}
+
+impl FromStr for Ipv6Network {
+    type Err = ConfigParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let network = s
+            .parse::<ipnetwork::Ipv6Network>()
+            .context(format!("s:{:?}を、Ipv6Networkにparseできませんでした。", s))?;
+        Ok(Self(network))
+    }
+}
+
+impl Ipv6Network {
+    pub fn from_u8_slice(bytes: &[u8]) -> Result<Vec<Self>, ConvertBytesToBgpMessageError> {
+        let mut networks = vec![];
+        let mut i = 0;
+        while bytes.len() > i {
+            let prefix = bytes[i];
+            if prefix > 128 {
+                return Err(ConvertBytesToBgpMessageError::from(anyhow::anyhow!(
+                    "bytes -> Ipv6に変換できませんでした。Prefix {}が0-128の間ではありません。",
+                    prefix
+                )));
+            }
+            let prefix_bytes_len = (prefix as usize).div_ceil(8);
+            let end = i + 1 + prefix_bytes_len;
+            let prefix_bytes = bytes.get(i + 1..end).context(format!(
+                "Prefixが{}のIPv6 NLRIのbytes列が途中で終わっています。",
+                prefix
+            ))?;
+            let mut octets = [0u8; 16];
+            octets[..prefix_bytes_len].copy_from_slice(prefix_bytes);
+            networks.push(Self(
+                ipnetwork::Ipv6Network::new(Ipv6Addr::from(octets), prefix)
+                    .context("bytes -> Ipv6に変換できませんでした。")?,
+            ));
+            i = end;
+        }
+        Ok(networks)
+    }
+
+    pub fn bytes_len(&self) -> usize {
+        1 + (self.prefix() as usize).div_ceil(8)
+    }
+}
+
+impl From<&Ipv6Network> for BytesMut {
+    fn from(network: &Ipv6Network) -> BytesMut {
+        let prefix = network.prefix();
+        let prefix_bytes_len = (prefix as usize).div_ceil(8);
+
+        let mut bytes = BytesMut::new();
+        bytes.put_u8(prefix);
+        bytes.put(&network.network().octets()[..prefix_bytes_len]);
+        bytes
+    }
+}
+
+/// MPLS L3VPNの経路(AFI=1, SAFI=128)。ラベルスタックは1段のみ扱う。
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct VpnV4Network {
+    pub label: u32,
+    pub route_distinguisher: RouteDistinguisher,
+    pub network: Ipv4Network,
+}
+
+impl VpnV4Network {
+    const LABEL_BITS: u8 = 24;
+    const ROUTE_DISTINGUISHER_BITS: u8 = 64;
+
+    pub fn new(label: u32, route_distinguisher: RouteDistinguisher, network: Ipv4Network) -> Self {
+        Self {
+            label,
+            route_distinguisher,
+            network,
+        }
+    }
+
+    pub fn from_u8_slice(bytes: &[u8]) -> Result<Vec<Self>, ConvertBytesToBgpMessageError> {
+        let mut networks = vec![];
+        let mut i = 0;
+        while bytes.len() > i {
+            let bits = bytes[i];
+            let prefix = bits
+                .checked_sub(Self::LABEL_BITS + Self::ROUTE_DISTINGUISHER_BITS)
+                .filter(|prefix| *prefix <= 32)
+                .context(format!(
+                    "VPNv4 NLRIの長さ{}bitsが24+64+(0..=32)ではありません。",
+                    bits
+                ))?;
+            let prefix_bytes_len = (prefix as usize).div_ceil(8);
+            let end = i + 1 + 3 + 8 + prefix_bytes_len;
+            if bytes.len() < end {
+                return Err(ConvertBytesToBgpMessageError::from(anyhow::anyhow!(
+                    "VPNv4 NLRIのbytes列が途中で終わっています。"
+                )));
+            }
+            let label = u32::from_be_bytes([0, bytes[i + 1], bytes[i + 2], bytes[i + 3]]) >> 4;
+            let route_distinguisher = RouteDistinguisher::try_from(&bytes[i + 4..i + 12])?;
+            let mut octets = [0u8; 4];
+            octets[..prefix_bytes_len].copy_from_slice(&bytes[i + 12..end]);
+            let network = Ipv4Network::new(Ipv4Addr::from(octets), prefix).context(format!(
+                "VPNv4 NLRIのprefix{}/{}からnetworkを作れませんでした。",
+                Ipv4Addr::from(octets),
+                prefix
+            ))?;
+            networks.push(Self::new(label, route_distinguisher, network));
+            i = end;
+        }
+        Ok(networks)
+    }
+
+    pub fn bytes_len(&self) -> usize {
+        1 + 3 + 8 + (self.network.prefix() as usize).div_ceil(8)
+    }
+}
+
+impl From<&VpnV4Network> for BytesMut {
+    fn from(network: &VpnV4Network) -> BytesMut {
+        let prefix = network.network.prefix();
+        let prefix_bytes_len = (prefix as usize).div_ceil(8);
+        let bottom_of_stack = 1;
+
+        let mut bytes = BytesMut::new();
+        bytes.put_u8(VpnV4Network::LABEL_BITS + VpnV4Network::ROUTE_DISTINGUISHER_BITS + prefix);
+        bytes.put(&((network.label << 4) | bottom_of_stack).to_be_bytes()[1..]);
+        bytes.put::<BytesMut>((&network.route_distinguisher).into());
+        bytes.put(&network.network.network().octets()[..prefix_bytes_len]);
+        bytes
+    }
+}
+
+impl AdjRibOut {
+    pub fn create_update_messages(&self, config: &Config) -> Vec<UpdateMessage> {
+        let mut updates = self.create_withdrawal_messages();
+        updates.extend(self.create_announcement_messages(config));
+        updates
+    }
+
+    /// 取り消された経路のUPDATEを作る。
+    /// 同じnetworkへの経路を広告し直す場合は、その広告で暗黙に置き換わるので含めない。
+    pub fn create_withdrawal_messages(&self) -> Vec<UpdateMessage> {
+        let mut withdrawn_routes: Vec<Ipv4Network> = self
+            .withdrawn_routes()
+            .map(|entry| entry.network_address)
+            .filter(|network| !self.routes().any(|e| e.network_address == *network))
+            .collect();
+        withdrawn_routes.sort();
+        withdrawn_routes.dedup();
+        if withdrawn_routes.is_empty() {
+            return vec![];
+        }
+        UpdateMessage::new(Arc::new(vec![]), vec![], withdrawn_routes).split_to_max_length()
+    }
+
+    /// 新しく広告する経路のUPDATEを作る。
+    pub fn create_announcement_messages(&self, config: &Config) -> Vec<UpdateMessage> {
+        Self::announcement_messages(self.new_routes(), config)
+    }
+
+    /// Route Refreshの要求に応じて、広告している全ての経路のUPDATEを作る。
+    pub fn create_refresh_messages(&self, config: &Config) -> Vec<UpdateMessage> {
+        Self::announcement_messages(self.routes(), config)
+    }
+
+    fn announcement_messages<'a>(
+        entries: impl Iterator<Item = &'a Arc<RibEntry>>,
+        config: &Config,
+    ) -> Vec<UpdateMessage> {
+        let rewrites_next_hop = config.rewrites_next_hop();
+        let is_ibgp = config.is_ibgp();
+        let strips_confederation = config.is_outside_confederation();
+        let mut hash_map: HashMap<Arc<Vec<PathAttribute>>, Vec<Ipv4Network>> = HashMap::new();
+        for entry in entries {
+            if let Some(routes) = hash_map.get_mut(&entry.path_attributes) {
+                routes.push(entry.network_address);
+            } else {
+                hash_map.insert(
+                    Arc::clone(&entry.path_attributes),
+                    vec![entry.network_address],
+                );
+            }
+        }
+
+        let mut updates = vec![];
+        for (path_attribute, routes) in hash_map.into_iter() {
+            let mut path_attributes = Arc::<Vec<PathAttribute>>::unwrap_or_clone(path_attribute);
+            // MEDは隣接ASにだけ意味を持つので、他のASから受信したMEDは伝えない。
+            let is_learned = path_attributes.iter().any(
+                |p| matches!(p, PathAttribute::AsPath(as_path) if as_path.sequence_as_numbers().next().is_some()),
+            );
+            if is_learned {
+                path_attributes.retain(|p| !matches!(p, PathAttribute::MultiExitDisc(_)));
+            }
+            // LOCAL_PREFはAS(confederation)の外には伝えない(RFC 4271 Section 5.1.5)。
+            if strips_confederation {
+                path_attributes.retain(|p| !matches!(p, PathAttribute::LocalPref(_)));
+            }
+            for p in path_attributes.iter_mut() {
+                // IBGPではIGPで解決できるよう、NEXT_HOPをそのまま伝える。
+                if let PathAttribute::NextHop(n) = p {
+                    if rewrites_next_hop {
+                        *n = config.local_ip
+                    }
+                }
+                if let PathAttribute::AsPath(ases) = p {
+                    if strips_confederation {
+                        *ases = ases.without_confederation_segments();
+                    }
+                    // IBGPではAS_PATHを変えずに伝える(RFC 4271 Section 5.1.2)。
+                    if !is_ibgp {
+                        ases.push(config.advertised_local_as());
+                    }
+                }
+            }
+
+            if config.graceful_shutdown {
+                PathAttribute::add_community(&mut path_attributes, GRACEFUL_SHUTDOWN);
+            }
+
+            updates.extend(
+                UpdateMessage::new(Arc::new(path_attributes), routes, vec![]).split_to_max_length(),
+            );
+        }
+        updates
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct AdjRibIn {
+    rib: Rib,
+    /// `bogon_filter`で捨てた経路の数。
+    bogon_routes_dropped: u64,
+    /// 1つのUPDATEのNLRIに重複して含まれていたため、取り除いたprefixの数。
+    duplicate_nlri: u64,
+}
+
+impl AdjRibIn {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// 受信した経路をMRT TABLE_DUMP_V2のRIB_IPV4_UNICASTとして書き込む。
+    pub fn write_mrt(&self, writer: &mut impl std::io::Write, peer: &MrtPeer) -> Result<()> {
+        mrt::write_table_dump(writer, peer, self.routes().map(|e| e.as_ref()))
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            rib: Rib::with_limit(config.max_rib_entries, config.rib_limit_policy),
+            bogon_routes_dropped: 0,
+            duplicate_nlri: 0,
+        }
+    }
+
+    /// `bogon_filter`で捨てた経路の数。
+    pub fn bogon_routes_dropped(&self) -> u64 {
+        self.bogon_routes_dropped
+    }
+
+    /// 1つのUPDATEのNLRIに重複して含まれていたため、取り除いたprefixの数。
+    pub fn duplicate_nlri(&self) -> u64 {
+        self.duplicate_nlri
+    }
+
+    /// NLRIから、同じUPDATEで既に現れたprefixを取り除く。取り除いた場合は警告して数える。
+    fn deduplicate_nlri(&mut self, nlri: Vec<Ipv4Network>) -> Vec<Ipv4Network> {
+        let mut seen = HashSet::new();
+        let (unique, duplicates): (Vec<_>, Vec<_>) =
+            nlri.into_iter().partition(|network| seen.insert(*network));
+        if !duplicates.is_empty() {
+            warn!(
+                "update message contains duplicate nlri, duplicates={:?}.",
+                duplicates
+            );
+            self.duplicate_nlri += duplicates.len() as u64;
+        }
+        unique
+    }
+
+    /// `own_prefix_reaction`が設定されていれば、新しく受信した経路のうち、
+    /// LocRibで自身が広告元のprefixへの経路を警告し、dropの場合は取り除く。
+    /// LocRibへ反映する前に呼ぶ。
+    pub fn check_own_prefixes(&mut self, loc_rib: &LocRib, config: &Config) {
+        let Some(reaction) = config.own_prefix_reaction else {
+            return;
+        };
+        let own_prefixes: Vec<Arc<RibEntry>> = self
+            .new_routes()
+            .filter(|e| loc_rib.is_own_prefix(e.network_address))
+            .cloned()
+            .collect();
+        for entry in own_prefixes {
+            warn!(
+                "own prefix is received from {}, network={:?}, reaction={:?}.",
+                config.remote_ip, entry.network_address, reaction
+            );
+            if reaction == OwnPrefixReaction::Drop {
+                self.remove_entry(&entry);
+            }
+        }
+    }
+    /// 受信した経路を、`load`で読み込めるJSONとして書き出す。
+    pub fn persist(&self, path: impl AsRef<Path>) -> Result<()> {
+        write_routes(path.as_ref(), self.routes())
+    }
+
+    /// `persist`で書き出した経路をstaleとして読み込み、読み込んだ数を返す。
+    pub fn load(&mut self, path: impl AsRef<Path>) -> Result<usize> {
+        self.load_stale(path.as_ref())
+    }
+
+    /// JSONのfixtureに書かれた`RibEntry`の配列を、受信した経路として読み込む。
+    /// 大きな経路表を用いるテストを再現できるようにするためのもの。
+    #[cfg(any(test, feature = "testing"))]
+    pub fn load_fixture(&mut self, path: impl AsRef<std::path::Path>) -> Result<()> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path)
+            .context(format!("cannot read fixture {}", path.display()))?;
+        let entries: Vec<RibEntry> = serde_json::from_str(&json).context(format!(
+            "cannot parse fixture {} as rib entries",
+            path.display()
+        ))?;
+        for entry in entries {
+            self.insert(Arc::new(entry));
+        }
+        Ok(())
+    }
+
+    /// UPDATEの取り消しを先に適用してから、広告された経路を適用する。
+    /// 同じUPDATEで取り消して広告し直したprefixは、広告された状態になる。
+    pub fn install_from_update(&mut self, update: UpdateMessage, config: &Config) {
+        for network in update.withdrawn_routes {
+            self.withdraw(network);
+        }
+        for path_attribute in update.path_attributes.iter() {
+            if let PathAttribute::MpReachNlri(MpReachNlri::VpnV4 { nlri, .. }) = path_attribute {
+                // VPNv4の経路はRIB, カーネルへのインストールに未対応。
+                debug!(
+                    "vpnv4 routes are received but not installed, nlri={:?}.",
+                    nlri
+                );
+            }
+        }
+        // 取り消しだけのUPDATEはpath attributeを持たないので、これ以上処理しない。
+        if update.network_layer_reachability_information.is_empty() {
+            return;
+        }
+        let nlri = self.deduplicate_nlri(update.network_layer_reachability_information);
+        let path_attributes = if update.path_attributes.iter().any(|p| {
+            matches!(
+                p,
+                PathAttribute::As4Path(_) | PathAttribute::As4Aggregator(..)
+            )
+        }) {
+            Arc::new(PathAttribute::reconstruct_four_octet_as_path(
+                &update.path_attributes,
+            ))
+        } else {
+            update.path_attributes
+        };
+        // AS(confederation)の外から受信したLOCAL_PREFは無視する(RFC 4271 Section 5.1.5)。
+        let path_attributes = if config.is_outside_confederation()
+            && path_attributes
+                .iter()
+                .any(|p| matches!(p, PathAttribute::LocalPref(_)))
+        {
+            Arc::new(
+                path_attributes
+                    .iter()
+                    .filter(|p| !matches!(p, PathAttribute::LocalPref(_)))
+                    .cloned()
+                    .collect(),
+            )
+        } else {
+            path_attributes
+        };
+        let local_as_count = path_attributes
+            .iter()
+            .find_map(|p| match p {
+                PathAttribute::AsPath(as_path) => Some(
+                    as_path.count(config.local_as)
+                        + config.confederation_id.map_or(0, |id| as_path.count(id)),
+                ),
+                _ => None,
+            })
+            .unwrap_or(0);
+        if local_as_count > config.allowas_in
+            || !ImportPolicy::from_config(config).accepts(&path_attributes)
+        {
+            // 以前に受け入れた経路があれば、それも取り消す。
+            debug!(
+                "routes are rejected by loop detection or import policy, nlri={:?}.",
+                nlri
+            );
+            for network in nlri {
+                self.withdraw(network);
+            }
+            return;
+        }
+        let import_policy = ImportPolicy::from_config(config);
+        for network in nlri {
+            if config.bogon_filter.is_some_and(|f| f.is_bogon(network)) {
+                debug!("route to bogon prefix is dropped, network={:?}.", network);
+                self.bogon_routes_dropped += 1;
+                self.withdraw(network);
+                continue;
+            }
+            let Some(path_attributes) = import_policy.apply(network, &path_attributes) else {
+                debug!("route is denied by route-map, network={:?}.", network);
+                self.withdraw(network);
+                continue;
+            };
+            let rib_entry = Arc::new(RibEntry {
+                network_address: network,
+                path_attributes,
+                learned_from: Some(config.remote_ip),
+            });
+
+            self.replace(rib_entry);
+        }
+    }
+}
+
+impl Deref for AdjRibIn {
+    type Target = Rib;
+
+    fn deref(&self) -> &Self::Target {
+        &self.rib
+    }
+}
+
+impl DerefMut for AdjRibIn {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.rib
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BinaryHeap;
+
+    use super::*;
+    use crate::config::NetworkStatement;
+    use crate::fib::{InMemoryFib, RTPROT_BGP};
+    use crate::path_attribute::AsPathSegment;
+    use rtnetlink::packet::constants::{RTPROT_BOOT, RTPROT_STATIC};
+
+    #[tokio::test]
+    async fn loclib_can_lookup_routing_table() {
+        let network: Ipv4Network = ipnetwork::Ipv4Network::new("10.200.100.0".parse().unwrap(), 24)
+            .unwrap()
+            .into();
+        let statement = NetworkStatement::from(network);
+        let routes: Vec<Ipv4Network> = KernelRoute::lookup_all()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|route| route.destination)
+            .filter(|destination| statement.matches(*destination))
+            .collect();
+        let expected = vec![network];
+        assert_eq!(routes, expected);
+    }
+
+    /// main tableにあるカーネルの経路。
+    fn kernel_route(destination: &str, protocol: u8) -> KernelRoute {
+        KernelRoute {
+            destination: destination.parse().unwrap(),
+            table: RT_TABLE_MAIN,
+            protocol,
+        }
+    }
+
+    #[test]
+    fn exact_network_statement_does_not_match_more_specific_kernel_route() {
+        let config: Config = "64513 10.200.100.3 64512 10.200.100.2 passive 10.210.0.0/24:exact"
+            .parse()
+            .unwrap();
+        let exact = kernel_route("10.210.0.0/24", RTPROT_BOOT);
+        let more_specific = kernel_route("10.210.0.0/25", RTPROT_BOOT);
+        let loc_rib = LocRib::with_kernel_routes(&config, &[exact, more_specific]).unwrap();
+        assert!(loc_rib.best_path(exact.destination).is_some());
+        assert!(loc_rib.best_path(more_specific.destination).is_none());
+    }
+
+    #[test]
+    fn cover_network_statement_matches_more_specific_kernel_route() {
+        let config: Config = "64513 10.200.100.3 64512 10.200.100.2 passive 10.210.1.0/24:cover"
+            .parse()
+            .unwrap();
+        let more_specific = kernel_route("10.210.1.0/25", RTPROT_BOOT);
+        let outside = kernel_route("10.210.2.0/25", RTPROT_BOOT);
+        let loc_rib = LocRib::with_kernel_routes(&config, &[more_specific, outside]).unwrap();
+        assert!(loc_rib.best_path(more_specific.destination).is_some());
+        assert!(loc_rib.best_path(outside.destination).is_none());
+    }
+
+    #[test]
+    fn network_statements_originate_routes_with_configured_origin() {
+        let routes = ["10.212.0.0/24", "10.212.1.0/24", "10.212.2.0/24"]
+            .map(|route| kernel_route(route, RTPROT_BOOT));
+        let config: Config = "64513 10.200.100.3 64512 10.200.100.2 passive default_origin=egp \
+            10.212.0.0/24:igp 10.212.1.0/24:exact:incomplete 10.212.2.0/24"
+            .parse()
+            .unwrap();
+        let loc_rib = LocRib::with_kernel_routes(&config, &routes).unwrap();
+        let origin = |network: Ipv4Network| {
+            loc_rib
+                .best_path(network)
+                .unwrap()
+                .path_attributes
+                .iter()
+                .find_map(|p| match p {
+                    PathAttribute::Origin(origin) => Some(*origin),
+                    _ => None,
+                })
+        };
+        assert_eq!(origin(routes[0].destination), Some(Origin::Igp));
+        assert_eq!(origin(routes[1].destination), Some(Origin::Incomplete));
+        assert_eq!(origin(routes[2].destination), Some(Origin::Egp));
+    }
+
+    #[test]
+    fn redistribute_static_originates_static_kernel_routes() {
+        let static_route = kernel_route("10.211.0.0/24", RTPROT_STATIC);
+        let bgp_route = kernel_route("10.211.1.0/24", RTPROT_BGP);
+        let other_table = KernelRoute {
+            table: RT_TABLE_MAIN + 1,
+            ..kernel_route("10.211.2.0/24", RTPROT_STATIC)
+        };
+        let kernel_routes = [static_route, bgp_route, other_table];
+
+        let config: Config = "64513 10.200.100.3 64512 10.200.100.2 passive redistribute=static"
+            .parse()
+            .unwrap();
+        let loc_rib = LocRib::with_kernel_routes(&config, &kernel_routes).unwrap();
+        assert!(loc_rib.best_path(static_route.destination).is_some());
+        // 自身がFIBに書き込んだ経路は再配布しない。
+        assert!(loc_rib.best_path(bgp_route.destination).is_none());
+        assert!(loc_rib.best_path(other_table.destination).is_none());
+
+        let without: Config = "64513 10.200.100.3 64512 10.200.100.2 passive"
+            .parse()
+            .unwrap();
+        let loc_rib_without = LocRib::with_kernel_routes(&without, &kernel_routes).unwrap();
+        assert!(loc_rib_without
+            .best_path(static_route.destination)
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn reloading_config_sends_only_changed_networks() {
+        let config: Config = "64513 10.200.100.3 64512 10.200.100.2 passive 10.100.220.0/24"
+            .parse()
+            .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(&loc_rib, &config);
+        adj_rib_out.update_to_all_changed();
+
+        let reloaded: Config = "64513 10.200.100.3 64512 10.200.100.2 passive 10.200.100.0/24"
+            .parse()
+            .unwrap();
+        let diff = loc_rib.reload(&reloaded).await.unwrap();
+        let added: Ipv4Network = "10.200.100.0/24".parse().unwrap();
+        let removed: Ipv4Network = "10.100.220.0/24".parse().unwrap();
+        assert_eq!(
+            diff.added
+                .iter()
+                .map(|e| e.network_address)
+                .collect::<Vec<_>>(),
+            vec![added]
+        );
+        assert_eq!(
+            diff.removed
+                .iter()
+                .map(|e| e.network_address)
+                .collect::<Vec<_>>(),
+            vec![removed]
+        );
+        assert!(diff.changed.is_empty());
+        assert!(!loc_rib.does_contain_changed_route());
+
+        adj_rib_out.install_from_loc_rib(&loc_rib, &reloaded);
+        let withdrawals = adj_rib_out.create_withdrawal_messages();
+        let announcements = adj_rib_out.create_announcement_messages(&reloaded);
+        assert_eq!(withdrawals.len(), 1);
+        assert_eq!(withdrawals[0].withdrawn_routes, vec![removed]);
+        assert_eq!(announcements.len(), 1);
+        assert_eq!(
+            announcements[0].network_layer_reachability_information,
+            vec![added]
+        );
+
+        let generation = loc_rib.generation();
+        assert!(loc_rib.reload(&reloaded).await.unwrap().is_empty());
+        assert_eq!(loc_rib.generation(), generation);
+    }
+
+    #[tokio::test]
+    async fn loclib_to_adj_rib_out() {
+        let config = "64513 10.200.100.3 64512 10.200.100.2 passive 10.100.220.0/24"
+            .parse()
+            .unwrap();
+        let loc_rib = LocRib::new(&config).await.unwrap();
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(&loc_rib, &config);
+
+        let mut expected_adj_rib_out = AdjRibOut::new();
+        expected_adj_rib_out.insert(Arc::new(RibEntry {
+            network_address: "10.100.220.0/24".parse().unwrap(),
+            path_attributes: Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::sequence(vec![])),
+                PathAttribute::NextHop("10.200.100.3".parse().unwrap()),
+            ]),
+            learned_from: None,
+        }));
+        assert_eq!(adj_rib_out, expected_adj_rib_out);
+    }
+
+    fn advertises_default_route(adj_rib_out: &AdjRibOut, local_ip: Ipv4Addr) -> bool {
+        adj_rib_out.routes().any(|e| {
+            e.network_address == "0.0.0.0/0".parse().unwrap() && e.next_hop() == Some(local_ip)
+        })
+    }
+
+    #[tokio::test]
+    async fn default_originate_always_advertises_default_route() {
+        let config: Config =
+            "64513 10.200.100.3 64512 10.200.100.2 passive default_originate=always"
+                .parse()
+                .unwrap();
+        let loc_rib = LocRib::new(&config).await.unwrap();
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(&loc_rib, &config);
+        assert!(advertises_default_route(&adj_rib_out, config.local_ip));
+
+        let updates = adj_rib_out.create_announcement_messages(&config);
+        assert_eq!(
+            updates[0].network_layer_reachability_information,
+            vec!["0.0.0.0/0".parse().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn default_originate_follows_conditional_prefix_in_loc_rib() {
+        let config: Config =
+            "64513 10.200.100.3 64512 10.200.100.2 passive default_originate=10.100.230.0/24"
+                .parse()
+                .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(&loc_rib, &config);
+        assert!(!advertises_default_route(&adj_rib_out, config.local_ip));
+
+        let network = "10.100.230.0/24".parse().unwrap();
+        loc_rib
+            .originate(network, config.local_ip, vec![], &InMemoryFib::new())
+            .await
+            .unwrap();
+        adj_rib_out.install_from_loc_rib(&loc_rib, &config);
+        assert!(advertises_default_route(&adj_rib_out, config.local_ip));
+
+        loc_rib
+            .withdraw(network, &InMemoryFib::new())
+            .await
+            .unwrap();
+        adj_rib_out.install_from_loc_rib(&loc_rib, &config);
+        assert!(!advertises_default_route(&adj_rib_out, config.local_ip));
+        assert_eq!(
+            adj_rib_out.create_withdrawal_messages()[0].withdrawn_routes,
+            vec!["0.0.0.0/0".parse().unwrap(), network]
+        );
+    }
+
+    fn rib_entry_with_as_path(network: &str, as_path: Vec<u32>) -> Arc<RibEntry> {
+        Arc::new(RibEntry {
+            network_address: network.parse().unwrap(),
+            path_attributes: Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::sequence(
+                    as_path
+                        .into_iter()
+                        .map(AutonomousSystemNumber::from)
+                        .collect(),
+                )),
+                PathAttribute::NextHop("10.200.100.3".parse().unwrap()),
+            ]),
+            learned_from: None,
+        })
+    }
+
+    #[test]
+    fn rib_diff_contains_only_added_routes() {
+        let before = Rib::new();
+        let mut after = Rib::new();
+        let entry = rib_entry_with_as_path("10.100.220.0/24", vec![64512]);
+        after.insert(Arc::clone(&entry));
+
+        let diff = before.diff(&after);
+        assert_eq!(
+            diff,
+            RibDiff {
+                added: vec![RibEntry::clone(&entry)],
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn rib_diff_contains_only_removed_routes() {
+        let mut before = Rib::new();
+        let mut after = Rib::new();
+        let kept = rib_entry_with_as_path("10.100.220.0/24", vec![64512]);
+        let removed = rib_entry_with_as_path("10.100.221.0/24", vec![64512]);
+        before.insert(Arc::clone(&kept));
+        before.insert(Arc::clone(&removed));
+        after.insert(Arc::clone(&kept));
+
+        let diff = before.diff(&after);
+        assert_eq!(
+            diff,
+            RibDiff {
+                removed: vec![RibEntry::clone(&removed)],
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn rib_diff_contains_routes_with_changed_attributes() {
+        let mut before = Rib::new();
+        let mut after = Rib::new();
+        before.insert(rib_entry_with_as_path("10.100.220.0/24", vec![64512]));
+        let changed = rib_entry_with_as_path("10.100.220.0/24", vec![64512, 64514]);
+        after.insert(Arc::clone(&changed));
+
+        let diff = before.diff(&after);
+        assert_eq!(
+            diff,
+            RibDiff {
+                changed: vec![RibEntry::clone(&changed)],
+                ..Default::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn adj_rib_in_loaded_from_fixture_is_advertised_to_other_neighbor() {
+        let config: Config = "64513 10.200.100.3 64514 10.200.100.4 passive"
+            .parse()
+            .unwrap();
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in
+            .load_fixture(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/fixtures/adj_rib_in_50_routes.json"
+            ))
+            .unwrap();
+        assert_eq!(adj_rib_in.len(), 50);
+
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        loc_rib.intsall_from_adj_rib_in(&adj_rib_in);
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(&loc_rib, &config);
+        assert_eq!(adj_rib_out.len(), 50);
+
+        let updates = adj_rib_out.create_announcement_messages(&config);
+        assert_eq!(updates.len(), 5);
+        let mut networks: Vec<Ipv4Network> = updates
+            .iter()
+            .flat_map(|update| update.network_layer_reachability_information.clone())
+            .collect();
+        networks.sort();
+        let expected: Vec<Ipv4Network> = (0..50)
+            .map(|i| format!("10.101.{i}.0/24").parse().unwrap())
+            .collect();
+        assert_eq!(networks, expected);
+        for update in updates {
+            assert!(update.path_attributes.iter().any(|p| matches!(
+                p,
+                PathAttribute::AsPath(as_path)
+                    if as_path.sequence_as_numbers().next() == Some(64512.into())
+                        && as_path.sequence_as_numbers().last() == Some(config.local_as)
+            )));
+        }
+    }
+
+    #[test]
+    fn confederation_segments_are_not_counted_in_as_path_length() {
+        let entry = |as_path: AsPath| RibEntry {
+            network_address: "10.100.220.0/24".parse().unwrap(),
+            path_attributes: Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(as_path),
+                PathAttribute::NextHop("10.200.100.2".parse().unwrap()),
+            ]),
+            learned_from: None,
+        };
+        let confed_sequence = AsPath(vec![AsPathSegment::AsConfedSequence(vec![
+            65001.into(),
+            65002.into(),
+        ])]);
+        let confed_set = AsPath(vec![AsPathSegment::AsConfedSet(
+            [65001.into(), 65002.into()].into_iter().collect(),
+        )]);
+        assert_eq!(entry(confed_sequence.clone()).as_path_length(), 0);
+        assert_eq!(entry(confed_set.clone()).as_path_length(), 0);
+        assert_eq!(
+            entry(AsPath::sequence(vec![64512.into()])).as_path_length(),
+            1
+        );
+
+        for as_path in [confed_sequence, confed_set] {
+            let attribute = PathAttribute::AsPath(as_path);
+            let bytes: BytesMut = (&attribute).into();
+            assert_eq!(
+                PathAttribute::from_u8_slice(&bytes).unwrap(),
+                vec![attribute]
+            );
+        }
+    }
+
+    #[test]
+    fn confederation_segments_are_stripped_for_peers_outside_confederation() {
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.insert(Arc::new(RibEntry {
+            network_address: "10.100.220.0/24".parse().unwrap(),
+            path_attributes: Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath(vec![AsPathSegment::AsConfedSequence(vec![
+                    65001.into()
+                ])])),
+                PathAttribute::NextHop("10.200.100.2".parse().unwrap()),
+            ]),
+            learned_from: None,
+        }));
+        let as_path = |config: &Config| {
+            adj_rib_out.create_announcement_messages(config)[0]
+                .path_attributes
+                .iter()
+                .find_map(|p| match p {
+                    PathAttribute::AsPath(as_path) => Some(as_path.clone()),
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        let external: Config = "64513 10.200.100.3 64514 10.200.100.4 active"
+            .parse()
+            .unwrap();
+        assert!(external.is_outside_confederation());
+        assert_eq!(as_path(&external), AsPath::sequence(vec![64513.into()]));
+
+        let confederation_peer: Config =
+            "64513 10.200.100.3 64514 10.200.100.4 active confederation_peer=64514"
+                .parse()
+                .unwrap();
+        assert!(!confederation_peer.is_outside_confederation());
+        assert_eq!(
+            as_path(&confederation_peer),
+            AsPath(vec![AsPathSegment::AsConfedSequence(vec![
+                65001.into(),
+                64513.into()
+            ])])
+        );
+    }
+
+    #[test]
+    fn confederation_and_external_segments_are_decoded_and_stripped_separately() {
+        // AS_CONFED_SEQUENCE(65001)に続くAS_SEQUENCE(64512, 64600)。
+        let bytes = [0x40, 2, 10, 3, 1, 0xfd, 0xe9, 2, 2, 0xfc, 0x00, 0xfc, 0x58];
+        let as_path = AsPath(vec![
+            AsPathSegment::AsConfedSequence(vec![65001.into()]),
+            AsPathSegment::AsSequence(vec![64512.into(), 64600.into()]),
+        ]);
+        let attribute = PathAttribute::AsPath(as_path.clone());
+        assert_eq!(
+            PathAttribute::from_u8_slice(&bytes).unwrap(),
+            vec![attribute.clone()]
+        );
+        assert_eq!(BytesMut::from(&attribute), BytesMut::from(&bytes[..]));
+        assert_eq!(as_path.length(), 2);
+
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.insert(Arc::new(RibEntry {
+            network_address: "10.100.220.0/24".parse().unwrap(),
+            path_attributes: Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                attribute,
+                PathAttribute::NextHop("10.200.100.2".parse().unwrap()),
+            ]),
+            learned_from: None,
+        }));
+        let external: Config = "64513 10.200.100.3 64514 10.200.100.4 active"
+            .parse()
+            .unwrap();
+        let advertised = adj_rib_out.create_announcement_messages(&external)[0]
+            .path_attributes
+            .iter()
+            .find_map(|p| match p {
+                PathAttribute::AsPath(as_path) => Some(as_path.clone()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(
+            advertised,
+            AsPath::sequence(vec![64512.into(), 64600.into(), 64513.into()])
+        );
+    }
+
+    #[test]
+    fn confederation_id_is_presented_to_peers_outside_confederation() {
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.insert(Arc::new(RibEntry {
+            network_address: "10.100.220.0/24".parse().unwrap(),
+            path_attributes: Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath(vec![AsPathSegment::AsConfedSequence(vec![
+                    65001.into()
+                ])])),
+                PathAttribute::NextHop("10.200.100.2".parse().unwrap()),
+            ]),
+            learned_from: None,
+        }));
+        let as_path = |config: &Config| {
+            adj_rib_out.create_announcement_messages(config)[0]
+                .path_attributes
+                .iter()
+                .find_map(|p| match p {
+                    PathAttribute::AsPath(as_path) => Some(as_path.clone()),
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        let external: Config = "64513 10.200.100.3 64600 10.200.100.4 active \
+            confederation_id=64500 confederation_peer=64514"
+            .parse()
+            .unwrap();
+        assert_eq!(external.advertised_local_as(), 64500.into());
+        assert_eq!(as_path(&external), AsPath::sequence(vec![64500.into()]));
+
+        let confederation_peer: Config = "64513 10.200.100.3 64514 10.200.100.4 active \
+            confederation_id=64500 confederation_peer=64514"
+            .parse()
+            .unwrap();
+        assert_eq!(confederation_peer.advertised_local_as(), 64513.into());
+        assert_eq!(
+            as_path(&confederation_peer),
+            AsPath(vec![AsPathSegment::AsConfedSequence(vec![
+                65001.into(),
+                64513.into()
+            ])])
+        );
+    }
+
+    #[tokio::test]
+    async fn static_route_is_advertised_with_configured_as_path_to_ibgp_peer() {
+        let config: Config = "64513 10.200.100.3 64513 10.200.100.4 active \
+            static_route=10.100.240.0/24:as_path=65001,65002:med=10"
+            .parse()
+            .unwrap();
+        let loc_rib = LocRib::new(&config).await.unwrap();
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(&loc_rib, &config);
+
+        let expected_as_path =
+            PathAttribute::AsPath(AsPath::sequence(vec![65001.into(), 65002.into()]));
+        let network: Ipv4Network = "10.100.240.0/24".parse().unwrap();
+        let entry = adj_rib_out
+            .routes()
+            .find(|e| e.network_address == network)
+            .unwrap();
+        assert!(entry.path_attributes.contains(&expected_as_path));
+        assert!(entry
+            .path_attributes
+            .contains(&PathAttribute::MultiExitDisc(10)));
+
+        let updates = adj_rib_out.create_update_messages(&config);
+        let update = updates
+            .iter()
+            .find(|u| u.network_layer_reachability_information.contains(&network))
+            .unwrap();
+        assert!(update.path_attributes.contains(&expected_as_path));
+        assert!(update
+            .path_attributes
+            .contains(&PathAttribute::NextHop(config.local_ip)));
+    }
+
+    #[test]
+    fn ibgp_keeps_next_hop_and_ebgp_rewrites_it() {
+        let received_next_hop: Ipv4Addr = "10.200.100.2".parse().unwrap();
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.insert(Arc::new(RibEntry {
+            network_address: "10.100.220.0/24".parse().unwrap(),
+            path_attributes: Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::sequence(vec![64512.into()])),
+                PathAttribute::NextHop(received_next_hop),
+            ]),
+            learned_from: None,
+        }));
+        let next_hop = |config: &Config| {
+            adj_rib_out.create_announcement_messages(config)[0]
+                .path_attributes
+                .iter()
+                .find_map(|p| match p {
+                    PathAttribute::NextHop(n) => Some(*n),
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        let ibgp: Config = "64513 10.200.100.3 64513 10.200.100.4 active"
+            .parse()
+            .unwrap();
+        assert!(ibgp.is_ibgp());
+        assert_eq!(next_hop(&ibgp), received_next_hop);
+
+        let ebgp: Config = "64513 10.200.100.3 64514 10.200.100.4 active"
+            .parse()
+            .unwrap();
+        assert_eq!(next_hop(&ebgp), ebgp.local_ip);
+
+        let ibgp_next_hop_self: Config =
+            "64513 10.200.100.3 64513 10.200.100.4 active next_hop_self=true"
+                .parse()
+                .unwrap();
+        assert_eq!(next_hop(&ibgp_next_hop_self), ibgp_next_hop_self.local_ip);
+
+        let ebgp_unchanged: Config =
+            "64513 10.200.100.3 64514 10.200.100.4 active next_hop_self=false"
+                .parse()
+                .unwrap();
+        assert_eq!(next_hop(&ebgp_unchanged), received_next_hop);
+    }
+
+    #[tokio::test]
+    async fn originated_route_is_advertised_only_to_neighbors_in_scope() {
+        let neighbor_a: Config =
+            "64513 10.200.100.3 64514 10.200.100.4 passive originate_to=10.100.220.0/24@64514"
+                .parse()
+                .unwrap();
+        let neighbor_b: Config =
+            "64513 10.200.100.3 64515 10.200.100.5 passive originate_to=10.100.220.0/24@64514"
+                .parse()
+                .unwrap();
+        let mut loc_rib = LocRib::new(&neighbor_a).await.unwrap();
+        loc_rib
+            .originate(
+                "10.100.220.0/24".parse().unwrap(),
+                neighbor_a.local_ip,
+                vec![],
+                &InMemoryFib::new(),
+            )
+            .await
+            .unwrap();
+        loc_rib
+            .originate(
+                "10.100.221.0/24".parse().unwrap(),
+                neighbor_a.local_ip,
+                vec![],
+                &InMemoryFib::new(),
+            )
+            .await
+            .unwrap();
+        let advertised = |config: &Config| {
+            let mut adj_rib_out = AdjRibOut::new();
+            adj_rib_out.install_from_loc_rib(&loc_rib, config);
+            let mut networks: Vec<String> = adj_rib_out
+                .routes()
+                .map(|e| e.network_address.to_string())
+                .collect();
+            networks.sort();
+            networks
+        };
+
+        assert_eq!(
+            advertised(&neighbor_a),
+            vec!["10.100.220.0/24", "10.100.221.0/24"]
+        );
+        assert_eq!(advertised(&neighbor_b), vec!["10.100.221.0/24"]);
+    }
+
+    #[tokio::test]
+    async fn backup_route_is_advertised_only_while_primary_is_absent() {
+        let config: Config = "64513 10.200.100.3 64514 10.200.100.4 passive \
+            advertise_if=10.100.221.0/24:absent=10.100.220.0/24"
+            .parse()
+            .unwrap();
+        let primary: Ipv4Network = "10.100.220.0/24".parse().unwrap();
+        let backup: Ipv4Network = "10.100.221.0/24".parse().unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        loc_rib
+            .originate(primary, config.local_ip, vec![], &InMemoryFib::new())
+            .await
+            .unwrap();
+        loc_rib
+            .originate(backup, config.local_ip, vec![], &InMemoryFib::new())
+            .await
+            .unwrap();
+        let mut adj_rib_out = AdjRibOut::new();
+        let mut advertised = |loc_rib: &LocRib| {
+            adj_rib_out.install_from_loc_rib(loc_rib, &config);
+            adj_rib_out.remove_withdrawn_routes();
+            let mut networks: Vec<Ipv4Network> =
+                adj_rib_out.routes().map(|e| e.network_address).collect();
+            networks.sort();
+            networks
+        };
+
+        assert_eq!(advertised(&loc_rib), vec![primary]);
+
+        loc_rib
+            .withdraw(primary, &InMemoryFib::new())
+            .await
+            .unwrap();
+        assert_eq!(advertised(&loc_rib), vec![backup]);
+
+        loc_rib
+            .originate(primary, config.local_ip, vec![], &InMemoryFib::new())
+            .await
+            .unwrap();
+        assert_eq!(advertised(&loc_rib), vec![primary]);
+    }
+
+    #[tokio::test]
+    async fn best_external_path_is_advertised_to_ibgp_neighbor() {
+        let config: Config = "64513 10.200.100.3 64513 10.200.100.5 passive best_external=true"
+            .parse()
+            .unwrap();
+        let network: Ipv4Network = "10.100.250.0/24".parse().unwrap();
+        let learned = |as_path: Vec<u32>, neighbor: &str| {
+            Arc::new(RibEntry {
+                network_address: network,
+                path_attributes: Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::sequence(
+                        as_path.into_iter().map(Into::into).collect(),
+                    )),
+                    PathAttribute::NextHop(neighbor.parse().unwrap()),
+                ]),
+                learned_from: Some(neighbor.parse().unwrap()),
+            })
+        };
+        // AS_PATHが短いので、IBGPで受信した経路がbest pathになる。
+        let internal = learned(vec![64600], "10.200.100.4");
+        let external = learned(vec![64514, 64600], "10.200.100.2");
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        loc_rib.add_ibgp_neighbor("10.200.100.4".parse().unwrap());
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.insert(Arc::clone(&internal));
+        adj_rib_in.insert(Arc::clone(&external));
+        loc_rib.intsall_from_adj_rib_in(&adj_rib_in);
+        assert_eq!(loc_rib.best_path(network), Some(&internal));
+
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(&loc_rib, &config);
+        let advertised: Vec<&Arc<RibEntry>> = adj_rib_out.routes().collect();
+        assert_eq!(advertised, vec![&external]);
+
+        // EBGPのneighborには、best-externalに関わらずbest pathだけを広告する。
+        let ebgp: Config = "64513 10.200.100.3 64515 10.200.100.6 passive best_external=true"
+            .parse()
+            .unwrap();
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(&loc_rib, &ebgp);
+        let advertised: Vec<&Arc<RibEntry>> = adj_rib_out.routes().collect();
+        assert_eq!(advertised, vec![&internal]);
+
+        // best-externalが無効なら、IBGPで受信したbest pathはIBGPのneighborに広告しない。
+        let ibgp: Config = "64513 10.200.100.3 64513 10.200.100.5 passive"
+            .parse()
+            .unwrap();
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(&loc_rib, &ibgp);
+        assert_eq!(adj_rib_out.routes().count(), 0);
+    }
+
+    #[test]
+    fn rib_refuses_new_route_when_max_entries_is_reached() {
+        let mut rib = Rib::with_limit(Some(2), RibLimitPolicy::RefuseNew);
+        assert!(rib.insert(rib_entry_with_as_path("10.100.220.0/24", vec![64512])));
+        assert!(rib.insert(rib_entry_with_as_path("10.100.221.0/24", vec![64512])));
+
+        assert!(!rib.insert(rib_entry_with_as_path("10.100.222.0/24", vec![64512])));
+        assert_eq!(rib.len(), 2);
+    }
+
+    #[test]
+    fn rib_evicts_least_preferred_route_when_max_entries_is_reached() {
+        let mut rib = Rib::with_limit(Some(2), RibLimitPolicy::EvictLeastPreferred);
+        let long_path = rib_entry_with_as_path("10.100.220.0/24", vec![64512, 64513, 64514]);
+        rib.insert(Arc::clone(&long_path));
+        rib.insert(rib_entry_with_as_path("10.100.221.0/24", vec![64512]));
 
-            updates.push(UpdateMessage::new(
-                Arc::new(path_attributes),
-                routes,
+        assert!(rib.insert(rib_entry_with_as_path("10.100.222.0/24", vec![64512])));
+        assert_eq!(rib.len(), 2);
+        assert!(!rib.routes().any(|e| *e == long_path));
+
+        assert!(!rib.insert(rib_entry_with_as_path(
+            "10.100.223.0/24",
+            vec![64512, 64513]
+        )));
+        assert_eq!(rib.len(), 2);
+    }
+
+    #[test]
+    fn rib_evicts_by_best_path_preference_not_only_as_path_length() {
+        let mut rib = Rib::with_limit(Some(2), RibLimitPolicy::EvictLeastPreferred);
+        let with_local_pref = |network: &str, as_path: Vec<u32>, local_pref: u32| {
+            let entry = rib_entry_with_as_path(network, as_path);
+            let mut path_attributes = Vec::clone(&entry.path_attributes);
+            path_attributes.push(PathAttribute::LocalPref(local_pref));
+            Arc::new(RibEntry {
+                path_attributes: Arc::new(path_attributes),
+                ..RibEntry::clone(&entry)
+            })
+        };
+        // AS_PATHは長いが、LOCAL_PREFが高いので最も優先される。
+        let preferred = with_local_pref("10.100.220.0/24", vec![64512, 64513, 64514], 200);
+        let least_preferred = with_local_pref("10.100.221.0/24", vec![64512], 50);
+        rib.insert(Arc::clone(&preferred));
+        rib.insert(Arc::clone(&least_preferred));
+
+        assert!(rib.insert(rib_entry_with_as_path(
+            "10.100.222.0/24",
+            vec![64512, 64513]
+        )));
+        assert!(rib.contains(&preferred));
+        assert!(!rib.contains(&least_preferred));
+        assert_eq!(rib.len(), 2);
+    }
+
+    #[test]
+    fn rib_len_counts_routes_that_are_not_withdrawn() {
+        let mut rib = Rib::new();
+        let first = rib_entry_with_as_path("10.100.220.0/24", vec![64512]);
+        let second = rib_entry_with_as_path("10.100.221.0/24", vec![64512]);
+        rib.insert(Arc::clone(&first));
+        rib.insert(Arc::clone(&first));
+        rib.insert(Arc::clone(&second));
+        assert_eq!(rib.len(), 2);
+
+        rib.withdraw(first.network_address);
+        rib.withdraw(first.network_address);
+        assert_eq!(rib.len(), 1);
+        rib.insert(Arc::clone(&first));
+        assert_eq!(rib.len(), 2);
+        rib.replace(rib_entry_with_as_path("10.100.220.0/24", vec![64513]));
+        assert_eq!(rib.len(), 2);
+        rib.remove(first.network_address);
+        assert_eq!(rib.len(), 1);
+        rib.remove_entry(&second);
+        assert!(rib.is_empty());
+
+        rib.insert(Arc::clone(&first));
+        rib.withdraw_all();
+        rib.remove_withdrawn_routes();
+        assert_eq!(rib.len(), rib.routes().count());
+    }
+
+    #[test]
+    fn adj_rib_in_reconstructs_four_octet_as_path_from_as4_path() {
+        let config = "64513 10.200.100.3 64512 10.200.100.2 passive"
+            .parse()
+            .unwrap();
+        let as_trans = AutonomousSystemNumber::AS_TRANS;
+        let update = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::sequence(vec![64512.into(), as_trans, as_trans])),
+                PathAttribute::NextHop("10.200.100.2".parse().unwrap()),
+                PathAttribute::As4Path(AsPath::sequence(vec![
+                    4200000001.into(),
+                    4200000002.into(),
+                ])),
+            ]),
+            vec!["10.100.220.0/24".parse().unwrap()],
+            vec![],
+        );
+        let update: UpdateMessage = BytesMut::from(update).try_into().unwrap();
+
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.install_from_update(update, &config);
+
+        let mut expected_adj_rib_in = AdjRibIn::new();
+        expected_adj_rib_in.insert(Arc::new(RibEntry {
+            network_address: "10.100.220.0/24".parse().unwrap(),
+            path_attributes: Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::sequence(vec![
+                    64512.into(),
+                    4200000001.into(),
+                    4200000002.into(),
+                ])),
+                PathAttribute::NextHop("10.200.100.2".parse().unwrap()),
+            ]),
+            learned_from: Some(config.remote_ip),
+        }));
+        assert_eq!(adj_rib_in, expected_adj_rib_in);
+    }
+
+    #[tokio::test]
+    async fn originate_and_withdraw_keep_routes_learned_from_peers() {
+        let config: Config = "64512 10.200.100.3 64513 10.200.100.2 active"
+            .parse()
+            .unwrap();
+        let network: Ipv4Network = "10.100.246.0/24".parse().unwrap();
+        let learned = Arc::new(RibEntry {
+            network_address: network,
+            path_attributes: Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::sequence(vec![64513.into()])),
+                PathAttribute::NextHop("10.200.100.2".parse().unwrap()),
+            ]),
+            learned_from: Some("10.200.100.2".parse().unwrap()),
+        });
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.insert(Arc::clone(&learned));
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let fib = InMemoryFib::new();
+        loc_rib.intsall_from_adj_rib_in(&adj_rib_in);
+        loc_rib.write_to_fib(&fib).await.unwrap();
+        loc_rib.update_to_all_changed();
+
+        // 自身で生成した経路は受信した経路より優先される。
+        let next_hop: Ipv4Addr = "10.200.100.4".parse().unwrap();
+        assert!(loc_rib
+            .originate(network, next_hop, vec![], &fib)
+            .await
+            .unwrap());
+        assert!(loc_rib.contains(&learned));
+        assert_ne!(loc_rib.best_path(network), Some(&learned));
+        assert_eq!(fib.gateway(network), Some(next_hop));
+
+        assert!(loc_rib.withdraw(network, &fib).await.unwrap());
+        assert!(loc_rib.contains(&learned));
+        assert_eq!(loc_rib.best_path(network), Some(&learned));
+        assert_eq!(fib.gateway(network), learned.next_hop());
+        assert!(!loc_rib.withdraw(network, &fib).await.unwrap());
+        assert_eq!(loc_rib.best_path(network), Some(&learned));
+    }
+
+    #[tokio::test]
+    async fn loc_rib_counts_churn_when_best_path_changes() {
+        let config = "64513 10.200.100.3 64512 10.200.100.2 passive"
+            .parse()
+            .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let flapping: Ipv4Network = "10.100.220.0/24".parse().unwrap();
+        let stable: Ipv4Network = "10.100.221.0/24".parse().unwrap();
+
+        loc_rib
+            .originate(
+                stable,
+                "10.200.100.3".parse().unwrap(),
                 vec![],
-            ));
+                &InMemoryFib::new(),
+            )
+            .await
+            .unwrap();
+        for next_hop in ["10.200.100.3", "10.200.100.4", "10.200.100.3"] {
+            loc_rib
+                .originate(
+                    flapping,
+                    next_hop.parse().unwrap(),
+                    vec![],
+                    &InMemoryFib::new(),
+                )
+                .await
+                .unwrap();
         }
-        updates
+        loc_rib
+            .withdraw(flapping, &InMemoryFib::new())
+            .await
+            .unwrap();
+
+        assert_eq!(loc_rib.churn(flapping).unwrap().count, 4);
+        assert_eq!(loc_rib.churn(stable).unwrap().count, 1);
+        assert!(loc_rib.best_path(flapping).is_none());
+        let top = loc_rib.top_churning_prefixes(1);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].0, flapping);
     }
-}
 
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub struct AdjRibIn(pub Rib);
+    #[tokio::test]
+    async fn loc_rib_counts_each_best_path_computation() {
+        let config = "64513 10.200.100.3 64512 10.200.100.2 passive"
+            .parse()
+            .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        assert_eq!(loc_rib.best_path_metrics().computations, 0);
+        assert!(loc_rib.last_best_path_change().is_none());
 
-impl AdjRibIn {
-    pub fn new() -> Self {
-        Self(Rib::new())
+        let network: Ipv4Network = "10.100.220.0/24".parse().unwrap();
+        loc_rib
+            .originate(
+                network,
+                "10.200.100.3".parse().unwrap(),
+                vec![],
+                &InMemoryFib::new(),
+            )
+            .await
+            .unwrap();
+        loc_rib
+            .originate(
+                network,
+                "10.200.100.4".parse().unwrap(),
+                vec![],
+                &InMemoryFib::new(),
+            )
+            .await
+            .unwrap();
+        loc_rib
+            .withdraw(network, &InMemoryFib::new())
+            .await
+            .unwrap();
+
+        let metrics = loc_rib.best_path_metrics();
+        assert_eq!(metrics.computations, 3);
+        assert_eq!(metrics.duration_histogram.iter().sum::<u64>(), 3);
+        assert_eq!(
+            loc_rib.last_best_path_change(),
+            Some(loc_rib.churn(network).unwrap().last_changed)
+        );
     }
-    pub fn install_from_update(&mut self, update: UpdateMessage, config: &Config) {
-        let path_attributes = update.path_attributes;
-        for network in update.network_layer_reachability_information {
-            let rib_entry = Arc::new(RibEntry {
+
+    fn rib_entry_with_med(neighbor_as: u32, med: u32, next_hop: &str) -> Arc<RibEntry> {
+        Arc::new(RibEntry {
+            network_address: "10.100.220.0/24".parse().unwrap(),
+            path_attributes: Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::sequence(vec![neighbor_as.into()])),
+                PathAttribute::NextHop(next_hop.parse().unwrap()),
+                PathAttribute::MultiExitDisc(med),
+            ]),
+            learned_from: None,
+        })
+    }
+
+    fn best_path_with(entries: &[Arc<RibEntry>], med_comparison: MedComparison) -> Arc<RibEntry> {
+        let mut rib = Rib::new();
+        entries.iter().for_each(|e| {
+            rib.insert(Arc::clone(e));
+        });
+        let network = "10.100.220.0/24".parse().unwrap();
+        LocRib::select_best_paths(
+            &rib,
+            med_comparison,
+            &BTreeSet::new(),
+            &HashSet::new(),
+            None,
+        )
+        .remove(&network)
+        .unwrap()
+    }
+
+    #[test]
+    fn rib_entries_are_ordered_by_best_path_preference() {
+        let entry = |as_path: Vec<u32>, origin: Origin, med: u32, next_hop: &str| {
+            Arc::new(RibEntry {
+                network_address: "10.100.220.0/24".parse().unwrap(),
+                path_attributes: Arc::new(vec![
+                    PathAttribute::Origin(origin),
+                    PathAttribute::AsPath(AsPath::sequence(
+                        as_path.into_iter().map(Into::into).collect(),
+                    )),
+                    PathAttribute::NextHop(next_hop.parse().unwrap()),
+                    PathAttribute::MultiExitDisc(med),
+                ]),
+                learned_from: None,
+            })
+        };
+        // 優先される順に並べている。
+        let expected = vec![
+            entry(vec![65002], Origin::Igp, 50, "10.200.100.5"),
+            // NEXT_HOPが大きい。
+            entry(vec![65003], Origin::Igp, 50, "10.200.100.6"),
+            // MEDが大きい。
+            entry(vec![65001], Origin::Igp, 100, "10.200.100.3"),
+            // ORIGINがINCOMPLETE。
+            entry(vec![65001], Origin::Incomplete, 0, "10.200.100.2"),
+            // AS_PATHが長い。
+            entry(vec![65001, 65002], Origin::Igp, 0, "10.200.100.1"),
+        ];
+        let mut entries = expected.clone();
+        entries.reverse();
+
+        let best = entries.iter().cloned().map(ByPreference).max().unwrap();
+        assert_eq!(best.0, expected[0]);
+        let always_compare_med = MedComparison {
+            always_compare_med: true,
+            ..Default::default()
+        };
+        assert_eq!(best_path_with(&entries, always_compare_med), expected[0]);
+
+        let mut heap: BinaryHeap<ByPreference> =
+            entries.iter().cloned().map(ByPreference).collect();
+        let mut popped = vec![];
+        while let Some(ByPreference(entry)) = heap.pop() {
+            popped.push(entry);
+        }
+        assert_eq!(popped, expected);
+
+        // 優先度が等しくても、Ribのkeyとしては別の経路である。
+        let same_preference = entry(vec![65004], Origin::Igp, 50, "10.200.100.5");
+        assert_eq!(
+            ByPreference(Arc::clone(&same_preference)),
+            ByPreference(Arc::clone(&expected[0]))
+        );
+        assert_ne!(same_preference, expected[0]);
+    }
+
+    #[tokio::test]
+    async fn invalid_routes_are_depreferred_or_dropped_by_origin_validation() {
+        let network: Ipv4Network = "10.100.220.0/24".parse().unwrap();
+        let route = |as_path: Vec<u32>, next_hop: &str| {
+            Arc::new(RibEntry {
                 network_address: network,
-                path_attributes: Arc::clone(&path_attributes),
-            });
+                path_attributes: Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::sequence(
+                        as_path.into_iter().map(Into::into).collect(),
+                    )),
+                    PathAttribute::NextHop(next_hop.parse().unwrap()),
+                ]),
+                learned_from: None,
+            })
+        };
+        // ROAではorigin ASは64512なので、AS_PATHが短い方がInvalidになる。
+        let invalid = route(vec![64513], "10.200.100.4");
+        let valid = route(vec![64514, 64512], "10.200.100.5");
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.insert(Arc::clone(&invalid));
+        adj_rib_in.insert(Arc::clone(&valid));
+
+        let loc_rib = |rpki_invalid: &str| {
+            let adj_rib_in = adj_rib_in.clone();
+            let config: Config = format!(
+                "64515 10.200.100.3 64513 10.200.100.4 active roa_file={}/tests/fixtures/roas.json \
+                rpki_invalid={rpki_invalid}",
+                env!("CARGO_MANIFEST_DIR")
+            )
+            .parse()
+            .unwrap();
+            async move {
+                let mut loc_rib = LocRib::new(&config).await.unwrap();
+                loc_rib.intsall_from_adj_rib_in(&adj_rib_in);
+                loc_rib
+            }
+        };
+
+        let accepted = loc_rib("accept").await;
+        assert_eq!(
+            accepted.validation_state(&invalid),
+            Some(ValidationState::Invalid)
+        );
+        assert_eq!(
+            accepted.validation_state(&valid),
+            Some(ValidationState::Valid)
+        );
+        assert_eq!(accepted.best_path(network), Some(&invalid));
+
+        let depreferred = loc_rib("de-prefer").await;
+        assert!(depreferred.routes().any(|e| *e == invalid));
+        assert_eq!(depreferred.best_path(network), Some(&valid));
+
+        let dropped = loc_rib("drop").await;
+        assert!(!dropped.routes().any(|e| *e == invalid));
+        assert_eq!(dropped.best_path(network), Some(&valid));
+    }
+
+    #[tokio::test]
+    async fn locally_originated_route_is_preferred_over_learned_route() {
+        let network: Ipv4Network = "10.0.0.0/24".parse().unwrap();
+        // AS_PATHもNEXT_HOPも、受信した経路の方が優先される値にしている。
+        let config: Config =
+            "64512 10.200.100.3 64513 10.200.100.2 active static_route=10.0.0.0/24:as_path=64600,64601"
+                .parse()
+                .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let originated = Arc::clone(loc_rib.best_path(network).unwrap());
+
+        let learned = Arc::new(RibEntry {
+            network_address: network,
+            path_attributes: Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::sequence(vec![64513.into()])),
+                PathAttribute::NextHop("10.200.100.2".parse().unwrap()),
+            ]),
+            learned_from: Some("10.200.100.2".parse().unwrap()),
+        });
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.insert(Arc::clone(&learned));
+        loc_rib.intsall_from_adj_rib_in(&adj_rib_in);
+
+        assert!(loc_rib.routes().any(|e| *e == learned));
+        assert_eq!(loc_rib.best_path(network), Some(&originated));
+    }
+
+    #[tokio::test]
+    async fn route_originated_by_ibgp_peer_is_not_treated_as_locally_originated() {
+        let network: Ipv4Network = "10.100.247.0/24".parse().unwrap();
+        let config: Config = "64512 10.200.100.3 64512 10.200.100.4 active"
+            .parse()
+            .unwrap();
+        let learned = |as_path: Vec<u32>, local_pref: u32, neighbor: &str| {
+            Arc::new(RibEntry {
+                network_address: network,
+                path_attributes: Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::sequence(
+                        as_path.into_iter().map(Into::into).collect(),
+                    )),
+                    PathAttribute::NextHop(neighbor.parse().unwrap()),
+                    PathAttribute::LocalPref(local_pref),
+                ]),
+                learned_from: Some(neighbor.parse().unwrap()),
+            })
+        };
+        // IBGPのpeerが広告元なのでAS_PATHは空だが、LOCAL_PREFが低い。
+        let internal = learned(vec![], 50, "10.200.100.4");
+        let external = learned(vec![64513], 100, "10.200.100.2");
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.insert(Arc::clone(&internal));
+        adj_rib_in.insert(Arc::clone(&external));
+        loc_rib.intsall_from_adj_rib_in(&adj_rib_in);
+
+        assert_eq!(loc_rib.best_path(network), Some(&external));
+        assert!(!internal.is_locally_originated());
+    }
+
+    #[tokio::test]
+    async fn persisted_routes_are_reloaded_as_stale_and_purged() {
+        let config: Config =
+            "64512 10.200.100.3 64513 10.200.100.2 active static_route=10.100.245.0/24"
+                .parse()
+                .unwrap();
+        let originated: Ipv4Network = "10.100.245.0/24".parse().unwrap();
+        let learned = Arc::new(RibEntry {
+            network_address: "10.100.246.0/24".parse().unwrap(),
+            path_attributes: Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::sequence(vec![64513.into()])),
+                PathAttribute::NextHop("10.200.100.2".parse().unwrap()),
+            ]),
+            learned_from: Some("10.200.100.2".parse().unwrap()),
+        });
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.insert(Arc::clone(&learned));
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        loc_rib.intsall_from_adj_rib_in(&adj_rib_in);
+
+        let dir = std::env::temp_dir().join(format!("mrbgpdv2-rib-store-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        loc_rib.persist(dir.join("loc_rib.json")).unwrap();
+        adj_rib_in.persist(dir.join("adj_rib_in.json")).unwrap();
+
+        // 再起動した後のLocRib。自身が広告元の経路はconfigから作り直され、staleにはならない。
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        assert_eq!(loc_rib.load(dir.join("loc_rib.json")).unwrap(), 1);
+        let mut adj_rib_in = AdjRibIn::new();
+        assert_eq!(adj_rib_in.load(dir.join("adj_rib_in.json")).unwrap(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(loc_rib.is_stale(&learned));
+        assert!(adj_rib_in.is_stale(&learned));
+        assert!(!loc_rib
+            .best_path(originated)
+            .is_some_and(|e| loc_rib.is_stale(e)));
+        // staleでも、他に経路が無ければbest pathとして用いる。
+        assert_eq!(loc_rib.best_path(learned.network_address), Some(&learned));
+
+        assert_eq!(loc_rib.purge_stale_routes(), 1);
+        assert_eq!(loc_rib.best_path(learned.network_address), None);
+        assert!(loc_rib.best_path(originated).is_some());
+    }
 
-            self.insert(rib_entry);
+    #[tokio::test]
+    async fn aggregate_address_with_as_set_summarizes_contributing_ases() {
+        let config: Config =
+            "64512 10.200.100.3 65001 10.200.100.2 active aggregate_address=10.100.0.0/16:as-set"
+                .parse()
+                .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let mut adj_rib_in = AdjRibIn::new();
+        for (network, neighbor_as, next_hop) in [
+            ("10.100.1.0/24", 65001, "10.200.100.2"),
+            ("10.100.2.0/24", 65002, "10.200.100.4"),
+        ] {
+            adj_rib_in.insert(Arc::new(RibEntry {
+                network_address: network.parse().unwrap(),
+                path_attributes: Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::sequence(vec![neighbor_as.into()])),
+                    PathAttribute::NextHop(next_hop.parse().unwrap()),
+                ]),
+                learned_from: Some(next_hop.parse().unwrap()),
+            }));
         }
+        loc_rib.intsall_from_adj_rib_in(&adj_rib_in);
+
+        let aggregate: Ipv4Network = "10.100.0.0/16".parse().unwrap();
+        let best = loc_rib.best_path(aggregate).unwrap();
+        assert!(best
+            .path_attributes
+            .contains(&PathAttribute::AsPath(AsPath::set(
+                [65001.into(), 65002.into()].into()
+            ))));
+        assert!(best
+            .path_attributes
+            .contains(&PathAttribute::Aggregator(64512.into(), config.local_ip)));
+
+        // 集約した経路は転送テーブルには書き込まない。
+        let fib = InMemoryFib::new();
+        loc_rib.write_to_fib(&fib).await.unwrap();
+        assert_eq!(fib.gateway(aggregate), None);
+        assert!(fib.gateway("10.100.1.0/24".parse().unwrap()).is_some());
+
+        adj_rib_in.withdraw_all();
+        loc_rib.intsall_from_adj_rib_in(&adj_rib_in);
+        assert!(loc_rib.best_path(aggregate).is_none());
     }
-}
 
-impl Deref for AdjRibIn {
-    type Target = Rib;
+    #[tokio::test]
+    async fn own_prefix_received_back_is_logged_or_dropped() {
+        let network: Ipv4Network = "10.0.0.0/24".parse().unwrap();
+        let update = || {
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::sequence(vec![65001.into()])),
+                    PathAttribute::NextHop("10.200.100.2".parse().unwrap()),
+                ]),
+                vec![network, "10.100.1.0/24".parse().unwrap()],
+                vec![],
+            )
+        };
+        let received = |reaction: &'static str| async move {
+            let config: Config = format!(
+                "64512 10.200.100.3 65001 10.200.100.2 active own_prefix_reaction={reaction}"
+            )
+            .parse()
+            .unwrap();
+            let mut loc_rib = LocRib::new(&config).await.unwrap();
+            loc_rib
+                .originate(network, config.local_ip, vec![], &InMemoryFib::new())
+                .await
+                .unwrap();
+            let mut adj_rib_in = AdjRibIn::from_config(&config);
+            adj_rib_in.install_from_update(update(), &config);
+            adj_rib_in.check_own_prefixes(&loc_rib, &config);
+            adj_rib_in
+                .routes()
+                .map(|e| e.network_address)
+                .collect::<BTreeSet<Ipv4Network>>()
+        };
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+        assert!(received("log").await.contains(&network));
+        assert_eq!(
+            received("drop").await,
+            BTreeSet::from(["10.100.1.0/24".parse().unwrap()])
+        );
     }
-}
 
-impl DerefMut for AdjRibIn {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+    #[tokio::test]
+    async fn fib_write_is_refused_when_table_shrinks_beyond_threshold() {
+        let config: Config =
+            "64512 10.200.100.3 64513 10.200.100.2 active fib_max_shrink_percent=50"
+                .parse()
+                .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        let networks: Vec<Ipv4Network> = (0..4)
+            .map(|i| format!("10.100.{}.0/24", 250 + i).parse().unwrap())
+            .collect();
+        let mut adj_rib_in = AdjRibIn::new();
+        for network in &networks {
+            adj_rib_in.insert(Arc::new(RibEntry {
+                network_address: *network,
+                path_attributes: Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::sequence(vec![64513.into()])),
+                    PathAttribute::NextHop("10.200.100.2".parse().unwrap()),
+                ]),
+                learned_from: Some("10.200.100.2".parse().unwrap()),
+            }));
+        }
+        let fib = InMemoryFib::new();
+        let apply = |adj_rib_in: &mut AdjRibIn, loc_rib: &mut LocRib| {
+            loc_rib.intsall_from_adj_rib_in(adj_rib_in);
+            adj_rib_in.update_to_all_changed();
+        };
+        apply(&mut adj_rib_in, &mut loc_rib);
+        loc_rib.write_to_fib(&fib).await.unwrap();
+        loc_rib.update_to_all_changed();
+
+        // 4経路から3経路へは、50%以内の減少なので書き込む。
+        adj_rib_in.withdraw(networks[0]);
+        apply(&mut adj_rib_in, &mut loc_rib);
+        loc_rib.write_to_fib(&fib).await.unwrap();
+        loc_rib.update_to_all_changed();
+        assert_eq!(fib.gateway(networks[0]), None);
+
+        // 3経路から1経路へは、50%を超えて減るので書き込まない。
+        adj_rib_in.withdraw(networks[1]);
+        adj_rib_in.withdraw(networks[2]);
+        apply(&mut adj_rib_in, &mut loc_rib);
+        assert!(loc_rib.write_to_fib(&fib).await.is_err());
+        for network in &networks[1..] {
+            assert_eq!(fib.gateway(*network), "10.200.100.2".parse().ok());
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tokio::time::{sleep, Duration};
+    #[tokio::test]
+    async fn equal_paths_are_installed_as_multipath_weighted_by_link_bandwidth() {
+        let network: Ipv4Network = "10.100.240.0/24".parse().unwrap();
+        let config: Config = "64512 10.200.100.3 64513 10.200.100.2 active maximum_paths=4"
+            .parse()
+            .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        // 1Gbpsと3Gbpsを、bytes/秒で広告する。
+        let path = |next_hop: &str, bandwidth: f32| {
+            Arc::new(RibEntry {
+                network_address: network,
+                path_attributes: Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::sequence(vec![64513.into()])),
+                    PathAttribute::NextHop(next_hop.parse().unwrap()),
+                    PathAttribute::ExtendedCommunities(vec![ExtendedCommunity::link_bandwidth(
+                        64513, bandwidth,
+                    )
+                    .into()]),
+                ]),
+                learned_from: Some(next_hop.parse().unwrap()),
+            })
+        };
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.insert(path("10.200.100.2", 125_000_000.0));
+        adj_rib_in.insert(path("10.200.100.4", 375_000_000.0));
+        loc_rib.intsall_from_adj_rib_in(&adj_rib_in);
+
+        let fib = InMemoryFib::new();
+        loc_rib.write_to_fib(&fib).await.unwrap();
+        assert_eq!(
+            fib.multipath(network),
+            Some(vec![
+                WeightedNextHop {
+                    gateway: "10.200.100.2".parse().unwrap(),
+                    weight: 1,
+                },
+                WeightedNextHop {
+                    gateway: "10.200.100.4".parse().unwrap(),
+                    weight: 3,
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn always_compare_med_compares_med_across_neighbor_ases() {
+        let high_med = rib_entry_with_med(65001, 100, "10.200.100.1");
+        let low_med = rib_entry_with_med(65002, 50, "10.200.100.2");
+        let entries = [Arc::clone(&high_med), Arc::clone(&low_med)];
+
+        let default = MedComparison::default();
+        assert_eq!(best_path_with(&entries, default), high_med);
+
+        let always_compare_med = MedComparison {
+            always_compare_med: true,
+            ..Default::default()
+        };
+        assert_eq!(best_path_with(&entries, always_compare_med), low_med);
+    }
+
+    #[test]
+    fn deterministic_med_groups_paths_by_neighbor_as_first() {
+        let a = rib_entry_with_med(65001, 200, "10.200.100.1");
+        let b = rib_entry_with_med(65002, 100, "10.200.100.2");
+        let c = rib_entry_with_med(65001, 50, "10.200.100.3");
+        let entries = [Arc::clone(&a), Arc::clone(&b), Arc::clone(&c)];
+
+        // a, b, cの順に比較すると、aがbに勝ち、cがaに勝つ。
+        assert_eq!(best_path_with(&entries, MedComparison::default()), c);
+
+        // 65001からはc、65002からはbが選ばれ、NEXT_HOPでbが勝つ。
+        let deterministic_med = MedComparison {
+            deterministic_med: true,
+            ..Default::default()
+        };
+        assert_eq!(best_path_with(&entries, deterministic_med), b);
+
+        // 全てのMEDを比較するとcが選ばれる。
+        let both = MedComparison {
+            always_compare_med: true,
+            deterministic_med: true,
+        };
+        assert_eq!(best_path_with(&entries, both), c);
+    }
+
+    #[test]
+    fn identical_paths_are_tie_broken_by_lower_neighbor_address() {
+        let learned_from = |address: &str| {
+            Arc::new(RibEntry {
+                learned_from: Some(address.parse().unwrap()),
+                ..(*rib_entry_with_med(65001, 100, "10.200.100.1")).clone()
+            })
+        };
+        let higher = learned_from("10.200.100.3");
+        let lower = learned_from("10.200.100.2");
+
+        // 比較する順序に関わらず、addressの小さいneighborからの経路が選ばれる。
+        let entries = [Arc::clone(&higher), Arc::clone(&lower)];
+        assert_eq!(best_path_with(&entries, MedComparison::default()), lower);
+        let entries = [Arc::clone(&lower), Arc::clone(&higher)];
+        assert_eq!(best_path_with(&entries, MedComparison::default()), lower);
+    }
+
+    #[test]
+    fn adj_rib_in_accepts_only_routes_with_matching_route_target() {
+        let config: Config =
+            "64513 10.200.100.3 64512 10.200.100.2 passive import_route_target=65000:100"
+                .parse()
+                .unwrap();
+        let update = |network: &str, local_admin: u32| {
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::sequence(vec![64512.into()])),
+                    PathAttribute::NextHop("10.200.100.2".parse().unwrap()),
+                    PathAttribute::ExtendedCommunities(vec![ExtendedCommunity::RouteTarget {
+                        global_admin: 65000,
+                        local_admin,
+                    }
+                    .into()]),
+                ]),
+                vec![network.parse().unwrap()],
+                vec![],
+            )
+        };
+
+        let mut adj_rib_in = AdjRibIn::from_config(&config);
+        adj_rib_in.install_from_update(update("10.100.220.0/24", 100), &config);
+        adj_rib_in.install_from_update(update("10.100.221.0/24", 200), &config);
+
+        let networks: Vec<Ipv4Network> = adj_rib_in.routes().map(|e| e.network_address).collect();
+        assert_eq!(networks, vec!["10.100.220.0/24".parse().unwrap()]);
+    }
+
+    #[test]
+    fn adj_rib_in_drops_routes_to_bogon_prefixes() {
+        let config: Config = "64513 10.200.100.3 64512 10.200.100.2 passive bogon_filter=martians"
+            .parse()
+            .unwrap();
+        let update = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::sequence(vec![64512.into()])),
+                PathAttribute::NextHop("10.200.100.2".parse().unwrap()),
+            ]),
+            vec![
+                "127.0.0.0/8".parse().unwrap(),
+                "203.0.113.0/24".parse().unwrap(),
+            ],
+            vec![],
+        );
+
+        let mut adj_rib_in = AdjRibIn::from_config(&config);
+        adj_rib_in.install_from_update(update, &config);
+
+        let networks: Vec<Ipv4Network> = adj_rib_in.routes().map(|e| e.network_address).collect();
+        assert_eq!(networks, vec!["203.0.113.0/24".parse().unwrap()]);
+        assert_eq!(adj_rib_in.bogon_routes_dropped(), 1);
+    }
+
+    #[test]
+    fn duplicate_nlri_in_one_update_is_installed_once() {
+        let config: Config = "64513 10.200.100.3 64512 10.200.100.2 passive"
+            .parse()
+            .unwrap();
+        let update = UpdateMessage::new(
+            Arc::new(vec![
+                PathAttribute::Origin(Origin::Igp),
+                PathAttribute::AsPath(AsPath::sequence(vec![64512.into()])),
+                PathAttribute::NextHop("10.200.100.2".parse().unwrap()),
+            ]),
+            vec![
+                "10.100.220.0/24".parse().unwrap(),
+                "10.100.221.0/24".parse().unwrap(),
+                "10.100.220.0/24".parse().unwrap(),
+            ],
+            vec![],
+        );
+
+        let mut adj_rib_in = AdjRibIn::from_config(&config);
+        adj_rib_in.install_from_update(update, &config);
+
+        let mut networks: Vec<Ipv4Network> =
+            adj_rib_in.routes().map(|e| e.network_address).collect();
+        networks.sort();
+        assert_eq!(
+            networks,
+            vec![
+                "10.100.220.0/24".parse().unwrap(),
+                "10.100.221.0/24".parse().unwrap()
+            ]
+        );
+        assert_eq!(adj_rib_in.new_routes().count(), 2);
+        assert_eq!(adj_rib_in.duplicate_nlri(), 1);
+    }
 
     #[tokio::test]
-    async fn loclib_can_lookup_routing_table() {
-        let network = ipnetwork::Ipv4Network::new("10.200.100.0".parse().unwrap(), 24)
-            .unwrap()
-            .into();
-        let routes = LocRib::lookup_kernel_routing_table(network).await.unwrap();
-        let expected = vec![network];
-        assert_eq!(routes, expected);
+    async fn as_override_replaces_remote_as_on_egress() {
+        let config: Config = "64513 10.200.100.3 64512 10.200.100.2 passive as_override=true"
+            .parse()
+            .unwrap();
+        let mut loc_rib = LocRib::new(&config).await.unwrap();
+        loc_rib
+            .originate(
+                "10.100.220.0/24".parse().unwrap(),
+                "10.200.100.4".parse().unwrap(),
+                vec![PathAttribute::AsPath(AsPath::sequence(vec![
+                    64514.into(),
+                    64512.into(),
+                ]))],
+                &InMemoryFib::new(),
+            )
+            .await
+            .unwrap();
+        let mut adj_rib_out = AdjRibOut::new();
+        adj_rib_out.install_from_loc_rib(&loc_rib, &config);
+
+        let updates = adj_rib_out.create_announcement_messages(&config);
+        assert_eq!(updates.len(), 1);
+        assert!(updates[0]
+            .path_attributes
+            .contains(&PathAttribute::AsPath(AsPath::sequence(vec![
+                64514.into(),
+                64513.into(),
+                64513.into()
+            ]))));
     }
 
     #[tokio::test]
-    async fn loclib_to_adj_rib_out() {
-        let config = "64513 10.200.100.3 64512 10.200.100.2 passive 10.100.220.0/24"
+    async fn graceful_shutdown_tags_announcements_with_community() {
+        let config: Config = "64513 10.200.100.3 64512 10.200.100.2 passive graceful_shutdown=true"
             .parse()
             .unwrap();
         let mut loc_rib = LocRib::new(&config).await.unwrap();
+        loc_rib
+            .originate(
+                "10.100.220.0/24".parse().unwrap(),
+                "10.200.100.4".parse().unwrap(),
+                vec![PathAttribute::AsPath(AsPath::sequence(vec![64514.into()]))],
+                &InMemoryFib::new(),
+            )
+            .await
+            .unwrap();
         let mut adj_rib_out = AdjRibOut::new();
-        adj_rib_out.install_from_loc_rib(&mut loc_rib, &config);
+        adj_rib_out.install_from_loc_rib(&loc_rib, &config);
 
-        let mut expected_adj_rib_out = AdjRibOut::new();
-        expected_adj_rib_out.insert(Arc::new(RibEntry {
-            network_address: "10.100.220.0/24".parse().unwrap(),
-            path_attributes: Arc::new(vec![
+        let updates = adj_rib_out.create_announcement_messages(&config);
+        assert_eq!(updates.len(), 1);
+        assert!(updates[0]
+            .path_attributes
+            .contains(&PathAttribute::Communities(vec![GRACEFUL_SHUTDOWN])));
+    }
+
+    #[test]
+    fn route_with_graceful_shutdown_community_is_depreferred() {
+        let entry = |as_path: Vec<u32>, communities: Vec<u32>| {
+            let mut path_attributes = vec![
                 PathAttribute::Origin(Origin::Igp),
-                PathAttribute::AsPath(AsPath::AsSequence(vec![])),
-                PathAttribute::NextHop("10.200.100.3".parse().unwrap()),
-            ]),
-        }));
-        assert_eq!(adj_rib_out, expected_adj_rib_out);
+                PathAttribute::AsPath(AsPath::sequence(
+                    as_path.into_iter().map(Into::into).collect(),
+                )),
+                PathAttribute::NextHop("10.200.100.2".parse().unwrap()),
+            ];
+            communities
+                .into_iter()
+                .for_each(|c| PathAttribute::add_community(&mut path_attributes, c));
+            Arc::new(RibEntry {
+                network_address: "10.100.220.0/24".parse().unwrap(),
+                path_attributes: Arc::new(path_attributes),
+                learned_from: None,
+            })
+        };
+        // AS_PATHは短いが、GRACEFUL_SHUTDOWNが付いているので選ばれない。
+        let shutting_down = entry(vec![64513], vec![GRACEFUL_SHUTDOWN]);
+        let other = entry(vec![64514, 64515, 64513], vec![]);
+        let entries = [Arc::clone(&shutting_down), Arc::clone(&other)];
+        assert_eq!(best_path_with(&entries, MedComparison::default()), other);
+
+        // 他のcommunityは優先度に影響しない。
+        let tagged = entry(vec![64513], vec![0xFDE8_0001]);
+        let entries = [Arc::clone(&tagged), Arc::clone(&other)];
+        assert_eq!(best_path_with(&entries, MedComparison::default()), tagged);
+    }
+
+    #[test]
+    fn allowas_in_accepts_local_as_up_to_configured_count() {
+        let config: Config = "64513 10.200.100.3 64512 10.200.100.2 passive allowas_in=2"
+            .parse()
+            .unwrap();
+        let update = |network: &str, as_path: Vec<u32>| {
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::sequence(
+                        as_path
+                            .into_iter()
+                            .map(AutonomousSystemNumber::from)
+                            .collect(),
+                    )),
+                    PathAttribute::NextHop("10.200.100.2".parse().unwrap()),
+                ]),
+                vec![network.parse().unwrap()],
+                vec![],
+            )
+        };
+
+        let mut adj_rib_in = AdjRibIn::from_config(&config);
+        adj_rib_in.install_from_update(
+            update("10.100.220.0/24", vec![64512, 64513, 64513]),
+            &config,
+        );
+        adj_rib_in.install_from_update(
+            update("10.100.221.0/24", vec![64512, 64513, 64513, 64513]),
+            &config,
+        );
+
+        let networks: Vec<Ipv4Network> = adj_rib_in.routes().map(|e| e.network_address).collect();
+        assert_eq!(networks, vec!["10.100.220.0/24".parse().unwrap()]);
+    }
+
+    #[test]
+    fn update_applies_withdrawals_before_announcements() {
+        let config: Config = "64513 10.200.100.3 64512 10.200.100.2 passive"
+            .parse()
+            .unwrap();
+        let update = |med: u32, nlri: Vec<&str>, withdrawn: Vec<&str>| {
+            UpdateMessage::new(
+                Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::sequence(vec![64512.into()])),
+                    PathAttribute::NextHop("10.200.100.2".parse().unwrap()),
+                    PathAttribute::MultiExitDisc(med),
+                ]),
+                nlri.into_iter().map(|n| n.parse().unwrap()).collect(),
+                withdrawn.into_iter().map(|n| n.parse().unwrap()).collect(),
+            )
+        };
+        let networks = |adj_rib_in: &AdjRibIn| {
+            let mut networks: Vec<(String, u32)> = adj_rib_in
+                .routes()
+                .map(|e| (e.network_address.to_string(), e.multi_exit_disc()))
+                .collect();
+            networks.sort();
+            networks
+        };
+
+        let mut adj_rib_in = AdjRibIn::new();
+        adj_rib_in.install_from_update(update(0, vec!["10.0.0.0/24"], vec![]), &config);
+        adj_rib_in
+            .install_from_update(update(0, vec!["10.0.1.0/24"], vec!["10.0.0.0/24"]), &config);
+        assert_eq!(networks(&adj_rib_in), vec![("10.0.1.0/24".to_owned(), 0)]);
+
+        // 同じUPDATEで取り消して広告し直したprefixは、新しい経路だけが残る。
+        adj_rib_in.install_from_update(
+            update(10, vec!["10.0.1.0/24"], vec!["10.0.1.0/24"]),
+            &config,
+        );
+        assert_eq!(networks(&adj_rib_in), vec![("10.0.1.0/24".to_owned(), 10)]);
+
+        // 取り消さずに広告し直した場合も、以前の経路を置き換える。
+        adj_rib_in.install_from_update(update(20, vec!["10.0.1.0/24"], vec![]), &config);
+        assert_eq!(networks(&adj_rib_in), vec![("10.0.1.0/24".to_owned(), 20)]);
+    }
+
+    #[test]
+    fn truncated_vpnv4_nlri_is_error() {
+        let network = VpnV4Network::new(
+            100,
+            RouteDistinguisher::Type0 {
+                admin: 64512,
+                assigned: 1,
+            },
+            "10.100.220.0/24".parse().unwrap(),
+        );
+        let bytes = BytesMut::from(&network);
+        assert_eq!(VpnV4Network::from_u8_slice(&bytes).unwrap(), vec![network]);
+
+        // labelとRDの途中、prefixの途中で終わっているもの。
+        for len in [2, 8, bytes.len() - 1] {
+            let error = VpnV4Network::from_u8_slice(&bytes[..len]).unwrap_err();
+            assert!(
+                format!("{error:?}").contains("VPNv4 NLRIのbytes列が途中で終わっています。"),
+                "{error:?}"
+            );
+        }
     }
 }
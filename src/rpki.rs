@@ -0,0 +1,137 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::bgp_type::AutonomousSystemNumber;
+use crate::path_attribute::{AsPath, AsPathSegment, PathAttribute};
+use crate::routing::{Ipv4Network, RibEntry};
+
+/// Route Origin Authorization。prefixとそれより長いmax_lengthまでのprefixを、origin_asが広告できる。
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
+pub struct Roa {
+    pub prefix: Ipv4Network,
+    pub max_length: u8,
+    pub origin_as: AutonomousSystemNumber,
+}
+
+/// 経路のorigin validationの結果(RFC 6811 Section 2)。
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Serialize, Deserialize)]
+pub enum ValidationState {
+    /// prefixを含むROAがあり、そのいずれかにorigin ASとprefix長が一致する。
+    Valid,
+    /// prefixを含むROAがあるが、どれにも一致しない。
+    Invalid,
+    /// prefixを含むROAが無い。
+    NotFound,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize, Deserialize)]
+pub struct RoaTable {
+    roas: Vec<Roa>,
+}
+
+impl RoaTable {
+    /// `{"roas": [{"prefix": "10.100.0.0/16", "max_length": 24, "origin_as": 64512}]}`
+    /// のように書かれたJSONのファイルから読み込む。
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path)
+            .context(format!("cannot read roa file {}", path.display()))?;
+        serde_json::from_str(&json).context(format!("cannot parse roa file {}", path.display()))
+    }
+
+    pub fn validate(&self, entry: &RibEntry) -> ValidationState {
+        let network = entry.network_address;
+        let origin_as = Self::origin_as(entry);
+        let mut covering = self
+            .roas
+            .iter()
+            .filter(|roa| network.is_subnet_of(*roa.prefix))
+            .peekable();
+        if covering.peek().is_none() {
+            return ValidationState::NotFound;
+        }
+        let matches =
+            |roa: &Roa| Some(roa.origin_as) == origin_as && network.prefix() <= roa.max_length;
+        if covering.any(matches) {
+            ValidationState::Valid
+        } else {
+            ValidationState::Invalid
+        }
+    }
+
+    /// AS_SEQUENCEの最後のAS。AS_SETで終わる場合などはorigin ASが無く、どのROAにも一致しない。
+    fn origin_as(entry: &RibEntry) -> Option<AutonomousSystemNumber> {
+        entry.path_attributes.iter().find_map(|p| match p {
+            PathAttribute::AsPath(AsPath(segments)) => match segments.last() {
+                Some(AsPathSegment::AsSequence(seq)) => seq.last().copied(),
+                _ => None,
+            },
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn entry(network: &str, as_path: Vec<u32>) -> RibEntry {
+        RibEntry {
+            network_address: network.parse().unwrap(),
+            path_attributes: Arc::new(vec![PathAttribute::AsPath(AsPath::sequence(
+                as_path.into_iter().map(Into::into).collect(),
+            ))]),
+            learned_from: None,
+        }
+    }
+
+    fn roa_table() -> RoaTable {
+        serde_json::from_str(
+            r#"{"roas": [{"prefix": "10.100.0.0/16", "max_length": 24, "origin_as": 64512}]}"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn route_from_authorized_origin_within_max_length_is_valid() {
+        let table = roa_table();
+        assert_eq!(
+            table.validate(&entry("10.100.220.0/24", vec![64513, 64512])),
+            ValidationState::Valid
+        );
+        assert_eq!(
+            table.validate(&entry("10.100.0.0/16", vec![64512])),
+            ValidationState::Valid
+        );
+    }
+
+    #[test]
+    fn route_from_wrong_origin_or_too_specific_is_invalid() {
+        let table = roa_table();
+        assert_eq!(
+            table.validate(&entry("10.100.220.0/24", vec![64512, 64513])),
+            ValidationState::Invalid
+        );
+        assert_eq!(
+            table.validate(&entry("10.100.220.0/25", vec![64512])),
+            ValidationState::Invalid
+        );
+    }
+
+    #[test]
+    fn route_without_covering_roa_is_not_found() {
+        let table = roa_table();
+        assert_eq!(
+            table.validate(&entry("10.200.0.0/24", vec![64512])),
+            ValidationState::NotFound
+        );
+        assert_eq!(
+            table.validate(&entry("10.0.0.0/8", vec![64512])),
+            ValidationState::NotFound
+        );
+    }
+}
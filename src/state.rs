@@ -1,4 +1,6 @@
-#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+use serde::Serialize;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, Serialize)]
 pub enum State {
     Idle,
     Connect,
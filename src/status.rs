@@ -0,0 +1,176 @@
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+
+use serde::Serialize;
+
+use crate::path_attribute::hex_bytes::to_hex;
+use crate::routing::RibEntry;
+use crate::state::State;
+
+/// peerの状態を外部に見せるための表現。JSONにserializeして用いる。
+#[derive(PartialEq, Eq, Debug, Clone, Serialize)]
+pub struct PeerStatus {
+    pub description: Option<String>,
+    pub local_as: u32,
+    pub remote_as: u32,
+    pub remote_ip: Ipv4Addr,
+    pub state: State,
+    pub adj_rib_in_routes: usize,
+    pub adj_rib_out_routes: usize,
+    pub notifications_received: u64,
+    /// Graceful Restart中に保持しているstaleな経路のprefix。
+    pub stale_routes: Vec<String>,
+    /// 解釈できずに捨てた、直近のMessage。
+    pub dead_letters: Vec<DeadLetter>,
+    /// OPENの交換で決まったパラメータ。OpenConfirm以降でだけ存在する。
+    pub negotiated: Option<NegotiatedParams>,
+    /// Establishedになってから、LocRibのbest pathが最後に変化するまでのmilli秒。
+    pub convergence_time_millis: Option<u64>,
+    /// 接続の失敗が続いた回数。OPENの交換に成功すると0に戻る。
+    pub connect_retry_counter: u32,
+    /// `bogon_filter`で捨てた受信経路の数。
+    pub bogon_routes_dropped: u64,
+    /// 1つのUPDATEのNLRIに重複して含まれていたため、取り除いたprefixの数。
+    pub duplicate_nlri: u64,
+    /// LocRib全体で、best pathの変化回数が多い順に並べたprefix。
+    pub top_churning_prefixes: Vec<ChurningPrefix>,
+}
+
+impl PeerStatus {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("PeerStatusはJSONにserializeできるはずです。")
+    }
+}
+
+/// best pathが何度も変化しているprefix。
+#[derive(PartialEq, Eq, Debug, Clone, Serialize)]
+pub struct ChurningPrefix {
+    pub network: String,
+    /// best pathが変化した回数。
+    pub count: u64,
+    /// best pathが最後に変化してからのmilli秒。
+    pub millis_since_last_change: u64,
+}
+
+/// OPENの交換で決まったセッションのパラメータ。
+#[derive(PartialEq, Eq, Debug, Clone, Serialize)]
+pub struct NegotiatedParams {
+    /// 自身と相手のHold Timeの小さい方。0の場合はKEEPALIVEを送らない。
+    pub hold_time: u16,
+    /// KEEPALIVEを送る間隔の目安。実際にはtimer_jitterの範囲で増減する。
+    pub keepalive_interval: u16,
+    /// 自身と相手の両方が広告したCapabilityの名前。
+    pub capabilities: Vec<String>,
+    pub remote_router_id: Ipv4Addr,
+    pub remote_as: u32,
+}
+
+/// 解釈できずに捨てたMessageと、その理由。
+#[derive(PartialEq, Eq, Debug, Clone, Serialize)]
+pub struct DeadLetter {
+    /// 受信したbytesを16進数で表したもの。
+    pub bytes: String,
+    pub reason: String,
+}
+
+/// 解釈できずに捨てたMessageを、直近のcapacity個だけ保持するring buffer。
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct DeadLetterLog {
+    dead_letters: VecDeque<DeadLetter>,
+    capacity: usize,
+}
+
+impl DeadLetterLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            dead_letters: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// capacityを超える場合は、最も古いものを捨てる。
+    pub fn push(&mut self, bytes: &[u8], reason: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.dead_letters.len() == self.capacity {
+            self.dead_letters.pop_front();
+        }
+        self.dead_letters.push_back(DeadLetter {
+            bytes: to_hex(bytes),
+            reason,
+        });
+    }
+
+    /// 古い順に返す。
+    pub fn dead_letters(&self) -> Vec<DeadLetter> {
+        self.dead_letters.iter().cloned().collect()
+    }
+}
+
+/// peerから受信した経路を、全てのPath Attributeとともに外部に見せるための表現。
+#[derive(PartialEq, Eq, Debug, Clone, Serialize)]
+pub struct AdjRibInStatus {
+    pub remote_ip: Ipv4Addr,
+    pub routes: Vec<RibEntry>,
+}
+
+impl AdjRibInStatus {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("AdjRibInStatusはJSONにserializeできるはずです。")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::path_attribute::{AsPath, Origin, PathAttribute};
+
+    #[test]
+    fn adj_rib_in_status_serializes_all_path_attributes() {
+        // 解釈しなかったattributeは、受信したbytesを16進数で表す。
+        let communities = vec![0xc0, 0x08, 0x04, 0xfd, 0xe9, 0x00, 0x64];
+        let status = AdjRibInStatus {
+            remote_ip: "10.200.100.3".parse().unwrap(),
+            routes: vec![RibEntry {
+                network_address: "10.100.220.0/24".parse().unwrap(),
+                path_attributes: Arc::new(vec![
+                    PathAttribute::Origin(Origin::Igp),
+                    PathAttribute::AsPath(AsPath::sequence(vec![65001.into(), 65002.into()])),
+                    PathAttribute::NextHop("10.200.100.3".parse().unwrap()),
+                    PathAttribute::MultiExitDisc(100),
+                    PathAttribute::ExtendedCommunities(vec![[
+                        0x00, 0x02, 0xfd, 0xe9, 0x00, 0x00, 0x00, 0x64,
+                    ]]),
+                    PathAttribute::DontKnow(communities),
+                ]),
+                learned_from: None,
+            }],
+        };
+
+        let json: serde_json::Value = serde_json::from_str(&status.to_json()).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "remote_ip": "10.200.100.3",
+                "routes": [{
+                    "network_address": "10.100.220.0/24",
+                    "path_attributes": [
+                        {"Origin": "Igp"},
+                        {"AsPath": [{"AsSequence": [65001, 65002]}]},
+                        {"NextHop": "10.200.100.3"},
+                        {"MultiExitDisc": 100},
+                        {"ExtendedCommunities": ["0002fde900000064"]},
+                        {"DontKnow": "c00804fde90064"},
+                    ],
+                }],
+            })
+        );
+        let route: RibEntry = serde_json::from_value(json["routes"][0].clone()).unwrap();
+        assert_eq!(route, status.routes[0]);
+    }
+}